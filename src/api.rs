@@ -1,16 +1,34 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Read, Write};
 use std::str::FromStr;
+use std::sync::Arc;
 
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, RootCertStore, SignatureScheme};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::net::TcpStream;
 use tokio::time::Duration;
 use tokio_tungstenite::{
-    connect_async,
+    connect_async_tls_with_config,
+    tungstenite::client::IntoClientRequest,
+    tungstenite::handshake::client::{Request, Response},
+    tungstenite::http::header::{HeaderName, HeaderValue, AUTHORIZATION},
     tungstenite::http::{uri, Uri},
-    MaybeTlsStream, WebSocketStream,
+    Connector, MaybeTlsStream, WebSocketStream,
 };
-use tracing::{debug, error, warn};
+use tracing::{debug, error, info, warn};
+use x509_parser::prelude::{ASN1Time, FromDer, X509Certificate};
 
-use crate::config::ConnectionConfig;
+use crate::config::{
+    check_server_scheme, host_match, AuthMode, Collector, Compression, CompressionConfig,
+    ConnectionConfig, Encoding, MetricsFormat, TransportKind, TrustedSelfSigned,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Message<T> {
@@ -23,69 +41,976 @@ pub struct ProbeConfig {
     pub metrics_interval: u64,
 }
 
-fn build_uri(server: &str, secret: &str) -> Uri {
-    let mut uri_parts = Uri::from_str(server).expect("Invalid URL").into_parts();
-    let path_and_query = uri_parts
-        .path_and_query
-        .as_ref()
-        .map(|pq| {
-            if pq.path() == "/" {
-                "/wss/probe".to_string()
-            } else {
-                pq.to_string()
+/// Sent by the server as `reconfigure` to change what an endpoint with
+/// `allow_remote_reconfigure` reports. Absent fields are left as they are;
+/// unknown ones reject the whole message.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Reconfigure {
+    #[serde(default)]
+    pub metrics_interval: Option<u64>,
+    #[serde(default)]
+    pub collectors: Option<Vec<Collector>>,
+    /// Replaces the endpoint's field map, see [`remap_fields`]
+    #[serde(default)]
+    pub field_map: Option<BTreeMap<String, String>>,
+    /// Switching to `array` sends a fresh `metrics_schema` first
+    #[serde(default)]
+    pub format: Option<MetricsFormat>,
+}
+
+/// Sent on connect when VM info deduplication is enabled; the server answers
+/// with `vm_info_known` or `vm_info_unknown`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VMInfoHash {
+    pub hash: String,
+}
+
+/// Sent instead of metrics by endpoints with `heartbeat_when_blind` when
+/// collection failed entirely, so the server can tell an agent that can't
+/// read metrics from one that's gone.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Heartbeat {
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+    pub uptime: u64,
+    pub collection_errors: Vec<String>,
+}
+
+/// Renames fields of a serialized report according to `field_map`, whose keys
+/// are dot-separated snake_case paths like `system.cpu_usage`. Paths that
+/// don't exist in `value` are ignored.
+pub fn remap_fields(value: &mut serde_json::Value, field_map: &BTreeMap<String, String>) {
+    let mut mappings: Vec<(Vec<String>, &String)> = field_map
+        .iter()
+        .map(|(path, output)| (path.split('.').map(to_camel_case).collect(), output))
+        .collect();
+    // Rename nested fields before their parents so parent paths still resolve
+    mappings.sort_by_key(|(segments, _)| std::cmp::Reverse(segments.len()));
+
+    'mappings: for (segments, output) in mappings {
+        let Some((field, parents)) = segments.split_last() else {
+            continue;
+        };
+        let mut target = &mut *value;
+        for segment in parents {
+            match target.get_mut(segment) {
+                Some(next) => target = next,
+                None => continue 'mappings,
+            }
+        }
+        if let Some(object) = target.as_object_mut() {
+            if let Some(field_value) = object.remove(field) {
+                object.insert(output.clone(), field_value);
+            }
+        }
+    }
+}
+
+/// Fields of a serialized report repeated in every message of a split one.
+const SHARED_FIELDS: [&str; 6] = [
+    "uptime",
+    "collectedAt",
+    "monoNs",
+    "seq",
+    "degraded",
+    "warmup",
+];
+
+/// Splits a serialized report into one `<collector>_metrics` message per
+/// collector in `collectors`, each carrying the shared fields (with `seq`
+/// added) and that collector's section. Sections of no particular collector,
+/// like gauges and collection errors, go with `system_metrics`, or with the
+/// first message when the system collector is off.
+pub fn split_by_collector(
+    value: serde_json::Value,
+    collectors: &[Collector],
+    seq: u64,
+) -> Vec<(String, serde_json::Value)> {
+    let serde_json::Value::Object(mut fields) = value else {
+        return Vec::new();
+    };
+    fields.insert("seq".to_string(), seq.into());
+    let shared: serde_json::Map<_, _> = fields
+        .iter()
+        .filter(|(key, _)| SHARED_FIELDS.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+
+    let mut messages: Vec<(String, serde_json::Map<_, _>)> = collectors
+        .iter()
+        .map(|collector| {
+            let mut data = shared.clone();
+            let section = report_section(*collector);
+            if let Some(value) = fields.remove(section) {
+                data.insert(section.to_string(), value);
             }
+            (format!("{}_metrics", collector), data)
         })
-        .unwrap_or_else(|| "/wss/probe".to_string());
+        .collect();
+
+    let rest = messages
+        .iter()
+        .position(|(r#type, _)| r#type == "system_metrics")
+        .or((!messages.is_empty()).then_some(0));
+    if let Some(rest) = rest {
+        // Sections of disabled collectors are absent anyway
+        let sections: Vec<&str> = Collector::ALL.into_iter().map(report_section).collect();
+        for (key, value) in fields {
+            if !SHARED_FIELDS.contains(&key.as_str()) && !sections.contains(&key.as_str()) {
+                messages[rest].1.insert(key, value);
+            }
+        }
+    }
+    messages
+        .into_iter()
+        .map(|(r#type, data)| (r#type, serde_json::Value::Object(data)))
+        .collect()
+}
+
+/// The order of the values in `metrics_array` messages, sent as
+/// `metrics_schema` before the first one and again whenever fields are
+/// added. Each field is the path of keys to a value of the `metrics` report.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricsSchema {
+    pub version: u32,
+    pub fields: Vec<Vec<String>>,
+}
+
+/// A report as the values of the fields of schema `schema_version`, in
+/// order, nil for fields the report doesn't have.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsArray {
+    pub schema_version: u32,
+    pub values: Vec<serde_json::Value>,
+}
+
+/// Encodes reports as [`MetricsArray`]s. Fields are only ever appended to
+/// the schema, so sections coming and going don't churn it.
+#[derive(Default)]
+pub struct ArrayEncoder {
+    schema: Option<MetricsSchema>,
+}
+
+impl ArrayEncoder {
+    /// Encodes a serialized report, also returning the schema if it changed
+    /// and has to be sent first.
+    pub fn encode(&mut self, report: &serde_json::Value) -> (Option<MetricsSchema>, MetricsArray) {
+        let mut leaves = HashMap::new();
+        collect_leaves(report, &mut Vec::new(), &mut leaves);
+
+        let mut changed = self.schema.is_none();
+        let schema = self.schema.get_or_insert(MetricsSchema {
+            version: 0,
+            fields: Vec::new(),
+        });
+        let known: HashSet<&Vec<String>> = schema.fields.iter().collect();
+        let mut added: Vec<Vec<String>> = leaves
+            .keys()
+            .filter(|path| !known.contains(path))
+            .cloned()
+            .collect();
+        if !added.is_empty() {
+            added.sort();
+            schema.fields.extend(added);
+            changed = true;
+        }
+        if changed {
+            schema.version += 1;
+        }
+
+        let values = schema
+            .fields
+            .iter()
+            .map(|field| leaves.remove(field).unwrap_or_default())
+            .collect();
+        let array = MetricsArray {
+            schema_version: schema.version,
+            values,
+        };
+        (changed.then(|| schema.clone()), array)
+    }
+}
+
+/// Rebuilds the serialized report of a `metrics_array` message from the
+/// schema it was sent with. The agent never decodes its own arrays; this is
+/// the reference decoder for servers built on this crate, and what the
+/// integration tests check the encoding against.
+pub fn decode_array(
+    schema: &MetricsSchema,
+    array: &MetricsArray,
+) -> Result<serde_json::Value, String> {
+    if schema.version != array.schema_version {
+        return Err(format!(
+            "metrics use schema version {}, not {}",
+            array.schema_version, schema.version
+        ));
+    }
+    if schema.fields.len() != array.values.len() {
+        return Err(format!(
+            "schema has {} fields, metrics have {} values",
+            schema.fields.len(),
+            array.values.len()
+        ));
+    }
+    let mut report = serde_json::Map::new();
+    for (field, value) in schema.fields.iter().zip(&array.values) {
+        let Some((last, parents)) = field.split_last() else {
+            continue;
+        };
+        if value.is_null() {
+            continue;
+        }
+        let mut object = &mut report;
+        for key in parents {
+            let entry = object
+                .entry(key.clone())
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+            let Some(next) = entry.as_object_mut() else {
+                return Err(format!("field '{}' is both a value and a section", key));
+            };
+            object = next;
+        }
+        object.insert(last.clone(), value.clone());
+    }
+    Ok(serde_json::Value::Object(report))
+}
+
+/// Maps the path of every value in `value` that isn't a non-empty object to
+/// the value.
+fn collect_leaves(
+    value: &serde_json::Value,
+    path: &mut Vec<String>,
+    leaves: &mut HashMap<Vec<String>, serde_json::Value>,
+) {
+    match value {
+        serde_json::Value::Object(object) if !object.is_empty() => {
+            for (key, value) in object {
+                path.push(key.clone());
+                collect_leaves(value, path, leaves);
+                path.pop();
+            }
+        }
+        _ => {
+            leaves.insert(path.clone(), value.clone());
+        }
+    }
+}
+
+/// The field of a serialized report holding what `collector` collected.
+fn report_section(collector: Collector) -> &'static str {
+    match collector {
+        Collector::System => "system",
+        Collector::Network => "network",
+        Collector::Disk => "disk",
+        Collector::Gateway => "gateway",
+        Collector::Oom => "oomKills",
+    }
+}
+
+fn to_camel_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut upper = false;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            result.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// The URL to connect to for `server`, with `secret` added to its query if
+/// given. A server given without a path connects to `path`, which may carry a
+/// query of its own.
+pub fn build_uri(server: &str, path: &str, secret: Option<&str>) -> Result<Uri, String> {
+    build_transport_uri(TransportKind::WebSocket, server, path, secret)
+}
+
+/// [`build_uri`] for a server reached over `transport`. A Unix socket server
+/// gets the handshake URL `ws://localhost` with `path`.
+pub fn build_transport_uri(
+    transport: TransportKind,
+    server: &str,
+    path: &str,
+    secret: Option<&str>,
+) -> Result<Uri, String> {
+    if server.is_empty() {
+        return Err("server URL is empty".to_string());
+    }
+    check_server_scheme(server, transport)?;
+    let server = match transport {
+        TransportKind::Unix => {
+            unix_socket_path(server)?;
+            "ws://localhost"
+        }
+        TransportKind::WebSocket | TransportKind::Http => server,
+    };
+    let mut uri_parts = Uri::from_str(server)
+        .map_err(|e| format!("'{}' is not a URL: {}", server, e))?
+        .into_parts();
+
+    let (default_path, default_query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let default_path = format!("/{}", default_path.trim_start_matches('/'));
+    let (path, query) = match &uri_parts.path_and_query {
+        Some(pq) if pq.path() != "/" => (pq.path().to_string(), pq.query().map(str::to_string)),
+        Some(pq) => {
+            let queries: Vec<&str> = [pq.query(), default_query].into_iter().flatten().collect();
+            (
+                default_path,
+                (!queries.is_empty()).then(|| queries.join("&")),
+            )
+        }
+        None => (default_path, default_query.map(str::to_string)),
+    };
+    if query
+        .as_deref()
+        .is_some_and(|query| query.split('&').any(|pair| pair.starts_with("secret=")))
+    {
+        return Err(format!(
+            "'{}' already carries a secret, set it with the endpoint's `secret` instead",
+            server
+        ));
+    }
+    let secret = secret.map(|secret| format!("secret={}", secret));
+    let path_and_query = match [query, secret].into_iter().flatten().collect::<Vec<_>>() {
+        query if query.is_empty() => path,
+        query => format!("{}?{}", path, query.join("&")),
+    };
 
     uri_parts.path_and_query = Some(
-        uri::PathAndQuery::from_str(&format!("{}?secret={}", path_and_query, secret)).unwrap(),
+        uri::PathAndQuery::from_str(&path_and_query)
+            .map_err(|_| "secret contains characters that can't be sent in a URL".to_string())?,
     );
+    Uri::from_parts(uri_parts).map_err(|e| format!("'{}' is not a valid URL: {}", server, e))
+}
+
+/// The socket path of a `unix://` server.
+pub fn unix_socket_path(server: &str) -> Result<&str, String> {
+    match server.strip_prefix("unix://") {
+        Some(path) if path.starts_with('/') => Ok(path),
+        _ => Err(format!(
+            "'{}' is not an absolute socket path such as unix:///run/collector.sock",
+            server
+        )),
+    }
+}
+
+/// The WebSocket handshake request for `server`, carrying `secret` the way
+/// `auth_mode` says.
+pub fn build_request(
+    server: &str,
+    path: &str,
+    secret: &str,
+    auth_mode: AuthMode,
+    headers: &BTreeMap<String, String>,
+) -> Result<Request, String> {
+    build_transport_request(
+        TransportKind::WebSocket,
+        server,
+        path,
+        secret,
+        auth_mode,
+        headers,
+    )
+}
+
+/// [`build_request`] for a server reached over `transport`: the WebSocket
+/// handshake, or for HTTP the `POST` every message is sent with.
+pub fn build_transport_request(
+    transport: TransportKind,
+    server: &str,
+    path: &str,
+    secret: &str,
+    auth_mode: AuthMode,
+    headers: &BTreeMap<String, String>,
+) -> Result<Request, String> {
+    let uri = match auth_mode {
+        AuthMode::Query => build_transport_uri(transport, server, path, Some(secret))?,
+        AuthMode::Header => build_transport_uri(transport, server, path, None)?,
+    };
+    let mut request = match transport {
+        TransportKind::WebSocket | TransportKind::Unix => {
+            uri.into_client_request().map_err(|e| e.to_string())?
+        }
+        TransportKind::Http => Request::post(uri).body(()).map_err(|e| e.to_string())?,
+    };
+    if auth_mode == AuthMode::Header {
+        let value = HeaderValue::from_str(&format!("Bearer {}", secret))
+            .map_err(|_| "secret contains characters that can't be sent in a header".to_string())?;
+        request.headers_mut().insert(AUTHORIZATION, value);
+    }
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("'{}' is not a valid header name", name))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|_| format!("header '{}' contains characters that can't be sent", name))?;
+        request.headers_mut().insert(name, value);
+    }
+    Ok(request)
+}
+
+/// Normalizes a SHA-256 fingerprint written as hex, optionally separated by
+/// colons as `openssl x509 -fingerprint` prints it, to lowercase hex.
+pub fn parse_fingerprint(pin: &str) -> Result<String, String> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a SHA-256 fingerprint", pin));
+    }
+    Ok(hex.to_ascii_lowercase())
+}
+
+/// Accepts exactly the server certificate whose SHA-256 fingerprint matches
+/// the pin. The pinned certificate is trusted directly instead of through a
+/// CA chain, so a certificate mis-issued by any CA is rejected while a
+/// self-signed one can be pinned.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = format!("{:x}", Sha256::digest(end_entity));
+        if fingerprint != self.fingerprint {
+            error!(
+                expected = %self.fingerprint,
+                actual = %fingerprint,
+                "Server certificate does not match the pinned fingerprint"
+            );
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn pinned_connector(pin: &str) -> Result<Connector, String> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+            fingerprint: parse_fingerprint(pin)?,
+            provider,
+        }))
+        .with_no_client_auth();
+    Ok(Connector::Rustls(Arc::new(config)))
+}
+
+/// Verifies server certificates against the webpki roots like the default
+/// connector, but lets hosts listed in `trusted` present a self-signed
+/// certificate instead. It must be signed by its own key, be within its
+/// validity period and name the host it was presented by, and the handshake
+/// still proves the server holds its key.
+#[derive(Debug)]
+struct SelfSignedVerifier {
+    /// With fingerprints normalized by [`parse_fingerprint`]
+    trusted: Vec<TrustedSelfSigned>,
+    roots: Arc<WebPkiServerVerifier>,
+}
+
+impl SelfSignedVerifier {
+    fn new(trusted: &[TrustedSelfSigned], provider: Arc<CryptoProvider>) -> Result<Self, String> {
+        let trusted = trusted
+            .iter()
+            .map(|entry| {
+                Ok(TrustedSelfSigned {
+                    host: entry.host.clone(),
+                    sha256: entry.sha256.as_deref().map(parse_fingerprint).transpose()?,
+                })
+            })
+            .collect::<Result<_, String>>()?;
+        let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let roots = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self { trusted, roots })
+    }
+
+    /// Accepts `end_entity` if it is signed by its own key, valid at `now`,
+    /// names `server_name` and matches `entry`'s fingerprint, if it has one.
+    fn verify_self_signed(
+        &self,
+        entry: &TrustedSelfSigned,
+        end_entity: &CertificateDer<'_>,
+        server_name: &ServerName<'_>,
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(expected) = &entry.sha256 {
+            let fingerprint = format!("{:x}", Sha256::digest(end_entity));
+            if fingerprint != *expected {
+                error!(
+                    host = %entry.host,
+                    expected = %expected,
+                    actual = %fingerprint,
+                    "Self-signed certificate does not match the trusted fingerprint"
+                );
+                return Err(rustls::Error::InvalidCertificate(
+                    CertificateError::ApplicationVerificationFailure,
+                ));
+            }
+        }
+        let (_, cert) = X509Certificate::from_der(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        // None checks the signature against the certificate's own key
+        cert.verify_signature(None)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadSignature))?;
+        let now = ASN1Time::from_timestamp(now.as_secs() as i64)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::Expired))?;
+        if now < cert.validity().not_before {
+            return Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidYet,
+            ));
+        }
+        if now > cert.validity().not_after {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::Expired));
+        }
+        webpki::EndEntityCert::try_from(end_entity)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?
+            .verify_is_valid_for_subject_name(server_name)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::NotValidForName))?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ServerCertVerifier for SelfSignedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.roots.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        );
+        let host = server_name.to_str();
+        match self
+            .trusted
+            .iter()
+            .find(|entry| host_match(&entry.host, &host))
+        {
+            Some(entry) if verified.is_err() => {
+                debug!(host = %host, "Certificate not issued by a known CA, checking it as self-signed");
+                self.verify_self_signed(entry, end_entity, server_name, now)
+            }
+            _ => verified,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.roots.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.roots.verify_tls13_signature(message, cert, dss)
+    }
 
-    Uri::from_parts(uri_parts).expect("Invalid URL")
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.roots.supported_verify_schemes()
+    }
+}
+
+fn self_signed_connector(trusted: &[TrustedSelfSigned]) -> Result<Connector, String> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let verifier = SelfSignedVerifier::new(trusted, provider.clone())?;
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(Connector::Rustls(Arc::new(config)))
+}
+
+/// Whether the connection failed because the pinned verifier (or a trusted
+/// self-signed fingerprint) rejected the server certificate, which retrying
+/// can't fix.
+fn is_pin_mismatch(e: &tokio_tungstenite::tungstenite::Error) -> bool {
+    let tokio_tungstenite::tungstenite::Error::Io(io) = e else {
+        return false;
+    };
+    is_pin_mismatch_io(io)
+}
+
+/// [`is_pin_mismatch`] for a TLS handshake made without tungstenite.
+pub(crate) fn is_pin_mismatch_io(io: &std::io::Error) -> bool {
+    matches!(
+        io.get_ref()
+            .and_then(|inner| inner.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::InvalidCertificate(
+            CertificateError::ApplicationVerificationFailure
+        ))
+    )
+}
+
+/// Handshake header naming the compression the client would like to use;
+/// a server that supports it answers with the same header and value.
+pub const COMPRESSION_HEADER: &str = "x-vmonitor-compression";
+
+/// Asks for `offered` in the handshake `request`.
+pub(crate) fn offer_compression(request: &mut Request, offered: Compression) {
+    if offered != Compression::None {
+        request
+            .headers_mut()
+            .insert(COMPRESSION_HEADER, HeaderValue::from_static(offered.name()));
+    }
+}
+
+/// What the server answered `offered` with in its handshake `response`.
+pub(crate) fn accepted_compression(
+    server: &str,
+    offered: Compression,
+    response: &Response,
+) -> Compression {
+    let accepted = response
+        .headers()
+        .get(COMPRESSION_HEADER)
+        .is_some_and(|value| value == offered.name());
+    if accepted {
+        return offered;
+    }
+    if offered != Compression::None {
+        info!(url = %server, "Server did not accept compression, sending frames uncompressed");
+    }
+    Compression::None
+}
+
+/// Compresses `data` as `compression` says, at its level or the
+/// algorithm's default. Everything that compresses goes through here.
+pub fn compress(data: &[u8], compression: CompressionConfig) -> std::io::Result<Vec<u8>> {
+    match compression.algorithm {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Gzip => {
+            let level = compression
+                .level
+                .map_or_else(flate2::Compression::default, flate2::Compression::new);
+            let mut encoder = GzEncoder::new(Vec::new(), level);
+            encoder.write_all(data).and_then(|_| encoder.finish())
+        }
+        // Level 0 is zstd's default
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::encode_all(data, compression.level.unwrap_or(0) as i32),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "vmonitor was built without the `zstd` feature",
+        )),
+    }
+}
+
+/// Serializes a message as `encoding`.
+pub fn encode<T: Serialize + ?Sized>(value: &T, encoding: Encoding) -> Result<Vec<u8>, String> {
+    match encoding {
+        Encoding::Msgpack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+        Encoding::Cbor => {
+            let mut encoded = Vec::new();
+            ciborium::into_writer(value, &mut encoded).map_err(|e| e.to_string())?;
+            Ok(encoded)
+        }
+    }
+}
+
+/// Undoes [`encode`].
+pub fn decode<T: serde::de::DeserializeOwned>(
+    frame: &[u8],
+    encoding: Encoding,
+) -> Result<T, String> {
+    match encoding {
+        Encoding::Msgpack => rmp_serde::from_slice(frame).map_err(|e| e.to_string()),
+        Encoding::Json => serde_json::from_slice(frame).map_err(|e| e.to_string()),
+        Encoding::Cbor => ciborium::from_reader(frame).map_err(|e| e.to_string()),
+    }
+}
+
+/// Compresses an encoded message and wraps it in a `compressed` message
+/// saying how, as `{type, encoding, data}` with `data` as msgpack binary.
+/// Returns `frame` as it is without compression or if compressing fails,
+/// which the server can tell from the type.
+pub fn compress_frame(frame: Vec<u8>, compression: CompressionConfig) -> Vec<u8> {
+    if compression.algorithm == Compression::None {
+        return frame;
+    }
+    let compressed = match compress(&frame, compression) {
+        Ok(compressed) => compressed,
+        Err(e) => {
+            warn!(error = %e, "Failed to compress frame, sending it uncompressed");
+            return frame;
+        }
+    };
+    let message = rmpv::Value::Map(vec![
+        (rmpv::Value::from("type"), rmpv::Value::from("compressed")),
+        (
+            rmpv::Value::from("encoding"),
+            rmpv::Value::from(compression.algorithm.name()),
+        ),
+        (rmpv::Value::from("data"), rmpv::Value::Binary(compressed)),
+    ]);
+    let mut encoded = Vec::new();
+    match rmpv::encode::write_value(&mut encoded, &message) {
+        Ok(()) => encoded,
+        Err(e) => {
+            warn!(error = %e, "Failed to encode compressed frame, sending it uncompressed");
+            frame
+        }
+    }
+}
+
+/// Undoes [`compress_frame`], passing frames that aren't `compressed`
+/// messages through. The agent only compresses; this is for servers built on
+/// this crate, and what the tests check compression against.
+pub fn decompress_frame(frame: &[u8]) -> Result<Vec<u8>, String> {
+    let value = rmpv::decode::read_value(&mut &frame[..]).map_err(|e| e.to_string())?;
+    let field = |name: &str| {
+        value
+            .as_map()
+            .and_then(|map| map.iter().find(|(key, _)| key.as_str() == Some(name)))
+            .map(|(_, value)| value)
+    };
+    if field("type").and_then(|t| t.as_str()) != Some("compressed") {
+        return Ok(frame.to_vec());
+    }
+    let data = field("data")
+        .and_then(|data| data.as_slice())
+        .ok_or("compressed message without binary data")?;
+    let mut decompressed = Vec::new();
+    match field("encoding").and_then(|encoding| encoding.as_str()) {
+        Some("gzip") => {
+            GzDecoder::new(data)
+                .read_to_end(&mut decompressed)
+                .map_err(|e| e.to_string())?;
+        }
+        #[cfg(feature = "zstd")]
+        Some("zstd") => decompressed = zstd::decode_all(data).map_err(|e| e.to_string())?,
+        other => return Err(format!("unsupported encoding {:?}", other)),
+    }
+    Ok(decompressed)
+}
+
+/// Why [`try_connect_websocket`] gave up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectError {
+    /// The server URL or the certificate settings can't be used
+    Config(String),
+    /// The server answered HTTP 401
+    Unauthorized,
+    /// The server certificate is not the pinned or trusted one
+    PinMismatch,
+    /// The last attempt failed with this error
+    Failed(String),
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Config(e) => write!(f, "{}", e),
+            ConnectError::Unauthorized => write!(f, "authentication failed (HTTP 401)"),
+            ConnectError::PinMismatch => {
+                write!(f, "the server certificate is not the pinned one")
+            }
+            ConnectError::Failed(e) => write!(f, "{}", e),
+        }
+    }
 }
 
-// Attempts to establish a WebSocket connection to the specified server with authentication.
-// Returns Some(WebSocketStream) if successful, None if authentication fails or max retries exceeded.
-//
-// # Arguments
-// * `server` - The WebSocket server URL (ws:// or wss://)
-// * `secret` - Authentication secret/token
-// * `config` - Connection retry configuration
-//
-// The function will automatically append the WebSocket path (/wss/master) and auth token
-// if not already present in the URL. It implements exponential backoff for retries,
-// starting at base_delay and doubling up to max_delay seconds between attempts.
-pub async fn connect_websocket(
+/// The TLS settings for connecting to `uri` when they differ from the
+/// defaults: only the pinned certificate, or the trusted self-signed ones as
+/// well as the usual roots.
+pub fn tls_connector(
+    uri: &Uri,
     server: &str,
+    pinned_cert_sha256: Option<&str>,
+    trusted_self_signed: &[TrustedSelfSigned],
+) -> Result<Option<Connector>, ConnectError> {
+    let secure = match uri.scheme_str() {
+        Some("http" | "https") => "https",
+        _ => "wss",
+    };
+    let uses_tls = uri.scheme_str() == Some(secure);
+    match pinned_cert_sha256 {
+        Some(_) if !uses_tls => {
+            error!(url = %server, "A pinned certificate requires a {}:// server", secure);
+            Err(ConnectError::Config(format!(
+                "a pinned certificate requires a {}:// server",
+                secure
+            )))
+        }
+        Some(pin) => match pinned_connector(pin) {
+            Ok(connector) => Ok(Some(connector)),
+            Err(e) => {
+                error!(url = %server, error = %e, "Invalid pinned_cert_sha256");
+                Err(ConnectError::Config(format!(
+                    "invalid pinned_cert_sha256: {}",
+                    e
+                )))
+            }
+        },
+        None if trusted_self_signed.is_empty() || !uses_tls => Ok(None),
+        None => match self_signed_connector(trusted_self_signed) {
+            Ok(connector) => Ok(Some(connector)),
+            Err(e) => {
+                error!(url = %server, error = %e, "Invalid trusted_self_signed");
+                Err(ConnectError::Config(format!(
+                    "invalid trusted_self_signed: {}",
+                    e
+                )))
+            }
+        },
+    }
+}
+
+/// The client config `connector` stands for, the platform's usual roots
+/// without one.
+pub fn tls_client_config(
+    connector: Option<Connector>,
+) -> Result<Arc<rustls::ClientConfig>, String> {
+    if let Some(Connector::Rustls(config)) = connector {
+        return Ok(config);
+    }
+    let roots = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config =
+        rustls::ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Connects to `server` with the secret and extra headers, appending `path`
+/// unless the URL has one, and returns the socket with the compression the
+/// server accepted. Failed attempts are retried with exponential backoff,
+/// from `base_delay` doubling up to `max_delay` seconds, up to `max_retries`
+/// times. A rejected secret, a pin mismatch or an unusable configuration
+/// end it right away, with the reason.
+///
+/// `pinned_cert_sha256` only accepts the server certificate with that
+/// fingerprint; `trusted_self_signed` lists hosts whose self-signed
+/// certificate is accepted.
+pub async fn try_connect_websocket(
+    server: &str,
+    path: &str,
     secret: &str,
+    headers: &BTreeMap<String, String>,
     config: &ConnectionConfig,
-) -> Option<WebSocketStream<MaybeTlsStream<TcpStream>>> {
-    let base_delay = config.base_delay;
-    let max_delay = config.max_delay;
+    pinned_cert_sha256: Option<&str>,
+    trusted_self_signed: &[TrustedSelfSigned],
+) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Compression), ConnectError> {
     let max_retries = config.max_retries;
 
     let mut retry_count = 0;
 
-    let uri = build_uri(server, secret);
+    let request = || build_request(server, path, secret, config.auth_mode, headers);
+    let uri = match request() {
+        Ok(request) => request.uri().clone(),
+        Err(e) => {
+            error!(url = %server, error = %e, "Invalid server URL");
+            return Err(ConnectError::Config(e));
+        }
+    };
+
+    let connector = tls_connector(&uri, server, pinned_cert_sha256, trusted_self_signed)?;
 
     debug!(url = %uri, "Connecting to WebSocket...");
 
     loop {
-        match connect_async(uri.clone()).await {
-            Ok((socket, _)) => {
+        // Checked above, so building it again can't fail
+        let mut request = request().map_err(ConnectError::Config)?;
+        let offered = config.compression.unwrap_or_default();
+        offer_compression(&mut request, offered);
+        let error = match connect_async_tls_with_config(request, None, false, connector.clone())
+            .await
+        {
+            Ok((socket, response)) => {
                 debug!(url = %uri, "WebSocket connection established");
-                return Some(socket);
+                let compression = accepted_compression(server, offered, &response);
+                return Ok((socket, compression));
             }
             Err(e) => {
                 error!(error = %e, url = %server, "WebSocket connection failed");
+                if is_pin_mismatch(&e) {
+                    error!(url = %server, "Refusing to connect, the server certificate is not the pinned one");
+                    return Err(ConnectError::PinMismatch);
+                }
                 if let tokio_tungstenite::tungstenite::Error::Http(response) = &e {
                     if response.status() == 401 {
                         error!(url = %server,"Authentication failed - invalid or missing auth token");
-                        return None;
+                        return Err(ConnectError::Unauthorized);
                     }
                 }
+                e
             }
-        }
+        };
 
         // Check max retries
         if max_retries >= 0 && retry_count >= max_retries {
@@ -93,13 +1018,11 @@ pub async fn connect_websocket(
                 "Failed to connect to WebSocket after {} attempts",
                 retry_count
             );
-            return None;
+            return Err(ConnectError::Failed(error.to_string()));
         }
 
         retry_count += 1;
-        // Calculate delay with exponential backoff, capped at max_delay
-        let delay = base_delay * 2u64.pow(retry_count.min(16) as u32 - 1);
-        let delay = delay.min(max_delay);
+        let delay = config.backoff_delay(retry_count as u32);
 
         warn!(
             retry = retry_count,
@@ -110,3 +1033,327 @@ pub async fn connect_websocket(
         tokio::time::sleep(Duration::from_secs(delay)).await;
     }
 }
+
+#[test]
+fn test_build_uri() {
+    let uri = |server| build_uri(server, "/wss/probe", Some("s3cret")).map(|uri| uri.to_string());
+    assert_eq!(
+        uri("wss://example.com").unwrap(),
+        "wss://example.com/wss/probe?secret=s3cret"
+    );
+    assert_eq!(
+        uri("ws://example.com/ws").unwrap(),
+        "ws://example.com/ws?secret=s3cret"
+    );
+    assert_eq!(
+        uri("wss://example.com/ws?region=eu").unwrap(),
+        "wss://example.com/ws?region=eu&secret=s3cret"
+    );
+}
+
+#[test]
+fn test_build_uri_with_configured_path() {
+    let uri = |server, path| build_uri(server, path, Some("s3cret")).unwrap().to_string();
+    // A root path is replaced by the configured one
+    assert_eq!(
+        uri("wss://example.com/", "/agent"),
+        "wss://example.com/agent?secret=s3cret"
+    );
+    assert_eq!(
+        uri("wss://example.com", "agent"),
+        "wss://example.com/agent?secret=s3cret"
+    );
+    // A path in the server URL wins
+    assert_eq!(
+        uri("wss://example.com/ws", "/agent"),
+        "wss://example.com/ws?secret=s3cret"
+    );
+    // The configured path's own query is kept
+    assert_eq!(
+        uri("wss://example.com", "/agent?v=2"),
+        "wss://example.com/agent?v=2&secret=s3cret"
+    );
+}
+
+#[test]
+fn test_build_uri_rejects_empty_server() {
+    assert!(build_uri("", "/wss/probe", Some("s3cret")).is_err());
+}
+
+#[test]
+fn test_build_uri_rejects_missing_scheme() {
+    assert!(build_uri("example.com", "/wss/probe", Some("s3cret")).is_err());
+    assert!(build_uri("example.com/ws", "/wss/probe", Some("s3cret")).is_err());
+    assert!(build_uri("https://example.com/ws", "/wss/probe", Some("s3cret")).is_err());
+    assert_eq!(
+        build_uri("wss//example.com", "/wss/probe", Some("s3cret")).unwrap_err(),
+        "'wss//example.com' is missing the ws:// or wss:// scheme"
+    );
+}
+
+#[test]
+fn test_build_uri_rejects_unsupported_scheme() {
+    assert_eq!(
+        build_uri("grpc://example.com", "/wss/probe", Some("s3cret")).unwrap_err(),
+        "unsupported scheme 'grpc' in 'grpc://example.com', expected ws:// or wss://"
+    );
+}
+
+#[test]
+fn test_build_uri_rejects_secret_in_server() {
+    assert!(build_uri(
+        "wss://example.com/ws?secret=other",
+        "/wss/probe",
+        Some("s3cret")
+    )
+    .is_err());
+    assert!(build_uri(
+        "wss://example.com/?region=eu&secret=other",
+        "/wss/probe",
+        Some("s3cret")
+    )
+    .is_err());
+}
+
+#[test]
+fn test_build_uri_rejects_unsendable_secret() {
+    assert!(build_uri("wss://example.com/ws", "/wss/probe", Some("with space")).is_err());
+}
+
+#[test]
+fn test_build_request_auth_modes() {
+    let none = BTreeMap::new();
+    let query = build_request(
+        "wss://example.com",
+        "/wss/probe",
+        "s3cret",
+        AuthMode::Query,
+        &none,
+    )
+    .unwrap();
+    assert_eq!(
+        query.uri().to_string(),
+        "wss://example.com/wss/probe?secret=s3cret"
+    );
+    assert!(query.headers().get(AUTHORIZATION).is_none());
+
+    let header = build_request(
+        "wss://example.com",
+        "/ws?v=2",
+        "s3cret",
+        AuthMode::Header,
+        &none,
+    )
+    .unwrap();
+    assert_eq!(header.uri().to_string(), "wss://example.com/ws?v=2");
+    assert_eq!(header.headers()[AUTHORIZATION], "Bearer s3cret");
+    // Still a WebSocket handshake
+    assert_eq!(header.headers()["upgrade"], "websocket");
+}
+
+#[test]
+fn test_build_request_custom_headers() {
+    let headers = BTreeMap::from([
+        ("X-Api-Key".to_string(), "k3y".to_string()),
+        ("Host".to_string(), "metrics.internal".to_string()),
+    ]);
+    let request = build_request(
+        "wss://example.com",
+        "/wss/probe",
+        "s3cret",
+        AuthMode::Query,
+        &headers,
+    )
+    .unwrap();
+    assert_eq!(request.headers()["x-api-key"], "k3y");
+    // Overrides the one derived from the URL, which still points at the proxy
+    assert_eq!(request.headers()["host"], "metrics.internal");
+    assert_eq!(request.headers().get_all("host").iter().count(), 1);
+    assert_eq!(request.uri().host(), Some("example.com"));
+    assert_eq!(request.headers()["upgrade"], "websocket");
+
+    let invalid = BTreeMap::from([("X-Api-Key".to_string(), "line\nbreak".to_string())]);
+    let invalid = build_request(
+        "wss://example.com",
+        "/wss/probe",
+        "s3cret",
+        AuthMode::Query,
+        &invalid,
+    );
+    assert!(invalid.is_err());
+}
+
+#[test]
+fn test_split_by_collector() {
+    let report = serde_json::json!({
+        "uptime": 42,
+        "collectedAt": 1000,
+        "system": { "cpuUsage": 1.5 },
+        "network": { "tcpCount": 3 },
+        "customGauges": { "queue": 7.0 },
+        "degraded": false,
+    });
+
+    let messages = split_by_collector(report.clone(), &[Collector::System, Collector::Network], 5);
+    assert_eq!(messages.len(), 2);
+    let (system_type, system) = &messages[0];
+    assert_eq!(system_type, "system_metrics");
+    assert_eq!(system["system"]["cpuUsage"], 1.5);
+    assert_eq!(system["customGauges"]["queue"], 7.0);
+    assert!(system.get("network").is_none());
+    let (network_type, network) = &messages[1];
+    assert_eq!(network_type, "network_metrics");
+    assert_eq!(network["network"]["tcpCount"], 3);
+    assert!(network.get("customGauges").is_none());
+    for (_, data) in &messages {
+        assert_eq!(data["seq"], 5);
+        assert_eq!(data["collectedAt"], 1000);
+        assert_eq!(data["uptime"], 42);
+    }
+
+    // Without the system collector, the rest goes with the first message
+    let messages = split_by_collector(report, &[Collector::Network], 6);
+    assert_eq!(messages[0].1["customGauges"]["queue"], 7.0);
+}
+
+#[test]
+fn test_array_encoder_appends_fields() {
+    let mut encoder = ArrayEncoder::default();
+    let first = serde_json::json!({"uptime": 1, "system": {"cpuUsage": 2.5}, "gauges": {}});
+    let (schema, array) = encoder.encode(&first);
+    let schema = schema.unwrap();
+    assert_eq!(schema.version, 1);
+    assert_eq!(decode_array(&schema, &array).unwrap(), first);
+
+    // Same fields, no new schema
+    let (unchanged, _) =
+        encoder.encode(&serde_json::json!({"uptime": 2, "system": {"cpuUsage": 1.0}}));
+    assert!(unchanged.is_none());
+
+    let second = serde_json::json!({"uptime": 3, "gauges": {"queue": 4}});
+    let (schema, array) = encoder.encode(&second);
+    let schema = schema.unwrap();
+    assert_eq!(schema.version, 2);
+    let path = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        schema.fields,
+        [
+            path(&["gauges"]),
+            path(&["system", "cpuUsage"]),
+            path(&["uptime"]),
+            path(&["gauges", "queue"])
+        ]
+    );
+    assert_eq!(
+        array.values,
+        [
+            serde_json::Value::Null,
+            serde_json::Value::Null,
+            3.into(),
+            4.into()
+        ]
+    );
+    assert_eq!(decode_array(&schema, &array).unwrap(), second);
+
+    // Values for an older schema are rejected rather than misread
+    let old = MetricsArray {
+        schema_version: 1,
+        values: array.values.clone(),
+    };
+    assert!(decode_array(&schema, &old).is_err());
+}
+
+#[test]
+fn test_compress_frame_round_trip() {
+    let report =
+        serde_json::json!({"type": "metrics", "data": {"uptime": 42, "gauges": {"queue": 7.0}}});
+    let frame = rmp_serde::to_vec_named(&report).unwrap();
+
+    assert_eq!(
+        compress_frame(frame.clone(), CompressionConfig::default()),
+        frame
+    );
+    assert_eq!(decompress_frame(&frame).unwrap(), frame);
+
+    let gzip = CompressionConfig {
+        algorithm: Compression::Gzip,
+        level: None,
+    };
+    let compressed = compress_frame(frame.clone(), gzip);
+    let envelope = rmpv::decode::read_value(&mut &compressed[..]).unwrap();
+    let field = |name: &str| {
+        envelope
+            .as_map()
+            .unwrap()
+            .iter()
+            .find(|(key, _)| key.as_str() == Some(name))
+            .unwrap()
+            .1
+            .clone()
+    };
+    assert_eq!(field("type").as_str(), Some("compressed"));
+    assert_eq!(field("encoding").as_str(), Some("gzip"));
+    let decoded = decompress_frame(&compressed).unwrap();
+    assert_eq!(
+        rmp_serde::from_slice::<serde_json::Value>(&decoded).unwrap(),
+        report
+    );
+}
+
+#[test]
+fn test_compress_levels() {
+    let data: Vec<u8> = (0..20_000u32)
+        .flat_map(|i| format!("cpu_usage={} ", i % 97).into_bytes())
+        .collect();
+    let at_level = |level| {
+        let compression = CompressionConfig {
+            algorithm: Compression::Gzip,
+            level: Some(level),
+        };
+        let compressed = compress(&data, compression).unwrap();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+        compressed.len()
+    };
+    assert!(at_level(9) <= at_level(1));
+}
+
+#[test]
+fn test_self_signed_certificate_must_be_valid_and_self_signed() {
+    use rustls::pki_types::pem::PemObject;
+
+    let cert = CertificateDer::from_pem_slice(include_bytes!("../tests/fixtures/pinned_cert.pem"))
+        .unwrap();
+    let provider = Arc::new(crypto::ring::default_provider());
+    let entry = TrustedSelfSigned {
+        host: "localhost".to_string(),
+        sha256: None,
+    };
+    let verifier = SelfSignedVerifier::new(std::slice::from_ref(&entry), provider).unwrap();
+    let name = ServerName::try_from("localhost").unwrap();
+    let at =
+        |year: u64| UnixTime::since_unix_epoch(Duration::from_secs((year - 1970) * 31_556_952));
+    let verify =
+        |cert: &CertificateDer<'_>, now| verifier.verify_self_signed(&entry, cert, &name, now);
+
+    assert!(verify(&cert, at(2030)).is_ok());
+    assert_eq!(
+        verify(&cert, at(2200)).unwrap_err(),
+        rustls::Error::InvalidCertificate(CertificateError::Expired)
+    );
+    assert_eq!(
+        verify(&cert, at(2000)).unwrap_err(),
+        rustls::Error::InvalidCertificate(CertificateError::NotValidYet)
+    );
+
+    // The signature is the last field of the certificate
+    let mut forged = cert.to_vec();
+    *forged.last_mut().unwrap() ^= 0xff;
+    assert_eq!(
+        verify(&CertificateDer::from(forged), at(2030)).unwrap_err(),
+        rustls::Error::InvalidCertificate(CertificateError::BadSignature)
+    );
+}
@@ -1,25 +1,154 @@
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use tokio::net::TcpListener;
 use tokio::signal;
-use tokio::sync::RwLock;
-use tokio::time::{interval, Duration};
-use tracing::{info, warn};
+use tokio::sync::{broadcast, watch, Mutex, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tracing::{debug, error, info, warn};
 
-use crate::config::AppConfig;
-use crate::monitor::Monitor;
+use crate::config::{
+    AppConfig, Collector, CompressionConfig, Endpoint, GaugeConfig, GuardConfig, ReportConfig,
+    SnmpDevice, TrustedSelfSigned,
+};
+use crate::config_watch::ConfigWatcher;
+use crate::dashboard;
+use crate::features::gauge;
+use crate::features::metrics::Metrics;
+use crate::features::snmp::{self, SnmpPoll};
+use crate::guard;
+use crate::history::{self, mono_nanos, now_millis, History, HistorySample};
+use crate::logfile::LogFile;
+use crate::monitor::{
+    AdaptiveInterval, IntervalOverride, Monitor, MonitorEvent, MonitorHandle, Pacer, SampleStats,
+    SharedCollector, SharedSample, StartupGate,
+};
+use crate::sinks::{self, Sink};
+use crate::supervisor::{supervise, RestartPolicy};
 
 pub struct App {
     config: Arc<RwLock<AppConfig>>,
-    endpoint_tasks: Arc<RwLock<Vec<tokio::task::JoinHandle<()>>>>,
+    /// Watched for changes and reloaded
+    config_path: String,
+    /// Running monitors by endpoint name
+    endpoint_tasks: Arc<RwLock<HashMap<String, EndpointTask>>>,
+    guard: Mutex<Option<GuardTask>>,
+    snmp_task: Mutex<Option<SnmpTask>>,
+    /// The last SNMP poll, read by every monitor and the local collector
+    snmp: watch::Sender<SnmpPoll>,
+    collect_override: Option<Vec<Collector>>,
+    dashboard: Option<String>,
+    public_key: Option<Vec<u8>>,
+    wait_for_network: Option<Duration>,
+    started: Instant,
+    history: Arc<History>,
+    /// Only used on the blocking pool, since writing may block on the
+    /// network or disk
+    sinks: Arc<std::sync::Mutex<Vec<Box<dyn Sink>>>>,
+    shutdown: Arc<Notify>,
+    reconnect: watch::Sender<()>,
+    interval_override: watch::Sender<Option<IntervalOverride>>,
+    events: broadcast::Sender<MonitorEvent>,
+    /// Collects for every monitor
+    shared: SharedCollector,
+    /// Of the running monitors, for the control socket's `STATUS`
+    sample_stats: watch::Sender<BTreeMap<String, Arc<SampleStats>>>,
+    log_file: Option<LogFile>,
+    machine_id: Option<String>,
 }
 
+/// Samples a monitor may fall behind the shared collector by before it
+/// skips the oldest. More would let an endpoint ride out a longer stall
+/// without gaps, at the cost of holding that many samples in memory; the
+/// collector never waits for a slow endpoint either way.
+const SHARED_SAMPLES: usize = 16;
+
 impl App {
-    pub fn new(config: AppConfig) -> Self {
+    pub fn new(config: AppConfig, config_path: String) -> Self {
+        // Starts the clock `monoNs` is counted on
+        history::mono_nanos();
+        let history = Arc::new(History::new(Duration::from_secs(
+            config.history.window_secs,
+        )));
         Self {
             config: Arc::new(RwLock::new(config)),
-            endpoint_tasks: Arc::new(RwLock::new(Vec::new())),
+            config_path,
+            endpoint_tasks: Arc::new(RwLock::new(HashMap::new())),
+            guard: Mutex::new(None),
+            snmp_task: Mutex::new(None),
+            snmp: watch::channel(SnmpPoll::default()).0,
+            collect_override: None,
+            dashboard: None,
+            public_key: None,
+            wait_for_network: None,
+            started: Instant::now(),
+            history,
+            sinks: Arc::default(),
+            shutdown: Arc::new(Notify::new()),
+            reconnect: watch::channel(()).0,
+            interval_override: watch::channel(None).0,
+            events: broadcast::channel(64).0,
+            shared: SharedCollector::new(SHARED_SAMPLES),
+            sample_stats: watch::channel(BTreeMap::new()).0,
+            log_file: None,
+            machine_id: None,
         }
     }
 
+    /// Run only the given collectors, ignoring `report.collect` from the config
+    /// file (including across reloads).
+    pub fn with_collectors(mut self, collectors: Vec<Collector>) -> Self {
+        self.collect_override = Some(collectors);
+        self
+    }
+
+    /// Serve the built-in dashboard on `addr`. `:8080` listens on all
+    /// interfaces when a `[control]` token is set, which the latest sample
+    /// then requires, and only on loopback otherwise.
+    pub fn with_dashboard(mut self, addr: String) -> Self {
+        self.dashboard = Some(addr);
+        self
+    }
+
+    /// Only reload config files whose signature verifies against
+    /// `public_key`, see [`AppConfig::from_signed_file`].
+    pub fn with_required_signature(mut self, public_key: Vec<u8>) -> Self {
+        self.public_key = Some(public_key);
+        self
+    }
+
+    /// Before the first connection attempts, wait up to `timeout` for a
+    /// default route (Linux only).
+    pub fn with_wait_for_network(mut self, timeout: Duration) -> Self {
+        self.wait_for_network = Some(timeout);
+        self
+    }
+
+    /// Reports `machine_id` from every endpoint, see
+    /// [`crate::features::machine_id::resolve`].
+    pub fn with_machine_id(mut self, machine_id: String) -> Self {
+        self.machine_id = Some(machine_id);
+        self
+    }
+
+    /// Lets the control socket's `REOPEN-LOGS` reopen `log_file`.
+    pub fn with_log_file(mut self, log_file: LogFile) -> Self {
+        self.log_file = Some(log_file);
+        self
+    }
+
+    /// Returns a handle that stops `run` gracefully when notified, for
+    /// embedders and tests that can't send Ctrl+C.
+    pub fn shutdown_handle(&self) -> Arc<Notify> {
+        self.shutdown.clone()
+    }
+
+    /// Receives samples and connection changes from every endpoint. A
+    /// subscriber that falls behind skips the oldest events.
+    pub fn subscribe(&self) -> broadcast::Receiver<MonitorEvent> {
+        self.events.subscribe()
+    }
+
     pub async fn run(&self) {
         // Listen for exit signals (Ctrl+C)
         let shutdown_signal = async {
@@ -30,73 +159,567 @@ impl App {
         };
 
         // Initial endpoint setup
-        // Run endpoint setup and monitoring tasks simultaneously
+        self.setup_endpoints().await;
+
         tokio::select! {
-            _ = self.setup_endpoints() => {
-                warn!("Endpoint setup completed");
-                                let mut tasks = self.endpoint_tasks.write().await;
-                for task in tasks.iter_mut() {
-                    task.abort();
-                }
-                tasks.clear();
-            }
-            _ = shutdown_signal => {
-                info!("Shutting down...");
-                // Abort all running tasks
-                let mut tasks = self.endpoint_tasks.write().await;
-                for task in tasks.iter_mut() {
-                    task.abort();
-                }
-                tasks.clear();
+            _ = shutdown_signal => {}
+            _ = self.shutdown.notified() => {
+                info!("Shutdown requested");
             }
             _ = self.monitor_config_changes() => {
                 warn!("Config monitoring completed");
-                // Abort all running tasks
-                let mut tasks = self.endpoint_tasks.write().await;
-                for task in tasks.iter_mut() {
-                    task.abort();
+            }
+            _ = self.refresh_dns() => {}
+            // Never completes, local services run until shutdown
+            _ = self.run_local_services() => {}
+        }
+
+        self.shutdown().await;
+    }
+
+    /// Shuts down in order: local collection has already stopped with the
+    /// `select!` in `run`, so flush what the sinks buffered, then close the
+    /// endpoint connections.
+    async fn shutdown(&self) {
+        info!("Shutting down...");
+
+        let sinks = self.sinks.clone();
+        let _ = tokio::task::spawn_blocking(move || {
+            for sink in sinks.lock().unwrap().iter_mut() {
+                if let Err(e) = sink.flush() {
+                    error!(sink = %sink.name(), error = %e, "Failed to flush sink");
+                }
+            }
+        })
+        .await;
+
+        // Abort all running tasks
+        let mut tasks = self.endpoint_tasks.write().await;
+        for (_, task) in tasks.drain() {
+            task.handle.abort();
+        }
+        if let Some(guard) = self.guard.lock().await.take() {
+            guard.handle.abort();
+        }
+        if let Some(snmp) = self.snmp_task.lock().await.take() {
+            snmp.handle.abort();
+        }
+    }
+
+    /// Runs the shared collector, and the control socket, the dashboard and
+    /// the history and sinks it feeds if any of them is configured.
+    async fn run_local_services(&self) {
+        let config = self.config.read().await;
+        #[cfg(unix)]
+        let socket = config.control.socket.clone();
+        #[cfg(not(unix))]
+        let socket: Option<String> = None;
+        let sink_configs = config.sinks.clone();
+        drop(config);
+
+        // Opening a sink may resolve a hostname or open a database
+        let sinks = self.sinks.clone();
+        let has_sinks = tokio::task::spawn_blocking(move || {
+            let mut sinks = sinks.lock().unwrap();
+            for sink_config in &sink_configs {
+                match sinks::build(sink_config) {
+                    Ok(sink) => sinks.push(sink),
+                    Err(e) => error!(sink = ?sink_config, error = %e, "Failed to open sink"),
+                }
+            }
+            !sinks.is_empty()
+        })
+        .await
+        .unwrap_or(false);
+
+        let local = socket.is_some() || has_sinks || self.dashboard.is_some();
+        tokio::join!(
+            self.serve_control(socket),
+            self.serve_dashboard(),
+            self.collect(local)
+        );
+    }
+
+    async fn serve_control(&self, socket: Option<String>) {
+        #[cfg(unix)]
+        if let Some(socket) = socket {
+            let control = self.config.read().await.control.clone();
+            let state = Arc::new(crate::control::ControlState {
+                history: self.history.clone(),
+                reconnect: self.reconnect.clone(),
+                interval_override: self.interval_override.clone(),
+                log_file: self.log_file.clone(),
+                sample_stats: self.sample_stats.subscribe(),
+                token: control.token,
+                allowed_uids: control.allowed_uids,
+            });
+            if let Err(e) = crate::control::serve(&socket, state).await {
+                error!(error = %e, path = %socket, "Control socket failed");
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = socket;
+    }
+
+    async fn serve_dashboard(&self) {
+        let Some(addr) = &self.dashboard else {
+            return;
+        };
+        let token = self.config.read().await.control.token.clone();
+        let bind_addr = match addr.strip_prefix(':') {
+            Some(port) if token.is_some() => format!("0.0.0.0:{}", port),
+            Some(port) => format!("127.0.0.1:{}", port),
+            None => addr.clone(),
+        };
+        match TcpListener::bind(&bind_addr).await {
+            Ok(listener) => {
+                info!(addr = %bind_addr, "Dashboard listening");
+                let exposed = listener
+                    .local_addr()
+                    .is_ok_and(|addr| !addr.ip().is_loopback());
+                if exposed && token.is_none() {
+                    warn!(addr = %bind_addr, "Dashboard serves metrics to anyone who can reach it, set a [control] token to require it");
+                }
+                if let Err(e) = dashboard::serve(listener, self.history.clone(), token).await {
+                    error!(error = %e, addr = %bind_addr, "Dashboard failed");
+                }
+            }
+            Err(e) => error!(error = %e, addr = %bind_addr, "Failed to bind dashboard"),
+        }
+    }
+
+    /// Collects once for every endpoint: each sample is broadcast to the
+    /// monitors, which cut it down to their own collectors and detail level.
+    /// Runs at the shortest interval any endpoint wants, and with `local`
+    /// also records into history and sinks every `report.interval_secs`,
+    /// independently of endpoint connectivity, so recent data is available
+    /// even while disconnected.
+    async fn collect(&self, local: bool) {
+        let mut metrics = Metrics::with_collectors(Vec::new());
+        metrics.snmp_shared = Some(self.snmp.subscribe());
+        let mut local_pacer = Pacer::default();
+        let mut adaptive: Option<AdaptiveInterval> = None;
+        loop {
+            let config = self.config.read().await;
+            let report = config.report.clone();
+            let local_collectors = self
+                .collect_override
+                .clone()
+                .unwrap_or_else(|| report.collect.clone());
+            let mut collectors = local_collectors.clone();
+            let local_period = Duration::from_secs(report.interval_secs.max(1));
+            let mut period = local.then_some(local_period);
+            let mut top_processes = 0;
+            for task in self.endpoint_tasks.read().await.values() {
+                let interval = task.monitor.effective_interval();
+                period = Some(period.map_or(interval, |period| period.min(interval)));
+                top_processes = top_processes.max(task.settings.endpoint.top_processes);
+                // A server may have asked an endpoint for more collectors
+                for collector in task.monitor.collectors() {
+                    if !collectors.contains(&collector) {
+                        collectors.push(collector);
+                    }
+                }
+            }
+            metrics.collectors = collectors;
+            metrics.configure(&report);
+            metrics.gauges = config.gauges.clone();
+            metrics.top_processes = top_processes;
+            drop(config);
+
+            // Nothing wants samples until an endpoint is set up
+            let Some(period) = period else {
+                tokio::select! {
+                    _ = sleep(local_period) => {}
+                    _ = self.shared.wake.notified() => {}
+                }
+                continue;
+            };
+            if adaptive
+                .as_ref()
+                .is_none_or(|adaptive| adaptive.configured() != period)
+            {
+                adaptive = Some(AdaptiveInterval::new(period));
+            }
+
+            let started = Instant::now();
+            let sample = SharedSample {
+                collected_at: now_millis(),
+                mono_ns: mono_nanos(),
+                report: metrics.collet_metrics().await,
+            };
+            let wait = match &mut adaptive {
+                Some(adaptive) if report.adaptive_interval => {
+                    adaptive.record(started.elapsed());
+                    adaptive.effective()
                 }
-                tasks.clear();
+                _ => period,
+            };
+            let sample = Arc::new(sample);
+            self.shared.publish(sample.clone());
+
+            if local && local_pacer.due(sample.mono_ns, local_period) {
+                let mut report = sample.report.clone().only(&local_collectors);
+                report.top_processes = None;
+                self.record_locally(HistorySample {
+                    collected_at: sample.collected_at,
+                    report,
+                })
+                .await;
+            }
+            tokio::select! {
+                _ = sleep_until(started + wait) => {}
+                _ = self.shared.wake.notified() => {}
             }
         }
     }
 
+    async fn record_locally(&self, sample: HistorySample) {
+        // A Graphite server that stopped reading or a busy database
+        // would otherwise stall every task on this thread
+        let sinks = self.sinks.clone();
+        let written = sample.clone();
+        let write = tokio::task::spawn_blocking(move || {
+            for sink in sinks.lock().unwrap().iter_mut() {
+                if let Err(e) = sink.write(&written) {
+                    warn!(sink = %sink.name(), error = %e, "Failed to write sample to sink");
+                }
+            }
+        });
+        self.history
+            .record_at(sample.collected_at, sample.report)
+            .await;
+        let _ = write.await;
+    }
+
+    /// Brings the running monitors in line with the config: endpoints whose
+    /// settings are unchanged keep their connection, changed ones are
+    /// restarted, new ones started and removed or disabled ones stopped.
     async fn setup_endpoints(&self) {
         let config = self.config.read().await;
         let mut tasks = self.endpoint_tasks.write().await;
+        let mut guard = self.guard.lock().await;
+
+        if guard.as_ref().map(|guard| &guard.config) != config.guard.as_ref() {
+            // Monitors keep following the guard they were started with
+            if let Some(old) = guard.take() {
+                old.handle.abort();
+            }
+            for (_, task) in tasks.drain() {
+                task.handle.abort();
+            }
+            if let Some(guard_config) = config.guard.clone() {
+                let (handle, state) = guard::spawn(guard_config.clone()).await;
+                *guard = Some(GuardTask {
+                    config: guard_config,
+                    handle,
+                    state,
+                });
+            }
+        }
+        let guard = guard.as_ref().map(|guard| guard.state.clone());
 
-        // Clear existing tasks
-        for task in tasks.iter_mut() {
-            task.abort();
+        // Polled as often as the most frequent collection wants it
+        let snmp_interval = config
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.enabled)
+            .filter_map(|endpoint| endpoint.metrics_interval)
+            .chain([config.report.interval_secs])
+            .min()
+            .unwrap_or_default()
+            .max(1);
+        let wanted_snmp = (!config.snmp.is_empty())
+            .then(|| (config.snmp.clone(), Duration::from_secs(snmp_interval)));
+        let mut snmp_task = self.snmp_task.lock().await;
+        if snmp_task
+            .as_ref()
+            .map(|task| (task.devices.clone(), task.interval))
+            != wanted_snmp
+        {
+            if let Some(old) = snmp_task.take() {
+                old.handle.abort();
+            }
+            self.snmp.send_replace(SnmpPoll::default());
+            *snmp_task = wanted_snmp.map(|(devices, interval)| SnmpTask {
+                handle: tokio::spawn(snmp::poll_every(
+                    devices.clone(),
+                    interval,
+                    self.snmp.clone(),
+                )),
+                devices,
+                interval,
+            });
         }
-        tasks.clear();
+        drop(snmp_task);
 
-        // Create new tasks for enabled endpoints
+        let collectors = self
+            .collect_override
+            .clone()
+            .unwrap_or_else(|| config.report.collect.clone());
+        let mut wanted = HashMap::new();
         for endpoint in config.endpoints.iter().filter(|e| e.enabled) {
-            let endpoint = endpoint.clone();
-            let tasks = self.endpoint_tasks.clone();
-            let task = tokio::spawn(async move {
-                let monitor = Monitor::new(endpoint);
-                monitor.run().await;
+            let mut endpoint = endpoint.clone();
+            // Filled in here rather than at load, so a reloaded config
+            // compares equal to the one it replaces
+            endpoint.connection = Some(config.connection_for(&endpoint));
+            let settings = MonitorSettings {
+                endpoint,
+                collectors: collectors.clone(),
+                field_map: config.field_map.clone(),
+                report: config.report.clone(),
+                gauges: config.gauges.clone(),
+                trusted_self_signed: config.security.trusted_self_signed.clone(),
+                compression: config.compression,
+            };
+            wanted.insert(settings.endpoint.name.clone(), settings);
+        }
+
+        tasks.retain(|name, task| {
+            let keep = wanted.get(name) == Some(&task.settings) && !task.handle.is_finished();
+            if !keep {
+                task.handle.abort();
+            }
+            keep
+        });
+
+        // Measured from startup, so endpoints recreated by a reload don't wait
+        let startup = StartupGate {
+            not_before: self.started + Duration::from_secs(config.startup_delay_secs),
+            network_deadline: self.wait_for_network.map(|timeout| self.started + timeout),
+        };
+        let fingerprint = config.fingerprint();
+        for task in tasks.values() {
+            task.monitor.set_config_fingerprint(fingerprint.clone());
+        }
+
+        for (name, settings) in wanted {
+            if tasks.contains_key(&name) {
+                continue;
+            }
+            let MonitorSettings {
+                endpoint,
+                collectors,
+                field_map,
+                report,
+                gauges,
+                trusted_self_signed,
+                compression,
+            } = settings.clone();
+            let mut monitor = Monitor::new(endpoint, collectors)
+                .with_field_map(field_map)
+                .with_report(report)
+                .with_snmp(self.snmp.subscribe())
+                .with_gauges(gauges)
+                .with_trusted_self_signed(trusted_self_signed)
+                .with_compression(compression)
+                .with_config_fingerprint(fingerprint.clone())
+                .with_startup_gate(startup)
+                .with_reconnect_signal(self.reconnect.subscribe())
+                .with_interval_override(self.interval_override.subscribe())
+                .with_events(self.events.clone())
+                .with_shared_collector(self.shared.clone());
+            if let Some(machine_id) = self.machine_id.clone() {
+                monitor = monitor.with_machine_id(machine_id);
+            }
+            if let Some(guard) = guard.clone() {
+                monitor = monitor.with_guard(guard);
+            }
+            let monitor_handle = monitor.handle();
+            let events = self.events.clone();
+            let handle = tokio::spawn(async move {
+                let endpoint = monitor.endpoint.name.clone();
+                supervise(&endpoint, RestartPolicy::default(), Some(events), || {
+                    monitor.run()
+                })
+                .await;
             });
-            let mut tasks_lock = tasks.write().await;
-            tasks_lock.push(task);
+            tasks.insert(
+                name,
+                EndpointTask {
+                    settings,
+                    handle,
+                    monitor: monitor_handle,
+                },
+            );
+        }
+        self.sample_stats.send_replace(
+            tasks
+                .iter()
+                .map(|(name, task)| (name.clone(), task.monitor.sample_stats()))
+                .collect(),
+        );
+    }
+
+    /// Periodically makes every endpoint reconnect when `dns_refresh_secs`
+    /// is set. The period is re-read each round so reloads take effect.
+    async fn refresh_dns(&self) {
+        loop {
+            let period = self.config.read().await.dns_refresh_secs.filter(|&s| s > 0);
+            match period {
+                Some(secs) => {
+                    sleep(Duration::from_secs(secs)).await;
+                    debug!("Reconnecting endpoints to refresh DNS");
+                    self.reconnect.send_replace(());
+                }
+                None => sleep(Duration::from_secs(1)).await,
+            }
         }
     }
 
     async fn monitor_config_changes(&self) {
-        let mut interval = interval(Duration::from_secs(1));
+        let mut watcher = ConfigWatcher::new(&self.config_path);
+        let min_interval = self.config.read().await.reload_min_interval_secs;
+        let mut throttle = ReloadThrottle::new(Duration::from_secs(min_interval));
+        let mut last_rejection = None;
+        let mut failures = 0;
+        let mut deferred_until = None;
         loop {
-            interval.tick().await;
-            if let Ok(new_config) = AppConfig::from_file("config.toml") {
-                let current_config = self.config.read().await;
-                if new_config != *current_config {
-                    info!("Configuration changed, reloading endpoints...");
-                    let mut config_lock = self.config.write().await;
-                    *config_lock = new_config;
-                    self.setup_endpoints().await;
+            tokio::select! {
+                _ = watcher.changed() => {}
+                _ = sleep_until(deferred_until.unwrap_or_else(Instant::now)), if deferred_until.is_some() => {}
+            }
+            deferred_until = None;
+            let new_config = match AppConfig::load(&self.config_path, self.public_key.as_deref()) {
+                Ok(new_config) => {
+                    last_rejection = None;
+                    failures = 0;
+                    new_config
                 }
+                Err(e) => {
+                    failures += 1;
+                    let config = self.config.read().await;
+                    if failures == config.reload_failure_threshold {
+                        error!(
+                            error = %e,
+                            failures,
+                            "Config keeps failing to reload, still running on the last good one"
+                        );
+                        if let Some(command) = config.reload_failure_command.clone() {
+                            run_reload_failure_command(command, e.clone());
+                        }
+                    } else if last_rejection.as_ref() != Some(&e) {
+                        // Retried every second, so only report each distinct rejection once
+                        warn!(error = %e, "Ignoring config change that failed to load");
+                    }
+                    last_rejection = Some(e);
+                    // A file that became unreadable raises no further events
+                    deferred_until = Some(Instant::now() + RELOAD_RETRY);
+                    continue;
+                }
+            };
+            let current_config = self.config.read().await;
+            if new_config != *current_config {
+                // Changes within the window are picked up once it closes,
+                // re-reading the latest file contents
+                if !throttle.try_acquire(Instant::now()) {
+                    debug!("Configuration changed, deferring reload");
+                    deferred_until = throttle.next_allowed();
+                    continue;
+                }
+                let diff = current_config.diff(&new_config);
+                drop(current_config);
+                info!(
+                    changes = %diff,
+                    count = diff.changes.len(),
+                    "Configuration changed, reloading endpoints..."
+                );
+                throttle.set_min_interval(Duration::from_secs(new_config.reload_min_interval_secs));
+                let mut config_lock = self.config.write().await;
+                *config_lock = new_config;
+                drop(config_lock);
+                self.setup_endpoints().await;
+            }
+        }
+    }
+}
+
+/// How soon a reload that failed is tried again.
+const RELOAD_RETRY: Duration = Duration::from_secs(1);
+
+/// Runs the alert for a config that keeps failing to reload, without
+/// holding up the reload loop.
+fn run_reload_failure_command(command: String, error: String) {
+    tokio::spawn(async move {
+        let status = gauge::shell(&command)
+            .env("VMONITOR_RELOAD_ERROR", error)
+            .status()
+            .await;
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                warn!(command = %command, status = %status, "Reload failure command failed")
+            }
+            Err(e) => warn!(command = %command, error = %e, "Failed to run reload failure command"),
+        }
+    });
+}
+
+/// Everything a monitor is started with, compared on reload to tell whether
+/// it has to be restarted.
+#[derive(Clone, PartialEq)]
+struct MonitorSettings {
+    endpoint: Endpoint,
+    collectors: Vec<Collector>,
+    field_map: BTreeMap<String, String>,
+    report: ReportConfig,
+    gauges: Vec<GaugeConfig>,
+    trusted_self_signed: Vec<TrustedSelfSigned>,
+    compression: CompressionConfig,
+}
+
+struct EndpointTask {
+    settings: MonitorSettings,
+    handle: JoinHandle<()>,
+    monitor: MonitorHandle,
+}
+
+/// Polls the SNMP devices for all monitors, see [`snmp::poll_every`].
+struct SnmpTask {
+    devices: Vec<SnmpDevice>,
+    interval: Duration,
+    handle: JoinHandle<()>,
+}
+
+/// The network guard shared by all monitors.
+struct GuardTask {
+    config: GuardConfig,
+    handle: JoinHandle<()>,
+    state: watch::Receiver<bool>,
+}
+
+/// Limits endpoint reconciles to at most one per `min_interval`, so a tool
+/// rewriting the config in a tight loop doesn't churn connections.
+pub struct ReloadThrottle {
+    min_interval: Duration,
+    last_reload: Option<Instant>,
+}
+
+impl ReloadThrottle {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last_reload: None,
+        }
+    }
+
+    pub fn set_min_interval(&mut self, min_interval: Duration) {
+        self.min_interval = min_interval;
+    }
+
+    /// When the next reload is allowed, if one has happened yet.
+    pub fn next_allowed(&self) -> Option<Instant> {
+        self.last_reload.map(|last| last + self.min_interval)
+    }
+
+    /// Returns `true` and records the reload if one is allowed at `now`.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        if let Some(last) = self.last_reload {
+            if now.saturating_duration_since(last) < self.min_interval {
+                return false;
             }
         }
+        self.last_reload = Some(now);
+        true
     }
 }
@@ -1,8 +1,18 @@
-use clap::Subcommand;
+use clap::{Subcommand, ValueEnum};
+use futures::SinkExt;
+use serde::{de::DeserializeOwned, Serialize};
 use std::env;
+use std::time::Duration;
 use tracing::error;
 
-use crate::config;
+use vmonitor::api;
+use vmonitor::config;
+use vmonitor::features::machine_id;
+use vmonitor::features::metrics::{Metrics, ReportData, SampleClock, VMInfo};
+use vmonitor::history::{mono_nanos, now_millis, HistorySample};
+use vmonitor::monitor::FrameEncoder;
+use vmonitor::signing;
+use vmonitor::transport;
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
@@ -10,7 +20,11 @@ pub enum Commands {
     Version,
 
     /// List all configured endpoints
-    List,
+    List {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ListFormat::Text)]
+        format: ListFormat,
+    },
 
     /// Add a new endpoint
     Add {
@@ -19,7 +33,7 @@ pub enum Commands {
         name: String,
 
         /// WebSocket URL
-        #[arg(short, long)]
+        #[arg(short = 'S', long)]
         server: String,
 
         /// Authentication secret
@@ -38,6 +52,29 @@ pub enum Commands {
         name: String,
     },
 
+    /// Change an endpoint in place, keeping everything not given
+    Edit {
+        /// Name of the endpoint to edit
+        #[arg(short, long)]
+        name: String,
+
+        /// New WebSocket URL
+        #[arg(short = 'S', long)]
+        server: Option<String>,
+
+        /// New authentication secret
+        #[arg(short, long)]
+        secret: Option<String>,
+
+        /// Enable or disable the endpoint
+        #[arg(short, long)]
+        enabled: Option<bool>,
+
+        /// New path to connect to when the server URL has none
+        #[arg(short, long)]
+        path: Option<String>,
+    },
+
     /// Enable an endpoint
     Enable {
         /// Name of the endpoint to enable
@@ -51,11 +88,162 @@ pub enum Commands {
         #[arg(short, long)]
         name: String,
     },
+
+    /// Connect to an endpoint once to check its URL and secret, without
+    /// sending any metrics
+    Test {
+        /// Name of the endpoint to test
+        #[arg(short, long)]
+        name: String,
+    },
+
+    /// Send samples recorded by a file or stdout sink to an endpoint at their
+    /// recorded pace, to exercise a collector without real hosts
+    Replay {
+        /// JSONL file of samples
+        file: String,
+
+        /// Name of the endpoint to send to
+        #[arg(short, long)]
+        endpoint: String,
+
+        /// How many times faster than recorded to send, e.g. 10
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+
+    /// Check the config for problems without connecting anywhere, e.g. in CI
+    /// before deploying it
+    Validate,
+
+    /// Check that collected data survives a serialize/parse round trip
+    Selftest,
+
+    /// Write a detached signature of the config file to `<config>.sig`
+    SignConfig {
+        /// ed25519 private key (PKCS#8 PEM, e.g. from `openssl genpkey -algorithm ed25519`)
+        #[arg(long)]
+        key: String,
+
+        /// Generate a new key pair at `--key` and `<key>.pub` before signing
+        #[arg(long)]
+        generate: bool,
+    },
+
+    /// Write VM info, one detailed sample from every collector and the
+    /// redacted config as a single JSON document, for attaching to bug reports
+    Snapshot {
+        /// File to write to instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Collect a single sample and print it with VM info, without connecting
+    /// to any endpoint
+    Once {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = OnceFormat::Json)]
+        format: OnceFormat,
+    },
+
+    /// Print samples buffered by the running daemon as JSONL
+    History {
+        /// How far back to look (e.g. 30s, 2m, 1h)
+        #[arg(long, default_value = "5m")]
+        since: String,
+    },
+
+    /// Make the running daemon send metrics at a different interval for a
+    /// while, e.g. `set-interval 1 --for 1m` while debugging
+    SetInterval {
+        /// Seconds between samples
+        secs: u64,
+
+        /// How long until the usual interval applies again (e.g. 30s, 2m, 1h)
+        #[arg(long = "for", default_value = "1m")]
+        duration: String,
+    },
+}
+
+/// Output formats of the `list` command.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListFormat {
+    /// One line per endpoint
+    Text,
+    /// A JSON array, for scripts
+    Json,
+}
+
+/// An endpoint as `list --format json` prints it.
+#[derive(Serialize)]
+struct ListedEndpoint<'a> {
+    name: &'a str,
+    server: &'a str,
+    enabled: bool,
+    has_connection_override: bool,
+}
+
+/// Output formats of the `once` command.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnceFormat {
+    /// Pretty-printed JSON
+    Json,
+    Toml,
+    /// The msgpack encoding sent to endpoints, as hex
+    MsgpackHex,
+}
+
+/// What `once` prints: the report's fields with the VM info alongside.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OnceOutput<'a> {
+    #[serde(flatten)]
+    report: &'a ReportData,
+    vm_info: &'a VMInfo,
+}
+
+impl Commands {
+    /// Whether the command writes to the config file.
+    fn modifies_config(&self) -> bool {
+        matches!(
+            self,
+            Commands::Add { .. }
+                | Commands::Remove { .. }
+                | Commands::Edit { .. }
+                | Commands::Enable { .. }
+                | Commands::Disable { .. }
+        )
+    }
 }
 
-pub fn handle_command(command: Commands, config_path: &str) -> std::process::ExitCode {
+/// Endpoints expanded from an `[[endpoint_template]]` are never saved back,
+/// so changing one would silently do nothing.
+fn is_from_template(config: &config::AppConfig, name: &str) -> bool {
+    let from_template = config
+        .endpoints
+        .iter()
+        .any(|e| e.name == name && e.from_template);
+    if from_template {
+        error!(
+            "Endpoint '{}' is expanded from an [[endpoint_template]], change the template instead",
+            name
+        );
+    }
+    from_template
+}
+
+pub async fn handle_command(
+    command: Commands,
+    config_path: &str,
+    read_only: bool,
+) -> std::process::ExitCode {
+    if read_only && command.modifies_config() {
+        error!("Refusing to modify config: {}", config::READ_ONLY_MESSAGE);
+        return std::process::ExitCode::FAILURE;
+    }
+
     match command {
-        Commands::List => {
+        Commands::List { format } => {
             // Load configuration from config file
             let config = match config::AppConfig::from_file(config_path) {
                 Ok(cfg) => cfg,
@@ -65,6 +253,24 @@ pub fn handle_command(command: Commands, config_path: &str) -> std::process::Exi
                 }
             };
 
+            if format == ListFormat::Json {
+                let endpoints: Vec<_> = config
+                    .endpoints
+                    .iter()
+                    .map(|endpoint| ListedEndpoint {
+                        name: &endpoint.name,
+                        server: &endpoint.server,
+                        enabled: endpoint.enabled,
+                        has_connection_override: endpoint.connection.is_some(),
+                    })
+                    .collect();
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&endpoints).expect("endpoints are serializable")
+                );
+                return std::process::ExitCode::SUCCESS;
+            }
+
             println!("Configured endpoints:");
             for endpoint in &config.endpoints {
                 println!(
@@ -105,7 +311,12 @@ pub fn handle_command(command: Commands, config_path: &str) -> std::process::Exi
                 secret,
                 enabled,
                 connection: None,
+                ..Default::default()
             });
+            if let Err(e) = config.validate() {
+                error!(error = %e, "Refusing to save an invalid config");
+                return std::process::ExitCode::FAILURE;
+            }
 
             // Save updated config
             if let Err(e) = config.save_to_file(config_path) {
@@ -124,6 +335,9 @@ pub fn handle_command(command: Commands, config_path: &str) -> std::process::Exi
                     return std::process::ExitCode::FAILURE;
                 }
             };
+            if is_from_template(&config, &name) {
+                return std::process::ExitCode::FAILURE;
+            }
 
             // Find and remove endpoint
             if let Some(pos) = config.endpoints.iter().position(|e| e.name == name) {
@@ -141,6 +355,53 @@ pub fn handle_command(command: Commands, config_path: &str) -> std::process::Exi
                 std::process::ExitCode::FAILURE
             }
         }
+        Commands::Edit {
+            name,
+            server,
+            secret,
+            enabled,
+            path,
+        } => {
+            let mut config = match config::AppConfig::from_file(config_path) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    error!(error = %e, "Failed to load config");
+                    return std::process::ExitCode::FAILURE;
+                }
+            };
+            if is_from_template(&config, &name) {
+                return std::process::ExitCode::FAILURE;
+            }
+
+            let Some(endpoint) = config.endpoints.iter_mut().find(|e| e.name == name) else {
+                error!("Endpoint with name '{}' not found", name);
+                return std::process::ExitCode::FAILURE;
+            };
+            if let Some(server) = server {
+                endpoint.server = server;
+            }
+            if let Some(secret) = secret {
+                endpoint.secret = secret;
+            }
+            if let Some(enabled) = enabled {
+                endpoint.enabled = enabled;
+            }
+            if let Some(path) = path {
+                endpoint.path = path;
+            }
+            if let Err(e) = config.validate() {
+                error!(error = %e, "Refusing to save an invalid config");
+                return std::process::ExitCode::FAILURE;
+            }
+
+            // Save updated config
+            if let Err(e) = config.save_to_file(config_path) {
+                error!(error = %e, "Failed to save config");
+                return std::process::ExitCode::FAILURE;
+            }
+            println!("Endpoint edited successfully");
+            std::process::ExitCode::SUCCESS
+        }
         Commands::Enable { name } => {
             let mut config = match config::AppConfig::from_file(config_path) {
                 Ok(cfg) => cfg,
@@ -149,6 +410,9 @@ pub fn handle_command(command: Commands, config_path: &str) -> std::process::Exi
                     return std::process::ExitCode::FAILURE;
                 }
             };
+            if is_from_template(&config, &name) {
+                return std::process::ExitCode::FAILURE;
+            }
 
             // Find and enable endpoint
             if let Some(endpoint) = config.endpoints.iter_mut().find(|e| e.name == name) {
@@ -174,6 +438,9 @@ pub fn handle_command(command: Commands, config_path: &str) -> std::process::Exi
                     return std::process::ExitCode::FAILURE;
                 }
             };
+            if is_from_template(&config, &name) {
+                return std::process::ExitCode::FAILURE;
+            }
 
             // Find and disable endpoint
             if let Some(endpoint) = config.endpoints.iter_mut().find(|e| e.name == name) {
@@ -191,5 +458,504 @@ pub fn handle_command(command: Commands, config_path: &str) -> std::process::Exi
                 std::process::ExitCode::FAILURE
             }
         }
+        Commands::Test { name } => {
+            let config = match config::AppConfig::from_file(config_path) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    error!(error = %e, "Failed to load config");
+                    return std::process::ExitCode::FAILURE;
+                }
+            };
+            let Some(endpoint) = config.endpoints.iter().find(|e| e.name == name) else {
+                error!("Endpoint with name '{}' not found", name);
+                return std::process::ExitCode::FAILURE;
+            };
+            test_endpoint(endpoint, &config).await
+        }
+        Commands::Replay {
+            file,
+            endpoint,
+            speed,
+        } => {
+            if !(speed.is_finite() && speed > 0.0) {
+                error!("Invalid speed {}, expected a positive number", speed);
+                return std::process::ExitCode::FAILURE;
+            }
+            let config = match config::AppConfig::from_file(config_path) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    error!(error = %e, "Failed to load config");
+                    return std::process::ExitCode::FAILURE;
+                }
+            };
+            let Some(endpoint) = config.endpoints.iter().find(|e| e.name == endpoint) else {
+                error!("Endpoint with name '{}' not found", endpoint);
+                return std::process::ExitCode::FAILURE;
+            };
+            let samples = match read_samples(&file) {
+                Ok(samples) => samples,
+                Err(e) => {
+                    error!(error = %e, "Failed to read samples");
+                    return std::process::ExitCode::FAILURE;
+                }
+            };
+            match replay(endpoint, &config, &samples, speed).await {
+                Ok(()) => {
+                    println!("Replayed {} sample(s) to {}", samples.len(), endpoint.name);
+                    std::process::ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    error!(error = %e, "Replay failed");
+                    std::process::ExitCode::FAILURE
+                }
+            }
+        }
+        Commands::Validate => {
+            let config = match config::AppConfig::from_file_unvalidated(config_path) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    println!("{}: does not load", config_path);
+                    return std::process::ExitCode::FAILURE;
+                }
+            };
+
+            let problems = check_config(&config);
+            if problems.is_empty() {
+                println!(
+                    "{}: OK, {} endpoint(s)",
+                    config_path,
+                    config.endpoints.len()
+                );
+                return std::process::ExitCode::SUCCESS;
+            }
+            for problem in &problems {
+                eprintln!("error: {}", problem);
+            }
+            println!("{}: {} problem(s) found", config_path, problems.len());
+            std::process::ExitCode::FAILURE
+        }
+        Commands::Selftest => {
+            let mut metrics = Metrics::new();
+            let report = metrics.collet_metrics().await;
+            let vm_info = metrics.collect_vm_info();
+
+            let results = [
+                (
+                    "msgpack",
+                    msgpack_roundtrip(&report).and_then(|_| msgpack_roundtrip(&vm_info)),
+                ),
+                (
+                    "json",
+                    json_roundtrip(&report).and_then(|_| json_roundtrip(&vm_info)),
+                ),
+            ];
+
+            let mut passed = true;
+            for (codec, result) in results {
+                match result {
+                    Ok(()) => println!("{}: passed", codec),
+                    Err(e) => {
+                        println!("{}: FAILED ({})", codec, e);
+                        passed = false;
+                    }
+                }
+            }
+
+            if passed {
+                println!("all codecs passed");
+                std::process::ExitCode::SUCCESS
+            } else {
+                std::process::ExitCode::FAILURE
+            }
+        }
+        Commands::Snapshot { output } => {
+            let config = match config::AppConfig::from_file(config_path) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    error!(error = %e, "Failed to load config");
+                    return std::process::ExitCode::FAILURE;
+                }
+            };
+
+            let snapshot = serde_json::to_string_pretty(&snapshot(&config, config_path).await)
+                .expect("snapshot is serializable");
+            match output {
+                Some(path) => {
+                    if let Err(e) = std::fs::write(&path, snapshot + "\n") {
+                        error!(error = %e, "Failed to write snapshot to {}", path);
+                        return std::process::ExitCode::FAILURE;
+                    }
+                    println!("Snapshot written to {}", path);
+                }
+                None => println!("{}", snapshot),
+            }
+            std::process::ExitCode::SUCCESS
+        }
+        Commands::Once { format } => {
+            let mut metrics = Metrics::new();
+            // CPU usage is measured between two refreshes, so the first sample
+            // would report zero
+            metrics.collet_metrics().await;
+            tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+            let report = metrics.collet_metrics().await;
+            let vm_info = metrics.collect_vm_info();
+
+            match format_once(
+                &OnceOutput {
+                    report: &report,
+                    vm_info: &vm_info,
+                },
+                format,
+            ) {
+                Ok(output) => {
+                    println!("{}", output);
+                    std::process::ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    error!(error = %e, "Failed to encode sample");
+                    std::process::ExitCode::FAILURE
+                }
+            }
+        }
+        Commands::History { since } => {
+            let Some(since) = parse_duration(&since) else {
+                error!("Invalid duration '{}', expected e.g. 30s, 2m or 1h", since);
+                return std::process::ExitCode::FAILURE;
+            };
+            let config = match config::AppConfig::from_file(config_path) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    error!(error = %e, "Failed to load config");
+                    return std::process::ExitCode::FAILURE;
+                }
+            };
+            let command = format!("HISTORY {}", since.as_secs());
+            control_request(&config, &command)
+        }
+        Commands::SetInterval { secs, duration } => {
+            let Some(duration) = parse_duration(&duration) else {
+                error!(
+                    "Invalid duration '{}', expected e.g. 30s, 2m or 1h",
+                    duration
+                );
+                return std::process::ExitCode::FAILURE;
+            };
+            let config = match config::AppConfig::from_file(config_path) {
+                Ok(cfg) => cfg,
+                Err(e) => {
+                    error!(error = %e, "Failed to load config");
+                    return std::process::ExitCode::FAILURE;
+                }
+            };
+            let command = format!("SET-INTERVAL {} {}", secs, duration.as_secs());
+            control_request(&config, &command)
+        }
+        Commands::SignConfig { key, generate } => match sign_config(config_path, &key, generate) {
+            Ok(signature_path) => {
+                println!("Wrote signature to {}", signature_path);
+                std::process::ExitCode::SUCCESS
+            }
+            Err(e) => {
+                error!(error = %e, "Failed to sign config");
+                std::process::ExitCode::FAILURE
+            }
+        },
     }
-} 
\ No newline at end of file
+}
+
+/// Everything wrong with `config` that would keep it from loading or an
+/// endpoint from connecting, checked without opening any sockets.
+fn check_config(config: &config::AppConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    let check_delays = |what: &str, connection: &config::ConnectionConfig| {
+        connection
+            .validate()
+            .err()
+            .map(|e| format!("{}: {}", what, e))
+    };
+    problems.extend(check_delays("connection", &config.connection));
+
+    // Duplicate names are reported by validate below
+    for endpoint in &config.endpoints {
+        let what = format!("endpoint '{}'", endpoint.name);
+        if let Err(e) =
+            api::build_transport_uri(endpoint.transport, &endpoint.server, &endpoint.path, None)
+        {
+            problems.push(format!("{}: {}", what, e));
+        }
+        match endpoint.resolve_secret() {
+            Ok(secret) if secret.is_empty() => problems.push(format!("{}: secret is empty", what)),
+            Ok(_) => {}
+            Err(e) => problems.push(format!("{}: failed to read secret file: {}", what, e)),
+        }
+        if let Some(connection) = &endpoint.connection {
+            problems.extend(check_delays(&what, connection));
+        }
+    }
+
+    // Whatever else keeps the daemon from starting
+    if let Err(e) = config.validate() {
+        if !problems.contains(&e) {
+            problems.push(e);
+        }
+    }
+    problems
+}
+
+/// Makes a single connection attempt to `endpoint` and reports how it went.
+async fn test_endpoint(
+    endpoint: &config::Endpoint,
+    config: &config::AppConfig,
+) -> std::process::ExitCode {
+    let secret = match endpoint.resolve_secret() {
+        Ok(secret) => secret,
+        Err(e) => {
+            error!(error = %e, "Failed to read secret file");
+            return std::process::ExitCode::FAILURE;
+        }
+    };
+    let connection = config::ConnectionConfig {
+        max_retries: 0,
+        ..config.connection_for(endpoint)
+    };
+
+    match transport::for_kind(endpoint.transport)
+        .connect(
+            endpoint,
+            &secret,
+            &connection,
+            &config.security.trusted_self_signed,
+        )
+        .await
+    {
+        Ok(mut connected) => {
+            let _ = connected.sink.close().await;
+            println!("{}: connected successfully", endpoint.name);
+            std::process::ExitCode::SUCCESS
+        }
+        Err(api::ConnectError::Unauthorized) => {
+            println!("{}: authentication failed, check the secret", endpoint.name);
+            std::process::ExitCode::FAILURE
+        }
+        Err(e) => {
+            println!("{}: connection failed: {}", endpoint.name, e);
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+/// Reads the JSON lines a file or stdout sink writes, skipping blank lines.
+fn read_samples(path: &str) -> Result<Vec<HistorySample>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|e| format!("{}:{}: {}", path, index + 1, e))
+        })
+        .collect()
+}
+
+/// Sends `samples` to `endpoint` as the daemon would send them, in its
+/// transport, encoding, format and field map, waiting between them the time
+/// between their collection divided by `speed`. Each is stamped with the
+/// time it's sent, so the collector takes it for a live sample.
+async fn replay(
+    endpoint: &config::Endpoint,
+    config: &config::AppConfig,
+    samples: &[HistorySample],
+    speed: f64,
+) -> Result<(), String> {
+    let secret = endpoint
+        .resolve_secret()
+        .map_err(|e| format!("failed to read secret file: {}", e))?;
+    let mut connected = transport::for_kind(endpoint.transport)
+        .connect(
+            endpoint,
+            &secret,
+            &config.connection_for(endpoint),
+            &config.security.trusted_self_signed,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    let compression = config::CompressionConfig {
+        algorithm: connected.compression,
+        ..config.compression
+    };
+    let mut encoder = FrameEncoder::new(endpoint, endpoint.format);
+    let mut clock = SampleClock::new(config.report.timestamp_source);
+
+    let mut previous: Option<u64> = None;
+    for sample in samples {
+        if let Some(previous) = previous {
+            let gap_secs = sample.collected_at.saturating_sub(previous) as f64 / 1000.0;
+            let wait = Duration::try_from_secs_f64(gap_secs / speed).map_err(|e| {
+                format!("can't wait {}s at speed {}: {}", gap_secs, speed, e)
+            })?;
+            tokio::time::sleep(wait).await;
+        }
+        previous = Some(sample.collected_at);
+
+        let mut report = sample.report.clone().with_counters(config.report.counters);
+        clock.stamp(&mut report, now_millis(), mono_nanos());
+        for frame in encoder.encode(report, &config.report.collect, &config.field_map)? {
+            connected
+                .sink
+                .send(transport::data_message(frame, endpoint.encoding, compression))
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    let _ = connected.sink.close().await;
+    Ok(())
+}
+
+/// Signs `config_path` with the private key at `key_path`, generating the
+/// key pair first if asked to. Returns the path of the written signature.
+fn sign_config(config_path: &str, key_path: &str, generate: bool) -> Result<String, String> {
+    if generate {
+        let public_key_path = format!("{}.pub", key_path);
+        for path in [key_path, public_key_path.as_str()] {
+            if std::path::Path::new(path).exists() {
+                return Err(format!("{} already exists", path));
+            }
+        }
+        let (private_key, public_key) = signing::generate_key_pair()?;
+        write_private_key(key_path, &private_key)
+            .map_err(|e| format!("failed to write {}: {}", key_path, e))?;
+        std::fs::write(&public_key_path, public_key)
+            .map_err(|e| format!("failed to write {}: {}", public_key_path, e))?;
+        println!("Generated key pair {} and {}", key_path, public_key_path);
+    }
+
+    let private_key = std::fs::read_to_string(key_path)
+        .map_err(|e| format!("failed to read {}: {}", key_path, e))?;
+    let contents =
+        std::fs::read(config_path).map_err(|e| format!("failed to read {}: {}", config_path, e))?;
+    let signature = signing::sign(&contents, &private_key)?;
+
+    let signature_path = signing::signature_path(config_path);
+    std::fs::write(&signature_path, signature + "\n")
+        .map_err(|e| format!("failed to write {}: {}", signature_path, e))?;
+    Ok(signature_path)
+}
+
+#[cfg(unix)]
+fn write_private_key(path: &str, pem: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(pem.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private_key(path: &str, pem: &str) -> std::io::Result<()> {
+    std::fs::write(path, pem)
+}
+
+async fn snapshot(config: &config::AppConfig, config_path: &str) -> serde_json::Value {
+    let mut metrics = Metrics::with_collectors(config::Collector::ALL.to_vec());
+    metrics.snmp_devices = config.snmp.clone();
+    metrics.gauges = config.gauges.clone();
+    metrics.config_fingerprint = Some(config.fingerprint());
+    metrics.machine_id = machine_id::lookup(Some(&machine_id::fallback_path(config_path)));
+
+    // CPU usage is measured between two refreshes, so the first sample
+    // would report zero
+    metrics.collet_metrics().await;
+    tokio::time::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL).await;
+    let report = metrics.collet_metrics().await;
+
+    serde_json::json!({
+        "vm_info": metrics.collect_vm_info(),
+        "report": report,
+        "config": config.redacted(),
+    })
+}
+
+fn format_once(output: &OnceOutput, format: OnceFormat) -> Result<String, String> {
+    match format {
+        OnceFormat::Json => serde_json::to_string_pretty(output).map_err(|e| e.to_string()),
+        OnceFormat::Toml => toml::to_string_pretty(output).map_err(|e| e.to_string()),
+        OnceFormat::MsgpackHex => rmp_serde::to_vec_named(output)
+            .map(|bytes| bytes.iter().map(|b| format!("{:02x}", b)).collect())
+            .map_err(|e| e.to_string()),
+    }
+}
+
+fn msgpack_roundtrip<T>(value: &T) -> Result<(), String>
+where
+    T: Serialize + DeserializeOwned + PartialEq,
+{
+    let encoded = rmp_serde::to_vec_named(value).map_err(|e| format!("encode: {}", e))?;
+    let decoded: T = rmp_serde::from_slice(&encoded).map_err(|e| format!("decode: {}", e))?;
+    check_roundtrip(value, &decoded)
+}
+
+fn json_roundtrip<T>(value: &T) -> Result<(), String>
+where
+    T: Serialize + DeserializeOwned + PartialEq,
+{
+    let encoded = serde_json::to_vec(value).map_err(|e| format!("encode: {}", e))?;
+    let decoded: T = serde_json::from_slice(&encoded).map_err(|e| format!("decode: {}", e))?;
+    check_roundtrip(value, &decoded)
+}
+
+fn check_roundtrip<T: PartialEq>(original: &T, decoded: &T) -> Result<(), String> {
+    if original == decoded {
+        Ok(())
+    } else {
+        Err("decoded value differs from the original".to_string())
+    }
+}
+
+#[cfg(unix)]
+fn control_request(config: &config::AppConfig, command: &str) -> std::process::ExitCode {
+    let Some(socket) = &config.control.socket else {
+        error!("No control socket configured, set `control.socket` in the config");
+        return std::process::ExitCode::FAILURE;
+    };
+    match vmonitor::control::request(socket, config.control.token.as_deref(), command) {
+        Ok(lines) => {
+            for line in lines {
+                println!("{}", line);
+            }
+            std::process::ExitCode::SUCCESS
+        }
+        Err(e) => {
+            error!(error = %e, path = %socket, "Failed to query control socket");
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn control_request(_config: &config::AppConfig, _command: &str) -> std::process::ExitCode {
+    error!("The control socket is only supported on Unix");
+    std::process::ExitCode::FAILURE
+}
+
+/// Parses durations like `90`, `30s`, `2m` or `1h`; bare numbers are seconds.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value: u64 = value.parse().ok()?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value.checked_mul(60)?,
+        "h" => value.checked_mul(60 * 60)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs))
+}
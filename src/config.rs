@@ -1,21 +1,282 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::features::machine_id;
+use crate::signing;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct AppConfig {
     pub endpoints: Vec<Endpoint>,
+    /// Endpoints whose `name`, `server` and `secret` may contain `${HOSTNAME}`,
+    /// `${MACHINE_ID}` or `${VAR}` for an environment variable. Each is
+    /// expanded into `endpoints` on load, so one definition can be deployed
+    /// to a fleet
+    #[serde(
+        default,
+        rename = "endpoint_template",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub endpoint_templates: Vec<Endpoint>,
     #[serde(default = "default_connection")]
     pub connection: ConnectionConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub report: ReportConfig,
+    #[serde(default = "default_reload_min_interval_secs")]
+    pub reload_min_interval_secs: u64,
+    /// Consecutive failed reloads after which the warning becomes an error
+    /// and `reload_failure_command` runs; the last good config stays in use.
+    /// 0 never escalates
+    #[serde(default = "default_reload_failure_threshold")]
+    pub reload_failure_threshold: u32,
+    /// Run through the shell once the threshold is reached, with the error
+    /// in `VMONITOR_RELOAD_ERROR`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reload_failure_command: Option<String>,
+    /// Seconds to wait after startup before the first connection attempt
+    #[serde(default)]
+    pub startup_delay_secs: u64,
+    /// Reconnect every endpoint this often so changed DNS records are
+    /// picked up without waiting for the connection to drop
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_refresh_secs: Option<u64>,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
+    #[serde(default)]
+    pub security: SecurityConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sinks: Vec<SinkConfig>,
+    /// Renames reported fields, keyed by their snake_case path such as
+    /// `system.cpu_usage`, so the output matches the server's schema
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub field_map: BTreeMap<String, String>,
+    /// Network conditions that must hold for endpoints to report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guard: Option<GuardConfig>,
+    /// Remote devices polled over SNMP once per collection interval and
+    /// shared by every endpoint (needs the `snmp` cargo feature)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snmp: Vec<SnmpDevice>,
+    /// Commands whose numeric output is reported on every collection
+    #[serde(default, rename = "gauge", skip_serializing_if = "Vec::is_empty")]
+    pub gauges: Vec<GaugeConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Endpoint {
     pub name: String,
     pub server: String,
+    /// Path to connect to when `server` has none; may include a query
+    #[serde(default = "default_path")]
+    pub path: String,
     pub secret: String,
     #[serde(default = "default_enabled")]
     pub enabled: bool,
     #[serde(default = "Option::default")]
     pub connection: Option<ConnectionConfig>,
+    #[serde(default)]
+    pub interval_authority: IntervalAuthority,
+    /// Seconds between samples until the server pushes another interval;
+    /// unset starts at 10
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics_interval: Option<u64>,
+    /// Send a hash of the VM info on connect and only send the full object
+    /// when the server hasn't seen it
+    #[serde(default)]
+    pub vm_info_dedup: bool,
+    /// Wait for the server to answer an application `ping` before sending
+    /// metrics
+    #[serde(default)]
+    pub ready_handshake: bool,
+    #[serde(default)]
+    pub detail_level: DetailLevel,
+    /// Milliseconds to hold outgoing messages so ones sent close together
+    /// go out as a single `batch` frame; 0 sends each immediately
+    #[serde(default)]
+    pub send_coalesce_ms: u64,
+    /// Hex SHA-256 fingerprint of the server's leaf certificate; when set,
+    /// only that exact certificate is accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pinned_cert_sha256: Option<String>,
+    /// Send a `heartbeat` carrying the collection errors when collection
+    /// fails entirely, instead of nothing
+    #[serde(default)]
+    pub heartbeat_when_blind: bool,
+    /// Seconds after which a connection is closed and reopened, so load
+    /// balancers get to move it; 0 keeps it for as long as it lasts
+    #[serde(default)]
+    pub max_connection_lifetime_secs: u64,
+    /// Number of processes using the most CPU to report with each sample,
+    /// when the system collector runs; 0 leaves the section out
+    #[serde(default = "default_top_processes")]
+    pub top_processes: usize,
+    /// Commands of each type the server may send per second before the
+    /// rest are dropped; VM info requests get a fifth of it. 0 = unlimited
+    #[serde(default = "default_max_commands_per_sec")]
+    pub max_commands_per_sec: u32,
+    /// Let the server change the collectors, field map, format and interval
+    /// of this endpoint with a `reconfigure` command
+    #[serde(default)]
+    pub allow_remote_reconfigure: bool,
+    /// Send each collector's part of a sample as its own message, typed
+    /// `system_metrics`, `network_metrics`, ..., instead of one `metrics`
+    #[serde(default)]
+    pub split_by_collector: bool,
+    #[serde(default)]
+    pub format: MetricsFormat,
+    /// How the server is reached, see [`TransportKind`]
+    #[serde(default)]
+    pub transport: TransportKind,
+    /// How every message is serialized, see [`Encoding`]
+    #[serde(default)]
+    pub encoding: Encoding,
+    /// Metrics messages kept while disconnected and sent, in `flush_order`,
+    /// on reconnecting; the oldest are dropped beyond it. 0 keeps none
+    #[serde(default)]
+    pub offline_buffer_size: usize,
+    #[serde(default)]
+    pub flush_order: FlushOrder,
+    /// Extra headers sent with the connection request, e.g. an API key a
+    /// reverse proxy in front of the server asks for
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// Expanded from an `[[endpoint_template]]`; never saved back
+    #[serde(skip)]
+    pub from_template: bool,
+    /// `(field, as written, as expanded)` for fields that referenced
+    /// environment variables, so saving writes the references back
+    #[serde(skip)]
+    pub env_expanded: Vec<(String, String, String)>,
+}
+
+/// Who decides the metrics interval of an endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IntervalAuthority {
+    /// The local configuration; `update_config` pushes are logged and ignored
+    Local,
+    /// The server, via `update_config` pushes
+    #[default]
+    Server,
+}
+
+/// How much of each sample an endpoint receives.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DetailLevel {
+    /// Aggregates only
+    #[default]
+    Summary,
+    /// Aggregates plus per-core, per-interface and per-disk vectors
+    Full,
+}
+
+/// How an endpoint's `metrics` messages are encoded.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsFormat {
+    /// A map with the field names, typed `metrics`
+    #[default]
+    Map,
+    /// Only the values, typed `metrics_array`, in the order of the last
+    /// `metrics_schema` message
+    Array,
+}
+
+/// How an endpoint reaches its server, see [`crate::transport`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    /// A WebSocket to a ws:// or wss:// server, which can send commands back
+    #[default]
+    WebSocket,
+    /// One HTTP POST per message to an http:// or https:// server, which
+    /// can't send anything back
+    Http,
+    /// A WebSocket over the Unix socket at unix:///path/to/socket, for a
+    /// server on the same host
+    Unix,
+}
+
+impl TransportKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            TransportKind::WebSocket => "websocket",
+            TransportKind::Http => "http",
+            TransportKind::Unix => "unix",
+        }
+    }
+
+    /// The URL schemes a server reached this way is given with.
+    pub fn schemes(self) -> &'static [&'static str] {
+        match self {
+            TransportKind::WebSocket => &["ws", "wss"],
+            TransportKind::Http => &["http", "https"],
+            TransportKind::Unix => &["unix"],
+        }
+    }
+
+    /// Whether the server can send messages back: commands such as
+    /// `get_info` and `reconfigure`, VM info negotiation, readiness and pong
+    /// answers. Without them the VM info is sent on connecting.
+    pub fn duplex(self) -> bool {
+        self != TransportKind::Http
+    }
+}
+
+/// How an endpoint's messages are serialized, independently of the
+/// [`MetricsFormat`] of its metrics.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Msgpack,
+    /// Sent as text frames over a WebSocket
+    Json,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Msgpack => "msgpack",
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    /// The `Content-Type` of messages sent over HTTP.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Msgpack => "application/msgpack",
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+        }
+    }
+}
+
+/// How the metrics kept while disconnected are sent on reconnecting.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FlushOrder {
+    /// In the order collected, ahead of the fresh ones
+    #[default]
+    OldestFirst,
+    /// The most recent first, for servers that care more about freshness
+    NewestFirst,
+    /// Only the most recent one, the rest are dropped
+    LatestOnly,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Copy)]
@@ -26,6 +287,512 @@ pub struct ConnectionConfig {
     pub max_delay: u64,
     #[serde(default = "default_max_retries")]
     pub max_retries: i32,
+    #[serde(default)]
+    pub auth_mode: AuthMode,
+    /// Seconds between WebSocket pings; 0 disables them
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    /// Seconds to wait for any frame after a ping before the connection is
+    /// taken for dead and reopened, at least 1
+    #[serde(default = "default_pong_timeout")]
+    pub pong_timeout: u64,
+    /// Compression offered to the server when connecting, `[compression]
+    /// algorithm` unless set; frames go out uncompressed unless it accepts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+}
+
+/// Algorithm and level shared by everything that compresses, see
+/// [`crate::api::compress`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub algorithm: Compression,
+    /// Higher trades CPU for a better ratio, see [`Compression::levels`];
+    /// the algorithm's own default unless set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<u32>,
+}
+
+impl CompressionConfig {
+    /// Rejects an algorithm this build can't do and a level out of its range.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.algorithm == Compression::Zstd && !cfg!(feature = "zstd") {
+            return Err("zstd needs vmonitor built with the `zstd` feature".to_string());
+        }
+        match (self.level, self.algorithm.levels()) {
+            (Some(level), Some(levels)) if !levels.contains(&level) => Err(format!(
+                "level {} is out of range for {} ({}-{})",
+                level,
+                self.algorithm.name(),
+                levels.start(),
+                levels.end()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How outgoing frames are compressed, see [`crate::api::compress_frame`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    /// Needs the `zstd` cargo feature
+    Zstd,
+}
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// The levels the algorithm takes, `None` for no compression.
+    pub fn levels(&self) -> Option<RangeInclusive<u32>> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some(0..=9),
+            Compression::Zstd => Some(1..=22),
+        }
+    }
+}
+
+/// How the endpoint secret is sent when connecting.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// As `?secret=` in the URL, where it can end up in proxy and access logs
+    #[default]
+    Query,
+    /// As an `Authorization: Bearer` header
+    Header,
+}
+
+impl ConnectionConfig {
+    /// Seconds to wait before reconnect `attempt` (counting from 1): doubles
+    /// from `base_delay` up to `max_delay`.
+    pub fn backoff_delay(&self, attempt: u32) -> u64 {
+        let delay = self.base_delay * 2u64.pow(attempt.clamp(1, 16) - 1);
+        delay.min(self.max_delay)
+    }
+
+    /// Rejects delays the backoff can't follow (a zero `base_delay` would
+    /// reconnect in a tight loop, one above `max_delay` is always clamped)
+    /// and compression this build can't do.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.base_delay == 0 {
+            return Err("base_delay must be at least 1 second".to_string());
+        }
+        if self.base_delay > self.max_delay {
+            return Err(format!(
+                "base_delay ({}) is greater than max_delay ({})",
+                self.base_delay, self.max_delay
+            ));
+        }
+        if self.pong_timeout == 0 {
+            return Err("pong_timeout must be at least 1 second".to_string());
+        }
+        if self.compression == Some(Compression::Zstd) && !cfg!(feature = "zstd") {
+            return Err(
+                "compression = \"zstd\" needs vmonitor built with the `zstd` feature".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReportConfig {
+    /// Collectors to run, see [`Collector`]
+    #[serde(default = "default_collect")]
+    pub collect: Vec<Collector>,
+    /// Seconds between samples collected locally for history and sinks
+    #[serde(default = "default_report_interval_secs")]
+    pub interval_secs: u64,
+    /// Per-disk budget for reading disk space, in milliseconds. When set,
+    /// disks that don't answer in time are skipped and listed in
+    /// `collectionErrors` instead of stalling the report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk_timeout_ms: Option<u64>,
+    /// Temporarily widen an endpoint's metrics interval while collection
+    /// takes longer than the interval, instead of falling behind
+    #[serde(default)]
+    pub adaptive_interval: bool,
+    /// Whether samples carry the cumulative counters (bytes since boot,
+    /// ...), the rates derived from them, or both, see [`CounterMode`]
+    #[serde(default)]
+    pub counters: CounterMode,
+    /// Number of intervals the total network and disk rates are averaged
+    /// over, to smooth out bursts; 1 reports each interval on its own
+    #[serde(default = "default_rate_window")]
+    pub rate_window: usize,
+    /// Number of first samples whose rates are left out and which are marked
+    /// `warmup`, as their baseline was taken mid-stream
+    #[serde(default = "default_warmup_samples")]
+    pub warmup_samples: u32,
+    /// Which timestamps samples sent to endpoints carry, see
+    /// [`TimestampSource`]
+    #[serde(default)]
+    pub timestamp_source: TimestampSource,
+    /// Interfaces counted in the network totals and listed per interface
+    #[serde(default)]
+    pub interface_filter: InterfaceFilter,
+    /// Process names reported as `<redacted>` in `topProcesses`, by patterns
+    /// where `*` matches any run of characters; their usage is still sent
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub process_name_denylist: Vec<String>,
+    /// Report each top process name as a short hash of it, the same for the
+    /// same name, instead of the name
+    #[serde(default)]
+    pub hash_process_names: bool,
+}
+
+/// Picks the network interfaces whose traffic is reported, by name patterns
+/// where `*` matches any run of characters. Loopback, container and bridge
+/// interfaces are excluded by default, as their traffic is counted again on
+/// the physical NIC.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct InterfaceFilter {
+    /// Only interfaces matching one of these count; all of them when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Interfaces matching one of these never count, even when included
+    #[serde(default = "default_interface_exclude")]
+    pub exclude: Vec<String>,
+}
+
+impl InterfaceFilter {
+    pub fn allows(&self, interface: &str) -> bool {
+        let matches = |patterns: &[String]| patterns.iter().any(|p| glob_match(p, interface));
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+/// Checks that `server` is a URL with one of the schemes of `transport`,
+/// e.g. ws:// or wss://, before anything tries to parse the rest of it.
+pub fn check_server_scheme(server: &str, transport: TransportKind) -> Result<(), String> {
+    let expected = transport
+        .schemes()
+        .iter()
+        .map(|scheme| format!("{}://", scheme))
+        .collect::<Vec<_>>()
+        .join(" or ");
+    match server.split_once("://") {
+        Some((scheme, _)) if transport.schemes().contains(&scheme) => Ok(()),
+        Some((scheme, _)) => Err(format!(
+            "unsupported scheme '{}' in '{}', expected {}",
+            scheme, server, expected
+        )),
+        None => Err(format!("'{}' is missing the {} scheme", server, expected)),
+    }
+}
+
+/// Whether `name` matches `pattern`, in which `*` stands for any run of
+/// characters, possibly empty.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            // Try every split for what the `*` takes
+            name.char_indices()
+                .map(|(i, _)| i)
+                .chain([name.len()])
+                .any(|i| glob_match(rest, &name[i..]))
+        }
+    }
+}
+
+/// Matches a host name against a pattern where `*` matches within a single
+/// DNS label, as in certificates: `*.example.com` matches `a.example.com`
+/// but neither `example.com` nor `a.b.example.com`. Case-insensitive.
+pub fn host_match(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    let labels = |name: &str| name.split('.').count();
+    labels(&pattern) == labels(&host)
+        && pattern
+            .split('.')
+            .zip(host.split('.'))
+            .all(|(pattern, label)| glob_match(pattern, label))
+}
+
+/// Which of the cumulative counters and the rates derived from them a sample
+/// carries. Servers computing their own rates across agent restarts want the
+/// counters, dashboards only need the rates.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CounterMode {
+    #[default]
+    Both,
+    RatesOnly,
+    CountersOnly,
+}
+
+/// Clock(s) a sample sent to an endpoint is stamped with.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampSource {
+    /// Unix time as `collectedAt`, easy to correlate but stepped by clock
+    /// adjustments
+    #[default]
+    Wall,
+    /// Time since the agent started as `monoNs`, which only ever increases
+    Monotonic,
+    /// Both of the above
+    Both,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HistoryConfig {
+    /// How far back buffered samples are kept, in seconds
+    #[serde(default = "default_history_window_secs")]
+    pub window_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ControlConfig {
+    /// Path of the Unix control socket; disabled when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket: Option<String>,
+    /// Token every request must start with (`AUTH <token> <command>`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Only accept connections from these user ids; any user with access
+    /// to the socket file when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_uids: Vec<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct SecurityConfig {
+    /// Refuse every operation that would modify the config file, for hosts
+    /// whose config is managed elsewhere
+    #[serde(default)]
+    pub read_only: bool,
+    /// Servers allowed to present a self-signed certificate; every other
+    /// server's certificate is verified against the usual CA roots
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_self_signed: Vec<TrustedSelfSigned>,
+}
+
+/// A server host whose self-signed certificate is accepted, see
+/// [`SecurityConfig::trusted_self_signed`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TrustedSelfSigned {
+    /// Host name or IP address as written in the endpoint URL; `*` matches
+    /// within a single label, see [`host_match`]
+    pub host: String,
+    /// Hex SHA-256 fingerprint the certificate must have. When unset, any
+    /// certificate signed by its own key, currently valid and naming the
+    /// host is accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+/// Conditions identifying a trusted network, see [`crate::guard`]. Every
+/// configured condition must hold.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GuardConfig {
+    /// MAC addresses the default gateway must have, e.g. `00:11:22:33:44:55`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub gateway_macs: Vec<String>,
+    /// `host:port` that must accept a TCP connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub require_reachable: Option<String>,
+    /// Seconds between re-evaluations of the conditions
+    #[serde(default = "default_guard_recheck_secs")]
+    pub recheck_secs: u64,
+}
+
+/// A device polled with SNMP v2c GET requests. SNMP v3 (USM) is not
+/// supported.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SnmpDevice {
+    /// Identifies the device in reports; defaults to `host`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// `host`, `host:port` or `[ipv6]:port`, port 161 by default
+    pub host: String,
+    /// Redacted from `config show` and fingerprints
+    #[serde(default = "default_snmp_community")]
+    pub community: String,
+    /// Numeric OIDs such as `1.3.6.1.2.1.1.3.0`
+    pub oids: Vec<String>,
+}
+
+impl SnmpDevice {
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.host)
+    }
+}
+
+/// A command whose stdout is a single number, reported under `name` in
+/// `customGauges`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GaugeConfig {
+    pub name: String,
+    /// Run through `sh -c` (`cmd /C` on Windows)
+    pub command: String,
+    /// Gauges whose command takes longer are skipped for that collection
+    #[serde(default = "default_gauge_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_gauge_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_snmp_community() -> String {
+    "public".to_string()
+}
+
+/// A local destination for collected samples, see [`crate::sinks`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// Append samples as JSON lines to `path`
+    File { path: String },
+    /// Print samples as JSON lines to stdout
+    Stdout,
+    /// Send numeric fields in the Graphite plaintext protocol over TCP
+    Graphite {
+        address: String,
+        #[serde(default = "default_metric_prefix")]
+        prefix: String,
+        /// Defaults to the hostname
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        host: Option<String>,
+    },
+    /// Send numeric fields as StatsD gauges over UDP
+    Statsd {
+        address: String,
+        #[serde(default = "default_metric_prefix")]
+        prefix: String,
+        /// Defaults to the hostname
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        host: Option<String>,
+    },
+    /// Insert samples into a SQLite database at `path` (needs the `sqlite`
+    /// cargo feature)
+    Sqlite {
+        path: String,
+        /// Days after which samples are deleted; 0 keeps them forever
+        #[serde(default = "default_retention_days")]
+        retention_days: u32,
+    },
+    /// Send a summary of `metrics` to a syslog server over UDP, plus a message
+    /// whenever one of `alerts` starts or stops firing (needs the `syslog`
+    /// cargo feature). Summaries have MSGID 1 and alerts MSGID 2
+    Syslog {
+        #[serde(default = "default_syslog_address")]
+        address: String,
+        #[serde(default = "default_syslog_facility")]
+        facility: String,
+        /// Snake_case paths like `system.cpu_usage`; empty sends a default set
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        metrics: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        alerts: Vec<SyslogAlert>,
+    },
+}
+
+/// A threshold on one metric, logged by the syslog sink at `severity` when
+/// the metric goes above it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SyslogAlert {
+    /// Snake_case path like `system.cpu_usage`
+    pub metric: String,
+    pub above: f64,
+    /// Syslog severity name, e.g. `warning`, `err` or `crit`
+    #[serde(default = "default_alert_severity")]
+    pub severity: String,
+}
+
+fn default_syslog_address() -> String {
+    "127.0.0.1:514".to_string()
+}
+
+fn default_syslog_facility() -> String {
+    "daemon".to_string()
+}
+
+fn default_alert_severity() -> String {
+    "warning".to_string()
+}
+
+fn default_metric_prefix() -> String {
+    "vmonitor".to_string()
+}
+
+fn default_retention_days() -> u32 {
+    30
+}
+
+/// A metrics collector that can be switched on or off via `report.collect`
+/// or the `--collect` command line flag. `gateway` is off by default since it
+/// sends probes on the network, and `oom` since it needs access to the kernel
+/// log (`/dev/kmsg`, Linux only).
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Collector {
+    System,
+    Network,
+    Disk,
+    Gateway,
+    Oom,
+}
+
+impl Collector {
+    pub const ALL: [Collector; 5] = [
+        Collector::System,
+        Collector::Network,
+        Collector::Disk,
+        Collector::Gateway,
+        Collector::Oom,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collector::System => "system",
+            Collector::Network => "network",
+            Collector::Disk => "disk",
+            Collector::Gateway => "gateway",
+            Collector::Oom => "oom",
+        }
+    }
+}
+
+impl fmt::Display for Collector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Collector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Collector::ALL
+            .into_iter()
+            .find(|c| c.name() == s.trim())
+            .ok_or_else(|| {
+                let known: Vec<&str> = Collector::ALL.iter().map(|c| c.name()).collect();
+                format!(
+                    "unknown collector '{}' (expected one of: {})",
+                    s,
+                    known.join(", ")
+                )
+            })
+    }
 }
 
 fn default_base_delay() -> u64 {
@@ -40,33 +807,822 @@ fn default_max_retries() -> i32 {
     -1
 }
 
+fn default_ping_interval() -> u64 {
+    30
+}
+
+fn default_pong_timeout() -> u64 {
+    10
+}
+
 fn default_enabled() -> bool {
     true
 }
 
+fn default_top_processes() -> usize {
+    5
+}
+
+fn default_max_commands_per_sec() -> u32 {
+    10
+}
+
+fn default_path() -> String {
+    "/wss/probe".to_string()
+}
+
 fn default_connection() -> ConnectionConfig {
     ConnectionConfig {
         base_delay: default_base_delay(),
         max_delay: default_max_delay(),
         max_retries: default_max_retries(),
+        auth_mode: AuthMode::default(),
+        ping_interval: default_ping_interval(),
+        pong_timeout: default_pong_timeout(),
+        compression: None,
+    }
+}
+
+fn default_reload_min_interval_secs() -> u64 {
+    2
+}
+
+fn default_reload_failure_threshold() -> u32 {
+    30
+}
+
+fn default_history_window_secs() -> u64 {
+    300
+}
+
+fn default_report_interval_secs() -> u64 {
+    10
+}
+
+fn default_guard_recheck_secs() -> u64 {
+    30
+}
+
+/// Replaces every `${NAME}` in `value` with `lookup(NAME)`, and every
+/// `${NAME:-default}` with `default` when there is none, failing on names
+/// without a value.
+pub fn expand_placeholders(
+    value: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(format!("unterminated placeholder in '{}'", value));
+        };
+        let placeholder = &rest[start + 2..start + 2 + len];
+        let (name, default) = match placeholder.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (placeholder, None),
+        };
+        let replacement = lookup(name)
+            .or_else(|| default.map(str::to_string))
+            .ok_or_else(|| format!("no value for ${{{}}}", name))?;
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&replacement);
+        rest = &rest[start + 3 + len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Where `--last-known-good` keeps a copy of the last config that loaded.
+pub fn last_known_good_path(config_path: &str) -> String {
+    format!("{}.last-good", config_path)
+}
+
+/// Copies the config at `path` (and its signature, if `signed`) to
+/// `cache_path`.
+fn remember(path: &str, cache_path: &str, signed: bool) -> std::io::Result<()> {
+    if signed {
+        std::fs::copy(
+            signing::signature_path(path),
+            signing::signature_path(cache_path),
+        )?;
+    }
+    std::fs::copy(path, cache_path)?;
+    Ok(())
+}
+
+/// Why a config change was refused in read-only mode.
+pub const READ_ONLY_MESSAGE: &str =
+    "config is read-only (--read-only or [security] read_only is set)";
+
+fn default_warmup_samples() -> u32 {
+    1
+}
+
+fn default_rate_window() -> usize {
+    1
+}
+
+fn default_interface_exclude() -> Vec<String> {
+    ["lo", "docker*", "veth*", "br-*"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_collect() -> Vec<Collector> {
+    vec![Collector::System, Collector::Network, Collector::Disk]
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            endpoint_templates: Vec::new(),
+            connection: default_connection(),
+            compression: CompressionConfig::default(),
+            report: ReportConfig::default(),
+            reload_min_interval_secs: default_reload_min_interval_secs(),
+            reload_failure_threshold: default_reload_failure_threshold(),
+            reload_failure_command: None,
+            startup_delay_secs: 0,
+            dns_refresh_secs: None,
+            history: HistoryConfig::default(),
+            control: ControlConfig::default(),
+            security: SecurityConfig::default(),
+            sinks: Vec::new(),
+            field_map: BTreeMap::new(),
+            guard: None,
+            snmp: Vec::new(),
+            gauges: Vec::new(),
+        }
+    }
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        default_connection()
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: default_history_window_secs(),
+        }
+    }
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            server: String::new(),
+            path: default_path(),
+            secret: String::new(),
+            enabled: default_enabled(),
+            connection: None,
+            interval_authority: IntervalAuthority::default(),
+            metrics_interval: None,
+            vm_info_dedup: false,
+            ready_handshake: false,
+            detail_level: DetailLevel::default(),
+            send_coalesce_ms: 0,
+            pinned_cert_sha256: None,
+            heartbeat_when_blind: false,
+            max_connection_lifetime_secs: 0,
+            top_processes: default_top_processes(),
+            max_commands_per_sec: default_max_commands_per_sec(),
+            allow_remote_reconfigure: false,
+            split_by_collector: false,
+            format: MetricsFormat::default(),
+            transport: TransportKind::default(),
+            encoding: Encoding::default(),
+            offline_buffer_size: 0,
+            flush_order: FlushOrder::default(),
+            headers: BTreeMap::new(),
+            from_template: false,
+            env_expanded: Vec::new(),
+        }
+    }
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        Self {
+            collect: default_collect(),
+            interval_secs: default_report_interval_secs(),
+            disk_timeout_ms: None,
+            adaptive_interval: false,
+            counters: CounterMode::default(),
+            rate_window: default_rate_window(),
+            warmup_samples: default_warmup_samples(),
+            timestamp_source: TimestampSource::default(),
+            interface_filter: InterfaceFilter::default(),
+            process_name_denylist: Vec::new(),
+            hash_process_names: false,
+        }
+    }
+}
+
+impl Default for InterfaceFilter {
+    fn default() -> Self {
+        Self {
+            include: Vec::new(),
+            exclude: default_interface_exclude(),
+        }
+    }
+}
+
+impl Endpoint {
+    /// Returns the secret to authenticate with. A secret of the form
+    /// `file:<path>` is read from that file on every call, so a token rotated
+    /// by an external agent is picked up on the next connection attempt.
+    pub fn resolve_secret(&self) -> Result<String, std::io::Error> {
+        match self.secret.strip_prefix("file:") {
+            Some(path) => Ok(std::fs::read_to_string(path)?.trim().to_string()),
+            None => Ok(self.secret.clone()),
+        }
+    }
+}
+
+/// What changed between two configs, see [`AppConfig::diff`].
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConfigDiff {
+    pub changes: Vec<ConfigChange>,
+}
+
+/// One change in a [`ConfigDiff`]. Settings are named by their path, such as
+/// `connection.max_delay`; their values are left out since some are secrets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigChange {
+    EndpointAdded(String),
+    EndpointRemoved(String),
+    EndpointEnabled(String),
+    EndpointDisabled(String),
+    /// Settings of the endpoint other than `enabled`
+    EndpointChanged {
+        name: String,
+        fields: Vec<String>,
+    },
+    /// `report.interval_secs`
+    IntervalChanged {
+        from: u64,
+        to: u64,
+    },
+    /// Any other setting
+    SettingChanged(String),
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigChange::EndpointAdded(name) => write!(f, "endpoint '{}' added", name),
+            ConfigChange::EndpointRemoved(name) => write!(f, "endpoint '{}' removed", name),
+            ConfigChange::EndpointEnabled(name) => write!(f, "endpoint '{}' enabled", name),
+            ConfigChange::EndpointDisabled(name) => write!(f, "endpoint '{}' disabled", name),
+            ConfigChange::EndpointChanged { name, fields } => {
+                write!(f, "endpoint '{}' changed {}", name, fields.join(", "))
+            }
+            ConfigChange::IntervalChanged { from, to } => {
+                write!(f, "report.interval_secs {} -> {}", from, to)
+            }
+            ConfigChange::SettingChanged(path) => write!(f, "{} changed", path),
+        }
+    }
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let changes: Vec<String> = self.changes.iter().map(ToString::to_string).collect();
+        f.write_str(&changes.join("; "))
+    }
+}
+
+/// Appends the paths of the leaves that differ between `old` and `new` to
+/// `changed`. Objects are compared key by key, anything else as a whole.
+fn changed_paths(
+    old: &serde_json::Value,
+    new: &serde_json::Value,
+    path: &str,
+    changed: &mut Vec<String>,
+) {
+    match (old.as_object(), new.as_object()) {
+        (Some(old), Some(new)) => {
+            let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for key in keys {
+                let null = serde_json::Value::Null;
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                let (old, new) = (old.get(key).unwrap_or(&null), new.get(key).unwrap_or(&null));
+                changed_paths(old, new, &child, changed);
+            }
+        }
+        _ if old != new => changed.push(path.to_string()),
+        _ => {}
     }
 }
 
 impl AppConfig {
+    /// The connection settings `endpoint` uses, its own or the shared ones,
+    /// with the compression to offer filled in.
+    pub fn connection_for(&self, endpoint: &Endpoint) -> ConnectionConfig {
+        let mut connection = endpoint.connection.unwrap_or(self.connection);
+        connection
+            .compression
+            .get_or_insert(self.compression.algorithm);
+        connection
+    }
+
+    /// Lists what changed from `self` to `other`: endpoints by name, then
+    /// every other setting that differs.
+    pub fn diff(&self, other: &AppConfig) -> ConfigDiff {
+        let mut changes = Vec::new();
+        for old in &self.endpoints {
+            let Some(new) = other.endpoints.iter().find(|e| e.name == old.name) else {
+                changes.push(ConfigChange::EndpointRemoved(old.name.clone()));
+                continue;
+            };
+            match (old.enabled, new.enabled) {
+                (false, true) => changes.push(ConfigChange::EndpointEnabled(old.name.clone())),
+                (true, false) => changes.push(ConfigChange::EndpointDisabled(old.name.clone())),
+                _ => {}
+            }
+            let mut fields = Vec::new();
+            let (old_json, new_json) = (serde_json::to_value(old), serde_json::to_value(new));
+            if let (Ok(old_json), Ok(new_json)) = (old_json, new_json) {
+                changed_paths(&old_json, &new_json, "", &mut fields);
+            }
+            fields.retain(|field| field != "enabled");
+            if !fields.is_empty() {
+                changes.push(ConfigChange::EndpointChanged {
+                    name: old.name.clone(),
+                    fields,
+                });
+            }
+        }
+        for new in &other.endpoints {
+            if !self.endpoints.iter().any(|e| e.name == new.name) {
+                changes.push(ConfigChange::EndpointAdded(new.name.clone()));
+            }
+        }
+
+        if self.report.interval_secs != other.report.interval_secs {
+            changes.push(ConfigChange::IntervalChanged {
+                from: self.report.interval_secs,
+                to: other.report.interval_secs,
+            });
+        }
+        let mut settings = Vec::new();
+        let (old_json, new_json) = (serde_json::to_value(self), serde_json::to_value(other));
+        if let (Ok(mut old_json), Ok(mut new_json)) = (old_json, new_json) {
+            for json in [&mut old_json, &mut new_json] {
+                if let Some(object) = json.as_object_mut() {
+                    object.remove("endpoints");
+                }
+            }
+            changed_paths(&old_json, &new_json, "", &mut settings);
+        }
+        settings.retain(|path| path != "report.interval_secs");
+        changes.extend(settings.into_iter().map(ConfigChange::SettingChanged));
+        ConfigDiff { changes }
+    }
+
+    /// Short hash of the configuration with endpoint secrets left out, so
+    /// reported data can be traced back to the config that produced it.
+    /// Changes whenever any other setting does.
+    pub fn fingerprint(&self) -> String {
+        let digest = Sha256::digest(self.redacted().to_string().as_bytes());
+        format!("{:x}", digest)[..12].to_string()
+    }
+
+    /// The configuration as JSON with endpoint and template secrets, their
+    /// header values and the control token nulled, safe to share in bug
+    /// reports.
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut redacted = serde_json::to_value(self).unwrap_or_default();
+        for list in ["endpoints", "endpoint_template"] {
+            let Some(endpoints) = redacted[list].as_array_mut() else {
+                continue;
+            };
+            for endpoint in endpoints {
+                endpoint["secret"] = serde_json::Value::Null;
+                // Usually credentials, like an API key for a proxy
+                if let Some(headers) = endpoint["headers"].as_object_mut() {
+                    for value in headers.values_mut() {
+                        *value = serde_json::Value::Null;
+                    }
+                }
+            }
+        }
+        if let Some(control) = redacted["control"].as_object_mut() {
+            if control.contains_key("token") {
+                control.insert("token".to_string(), serde_json::Value::Null);
+            }
+        }
+        // The community is the only access control an SNMP v2c agent has
+        if let Some(devices) = redacted["snmp"].as_array_mut() {
+            for device in devices {
+                device["community"] = serde_json::Value::Null;
+            }
+        }
+        redacted
+    }
+
     pub fn from_file(path: &str) -> Result<Self, config::ConfigError> {
+        let app_config = Self::from_file_unvalidated(path)?;
+        app_config
+            .validate()
+            .map_err(config::ConfigError::Message)?;
+        Ok(app_config)
+    }
+
+    /// Like [`AppConfig::from_file`], but leaves [`AppConfig::validate`] to
+    /// the caller, e.g. to report every problem instead of the first.
+    pub fn from_file_unvalidated(path: &str) -> Result<Self, config::ConfigError> {
         let cfg = config::Config::builder()
             .add_source(config::File::with_name(path))
             .build()?;
-        cfg.try_deserialize()
+        let app_config: Self = cfg.try_deserialize()?;
+        app_config
+            .expand_env()
+            .and_then(|app_config| app_config.expand_templates(path))
+            .map_err(config::ConfigError::Message)
+    }
+
+    /// Loads `path` only if its detached signature (see
+    /// [`crate::signing::signature_path`]) verifies against `public_key`.
+    /// The verified bytes are the ones parsed, so the file can't be swapped
+    /// in between.
+    pub fn from_signed_file(path: &str, public_key: &[u8]) -> Result<Self, String> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let signature_path = signing::signature_path(path);
+        let signature = std::fs::read_to_string(&signature_path)
+            .map_err(|e| format!("failed to read {}: {}", signature_path, e))?;
+        signing::verify(contents.as_bytes(), &signature, public_key)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Self::from_toml(path, &contents)
+    }
+
+    /// Parses `contents`, read from `path`.
+    fn from_toml(path: &str, contents: &str) -> Result<Self, String> {
+        let cfg = config::Config::builder()
+            .add_source(config::File::from_str(contents, config::FileFormat::Toml))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let app_config = cfg
+            .try_deserialize::<Self>()
+            .map_err(|e| e.to_string())?
+            .expand_env()?
+            .expand_templates(path)?;
+        app_config.validate()?;
+        Ok(app_config)
+    }
+
+    /// Expands `${VAR}` and `${VAR:-default}` in the endpoints' name, server,
+    /// path and secret from the environment. Templates are left to
+    /// [`AppConfig::expand_templates`].
+    fn expand_env(mut self) -> Result<Self, String> {
+        let lookup = |name: &str| std::env::var(name).ok();
+        for endpoint in &mut self.endpoints {
+            let name = endpoint.name.clone();
+            let mut fields = vec![
+                ("name".to_string(), &mut endpoint.name),
+                ("server".to_string(), &mut endpoint.server),
+                ("path".to_string(), &mut endpoint.path),
+                ("secret".to_string(), &mut endpoint.secret),
+            ];
+            fields.extend(
+                endpoint
+                    .headers
+                    .iter_mut()
+                    .map(|(header, value)| (format!("headers.{}", header), value)),
+            );
+            for (field, value) in fields {
+                if !value.contains("${") {
+                    continue;
+                }
+                let expanded = expand_placeholders(value, lookup)
+                    .map_err(|e| format!("endpoint '{}' {}: {}", name, field, e))?;
+                endpoint.env_expanded.push((
+                    field,
+                    std::mem::replace(value, expanded.clone()),
+                    expanded,
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Expands endpoint templates of a config loaded from `path`.
+    fn expand_templates(mut self, path: &str) -> Result<Self, String> {
+        if !self.endpoint_templates.is_empty() {
+            // The daemon creates the fallback id before loading the config
+            let machine_id = machine_id::lookup(Some(&machine_id::fallback_path(path)));
+            let hostname = sysinfo::System::host_name();
+            let lookup = |name: &str| match name {
+                "HOSTNAME" => hostname.clone(),
+                "MACHINE_ID" => machine_id.clone(),
+                _ => std::env::var(name).ok(),
+            };
+            for template in &self.endpoint_templates {
+                let expand = |field: &str, value: &str| {
+                    expand_placeholders(value, lookup).map_err(|e| {
+                        format!("endpoint_template '{}' {}: {}", template.name, field, e)
+                    })
+                };
+                let mut headers = BTreeMap::new();
+                for (header, value) in &template.headers {
+                    let field = format!("headers.{}", header);
+                    headers.insert(header.clone(), expand(&field, value)?);
+                }
+                self.endpoints.push(Endpoint {
+                    name: expand("name", &template.name)?,
+                    server: expand("server", &template.server)?,
+                    path: expand("path", &template.path)?,
+                    secret: expand("secret", &template.secret)?,
+                    headers,
+                    from_template: true,
+                    ..template.clone()
+                });
+            }
+        }
+        Ok(self)
+    }
+
+    /// Loads `path`, requiring a valid signature when `public_key` is given.
+    pub fn load(path: &str, public_key: Option<&[u8]>) -> Result<Self, String> {
+        match public_key {
+            Some(public_key) => Self::from_signed_file(path, public_key),
+            None => Self::from_file(path).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Like [`AppConfig::load`], but keeps a copy of a config that loads at
+    /// [`last_known_good_path`] and falls back to that copy when `path`
+    /// doesn't load. The copy is only ever replaced by a config that loaded,
+    /// signature included. Returns the error that caused a fallback next to
+    /// the config.
+    pub fn load_or_last_known_good(
+        path: &str,
+        public_key: Option<&[u8]>,
+    ) -> Result<(Self, Option<String>), String> {
+        let cache_path = last_known_good_path(path);
+        match Self::load(path, public_key) {
+            Ok(config) => {
+                if let Err(e) = remember(path, &cache_path, public_key.is_some()) {
+                    warn!(error = %e, path = %cache_path, "Failed to keep last known good config");
+                }
+                Ok((config, None))
+            }
+            Err(error) => {
+                let cached = match public_key {
+                    Some(public_key) => Self::from_signed_file(&cache_path, public_key),
+                    None => std::fs::read_to_string(&cache_path)
+                        .map_err(|e| format!("failed to read {}: {}", cache_path, e))
+                        .and_then(|contents| Self::from_toml(&cache_path, &contents)),
+                };
+                match cached {
+                    Ok(config) => Ok((config, Some(error))),
+                    Err(cache_error) => Err(format!(
+                        "{} (last known good config unusable: {})",
+                        error, cache_error
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Rejects configs that name two things the same or point two sinks at
+    /// the same destination, which would otherwise fail confusingly at
+    /// runtime.
+    pub fn validate(&self) -> Result<(), String> {
+        self.connection
+            .validate()
+            .map_err(|e| format!("connection: {}", e))?;
+        self.compression
+            .validate()
+            .map_err(|e| format!("compression: {}", e))?;
+        let mut endpoints = HashSet::new();
+        let mut duplicates = BTreeSet::new();
+        for endpoint in &self.endpoints {
+            if !endpoints.insert(endpoint.name.as_str()) {
+                duplicates.insert(format!("'{}'", endpoint.name));
+            }
+        }
+        if !duplicates.is_empty() {
+            let plural = if duplicates.len() > 1 { "s" } else { "" };
+            let names: Vec<String> = duplicates.into_iter().collect();
+            return Err(format!(
+                "duplicate endpoint name{} {}",
+                plural,
+                names.join(", ")
+            ));
+        }
+        for endpoint in &self.endpoints {
+            if let Some(connection) = &endpoint.connection {
+                connection
+                    .validate()
+                    .map_err(|e| format!("endpoint '{}': {}", endpoint.name, e))?;
+            }
+            // The shared level has to suit the algorithm the endpoint offers
+            CompressionConfig {
+                algorithm: self
+                    .connection_for(endpoint)
+                    .compression
+                    .unwrap_or_default(),
+                ..self.compression
+            }
+            .validate()
+            .map_err(|e| format!("endpoint '{}': compression: {}", endpoint.name, e))?;
+            if endpoint.metrics_interval == Some(0) {
+                return Err(format!(
+                    "endpoint '{}': metrics_interval must be at least 1 second",
+                    endpoint.name
+                ));
+            }
+            if endpoint.split_by_collector && endpoint.format == MetricsFormat::Array {
+                return Err(format!(
+                    "endpoint '{}': split_by_collector can't be combined with format = \"array\"",
+                    endpoint.name
+                ));
+            }
+            let auth_mode = endpoint
+                .connection
+                .as_ref()
+                .unwrap_or(&self.connection)
+                .auth_mode;
+            if auth_mode == AuthMode::Header
+                && endpoint
+                    .headers
+                    .keys()
+                    .any(|header| header.eq_ignore_ascii_case("authorization"))
+            {
+                return Err(format!(
+                    "endpoint '{}': headers can't set Authorization with auth_mode = \"header\"",
+                    endpoint.name
+                ));
+            }
+            // An empty server is reported when the endpoint connects
+            if !endpoint.server.is_empty() {
+                check_server_scheme(&endpoint.server, endpoint.transport)
+                    .map_err(|e| format!("endpoint '{}': {}", endpoint.name, e))?;
+            }
+            self.check_transport(endpoint)
+                .map_err(|e| format!("endpoint '{}': {}", endpoint.name, e))?;
+        }
+
+        let mut devices = HashSet::new();
+        for device in &self.snmp {
+            if !devices.insert(device.display_name()) {
+                return Err(format!("duplicate snmp device '{}'", device.display_name()));
+            }
+        }
+
+        let mut gauges = HashSet::new();
+        for gauge in &self.gauges {
+            if !gauges.insert(gauge.name.as_str()) {
+                return Err(format!("duplicate gauge name '{}'", gauge.name));
+            }
+        }
+
+        let mut paths = HashSet::new();
+        paths.extend(self.control.socket.as_deref());
+        let mut addresses = HashSet::new();
+        for sink in &self.sinks {
+            match sink {
+                SinkConfig::File { path } | SinkConfig::Sqlite { path, .. } => {
+                    if !paths.insert(path.as_str()) {
+                        return Err(format!("sink path '{}' is already in use", path));
+                    }
+                }
+                SinkConfig::Graphite { address, .. } => {
+                    if !addresses.insert(("graphite", address.as_str())) {
+                        return Err(format!("duplicate graphite sink for '{}'", address));
+                    }
+                }
+                SinkConfig::Statsd { address, .. } => {
+                    if !addresses.insert(("statsd", address.as_str())) {
+                        return Err(format!("duplicate statsd sink for '{}'", address));
+                    }
+                }
+                SinkConfig::Syslog { address, .. } => {
+                    if !addresses.insert(("syslog", address.as_str())) {
+                        return Err(format!("duplicate syslog sink for '{}'", address));
+                    }
+                }
+                SinkConfig::Stdout => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects settings `endpoint`'s transport or encoding can't carry out.
+    fn check_transport(&self, endpoint: &Endpoint) -> Result<(), String> {
+        let transport = endpoint.transport;
+        if !transport.duplex() {
+            let answered = [
+                (
+                    endpoint.allow_remote_reconfigure,
+                    "allow_remote_reconfigure",
+                ),
+                (endpoint.vm_info_dedup, "vm_info_dedup"),
+                (endpoint.ready_handshake, "ready_handshake"),
+            ];
+            if let Some((_, setting)) = answered.iter().find(|(enabled, _)| *enabled) {
+                return Err(format!(
+                    "{} needs commands from the server, which aren't available over transport = \"{}\"",
+                    setting,
+                    transport.name()
+                ));
+            }
+        }
+        if transport == TransportKind::Unix && endpoint.pinned_cert_sha256.is_some() {
+            return Err(
+                "pinned_cert_sha256 can't be used with transport = \"unix\", which has no TLS"
+                    .to_string(),
+            );
+        }
+        if endpoint.encoding != Encoding::Msgpack {
+            // Both wrap the messages in msgpack envelopes
+            let compression = self
+                .connection_for(endpoint)
+                .compression
+                .unwrap_or_default();
+            let msgpack_only = [
+                (compression != Compression::None, "compression"),
+                (endpoint.send_coalesce_ms > 0, "send_coalesce_ms"),
+            ];
+            if let Some((_, setting)) = msgpack_only.iter().find(|(enabled, _)| *enabled) {
+                return Err(format!(
+                    "{} needs encoding = \"msgpack\", not \"{}\"",
+                    setting,
+                    endpoint.encoding.name()
+                ));
+            }
+        }
+        Ok(())
     }
 
     pub fn save_to_file(&self, path: &str) -> Result<(), std::io::Error> {
-        let toml = toml::to_string_pretty(self).map_err(|e| {
-            std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to serialize config: {}", e),
-            )
-        })?;
-        std::fs::write(path, toml)
+        if self.security.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                READ_ONLY_MESSAGE,
+            ));
+        }
+        let mut saved = self.clone();
+        saved.endpoints.retain(|endpoint| !endpoint.from_template);
+        for endpoint in &mut saved.endpoints {
+            for (field, written, expanded) in std::mem::take(&mut endpoint.env_expanded) {
+                let value = match field.as_str() {
+                    "name" => Some(&mut endpoint.name),
+                    "server" => Some(&mut endpoint.server),
+                    "path" => Some(&mut endpoint.path),
+                    "secret" => Some(&mut endpoint.secret),
+                    _ => field
+                        .strip_prefix("headers.")
+                        .and_then(|header| endpoint.headers.get_mut(header)),
+                };
+                // Unless it was changed or removed since, e.g. by `vmonitor edit`
+                if let Some(value) = value.filter(|value| **value == expanded) {
+                    *value = written;
+                }
+            }
+        }
+        let toml = toml::to_string_pretty(&saved)
+            .map_err(|e| std::io::Error::other(format!("Failed to serialize config: {}", e)))?;
+        write_atomically(path, toml.as_bytes())
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over
+/// `path`, so a crash mid-write leaves either the old file or the new one,
+/// never a truncated one. The permissions of an existing file are kept.
+fn write_atomically(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let target = std::path::Path::new(path);
+    let file_name = target
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file path"))?;
+    let temp_path = target.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&temp_path)?;
+        if let Ok(metadata) = std::fs::metadata(target) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, target)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
     }
+    result
 }
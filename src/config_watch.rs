@@ -0,0 +1,175 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use tokio::sync::mpsc;
+use tokio::time::{interval, Duration, Interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+/// Editors save with a burst of writes (write, truncate, rename); events
+/// within this long of each other are reported as one change.
+const COALESCE: Duration = Duration::from_millis(200);
+
+/// Waits for the config file to change. Watches the file's directory, so
+/// renames over the file are seen too, and polls once a second where that
+/// isn't possible. While the directory is removed it polls, and watches it
+/// again once it is back.
+pub struct ConfigWatcher {
+    dir: PathBuf,
+    names: Vec<OsString>,
+    source: Source,
+}
+
+enum Source {
+    Notify {
+        /// Stops watching when dropped
+        _debouncer: Debouncer<RecommendedWatcher>,
+        events: mpsc::UnboundedReceiver<DebounceEventResult>,
+    },
+    Poll(Interval),
+}
+
+impl ConfigWatcher {
+    /// Watches `path` and, for signed configs, its detached signature.
+    pub fn new(path: &str) -> Self {
+        let names = [path.to_string(), crate::signing::signature_path(path)]
+            .iter()
+            .filter_map(|path| Path::new(path).file_name().map(OsString::from))
+            .collect::<Vec<_>>();
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => Path::new(".").to_path_buf(),
+        };
+
+        let source = match watch(&dir) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!(error = %e, dir = %dir.display(), "Failed to watch the config directory, polling instead");
+                polling()
+            }
+        };
+        Self { dir, names, source }
+    }
+
+    /// Resolves once the file may have changed. Spurious wakeups are possible,
+    /// callers compare the contents anyway.
+    pub async fn changed(&mut self) {
+        loop {
+            match &mut self.source {
+                Source::Notify { events, .. } => {
+                    let changed = match events.recv().await {
+                        Some(Ok(events)) => events.iter().any(|event| {
+                            event
+                                .path
+                                .file_name()
+                                .is_some_and(|name| self.names.iter().any(|n| n == name))
+                        }),
+                        Some(Err(e)) => {
+                            warn!(error = %e, dir = %self.dir.display(), "Failed to watch the config directory");
+                            false
+                        }
+                        None => false,
+                    };
+                    // The watch ends with the directory, so poll until a new
+                    // one is created in its place
+                    if !self.dir.is_dir() {
+                        warn!(dir = %self.dir.display(), "Config directory was removed, polling until it is back");
+                        self.source = polling();
+                        return;
+                    }
+                    if changed {
+                        return;
+                    }
+                }
+                Source::Poll(interval) => {
+                    interval.tick().await;
+                    if self.dir.is_dir() {
+                        if let Ok(source) = watch(&self.dir) {
+                            info!(dir = %self.dir.display(), "Watching the config directory again");
+                            self.source = source;
+                        }
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn watch(dir: &Path) -> notify::Result<Source> {
+    let (tx, events) = mpsc::unbounded_channel();
+    let mut debouncer = new_debouncer(COALESCE, move |result| {
+        let _ = tx.send(result);
+    })?;
+    debouncer
+        .watcher()
+        .watch(dir, RecursiveMode::NonRecursive)?;
+    Ok(Source::Notify {
+        _debouncer: debouncer,
+        events,
+    })
+}
+
+/// Reports a change every second, for when the directory can't be watched.
+fn polling() -> Source {
+    let mut interval = interval(Duration::from_secs(1));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Source::Poll(interval)
+}
+
+#[tokio::test]
+async fn test_watcher_sees_rename_over_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(&path, "a = 1").unwrap();
+    let mut watcher = ConfigWatcher::new(path.to_str().unwrap());
+    assert!(matches!(watcher.source, Source::Notify { .. }));
+
+    // Unrelated files in the same directory don't wake it
+    std::fs::write(dir.path().join("other.toml"), "b = 1").unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(600), watcher.changed())
+            .await
+            .is_err()
+    );
+
+    let tmp = dir.path().join("config.toml.tmp");
+    std::fs::write(&tmp, "a = 2").unwrap();
+    std::fs::rename(&tmp, &path).unwrap();
+    tokio::time::timeout(Duration::from_secs(2), watcher.changed())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_watcher_survives_directory_removal() {
+    let parent = tempfile::tempdir().unwrap();
+    let dir = parent.path().join("vmonitor");
+    std::fs::create_dir(&dir).unwrap();
+    let path = dir.join("config.toml");
+    std::fs::write(&path, "a = 1").unwrap();
+    let mut watcher = ConfigWatcher::new(path.to_str().unwrap());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    // The file's removal may be reported first
+    for _ in 0..2 {
+        tokio::time::timeout(Duration::from_secs(2), watcher.changed())
+            .await
+            .unwrap();
+        if matches!(watcher.source, Source::Poll(_)) {
+            break;
+        }
+    }
+    assert!(matches!(watcher.source, Source::Poll(_)));
+
+    std::fs::create_dir(&dir).unwrap();
+    tokio::time::timeout(Duration::from_secs(3), watcher.changed())
+        .await
+        .unwrap();
+    assert!(matches!(watcher.source, Source::Notify { .. }));
+    std::fs::write(&path, "a = 2").unwrap();
+    tokio::time::timeout(Duration::from_secs(2), watcher.changed())
+        .await
+        .unwrap();
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::history::History;
+use crate::logfile::LogFile;
+use crate::monitor::{IntervalOverride, SampleStats};
+use crate::signing;
+
+/// Requests are a single short line; anything longer is refused.
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// Shared daemon state that control-socket commands operate on.
+pub struct ControlState {
+    pub history: Arc<History>,
+    /// Signals every endpoint to reconnect, see [`crate::monitor::Monitor::with_reconnect_signal`]
+    pub reconnect: watch::Sender<()>,
+    /// Set by `SET-INTERVAL`, see [`crate::monitor::Monitor::with_interval_override`]
+    pub interval_override: watch::Sender<Option<IntervalOverride>>,
+    /// Reopened by `REOPEN-LOGS`, if logging to a file
+    pub log_file: Option<LogFile>,
+    /// Of every running endpoint by name, reported by `STATUS`
+    pub sample_stats: watch::Receiver<BTreeMap<String, Arc<SampleStats>>>,
+    /// Required as `AUTH <token>` in front of every command when set
+    pub token: Option<String>,
+    /// User ids allowed to connect; everyone who can open the socket when
+    /// empty
+    pub allowed_uids: Vec<u32>,
+}
+
+/// Serves the line-based control protocol on a Unix socket. Each connection
+/// sends a single command line and receives the response until EOF; errors
+/// are reported as a line starting with `ERR`.
+///
+/// The socket file is only accessible to the daemon's user. When a token is
+/// configured every command has to be sent as `AUTH <token> <command>`, and
+/// connections from users outside `allowed_uids` are turned away.
+///
+/// Commands:
+/// * `HISTORY <secs>` - buffered samples from the last `secs` seconds as JSONL
+/// * `RERESOLVE` - make every endpoint reconnect, picking up new DNS records
+/// * `REOPEN-LOGS` - reopen the `--log-file` after it was rotated
+/// * `SET-INTERVAL <secs> <for-secs>` - send metrics every `secs` seconds for
+///   the next `for-secs` seconds, then go back to the usual interval
+/// * `STATUS` - a line `<endpoint> lag=<n> dropped=<n>` per endpoint: the
+///   samples of the shared collector it hasn't sent yet, and those it skipped
+///   because its server didn't keep up
+pub async fn serve(path: &str, state: Arc<ControlState>) -> io::Result<()> {
+    // A stale socket from a previous run would make bind fail
+    if Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    debug!(path = %path, "Control socket listening");
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &state).await {
+                warn!(error = %e, "Control connection failed");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: UnixStream, state: &ControlState) -> io::Result<()> {
+    let peer = stream.peer_cred()?;
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    AsyncBufReader::new(read.take(MAX_REQUEST_BYTES))
+        .read_line(&mut line)
+        .await?;
+
+    let response = if line.len() as u64 >= MAX_REQUEST_BYTES && !line.ends_with('\n') {
+        warn!("Rejected oversized control request");
+        "ERR request too long\n".to_string()
+    } else {
+        match authorize(&peer, line.trim(), state) {
+            Ok(command) => handle_command(command, state).await,
+            Err(error) => {
+                warn!(error = %error, "Rejected control request");
+                format!("ERR {}\n", error)
+            }
+        }
+    };
+    write.write_all(response.as_bytes()).await?;
+    write.shutdown().await
+}
+
+/// Checks the peer and token of a request, returning the command without
+/// its `AUTH` prefix.
+fn authorize<'a>(
+    peer: &tokio::net::unix::UCred,
+    line: &'a str,
+    state: &ControlState,
+) -> Result<&'a str, String> {
+    if !state.allowed_uids.is_empty() && !state.allowed_uids.contains(&peer.uid()) {
+        return Err(format!(
+            "user {} is not allowed to use the control socket",
+            peer.uid()
+        ));
+    }
+    let Some(token) = &state.token else {
+        return Ok(line);
+    };
+    let Some((given, command)) = line
+        .strip_prefix("AUTH ")
+        .map(|rest| rest.split_once(' ').unwrap_or((rest, "")))
+    else {
+        return Err("authentication required, send AUTH <token> <command>".to_string());
+    };
+    if !signing::constant_time_eq(given.as_bytes(), token.as_bytes()) {
+        return Err("invalid token".to_string());
+    }
+    Ok(command.trim_start())
+}
+
+async fn handle_command(command: &str, state: &ControlState) -> String {
+    let mut parts = command.split_whitespace();
+    match parts.next() {
+        Some("HISTORY") => {
+            let Some(secs) = parts.next().and_then(|s| s.parse::<u64>().ok()) else {
+                return "ERR usage: HISTORY <secs>\n".to_string();
+            };
+            let mut response = String::new();
+            for sample in state.history.since(Duration::from_secs(secs)).await {
+                match serde_json::to_string(&sample) {
+                    Ok(json) => {
+                        response.push_str(&json);
+                        response.push('\n');
+                    }
+                    Err(e) => warn!(error = %e, "Failed to serialize history sample"),
+                }
+            }
+            response
+        }
+        Some("RERESOLVE") => {
+            state.reconnect.send_replace(());
+            "OK\n".to_string()
+        }
+        Some("REOPEN-LOGS") => match &state.log_file {
+            Some(log_file) => match log_file.reopen() {
+                Ok(()) => {
+                    info!(path = %log_file.path().display(), "Reopened log file");
+                    "OK\n".to_string()
+                }
+                Err(e) => format!("ERR failed to reopen log file: {}\n", e),
+            },
+            None => "ERR not logging to a file\n".to_string(),
+        },
+        Some("SET-INTERVAL") => {
+            let secs = parts.next().and_then(|s| s.parse::<u64>().ok());
+            let duration = parts.next().and_then(|s| s.parse::<u64>().ok());
+            let (Some(secs @ 1..), Some(duration)) = (secs, duration) else {
+                return "ERR usage: SET-INTERVAL <secs> <for-secs>, secs at least 1\n".to_string();
+            };
+            let Some(until) = Instant::now().checked_add(Duration::from_secs(duration)) else {
+                return "ERR for-secs is too large\n".to_string();
+            };
+            let interval_override = IntervalOverride {
+                interval: Duration::from_secs(secs),
+                until,
+            };
+            state
+                .interval_override
+                .send_replace(Some(interval_override));
+            info!(secs, duration, "Metrics interval overridden");
+
+            let overrides = state.interval_override.clone();
+            tokio::spawn(async move {
+                sleep_until(interval_override.until).await;
+                // Unless a later SET-INTERVAL replaced this one
+                let reverted = overrides.send_if_modified(|current| {
+                    let expired = *current == Some(interval_override);
+                    if expired {
+                        *current = None;
+                    }
+                    expired
+                });
+                if reverted {
+                    info!("Metrics interval override expired");
+                }
+            });
+            "OK\n".to_string()
+        }
+        Some("STATUS") => state
+            .sample_stats
+            .borrow()
+            .iter()
+            .map(|(endpoint, stats)| {
+                format!(
+                    "{} lag={} dropped={}\n",
+                    endpoint,
+                    stats.lag(),
+                    stats.dropped()
+                )
+            })
+            .collect(),
+        Some(other) => format!("ERR unknown command '{}'\n", other),
+        None => "ERR empty command\n".to_string(),
+    }
+}
+
+/// Sends `command` to the control socket at `path`, authenticated with
+/// `token` if given, and returns the response lines. Blocking, for use from
+/// the CLI.
+pub fn request(path: &str, token: Option<&str>, command: &str) -> io::Result<Vec<String>> {
+    let mut stream = StdUnixStream::connect(path)?;
+    if let Some(token) = token {
+        write!(stream, "AUTH {} ", token)?;
+    }
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    let lines = BufReader::new(stream)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()?;
+    if let Some(error) = lines.first().and_then(|l| l.strip_prefix("ERR ")) {
+        return Err(io::Error::other(error.to_string()));
+    }
+    Ok(lines)
+}
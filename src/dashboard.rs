@@ -0,0 +1,141 @@
+use std::io;
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::history::History;
+use crate::signing;
+
+/// Self-contained page polling [`METRICS_PATH`], embedded so the binary needs
+/// no external files.
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+pub const METRICS_PATH: &str = "/api/metrics";
+/// Requests are a single line plus a few headers; anything larger is refused.
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// Serves the dashboard page on `/` and the latest collected sample as JSON
+/// on [`METRICS_PATH`]. Every response closes the connection.
+///
+/// With a `token`, the sample is only served to requests carrying it as
+/// `Authorization: Bearer <token>`; the page passes on the one it was opened
+/// with as `/?token=<token>`.
+pub async fn serve(
+    listener: TcpListener,
+    history: Arc<History>,
+    token: Option<String>,
+) -> io::Result<()> {
+    let token = Arc::new(token);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let history = history.clone();
+        let token = token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &history, token.as_deref()).await {
+                debug!(peer = %peer, error = %e, "Dashboard connection failed");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    history: &History,
+    token: Option<&str>,
+) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > MAX_REQUEST_BYTES {
+            return respond(&mut stream, "413 Payload Too Large", "text/plain", "").await;
+        }
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+    // Ignore the query string, the page adds one to defeat caching
+    let path = path.map(|p| p.split('?').next().unwrap_or(p));
+
+    match (method, path) {
+        (Some("GET"), Some("/")) => {
+            respond(
+                &mut stream,
+                "200 OK",
+                "text/html; charset=utf-8",
+                DASHBOARD_HTML,
+            )
+            .await
+        }
+        (Some("GET"), Some(METRICS_PATH)) if !authorized(&request, token) => {
+            respond(
+                &mut stream,
+                "401 Unauthorized",
+                "text/plain",
+                "token required",
+            )
+            .await
+        }
+        (Some("GET"), Some(METRICS_PATH)) => match history.latest().await {
+            Some(sample) => match serde_json::to_string(&sample) {
+                Ok(json) => respond(&mut stream, "200 OK", "application/json", &json).await,
+                Err(e) => {
+                    warn!(error = %e, "Failed to serialize sample for dashboard");
+                    respond(&mut stream, "500 Internal Server Error", "text/plain", "").await
+                }
+            },
+            None => {
+                respond(
+                    &mut stream,
+                    "503 Service Unavailable",
+                    "text/plain",
+                    "no samples yet",
+                )
+                .await
+            }
+        },
+        (Some("GET"), _) => respond(&mut stream, "404 Not Found", "text/plain", "not found").await,
+        _ => respond(&mut stream, "405 Method Not Allowed", "text/plain", "").await,
+    }
+}
+
+/// Whether `request` carries `token`, if one is required.
+fn authorized(request: &str, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    request
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .filter_map(|(_, value)| value.trim().strip_prefix("Bearer "))
+        .any(|given| signing::constant_time_eq(given.trim().as_bytes(), token.as_bytes()))
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await
+}
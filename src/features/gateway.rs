@@ -0,0 +1,270 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Duration, Instant};
+
+/// How long to wait for an answer before declaring a host unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Port used to probe the gateway when no ICMP socket can be opened. A
+/// refused connection still proves the gateway answered, so the port doesn't
+/// need to be open.
+const GATEWAY_PROBE_PORT: u16 = 80;
+const DNS_PORT: u16 = 53;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HostHealth {
+    pub address: String,
+    pub reachable: bool,
+    pub rtt_ms: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GatewayHealth {
+    pub gateway: Option<HostHealth>,
+    pub dns: Vec<HostHealth>,
+}
+
+/// Probes the gateway and every DNS server at once, so a sample waits for
+/// the slowest probe instead of all of them in a row.
+pub async fn collect_gateway_health() -> GatewayHealth {
+    let gateway = async {
+        match default_gateway()? {
+            IpAddr::V4(addr) => Some(ping(addr, PROBE_TIMEOUT).await),
+            addr => Some(tcp_ping(SocketAddr::new(addr, GATEWAY_PROBE_PORT), PROBE_TIMEOUT).await),
+        }
+    };
+    let dns = futures::future::join_all(
+        dns_servers()
+            .into_iter()
+            .map(|server| tcp_ping(SocketAddr::new(server, DNS_PORT), PROBE_TIMEOUT)),
+    );
+    let (gateway, dns) = tokio::join!(gateway, dns);
+
+    GatewayHealth { gateway, dns }
+}
+
+/// Sends an ICMP echo to `addr`, which a gateway answers even when it
+/// filters every TCP port. Falls back to [`tcp_ping`] on port 80 when neither
+/// an unprivileged ping socket nor a raw socket can be opened.
+pub async fn ping(addr: Ipv4Addr, probe_timeout: Duration) -> HostHealth {
+    match icmp_socket() {
+        Some(socket) => icmp_ping(&socket, addr, probe_timeout).await,
+        None => {
+            tcp_ping(
+                SocketAddr::new(addr.into(), GATEWAY_PROBE_PORT),
+                probe_timeout,
+            )
+            .await
+        }
+    }
+}
+
+/// An ICMP socket, preferring the unprivileged kind (`ping_group_range` on
+/// Linux) over a raw one, which needs `CAP_NET_RAW`.
+fn icmp_socket() -> Option<UdpSocket> {
+    [Type::DGRAM, Type::RAW].into_iter().find_map(|kind| {
+        let socket = Socket::new(Domain::IPV4, kind, Some(Protocol::ICMPV4)).ok()?;
+        socket.set_nonblocking(true).ok()?;
+        UdpSocket::from_std(socket.into()).ok()
+    })
+}
+
+async fn icmp_ping(socket: &UdpSocket, addr: Ipv4Addr, probe_timeout: Duration) -> HostHealth {
+    // Ping sockets replace the identifier with their own and only deliver
+    // matching replies; raw sockets see every reply, so it's checked below
+    let id = std::process::id() as u16;
+    let start = Instant::now();
+    let exchange = async {
+        socket
+            .send_to(&echo_request(id, 1), SocketAddr::new(addr.into(), 0))
+            .await?;
+        let mut buf = [0u8; 1500];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await?;
+            if from.ip() == IpAddr::V4(addr) && is_echo_reply(&buf[..len], id) {
+                return Ok::<_, std::io::Error>(());
+            }
+        }
+    };
+    let reachable = matches!(timeout(probe_timeout, exchange).await, Ok(Ok(())));
+
+    HostHealth {
+        address: addr.to_string(),
+        reachable,
+        rtt_ms: reachable.then(|| start.elapsed().as_secs_f64() * 1000.0),
+    }
+}
+
+fn echo_request(id: u16, seq: u16) -> Vec<u8> {
+    let mut packet = vec![ICMP_ECHO_REQUEST, 0, 0, 0];
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(b"vmonitor");
+    let checksum = icmp_checksum(&packet);
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// Whether `packet` is an echo reply, for `id` when it came through a raw
+/// socket. Raw sockets (and ping sockets on macOS) hand over the IPv4 header
+/// too, recognizable by its version nibble.
+fn is_echo_reply(packet: &[u8], id: u16) -> bool {
+    let (icmp, raw) = match packet.first() {
+        Some(first) if first >> 4 == 4 => {
+            let header_len = usize::from(first & 0x0f) * 4;
+            (packet.get(header_len..).unwrap_or_default(), true)
+        }
+        _ => (packet, false),
+    };
+    icmp.len() >= 8
+        && icmp[0] == ICMP_ECHO_REPLY
+        && (!raw || u16::from_be_bytes([icmp[4], icmp[5]]) == id)
+}
+
+/// The internet checksum: the ones' complement of the ones' complement sum
+/// of 16-bit words.
+fn icmp_checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Measures reachability with a TCP handshake, which works without the raw
+/// socket privileges ICMP needs. Both an accepted and a refused connection
+/// count as reachable; only a timeout or a network error does not.
+pub async fn tcp_ping(addr: SocketAddr, probe_timeout: Duration) -> HostHealth {
+    let start = Instant::now();
+    let reachable = match timeout(probe_timeout, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => e.kind() == std::io::ErrorKind::ConnectionRefused,
+        Err(_) => false,
+    };
+
+    HostHealth {
+        address: addr.ip().to_string(),
+        reachable,
+        rtt_ms: reachable.then(|| start.elapsed().as_secs_f64() * 1000.0),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn default_gateway() -> Option<IpAddr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_gateway(&routes)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn default_gateway() -> Option<IpAddr> {
+    None
+}
+
+/// Parses `/proc/net/route`, where addresses are hex in host byte order.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_default_gateway(routes: &str) -> Option<IpAddr> {
+    const RTF_GATEWAY: u32 = 0x2;
+
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[1] != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        let flags = u32::from_str_radix(fields[3], 16).ok()?;
+        if gateway == 0 || flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        Some(IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())))
+    })
+}
+
+#[cfg(unix)]
+fn dns_servers() -> Vec<IpAddr> {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .map(|conf| parse_nameservers(&conf))
+        .unwrap_or_default()
+}
+
+#[cfg(not(unix))]
+fn dns_servers() -> Vec<IpAddr> {
+    Vec::new()
+}
+
+fn parse_nameservers(conf: &str) -> Vec<IpAddr> {
+    conf.lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("nameserver") => parts.next()?.parse().ok(),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_tcp_ping_measures_rtt() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let health = tcp_ping(addr, PROBE_TIMEOUT).await;
+
+    assert!(health.reachable);
+    assert_eq!(health.address, "127.0.0.1");
+    assert!(health.rtt_ms.is_some_and(|rtt| rtt >= 0.0));
+}
+
+#[tokio::test]
+async fn test_ping_loopback() {
+    // ICMP when the sandbox allows it, the TCP fallback otherwise; loopback
+    // answers either way, unlike a port nobody listens on behind a firewall
+    let health = ping(Ipv4Addr::LOCALHOST, PROBE_TIMEOUT).await;
+
+    assert!(health.reachable);
+    assert_eq!(health.address, "127.0.0.1");
+}
+
+#[test]
+fn test_echo_packets() {
+    let request = echo_request(0x1234, 1);
+    assert_eq!(request[0], ICMP_ECHO_REQUEST);
+    // A packet including its own checksum sums to zero
+    assert_eq!(icmp_checksum(&request), 0);
+
+    let mut reply = request.clone();
+    reply[0] = ICMP_ECHO_REPLY;
+    assert!(is_echo_reply(&reply, 0x1234));
+    assert!(!is_echo_reply(&request, 0x1234));
+
+    // Through a raw socket, behind a 20-byte IPv4 header
+    let mut raw = vec![0x45];
+    raw.resize(20, 0);
+    raw.extend_from_slice(&reply);
+    assert!(is_echo_reply(&raw, 0x1234));
+    assert!(!is_echo_reply(&raw, 0x4321));
+}
+
+#[test]
+fn test_parse_default_gateway() {
+    let routes =
+        "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                  eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+                  eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+    let expected = Ipv4Addr::from(0x0101A8C0u32.to_ne_bytes());
+
+    assert_eq!(parse_default_gateway(routes), Some(IpAddr::V4(expected)));
+    assert_eq!(
+        parse_nameservers("# comment\nnameserver 1.1.1.1\nsearch lan\n"),
+        vec!["1.1.1.1".parse::<IpAddr>().unwrap()]
+    );
+}
@@ -0,0 +1,43 @@
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+
+use crate::config::GaugeConfig;
+
+/// Runs the gauge's command through the shell and parses its trimmed stdout
+/// as a number.
+pub async fn read(gauge: &GaugeConfig) -> Result<f64, String> {
+    let mut command = shell(&gauge.command);
+    // Don't leave a hung script behind when it times out
+    command.kill_on_drop(true);
+    let budget = Duration::from_millis(gauge.timeout_ms);
+    let output = timeout(budget, command.output())
+        .await
+        .map_err(|_| format!("timed out after {}ms", gauge.timeout_ms))?
+        .map_err(|e| format!("failed to run: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("exited with {}", output.status));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let value = stdout.trim();
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite())
+        .ok_or_else(|| format!("output '{}' is not a number", value))
+}
+
+/// Runs `command` through the platform shell.
+#[cfg(unix)]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+pub fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
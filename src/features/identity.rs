@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+/// Capability names by bit number, from `linux/capability.h`.
+const CAPABILITY_NAMES: [&str; 41] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Who vmonitor runs as, to explain metrics missing for lack of privileges
+/// (e.g. socket counts without `CAP_NET_ADMIN`).
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessIdentity {
+    pub euid: u32,
+    pub egid: u32,
+    /// Effective capability mask as hex, as in `/proc/self/status`
+    pub cap_eff: String,
+    /// Names of the effective capabilities; unknown bits show as `CAP_<n>`
+    pub capabilities: Vec<String>,
+}
+
+#[cfg(target_os = "linux")]
+pub fn collect() -> Option<ProcessIdentity> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    parse_status(&status)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn collect() -> Option<ProcessIdentity> {
+    None
+}
+
+/// Parses the `Uid`, `Gid` and `CapEff` lines of `/proc/<pid>/status`; the
+/// effective ID is the second column.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_status(status: &str) -> Option<ProcessIdentity> {
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .map(str::split_whitespace)
+    };
+
+    let euid = field("Uid")?.nth(1)?.parse().ok()?;
+    let egid = field("Gid")?.nth(1)?.parse().ok()?;
+    let cap_eff = field("CapEff")?.next()?.to_string();
+    let mask = u64::from_str_radix(&cap_eff, 16).ok()?;
+
+    let capabilities = (0..64)
+        .filter(|bit| mask & (1 << bit) != 0)
+        .map(|bit| match CAPABILITY_NAMES.get(bit) {
+            Some(name) => name.to_string(),
+            None => format!("CAP_{}", bit),
+        })
+        .collect();
+
+    Some(ProcessIdentity {
+        euid,
+        egid,
+        cap_eff,
+        capabilities,
+    })
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_reports_effective_uid_and_capabilities() {
+    let identity = collect().unwrap();
+
+    assert_eq!(identity.euid, unsafe { libc::geteuid() });
+    assert_eq!(identity.egid, unsafe { libc::getegid() });
+    assert_eq!(identity.cap_eff.len(), 16);
+
+    let parsed =
+        parse_status("Uid:\t0\t1000\t0\t0\nGid:\t0\t100\t0\t0\nCapEff:\t0000000000003000\n")
+            .unwrap();
+    assert_eq!(parsed.euid, 1000);
+    assert_eq!(parsed.capabilities, vec!["CAP_NET_ADMIN", "CAP_NET_RAW"]);
+}
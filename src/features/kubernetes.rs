@@ -0,0 +1,88 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// Written into every pod that mounts a service account token.
+const NAMESPACE_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// Where vmonitor runs when deployed as a Kubernetes pod. The pod and node
+/// names come from the downward API, which has to map them to `POD_NAME`
+/// and `NODE_NAME` in the pod spec.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct K8sInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pod_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+}
+
+/// Reads the pod identity from the environment, or `None` outside
+/// Kubernetes.
+pub fn detect() -> Option<K8sInfo> {
+    from_env(|name| std::env::var(name).ok(), Path::new(NAMESPACE_FILE))
+}
+
+/// Kubernetes is recognized by `KUBERNETES_SERVICE_HOST`, which it sets in
+/// every container. `POD_NAMESPACE` wins over the service account's
+/// `namespace_file`.
+pub fn from_env(lookup: impl Fn(&str) -> Option<String>, namespace_file: &Path) -> Option<K8sInfo> {
+    lookup("KUBERNETES_SERVICE_HOST")?;
+    let var = |name: &str| lookup(name).filter(|value| !value.is_empty());
+    let namespace = var("POD_NAMESPACE").or_else(|| {
+        let namespace = std::fs::read_to_string(namespace_file).ok()?;
+        Some(namespace.trim().to_string()).filter(|namespace| !namespace.is_empty())
+    });
+    Some(K8sInfo {
+        pod_name: var("POD_NAME"),
+        namespace,
+        node_name: var("NODE_NAME"),
+    })
+}
+
+#[test]
+fn test_pod_identity_from_env() {
+    let dir = tempfile::tempdir().unwrap();
+    let namespace_file = dir.path().join("namespace");
+    std::fs::write(&namespace_file, "monitoring\n").unwrap();
+    let env = |vars: &'static [(&'static str, &'static str)]| {
+        move |name: &str| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    };
+
+    let pod = from_env(
+        env(&[
+            ("KUBERNETES_SERVICE_HOST", "10.96.0.1"),
+            ("POD_NAME", "vmonitor-7d9f"),
+            ("NODE_NAME", "worker-3"),
+        ]),
+        &namespace_file,
+    );
+    assert_eq!(
+        pod,
+        Some(K8sInfo {
+            pod_name: Some("vmonitor-7d9f".to_string()),
+            namespace: Some("monitoring".to_string()),
+            node_name: Some("worker-3".to_string()),
+        })
+    );
+
+    let explicit = from_env(
+        env(&[
+            ("KUBERNETES_SERVICE_HOST", "10.96.0.1"),
+            ("POD_NAMESPACE", "default"),
+        ]),
+        &namespace_file,
+    );
+    assert_eq!(explicit.unwrap().namespace.as_deref(), Some("default"));
+
+    assert_eq!(
+        from_env(env(&[("POD_NAME", "vmonitor-7d9f")]), &namespace_file),
+        None
+    );
+}
@@ -0,0 +1,171 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::warn;
+
+/// Keys the hash of the OS machine id, so the id vmonitor reports can't be
+/// correlated with the one other software reports, as machine-id(5) asks.
+const APP_ID: [u8; 16] = [
+    0xf9, 0x9d, 0x98, 0x4d, 0xed, 0xe8, 0x81, 0x91, 0x7a, 0xaa, 0xc4, 0x0b, 0xd3, 0x1b, 0x1d, 0x51,
+];
+
+/// Identifies the host across hostname changes: the OS machine id if there
+/// is one, otherwise a random UUID persisted at `fallback` so it survives
+/// restarts. Only the daemon should call this, as it may create `fallback`.
+pub fn resolve(fallback: Option<&Path>) -> Option<String> {
+    if let Some(id) = platform_id() {
+        return Some(id);
+    }
+    let path = fallback?;
+    match load_or_generate(path) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            warn!(error = %e, path = %path.display(), "Failed to persist a machine id");
+            None
+        }
+    }
+}
+
+/// Like [`resolve`], but only reads `fallback`, leaving it to the daemon to
+/// create it.
+pub fn lookup(fallback: Option<&Path>) -> Option<String> {
+    platform_id().or_else(|| non_empty(std::fs::read_to_string(fallback?).ok()?))
+}
+
+/// Where the generated id is kept: next to the config file.
+pub fn fallback_path(config_path: &str) -> PathBuf {
+    Path::new(config_path).with_file_name("vmonitor-machine-id")
+}
+
+/// The OS machine id, hashed with [`APP_ID`] into a UUID so the raw id never
+/// leaves the host.
+pub fn platform_id() -> Option<String> {
+    os_id().map(|id| app_specific(&id))
+}
+
+fn app_specific(id: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, id.as_bytes());
+    let tag = hmac::sign(&key, &APP_ID);
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&tag.as_ref()[..16]);
+    format_uuid(bytes)
+}
+
+/// Reads the id assigned by systemd/dbus at install time.
+#[cfg(target_os = "linux")]
+fn os_id() -> Option<String> {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .find_map(|path| non_empty(std::fs::read_to_string(path).ok()?))
+}
+
+/// Reads `IOPlatformUUID` from the IOKit registry.
+#[cfg(target_os = "macos")]
+fn os_id() -> Option<String> {
+    let output = std::process::Command::new("ioreg")
+        .args(["-rd1", "-c", "IOPlatformExpertDevice"])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    // "IOPlatformUUID" = "564D7F5E-..."
+    let line = output
+        .lines()
+        .find(|line| line.contains("\"IOPlatformUUID\""))?;
+    non_empty(line.rsplit('"').nth(1)?.to_string())
+}
+
+/// Reads `MachineGuid`, set by Windows setup.
+#[cfg(windows)]
+fn os_id() -> Option<String> {
+    let output = std::process::Command::new("reg")
+        .args([
+            "query",
+            r"HKLM\SOFTWARE\Microsoft\Cryptography",
+            "/v",
+            "MachineGuid",
+        ])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    // MachineGuid    REG_SZ    3f1c...
+    let line = output.lines().find(|line| line.contains("MachineGuid"))?;
+    non_empty(line.split_whitespace().last()?.to_string())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn os_id() -> Option<String> {
+    None
+}
+
+fn non_empty(id: String) -> Option<String> {
+    let id = id.trim();
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+fn load_or_generate(path: &Path) -> io::Result<String> {
+    if let Ok(existing) = std::fs::read_to_string(path) {
+        if let Some(id) = non_empty(existing) {
+            return Ok(id);
+        }
+    }
+    let id = random_uuid()?;
+    std::fs::write(path, format!("{}\n", id))?;
+    Ok(id)
+}
+
+/// A random (version 4) UUID.
+fn random_uuid() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| io::Error::other("no randomness available"))?;
+    Ok(format_uuid(bytes))
+}
+
+/// Formats `bytes` as a version 4 UUID, overwriting its version and variant.
+fn format_uuid(mut bytes: [u8; 16]) -> String {
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[test]
+fn test_generated_id_is_persisted() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vmonitor-machine-id");
+
+    let id = load_or_generate(&path).unwrap();
+    assert_eq!(id.len(), 36);
+    assert_eq!(&id[14..15], "4");
+    assert_eq!(load_or_generate(&path).unwrap(), id);
+}
+
+#[test]
+fn test_os_id_is_hashed_per_app() {
+    let os_id = "4f1c2a0e8b7d4c3e9a6b5d2c1e0f9a8b";
+    let id = app_specific(os_id);
+    assert_eq!(id.len(), 36);
+    assert_eq!(&id[14..15], "4");
+    assert!(!id.replace('-', "").contains(os_id));
+    assert_eq!(app_specific(os_id), id);
+    assert_ne!(app_specific("0f1c2a0e8b7d4c3e9a6b5d2c1e0f9a8b"), id);
+}
+
+#[test]
+fn test_lookup_does_not_create_the_fallback() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("vmonitor-machine-id");
+
+    lookup(Some(&path));
+    assert!(!path.exists());
+}
@@ -1,8 +1,34 @@
-use netstat2::{get_sockets_info, AddressFamilyFlags, ProtocolFlags, ProtocolSocketInfo};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use netstat2::{get_sockets_info, AddressFamilyFlags, ProtocolFlags, ProtocolSocketInfo, TcpState};
 use serde::{Deserialize, Serialize};
-use sysinfo::{Disks, Networks, RefreshKind, System};
+use sha2::{Digest, Sha256};
+use sysinfo::{
+    Components, DiskRefreshKind, Disks, Networks, ProcessStatus, ProcessesToUpdate, RefreshKind,
+    System,
+};
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::config::SnmpDevice;
+use crate::config::{
+    glob_match, Collector, CounterMode, DetailLevel, GaugeConfig, InterfaceFilter, ReportConfig,
+    TimestampSource,
+};
+use crate::features::gateway::{self, GatewayHealth};
+use crate::features::gauge;
+use crate::features::identity::{self, ProcessIdentity};
+use crate::features::kubernetes::{self, K8sInfo};
+use crate::features::machine_id;
+use crate::features::oom::OomEvent;
+use crate::features::snmp::{SnmpPoll, SnmpReading};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct VMInfo {
     os: String,
@@ -17,18 +43,116 @@ pub struct VMInfo {
     uptime: u64,
     disk: u64,
     version: String,
+    /// Stable host identifier that survives hostname changes, see
+    /// [`crate::features::machine_id`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    machine_id: Option<String>,
+    /// Effective user and capabilities of the vmonitor process (Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    process: Option<ProcessIdentity>,
+    /// Pod, namespace and node when running on Kubernetes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kubernetes: Option<K8sInfo>,
+    /// Identifies the agent's effective configuration, see
+    /// [`crate::config::AppConfig::fingerprint`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config_fingerprint: Option<String>,
+}
+
+impl VMInfo {
+    /// Hex SHA-256 of the inventory, leaving out `uptime` so the hash stays
+    /// stable across reconnects of an otherwise unchanged host.
+    pub fn content_hash(&self) -> String {
+        let stable = VMInfo {
+            uptime: 0,
+            ..self.clone()
+        };
+        let bytes = rmp_serde::to_vec_named(&stable).unwrap_or_default();
+        format!("{:x}", Sha256::digest(&bytes))
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct ReportData {
     pub uptime: u64,
-    pub system: SystemInfo,
-    pub network: NetworkInfo,
-    pub disk: DiskInfo,
+    /// Unix time in milliseconds the sample was collected, set when sending
+    /// to an endpoint with a wall [`TimestampSource`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collected_at: Option<u64>,
+    /// Nanoseconds since vmonitor started when the sample was collected, set
+    /// when sending to an endpoint with a monotonic [`TimestampSource`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mono_ns: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<SystemInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disk: Option<DiskInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<GatewayHealth>,
+    /// OOM kills since the previous sample
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oom_kills: Option<Vec<OomEvent>>,
+    /// Values polled from the configured SNMP devices
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub snmp: Vec<SnmpReading>,
+    /// The processes using the most CPU, then memory; absent when the
+    /// endpoint's `top_processes` is 0 or the system collector is off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_processes: Option<Vec<ProcessInfo>>,
+    /// Hardware sensors such as CPU and board temperatures, read with the
+    /// system collector; empty where none are exposed, as in most VMs
+    #[serde(default)]
+    pub temperatures: Vec<TemperatureInfo>,
+    /// Output of the configured `[[gauge]]` commands by name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub custom_gauges: HashMap<String, f64>,
+    /// Set when the system collector couldn't read real values (e.g. `/proc`
+    /// isn't mounted), so zeros shouldn't be taken for an idle host.
+    #[serde(default)]
+    pub degraded: bool,
+    /// One of the first `report.warmup_samples` samples, whose counters only
+    /// seed the baselines; its rates are left out. Only sent when set
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub warmup: bool,
+    /// Parts of the sample that couldn't be collected, e.g. a disk that
+    /// didn't answer within its budget
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub collection_errors: Vec<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    /// Percent of one core, so above 100 for multithreaded processes
+    pub cpu_usage: f32,
+    /// Resident memory in bytes
+    pub memory: u64,
+    /// Bytes read from disk since the previous sample
+    pub disk_read: u64,
+    /// Bytes written to disk since the previous sample
+    pub disk_write: u64,
+}
+
+/// One temperature sensor, in degrees Celsius.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TemperatureInfo {
+    pub label: String,
+    pub temperature: f32,
+    /// Highest temperature seen since vmonitor started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f32>,
+    /// Temperature at which the hardware shuts down or throttles
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub critical: Option<f32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SystemLoadAvg {
     pub one: f64,
@@ -36,7 +160,7 @@ pub struct SystemLoadAvg {
     pub fifteen: f64,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SystemInfo {
     pub cpu_usage: f32,
@@ -44,43 +168,677 @@ pub struct SystemInfo {
     pub memory_total: u64,
     pub swap_used: u64,
     pub swap_total: u64,
+    /// Pages swapped in per second since the previous sample (Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap_in_rate: Option<f64>,
+    /// Pages swapped out per second since the previous sample (Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap_out_rate: Option<f64>,
+    /// Pages swapped in since boot (Linux only), a raw counter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap_pages_in: Option<u64>,
+    /// Pages swapped out since boot (Linux only), a raw counter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swap_pages_out: Option<u64>,
+    /// CFS throttling since the previous sample when running in a CPU-limited
+    /// cgroup v2 (Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_throttle: Option<CpuThrottle>,
     pub process_count: u32,
-    pub load_avg: SystemLoadAvg,
+    /// Processes that exited but weren't reaped by their parent. Reliable on
+    /// Linux, FreeBSD and macOS; absent on Windows, which has no such state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zombie_count: Option<u32>,
+    /// Processes in uninterruptible sleep (D state), usually waiting on
+    /// storage; a growing count points at a hung disk or NFS mount (Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uninterruptible_count: Option<u32>,
+    /// Names of the processes in D state, sorted, at most five
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub uninterruptible_processes: Vec<String>,
+    /// Absent on Windows, which has no load average
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub load_avg: Option<SystemLoadAvg>,
+    /// Usage of each core, only sent at [`DetailLevel::Full`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cpu_cores: Vec<f32>,
+    /// Current frequency of each core in kHz (Linux cpufreq), only sent at
+    /// [`DetailLevel::Full`]; empty where cpufreq isn't exposed, as in most VMs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cpu_freqs: Vec<u64>,
+    /// Active cpufreq scaling governor, e.g. `powersave` (Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_governor: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuThrottle {
+    /// Scheduler periods in which the cgroup hit its quota
+    pub throttled_count: u64,
+    /// Time the cgroup's tasks were held back from running
+    pub throttled_time_ms: f64,
+}
+
+/// Per-second rates of the TCP counters that point at network trouble.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TcpErrorRates {
+    /// Segments retransmitted (`RetransSegs`)
+    pub retransmits: f64,
+    /// Segments received with errors such as bad checksums (`InErrs`)
+    pub in_errors: f64,
+    /// Resets sent (`OutRsts`)
+    pub out_resets: f64,
+    /// Connection attempts that failed (`AttemptFails`)
+    pub attempt_fails: f64,
+    /// Established connections reset (`EstabResets`)
+    pub estab_resets: f64,
+    /// Retransmission timeouts (`TCPTimeouts`), absent when
+    /// `/proc/net/netstat` couldn't be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeouts: Option<f64>,
+    /// Connections dropped by full listen queues (`ListenDrops`), absent when
+    /// `/proc/net/netstat` couldn't be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen_drops: Option<f64>,
+}
+
+/// TCP counters from `/proc/net/snmp` and `/proc/net/netstat` at the time
+/// they were read.
+#[derive(Debug, Clone, Copy)]
+struct TcpCounters {
+    retransmits: u64,
+    in_errors: u64,
+    out_resets: u64,
+    attempt_fails: u64,
+    estab_resets: u64,
+    timeouts: Option<u64>,
+    listen_drops: Option<u64>,
+    read_at: Instant,
+}
+
+/// `nr_throttled`/`throttled_usec` from a cgroup's `cpu.stat`.
+#[derive(Debug, Clone, Copy)]
+struct ThrottleCounters {
+    nr_throttled: u64,
+    throttled_usec: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct NetworkInfo {
-    download_traffic: u64,
-    upload_traffic: u64,
-    tcp_count: u32,
-    udp_count: u32,
+    /// Bytes received since boot, a raw counter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download_traffic: Option<u64>,
+    /// Bytes sent since boot, a raw counter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_traffic: Option<u64>,
+    /// Bytes received per second since the previous sample, 0 on the first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    download_rate: Option<f64>,
+    /// Bytes sent per second since the previous sample, 0 on the first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upload_rate: Option<f64>,
+    /// Absent when the socket table couldn't be read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tcp_count: Option<u32>,
+    /// TCP sockets per state, e.g. `TIME_WAIT`, adding up to `tcp_count`.
+    /// One entry per state seen, however many sockets are in it
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tcp_states: HashMap<String, u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    udp_count: Option<u32>,
+    /// TCP connections the peer closed but the local side hasn't, the usual
+    /// sign of an application leaking sockets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    close_wait_count: Option<u32>,
+    /// How long the oldest of them has been in CLOSE_WAIT, counted from the
+    /// first collection that saw it, so a lower bound
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    oldest_close_wait_secs: Option<u64>,
+    /// TCP retransmits and errors per second since the previous sample
+    /// (Linux only)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tcp_errors: Option<TcpErrorRates>,
+    /// Traffic of each interface, only sent at [`DetailLevel::Full`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<InterfaceInfo>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceInfo {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_traffic: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_traffic: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_rate: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct DiskInfo {
     space_used: u64,
     space_total: u64,
+    /// Bytes read since boot, a raw counter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read: Option<u64>,
+    /// Bytes written since boot, a raw counter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    write: Option<u64>,
+    /// Bytes read per second since the previous sample, 0 on the first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_rate: Option<f64>,
+    /// Bytes written per second since the previous sample, 0 on the first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    write_rate: Option<f64>,
+    /// Space of each mounted disk, only sent at [`DetailLevel::Full`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disks: Vec<DiskDetail>,
+    /// Latency and queue depth of each block device (Linux only), only sent
+    /// at [`DetailLevel::Full`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DiskIoStats>,
+    /// Totals of NFS/CIFS/... mounts, which are not part of `space_used`
+    /// and `space_total`
+    #[serde(default)]
+    pub network_space_used: u64,
+    #[serde(default)]
+    pub network_space_total: u64,
+    /// Space of each network mount, only sent at [`DetailLevel::Full`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub network_mounts: Vec<DiskDetail>,
+}
+
+/// Filesystem types whose data lives on another host.
+const NETWORK_FILESYSTEMS: &[&str] = &[
+    "nfs",
+    "nfs4",
+    "cifs",
+    "smb3",
+    "smbfs",
+    "ceph",
+    "glusterfs",
+    "9p",
+    "afs",
+    "fuse.sshfs",
+];
+
+/// Budget for reading the space of a network mount sysinfo doesn't list when
+/// `disk_timeout` is unset, since a hard NFS or CIFS mount can hang.
+const NETWORK_MOUNT_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn is_network_filesystem(fstype: &str) -> bool {
+    NETWORK_FILESYSTEMS.contains(&fstype)
+}
+
+/// Mount points of network filesystems in a `/proc/mounts` listing.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn network_mount_points(mounts: &str) -> HashSet<String> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (_source, mount_point, fstype) = (fields.next()?, fields.next()?, fields.next()?);
+            // Spaces in mount points are escaped as \040
+            is_network_filesystem(fstype).then(|| mount_point.replace("\\040", " "))
+        })
+        .collect()
+}
+
+impl DiskInfo {
+    /// Totals `disks`, keeping those mounted at `network_mounts` out of the
+    /// local totals and in a list and aggregate of their own.
+    fn from_disks(
+        disks: Vec<DiskDetail>,
+        network_mounts: &HashSet<String>,
+        io: DiskTraffic,
+        devices: Vec<DiskIoStats>,
+    ) -> Self {
+        let (network_mounts, disks): (Vec<_>, Vec<_>) = disks
+            .into_iter()
+            .partition(|disk| network_mounts.contains(&disk.mount_point));
+        Self {
+            space_used: disks.iter().map(|d| d.space_used).sum(),
+            space_total: disks.iter().map(|d| d.space_total).sum(),
+            read: Some(io.read),
+            write: Some(io.write),
+            read_rate: io.read_rate,
+            write_rate: io.write_rate,
+            disks,
+            devices,
+            network_space_used: network_mounts.iter().map(|d| d.space_used).sum(),
+            network_space_total: network_mounts.iter().map(|d| d.space_total).sum(),
+            network_mounts,
+        }
+    }
+}
+
+/// Bytes moved by all disks since boot and per second since the previous
+/// sample.
+#[derive(Debug, Clone, Copy, Default)]
+struct DiskTraffic {
     read: u64,
     write: u64,
+    read_rate: Option<f64>,
+    write_rate: Option<f64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskIoStats {
+    pub name: String,
+    /// Average time per request completed since the previous sample,
+    /// including time spent queued; `None` if nothing completed
+    pub avg_io_time_ms: Option<f64>,
+    /// Requests in flight when sampled
+    pub queue_depth: u64,
+}
+
+/// Cumulative `/proc/diskstats` counters of one device.
+#[derive(Debug, Clone, Copy)]
+struct DiskStatsCounters {
+    completed: u64,
+    io_time_ms: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskDetail {
+    pub mount_point: String,
+    pub space_used: u64,
+    pub space_total: u64,
+    /// Hours until the disk fills up if free space keeps shrinking at the
+    /// rate seen over the last [`SPACE_TREND_WINDOW`]; `None` while it
+    /// isn't shrinking or there are too few samples
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hours_to_full: Option<f64>,
+}
+
+/// How far back free space samples are used to estimate `hours_to_full`.
+pub const SPACE_TREND_WINDOW: Duration = Duration::from_secs(3600);
+/// Fewest samples a free space trend is fitted to.
+const MIN_TREND_SAMPLES: usize = 3;
+/// Most process names listed in `uninterruptible_processes`.
+const MAX_UNINTERRUPTIBLE_NAMES: usize = 5;
+
+/// Reported instead of a top process name on `process_name_denylist`.
+pub const REDACTED_PROCESS_NAME: &str = "<redacted>";
+
+impl ReportData {
+    /// Drops the per-core, per-interface and per-disk vectors unless `level`
+    /// is [`DetailLevel::Full`], so one collected sample can serve endpoints
+    /// that want different levels of detail.
+    pub fn at_level(mut self, level: DetailLevel) -> Self {
+        if level == DetailLevel::Summary {
+            if let Some(system) = &mut self.system {
+                system.cpu_cores.clear();
+                system.cpu_freqs.clear();
+            }
+            if let Some(network) = &mut self.network {
+                network.interfaces.clear();
+            }
+            if let Some(disk) = &mut self.disk {
+                disk.disks.clear();
+                disk.devices.clear();
+                disk.network_mounts.clear();
+            }
+        }
+        self
+    }
+
+    /// Drops the sections of collectors not in `collectors`, for an endpoint
+    /// that was reconfigured to fewer collectors than the shared collection
+    /// runs. Sections of no particular collector, like gauges, stay.
+    pub fn only(mut self, collectors: &[Collector]) -> Self {
+        if !collectors.contains(&Collector::System) {
+            self.system = None;
+            self.top_processes = None;
+            self.temperatures.clear();
+        }
+        if !collectors.contains(&Collector::Network) {
+            self.network = None;
+        }
+        if !collectors.contains(&Collector::Disk) {
+            self.disk = None;
+        }
+        if !collectors.contains(&Collector::Gateway) {
+            self.gateway = None;
+        }
+        if !collectors.contains(&Collector::Oom) {
+            self.oom_kills = None;
+        }
+        self
+    }
+
+    /// Whether collection failed entirely: something went wrong and no
+    /// section holds real data (a degraded system section only has zeros).
+    /// Top processes don't count, they come with the system section.
+    pub fn is_blind(&self) -> bool {
+        let failed = self.degraded || !self.collection_errors.is_empty();
+        let has_system = self.system.is_some() && !self.degraded;
+        failed
+            && !has_system
+            && self.network.is_none()
+            && self.disk.is_none()
+            && self.gateway.is_none()
+            && self.oom_kills.is_none()
+            && self.snmp.is_empty()
+            && self.temperatures.is_empty()
+            && self.custom_gauges.is_empty()
+    }
+
+    /// Drops the cumulative since-boot counters, keeping the rates derived
+    /// from them.
+    pub fn without_raw_counters(mut self) -> Self {
+        if let Some(system) = &mut self.system {
+            system.swap_pages_in = None;
+            system.swap_pages_out = None;
+        }
+        if let Some(network) = &mut self.network {
+            network.download_traffic = None;
+            network.upload_traffic = None;
+            for interface in &mut network.interfaces {
+                interface.download_traffic = None;
+                interface.upload_traffic = None;
+            }
+        }
+        if let Some(disk) = &mut self.disk {
+            disk.read = None;
+            disk.write = None;
+        }
+        self
+    }
+
+    /// Drops the rates computed from the previous sample, keeping the
+    /// counters they are computed from. That includes the per-core CPU usage
+    /// and throttling; the overall `cpu_usage` is required on the wire and
+    /// stays, measured from the CPU refresh done when [`Metrics`] is created.
+    pub fn without_rates(mut self) -> Self {
+        if let Some(system) = &mut self.system {
+            system.swap_in_rate = None;
+            system.swap_out_rate = None;
+            system.cpu_throttle = None;
+            system.cpu_cores.clear();
+        }
+        if let Some(network) = &mut self.network {
+            network.download_rate = None;
+            network.upload_rate = None;
+            network.tcp_errors = None;
+            for interface in &mut network.interfaces {
+                interface.download_rate = None;
+                interface.upload_rate = None;
+            }
+        }
+        if let Some(disk) = &mut self.disk {
+            disk.read_rate = None;
+            disk.write_rate = None;
+        }
+        self
+    }
+
+    /// Keeps the counters, the rates or both, as `mode` says.
+    pub fn with_counters(self, mode: CounterMode) -> Self {
+        match mode {
+            CounterMode::Both => self,
+            CounterMode::RatesOnly => self.without_raw_counters(),
+            CounterMode::CountersOnly => self.without_rates(),
+        }
+    }
+}
+
+/// Stamps samples with the clocks picked by a [`TimestampSource`], keeping
+/// `mono_ns` strictly increasing from one sample to the next.
+#[derive(Debug, Clone, Copy)]
+pub struct SampleClock {
+    source: TimestampSource,
+    last_mono_ns: Option<u64>,
+}
+
+impl SampleClock {
+    pub fn new(source: TimestampSource) -> Self {
+        Self {
+            source,
+            last_mono_ns: None,
+        }
+    }
+
+    /// Sets the timestamps of `report` from `wall_ms` (Unix time in
+    /// milliseconds) and `mono_ns` (see [`crate::history::mono_nanos`]).
+    pub fn stamp(&mut self, report: &mut ReportData, wall_ms: u64, mono_ns: u64) {
+        let wall = matches!(self.source, TimestampSource::Wall | TimestampSource::Both);
+        let monotonic = matches!(
+            self.source,
+            TimestampSource::Monotonic | TimestampSource::Both
+        );
+        report.collected_at = wall.then_some(wall_ms);
+        report.mono_ns = monotonic.then(|| {
+            // Two samples within the clock's resolution still get ordered
+            let mono_ns = match self.last_mono_ns {
+                Some(last) => mono_ns.max(last + 1),
+                None => mono_ns,
+            };
+            self.last_mono_ns = Some(mono_ns);
+            mono_ns
+        });
+    }
 }
 
 pub struct Metrics {
     pub system: System,
     pub networks: Networks,
     pub disks: Disks,
+    pub components: Components,
+    pub collectors: Vec<Collector>,
+    degraded: bool,
+    swap_counters: Option<SwapCounters>,
+    tcp_counters: Option<TcpCounters>,
+    throttle_counters: Option<ThrottleCounters>,
+    /// When network and disk I/O counters were last refreshed, to turn the
+    /// bytes moved since into rates
+    network_read_at: Option<Instant>,
+    disk_read_at: Option<Instant>,
+    /// Recent intervals of total network and disk traffic, see `rate_window`
+    network_window: RateWindow,
+    disk_window: RateWindow,
+    /// When each connection currently in CLOSE_WAIT was first seen in it
+    close_wait_since: HashMap<ConnectionKey, Instant>,
+    /// Number of collection intervals the total network and disk rates are
+    /// averaged over; 1 uses only the interval since the previous collection
+    pub rate_window: usize,
+    /// Whether samples keep the cumulative counters, their rates or both
+    pub counters: CounterMode,
+    /// Interfaces counted in the network totals
+    pub interface_filter: InterfaceFilter,
+    /// Number of processes reported in `top_processes`; 0 leaves them out
+    pub top_processes: usize,
+    /// Top process names reported as [`REDACTED_PROCESS_NAME`]
+    pub process_name_denylist: Vec<String>,
+    /// Whether top process names are reported as a hash of the name
+    pub hash_process_names: bool,
+    /// Number of first samples marked `warmup` and sent without rates
+    pub warmup_samples: u32,
+    /// Samples collected so far, counted up to `warmup_samples`
+    samples_collected: u32,
+    /// Per-disk budget for reading disk space; unset reads all disks at once
+    pub disk_timeout: Option<Duration>,
+    disk_space: Arc<dyn DiskSpace>,
+    pending_disk_reads: PendingReads,
+    /// Where network mounts are listed
+    #[cfg(target_os = "linux")]
+    mounts_path: PathBuf,
+    #[cfg(target_os = "linux")]
+    kmsg: Option<crate::features::oom::KmsgReader>,
+    kmsg_unavailable: bool,
+    diskstats: HashMap<String, DiskStatsCounters>,
+    /// Recent free space of each mount point, oldest first
+    free_space: HashMap<String, VecDeque<(Instant, u64)>>,
+    /// Remote devices polled on every collection
+    pub snmp_devices: Vec<SnmpDevice>,
+    /// Readings of devices polled once for every collector, reported instead
+    /// of polling `snmp_devices`, see [`crate::features::snmp::poll_every`]
+    pub snmp_shared: Option<watch::Receiver<SnmpPoll>>,
+    /// Commands run on every collection
+    pub gauges: Vec<GaugeConfig>,
+    /// Reported in [`VMInfo`]
+    pub config_fingerprint: Option<String>,
+    /// Reported in [`VMInfo`]; read from the OS on first use when unset
+    pub machine_id: Option<String>,
+    #[cfg(feature = "snmp")]
+    snmp_request_id: i32,
+    #[cfg(not(feature = "snmp"))]
+    snmp_unavailable: bool,
+}
+
+/// Reads the total and available space of a mount point. Split out from
+/// [`Metrics`] so a hung mount can be simulated.
+pub trait DiskSpace: Send + Sync {
+    fn space(&self, mount_point: &Path) -> Option<(u64, u64)>;
+}
+
+/// [`DiskSpace`] backed by `statvfs`, the call that blocks on a stale mount.
+pub struct StatvfsSpace;
+
+/// Mount points whose space read is still blocked on the blocking pool. A
+/// read that timed out keeps its thread until the call returns, so the mount
+/// is skipped until then instead of tying up another thread every interval.
+#[derive(Clone, Default)]
+pub struct PendingReads(Arc<Mutex<HashSet<PathBuf>>>);
+
+impl PendingReads {
+    /// The reads through [`StatvfsSpace`], shared by every [`Metrics`] since
+    /// a hung mount hangs for all of them.
+    fn statvfs() -> Self {
+        static PENDING: OnceLock<PendingReads> = OnceLock::new();
+        PENDING.get_or_init(PendingReads::default).clone()
+    }
+
+    /// Marks `mount_point` as being read, unless it already is.
+    fn start(&self, mount_point: &Path) -> bool {
+        self.0.lock().unwrap().insert(mount_point.to_path_buf())
+    }
+
+    fn finish(&self, mount_point: &Path) {
+        self.0.lock().unwrap().remove(mount_point);
+    }
+}
+
+impl DiskSpace for StatvfsSpace {
+    #[cfg(unix)]
+    #[allow(clippy::unnecessary_cast)] // statvfs field widths vary by platform
+    fn space(&self, mount_point: &Path) -> Option<(u64, u64)> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(mount_point.as_os_str().as_bytes()).ok()?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return None;
+        }
+        let stat = unsafe { stat.assume_init() };
+        let block_size = stat.f_frsize as u64;
+        Some((
+            stat.f_blocks as u64 * block_size,
+            stat.f_bavail as u64 * block_size,
+        ))
+    }
+
+    #[cfg(not(unix))]
+    fn space(&self, mount_point: &Path) -> Option<(u64, u64)> {
+        Disks::new_with_refreshed_list()
+            .list()
+            .iter()
+            .find(|disk| disk.mount_point() == mount_point)
+            .map(|disk| (disk.total_space(), disk.available_space()))
+    }
+}
+
+/// `pswpin`/`pswpout` from `/proc/vmstat` at the time they were read.
+#[derive(Debug, Clone, Copy)]
+struct SwapCounters {
+    pages_in: u64,
+    pages_out: u64,
+    read_at: Instant,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Metrics {
     pub fn new() -> Self {
+        Self::with_collectors(ReportConfig::default().collect)
+    }
+
+    pub fn with_collectors(collectors: Vec<Collector>) -> Self {
         Self {
             system: System::new_all(),
             networks: Networks::new(),
             disks: Disks::new(),
+            components: Components::new(),
+            collectors,
+            degraded: false,
+            swap_counters: None,
+            tcp_counters: None,
+            throttle_counters: None,
+            network_read_at: None,
+            disk_read_at: None,
+            network_window: RateWindow::default(),
+            disk_window: RateWindow::default(),
+            close_wait_since: HashMap::new(),
+            rate_window: 1,
+            counters: CounterMode::default(),
+            interface_filter: InterfaceFilter::default(),
+            top_processes: 0,
+            process_name_denylist: Vec::new(),
+            hash_process_names: false,
+            warmup_samples: 0,
+            samples_collected: 0,
+            disk_timeout: None,
+            disk_space: Arc::new(StatvfsSpace),
+            pending_disk_reads: PendingReads::statvfs(),
+            #[cfg(target_os = "linux")]
+            mounts_path: PathBuf::from("/proc/mounts"),
+            #[cfg(target_os = "linux")]
+            kmsg: None,
+            kmsg_unavailable: false,
+            diskstats: HashMap::new(),
+            free_space: HashMap::new(),
+            snmp_devices: Vec::new(),
+            snmp_shared: None,
+            gauges: Vec::new(),
+            config_fingerprint: None,
+            machine_id: None,
+            #[cfg(feature = "snmp")]
+            snmp_request_id: 0,
+            #[cfg(not(feature = "snmp"))]
+            snmp_unavailable: false,
         }
     }
 
+    /// Applies the `[report]` settings that shape each sample. `collect` and
+    /// `interval_secs` are left to the caller, which may override them.
+    pub fn configure(&mut self, report: &ReportConfig) {
+        self.disk_timeout = report.disk_timeout_ms.map(Duration::from_millis);
+        self.counters = report.counters;
+        self.rate_window = report.rate_window;
+        self.warmup_samples = report.warmup_samples;
+        self.interface_filter = report.interface_filter.clone();
+        self.process_name_denylist = report.process_name_denylist.clone();
+        self.hash_process_names = report.hash_process_names;
+    }
+
+    fn is_enabled(&self, collector: Collector) -> bool {
+        self.collectors.contains(&collector)
+    }
+
     pub fn collect_vm_info(&mut self) -> VMInfo {
         let cpus: Vec<String> = self
             .system
@@ -96,6 +854,9 @@ impl Metrics {
             .collect();
 
         let os_info = os_info::get();
+        if self.machine_id.is_none() {
+            self.machine_id = machine_id::platform_id();
+        }
 
         VMInfo {
             os: os_info.os_type().to_string(),
@@ -117,26 +878,168 @@ impl Metrics {
             disk: self.disks.list().iter().map(|d| d.total_space()).sum(),
             uptime: System::uptime(),
             version: env!("CARGO_PKG_VERSION").to_string(),
+            machine_id: self.machine_id.clone(),
+            process: identity::collect(),
+            kubernetes: kubernetes::detect(),
+            config_fingerprint: self.config_fingerprint.clone(),
         }
     }
 
     pub async fn collet_metrics(&mut self) -> ReportData {
-        let system_data = self.collect_system_info();
-        let network_data = self.collect_network_info();
-        let disk_data = self.collect_disk_info();
+        let system_data = self
+            .is_enabled(Collector::System)
+            .then(|| self.collect_system_info());
+        // Process CPU usage is measured between the system collector's refreshes
+        let top_processes = (self.top_processes > 0 && self.is_enabled(Collector::System))
+            .then(|| self.collect_top_processes(self.top_processes));
+        let temperatures = if self.is_enabled(Collector::System) {
+            self.collect_temperature_info()
+        } else {
+            Vec::new()
+        };
+        let network_data = self
+            .is_enabled(Collector::Network)
+            .then(|| self.collect_network_info());
+        let mut collection_errors = Vec::new();
+        let disk_data = match (self.is_enabled(Collector::Disk), self.disk_timeout) {
+            (false, _) => None,
+            (true, None) => {
+                let (disk_info, errors) = self.collect_disk_info().await;
+                collection_errors.extend(errors);
+                Some(disk_info)
+            }
+            (true, Some(budget)) => {
+                let (disk_info, errors) = self.collect_disk_info_bounded(budget).await;
+                // Totals of zero disks would pass for an empty host
+                let none_answered = !errors.is_empty()
+                    && disk_info.disks.is_empty()
+                    && disk_info.network_mounts.is_empty();
+                collection_errors.extend(errors);
+                (!none_answered).then_some(disk_info)
+            }
+        };
+        let gateway_data = if self.is_enabled(Collector::Gateway) {
+            Some(gateway::collect_gateway_health().await)
+        } else {
+            None
+        };
+
+        let oom_kills = if self.is_enabled(Collector::Oom) {
+            self.collect_oom_kills()
+        } else {
+            None
+        };
 
-        ReportData {
+        let snmp = match &self.snmp_shared {
+            Some(shared) => {
+                let poll = shared.borrow().clone();
+                collection_errors.extend(poll.errors);
+                poll.readings
+            }
+            None => self.collect_snmp(&mut collection_errors).await,
+        };
+        let custom_gauges = self.collect_gauges(&mut collection_errors).await;
+
+        let degraded = system_data
+            .as_ref()
+            .is_some_and(|system| is_degraded(system, self.system.cpus().len()));
+        if degraded && !self.degraded {
+            warn!("System metrics unavailable — is /proc mounted? Reporting as degraded");
+        }
+        self.degraded = degraded;
+
+        let report = ReportData {
             uptime: System::uptime(),
+            collected_at: None,
+            mono_ns: None,
             system: system_data,
             network: network_data,
             disk: disk_data,
+            gateway: gateway_data,
+            oom_kills,
+            snmp,
+            top_processes,
+            temperatures,
+            custom_gauges,
+            degraded,
+            warmup: false,
+            collection_errors,
+        };
+        let report = if self.samples_collected < self.warmup_samples {
+            self.samples_collected += 1;
+            ReportData {
+                warmup: true,
+                ..report.without_rates()
+            }
+        } else {
+            report
+        };
+        report.with_counters(self.counters)
+    }
+
+    /// Runs every gauge command concurrently. Gauges that fail or time out
+    /// are listed in `collection_errors` and left out.
+    async fn collect_gauges(&self, collection_errors: &mut Vec<String>) -> HashMap<String, f64> {
+        let reads = self.gauges.iter().map(gauge::read);
+        let results = futures::future::join_all(reads).await;
+
+        let mut values = HashMap::new();
+        for (gauge, result) in self.gauges.iter().zip(results) {
+            match result {
+                Ok(value) => {
+                    values.insert(gauge.name.clone(), value);
+                }
+                Err(e) => {
+                    warn!(gauge = %gauge.name, error = %e, "Skipping gauge");
+                    collection_errors.push(format!("gauge {}: {}", gauge.name, e));
+                }
+            }
         }
+        values
+    }
+
+    /// Polls every SNMP device concurrently. Devices that don't answer are
+    /// listed in `collection_errors` and leave out their readings.
+    #[cfg(feature = "snmp")]
+    async fn collect_snmp(&mut self, collection_errors: &mut Vec<String>) -> Vec<SnmpReading> {
+        let first_id = self.snmp_request_id;
+        self.snmp_request_id = first_id.wrapping_add(self.snmp_devices.len() as i32);
+        let poll = crate::features::snmp::poll_all(&self.snmp_devices, first_id).await;
+        collection_errors.extend(poll.errors);
+        poll.readings
+    }
+
+    #[cfg(not(feature = "snmp"))]
+    async fn collect_snmp(&mut self, _collection_errors: &mut Vec<String>) -> Vec<SnmpReading> {
+        if !self.snmp_devices.is_empty() && !self.snmp_unavailable {
+            let devices: Vec<&str> = self.snmp_devices.iter().map(|d| d.display_name()).collect();
+            warn!(
+                devices = ?devices,
+                "SNMP devices are configured, but vmonitor was built without the snmp feature"
+            );
+            self.snmp_unavailable = true;
+        }
+        Vec::new()
     }
 
     fn collect_system_info(&mut self) -> SystemInfo {
         self.system.refresh_specifics(RefreshKind::everything());
 
         let load_avg = System::load_average();
+        let (swap_in_rate, swap_out_rate) = self.collect_swap_rates();
+        let cpu_throttle = self.collect_cpu_throttle();
+        let (cpu_freqs, cpu_governor) = collect_cpufreq();
+        let (uninterruptible_count, uninterruptible_processes) = if cfg!(target_os = "linux") {
+            let (count, names) = find_uninterruptible(
+                self.system
+                    .processes()
+                    .values()
+                    .map(|p| (p.status(), p.name().to_string_lossy())),
+            );
+            (Some(count), names)
+        } else {
+            (None, Vec::new())
+        };
 
         SystemInfo {
             cpu_usage: self.system.global_cpu_usage(),
@@ -144,16 +1047,342 @@ impl Metrics {
             memory_total: self.system.total_memory(),
             swap_used: self.system.used_swap(),
             swap_total: self.system.total_swap(),
+            swap_in_rate,
+            swap_out_rate,
+            swap_pages_in: self.swap_counters.map(|counters| counters.pages_in),
+            swap_pages_out: self.swap_counters.map(|counters| counters.pages_out),
+            cpu_throttle,
             process_count: self.system.processes().len() as u32,
-            load_avg: SystemLoadAvg {
+            zombie_count: (!cfg!(windows))
+                .then(|| count_zombies(self.system.processes().values().map(|p| p.status()))),
+            uninterruptible_count,
+            uninterruptible_processes,
+            // sysinfo reports zeros on Windows
+            load_avg: (!cfg!(windows)).then_some(SystemLoadAvg {
                 one: load_avg.one,
                 five: load_avg.five,
                 fifteen: load_avg.fifteen,
-            },
+            }),
+            cpu_cores: self
+                .system
+                .cpus()
+                .iter()
+                .map(|cpu| cpu.cpu_usage())
+                .collect(),
+            cpu_freqs,
+            cpu_governor,
+        }
+    }
+
+    /// The `n` processes using the most CPU, with memory breaking ties. The
+    /// process list is refreshed here unless the system collector just did,
+    /// since CPU usage is measured between two refreshes.
+    pub fn collect_top_processes(&mut self, n: usize) -> Vec<ProcessInfo> {
+        if !self.is_enabled(Collector::System) {
+            self.system.refresh_processes(ProcessesToUpdate::All, true);
+        }
+        let mut processes: Vec<ProcessInfo> = self
+            .system
+            .processes()
+            .values()
+            .map(|process| {
+                let disk_usage = process.disk_usage();
+                ProcessInfo {
+                    pid: process.pid().as_u32(),
+                    name: process.name().to_string_lossy().into_owned(),
+                    cpu_usage: process.cpu_usage(),
+                    memory: process.memory(),
+                    disk_read: disk_usage.read_bytes,
+                    disk_write: disk_usage.written_bytes,
+                }
+            })
+            .collect();
+        processes.sort_by(|a, b| {
+            b.cpu_usage
+                .total_cmp(&a.cpu_usage)
+                .then(b.memory.cmp(&a.memory))
+        });
+        processes.truncate(n);
+        for process in &mut processes {
+            process.name = self.masked_process_name(&process.name);
+        }
+        processes
+    }
+
+    /// `name` as reported under `process_name_denylist` and
+    /// `hash_process_names`.
+    fn masked_process_name(&self, name: &str) -> String {
+        if self
+            .process_name_denylist
+            .iter()
+            .any(|pattern| glob_match(pattern, name))
+        {
+            REDACTED_PROCESS_NAME.to_string()
+        } else if self.hash_process_names {
+            format!("{:x}", Sha256::digest(name.as_bytes()))[..12].to_string()
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Reads every sensor sysinfo knows about, leaving out those without a
+    /// current reading.
+    fn collect_temperature_info(&mut self) -> Vec<TemperatureInfo> {
+        self.components.refresh(true);
+        self.components
+            .list()
+            .iter()
+            .filter_map(|component| {
+                Some(TemperatureInfo {
+                    label: component.label().to_string(),
+                    temperature: component.temperature()?,
+                    max: component.max(),
+                    critical: component.critical(),
+                })
+            })
+            .collect()
+    }
+
+    /// Opens the kernel log on first use; if that fails (usually missing
+    /// privileges) the collector warns once and reports nothing.
+    #[cfg(target_os = "linux")]
+    fn collect_oom_kills(&mut self) -> Option<Vec<OomEvent>> {
+        if self.kmsg.is_none() && !self.kmsg_unavailable {
+            match crate::features::oom::KmsgReader::open() {
+                Ok(reader) => self.kmsg = Some(reader),
+                Err(e) => {
+                    warn!(error = %e, "Cannot read /dev/kmsg, OOM kills won't be reported");
+                    self.kmsg_unavailable = true;
+                }
+            }
         }
+        self.kmsg.as_mut().map(|reader| reader.read_events())
     }
 
-    fn collect_socket_number() -> (u32, u32) {
+    #[cfg(not(target_os = "linux"))]
+    fn collect_oom_kills(&mut self) -> Option<Vec<OomEvent>> {
+        if !self.kmsg_unavailable {
+            warn!("OOM kill reporting is only supported on Linux");
+            self.kmsg_unavailable = true;
+        }
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    fn collect_disk_io(&mut self) -> Vec<DiskIoStats> {
+        match std::fs::read_to_string("/proc/diskstats") {
+            Ok(diskstats) => self.update_disk_io(&diskstats),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn collect_disk_io(&mut self) -> Vec<DiskIoStats> {
+        Vec::new()
+    }
+
+    /// Records the counters from a `/proc/diskstats` snapshot and returns
+    /// per-device latency since the previous snapshot. Devices seen for the
+    /// first time report their queue depth but no latency.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn update_disk_io(&mut self, diskstats: &str) -> Vec<DiskIoStats> {
+        let mut devices = Vec::new();
+        let mut counters = HashMap::new();
+        for line in diskstats.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // major minor name, then at least the 11 classic counters
+            if fields.len() < 14 {
+                continue;
+            }
+            let name = fields[2];
+            if name.starts_with("loop") || name.starts_with("ram") {
+                continue;
+            }
+            let counter = |i: usize| fields[i].parse::<u64>().unwrap_or(0);
+            let current = DiskStatsCounters {
+                completed: counter(3) + counter(7),
+                io_time_ms: counter(6) + counter(10),
+            };
+
+            let avg_io_time_ms = self.diskstats.get(name).and_then(|previous| {
+                let completed = current.completed.checked_sub(previous.completed)?;
+                let io_time_ms = current.io_time_ms.checked_sub(previous.io_time_ms)?;
+                (completed > 0).then(|| io_time_ms as f64 / completed as f64)
+            });
+            devices.push(DiskIoStats {
+                name: name.to_string(),
+                avg_io_time_ms,
+                queue_depth: counter(11),
+            });
+            counters.insert(name.to_string(), current);
+        }
+        self.diskstats = counters;
+        devices
+    }
+
+    #[cfg(target_os = "linux")]
+    fn collect_swap_rates(&mut self) -> (Option<f64>, Option<f64>) {
+        match std::fs::read_to_string("/proc/vmstat") {
+            Ok(vmstat) => self.update_swap_rates(&vmstat, Instant::now()),
+            Err(_) => (None, None),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn collect_swap_rates(&mut self) -> (Option<f64>, Option<f64>) {
+        (None, None)
+    }
+
+    /// Records the swap counters from a `/proc/vmstat` snapshot and returns
+    /// the page-in/page-out rates since the previous one. The first snapshot
+    /// has nothing to compare against and yields `None`.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn update_swap_rates(&mut self, vmstat: &str, read_at: Instant) -> (Option<f64>, Option<f64>) {
+        let Some((pages_in, pages_out)) = parse_swap_counters(vmstat) else {
+            return (None, None);
+        };
+        let current = SwapCounters {
+            pages_in,
+            pages_out,
+            read_at,
+        };
+        let Some(previous) = self.swap_counters.replace(current) else {
+            return (None, None);
+        };
+
+        let elapsed = current
+            .read_at
+            .duration_since(previous.read_at)
+            .as_secs_f64();
+        if elapsed <= 0.0 {
+            return (None, None);
+        }
+        // Counters reset on reboot, which would otherwise show up as a huge rate
+        let rate = |now: u64, before: u64| now.checked_sub(before).map(|d| d as f64 / elapsed);
+        (
+            rate(current.pages_in, previous.pages_in),
+            rate(current.pages_out, previous.pages_out),
+        )
+    }
+
+    #[cfg(target_os = "linux")]
+    fn collect_tcp_errors(&mut self) -> Option<TcpErrorRates> {
+        let snmp = std::fs::read_to_string("/proc/net/snmp").ok()?;
+        let netstat = std::fs::read_to_string("/proc/net/netstat").ok();
+        self.update_tcp_errors(&snmp, netstat.as_deref(), Instant::now())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn collect_tcp_errors(&mut self) -> Option<TcpErrorRates> {
+        None
+    }
+
+    /// Records the TCP counters from `/proc/net/snmp` and `/proc/net/netstat`
+    /// snapshots and returns their rates since the previous ones. The first
+    /// snapshot has nothing to compare against and yields `None`.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn update_tcp_errors(
+        &mut self,
+        snmp: &str,
+        netstat: Option<&str>,
+        read_at: Instant,
+    ) -> Option<TcpErrorRates> {
+        let tcp = parse_proc_net_table(snmp, "Tcp");
+        let tcp_ext = netstat.map(|netstat| parse_proc_net_table(netstat, "TcpExt"));
+        let ext = |name: &str| tcp_ext.as_ref().and_then(|ext| ext.get(name).copied());
+        let current = TcpCounters {
+            retransmits: *tcp.get("RetransSegs")?,
+            in_errors: *tcp.get("InErrs")?,
+            out_resets: *tcp.get("OutRsts")?,
+            attempt_fails: *tcp.get("AttemptFails")?,
+            estab_resets: *tcp.get("EstabResets")?,
+            timeouts: ext("TCPTimeouts"),
+            listen_drops: ext("ListenDrops"),
+            read_at,
+        };
+        let previous = self.tcp_counters.replace(current)?;
+
+        let elapsed = current
+            .read_at
+            .duration_since(previous.read_at)
+            .as_secs_f64();
+        if elapsed <= 0.0 {
+            return None;
+        }
+        // A counter going backwards was reset, report no traffic rather
+        // than a huge rate
+        let rate = |now: u64, before: u64| now.saturating_sub(before) as f64 / elapsed;
+        Some(TcpErrorRates {
+            retransmits: rate(current.retransmits, previous.retransmits),
+            in_errors: rate(current.in_errors, previous.in_errors),
+            out_resets: rate(current.out_resets, previous.out_resets),
+            attempt_fails: rate(current.attempt_fails, previous.attempt_fails),
+            estab_resets: rate(current.estab_resets, previous.estab_resets),
+            timeouts: current
+                .timeouts
+                .zip(previous.timeouts)
+                .map(|(now, before)| rate(now, before)),
+            listen_drops: current
+                .listen_drops
+                .zip(previous.listen_drops)
+                .map(|(now, before)| rate(now, before)),
+        })
+    }
+
+    /// Reads `cpu.stat` of the cgroup v2 this process runs in. Cgroups
+    /// without a CPU quota are never throttled and report nothing.
+    #[cfg(target_os = "linux")]
+    fn collect_cpu_throttle(&mut self) -> Option<CpuThrottle> {
+        let cgroup = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+        // cgroup v2 has a single `0::<path>` line
+        let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+        let dir = Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/'));
+        let cpu_max = std::fs::read_to_string(dir.join("cpu.max")).ok()?;
+        if cpu_max.split_whitespace().next() == Some("max") {
+            self.throttle_counters = None;
+            return None;
+        }
+        let cpu_stat = std::fs::read_to_string(dir.join("cpu.stat")).ok()?;
+        self.update_cpu_throttle(&cpu_stat)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn collect_cpu_throttle(&mut self) -> Option<CpuThrottle> {
+        None
+    }
+
+    /// Records the counters from a `cpu.stat` snapshot and returns the
+    /// throttling since the previous one. The first snapshot has nothing to
+    /// compare against and yields `None`.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn update_cpu_throttle(&mut self, cpu_stat: &str) -> Option<CpuThrottle> {
+        let mut nr_throttled = None;
+        let mut throttled_usec = None;
+        for line in cpu_stat.lines() {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some("nr_throttled"), Some(value)) => nr_throttled = value.parse().ok(),
+                (Some("throttled_usec"), Some(value)) => throttled_usec = value.parse().ok(),
+                _ => {}
+            }
+        }
+        let current = ThrottleCounters {
+            nr_throttled: nr_throttled?,
+            throttled_usec: throttled_usec?,
+        };
+        let previous = self.throttle_counters.replace(current)?;
+        let throttled_usec = current
+            .throttled_usec
+            .checked_sub(previous.throttled_usec)?;
+        Some(CpuThrottle {
+            throttled_count: current.nr_throttled.checked_sub(previous.nr_throttled)?,
+            throttled_time_ms: throttled_usec as f64 / 1000.0,
+        })
+    }
+
+    /// Counts TCP and UDP sockets and TCP sockets per state, and lists the
+    /// TCP connections in CLOSE_WAIT by their addresses.
+    fn collect_sockets() -> Option<SocketCounts> {
         let af_flags = AddressFamilyFlags::IPV4 | AddressFamilyFlags::IPV6;
         let proto_flags = ProtocolFlags::TCP | ProtocolFlags::UDP;
 
@@ -161,17 +1390,31 @@ impl Metrics {
             Ok(s) => s,
             Err(e) => {
                 eprintln!("Failed to get socket info: {}", e);
-                return (0, 0);
+                return None;
             }
         };
 
+        let tcp_states =
+            tally_tcp_states(sockets.iter().filter_map(
+                |socket| match &socket.protocol_socket_info {
+                    ProtocolSocketInfo::Tcp(tcp) => Some(tcp.state),
+                    ProtocolSocketInfo::Udp(_) => None,
+                },
+            ));
         let mut tcp_count = 0;
         let mut udp_count = 0;
+        let mut close_wait = Vec::new();
 
         for socket in sockets {
             match socket.protocol_socket_info {
-                ProtocolSocketInfo::Tcp(_) => {
+                ProtocolSocketInfo::Tcp(tcp) => {
                     tcp_count += 1;
+                    if tcp.state == TcpState::CloseWait {
+                        close_wait.push((
+                            SocketAddr::new(tcp.local_addr, tcp.local_port),
+                            SocketAddr::new(tcp.remote_addr, tcp.remote_port),
+                        ));
+                    }
                 }
                 ProtocolSocketInfo::Udp(_) => {
                     udp_count += 1;
@@ -179,54 +1422,577 @@ impl Metrics {
             }
         }
 
-        (tcp_count, udp_count)
+        Some(SocketCounts {
+            tcp_count,
+            udp_count,
+            tcp_states,
+            close_wait,
+        })
     }
 
     fn collect_network_info(&mut self) -> NetworkInfo {
         self.networks.refresh(true);
+        let elapsed = since_last_read(&mut self.network_read_at);
 
-        let mut download_traffic = 0;
-        let mut upload_traffic = 0;
+        let counters = self.networks.list().iter().map(|(name, network)| {
+            let counters = InterfaceCounters {
+                total_received: network.total_received(),
+                total_transmitted: network.total_transmitted(),
+                received: network.received(),
+                transmitted: network.transmitted(),
+            };
+            (name.as_str(), counters)
+        });
+        let (interfaces, [download_traffic, upload_traffic], [downloaded, uploaded]) =
+            tally_interfaces(counters, &self.interface_filter, elapsed);
 
-        for network in self.networks.list().values() {
-            download_traffic += network.total_received();
-            upload_traffic += network.total_transmitted();
-        }
+        let sockets = Metrics::collect_sockets();
+        let close_wait = sockets.as_ref().map(|sockets| {
+            tally_close_wait(
+                &mut self.close_wait_since,
+                &sockets.close_wait,
+                Instant::now(),
+            )
+        });
 
-        let (tcp_count, udp_count) = Metrics::collect_socket_number();
+        let [download_rate, upload_rate] =
+            self.network_window
+                .record(elapsed, [downloaded, uploaded], self.rate_window);
+        let tcp_errors = self.collect_tcp_errors();
 
         NetworkInfo {
-            download_traffic,
-            upload_traffic,
-            tcp_count,
-            udp_count,
+            download_traffic: Some(download_traffic),
+            upload_traffic: Some(upload_traffic),
+            download_rate,
+            upload_rate,
+            tcp_count: sockets.as_ref().map(|sockets| sockets.tcp_count),
+            udp_count: sockets.as_ref().map(|sockets| sockets.udp_count),
+            close_wait_count: close_wait.map(|(count, _)| count),
+            oldest_close_wait_secs: close_wait.and_then(|(_, oldest)| oldest),
+            tcp_states: sockets
+                .map(|sockets| sockets.tcp_states)
+                .unwrap_or_default(),
+            tcp_errors,
+            interfaces,
         }
     }
 
-    fn collect_disk_info(&mut self) -> DiskInfo {
+    /// Reads the space of all disks at once, except for network mounts
+    /// sysinfo doesn't list, which get [`NETWORK_MOUNT_TIMEOUT`] to answer and
+    /// are returned as errors when they don't.
+    async fn collect_disk_info(&mut self) -> (DiskInfo, Vec<String>) {
         self.disks.refresh(true);
+        let io = self.disk_traffic();
 
-        let mut space_used = 0;
-        let mut space_total = 0;
-        let mut read = 0;
-        let mut write = 0;
+        let mut disks = Vec::new();
+        for disk in self.disks.list() {
+            disks.push(DiskDetail {
+                mount_point: disk.mount_point().display().to_string(),
+                space_used: disk.total_space() - disk.available_space(),
+                space_total: disk.total_space(),
+                hours_to_full: None,
+            });
+        }
+        let network_mounts = self.collect_network_mount_points();
+        let (unlisted, errors) = collect_disk_space(
+            self.unlisted_mounts(&network_mounts),
+            self.disk_space.clone(),
+            &self.pending_disk_reads,
+            NETWORK_MOUNT_TIMEOUT,
+        )
+        .await;
+        disks.extend(unlisted);
+        self.update_space_trends(&mut disks, Instant::now());
 
+        (
+            DiskInfo::from_disks(disks, &network_mounts, io, self.collect_disk_io()),
+            errors,
+        )
+    }
+
+    /// Sums the I/O of all disks as of the last refresh.
+    fn disk_traffic(&mut self) -> DiskTraffic {
+        let elapsed = since_last_read(&mut self.disk_read_at);
+        let mut io = DiskTraffic::default();
+        let (mut read, mut written) = (0, 0);
         for disk in self.disks.list() {
-            space_total += disk.total_space();
-            space_used += disk.total_space() - disk.available_space();
-            read += disk.usage().total_read_bytes;
-            write += disk.usage().total_written_bytes;
+            io.read += disk.usage().total_read_bytes;
+            io.write += disk.usage().total_written_bytes;
+            read += disk.usage().read_bytes;
+            written += disk.usage().written_bytes;
+        }
+        [io.read_rate, io.write_rate] =
+            self.disk_window
+                .record(elapsed, [read, written], self.rate_window);
+        io
+    }
+
+    /// Records the free space of `disks` read at `now` and fills in their
+    /// `hours_to_full` from a least-squares fit over the trend window.
+    fn update_space_trends(&mut self, disks: &mut [DiskDetail], now: Instant) {
+        let mut free_space = HashMap::new();
+        for disk in disks.iter_mut() {
+            let free = disk.space_total.saturating_sub(disk.space_used);
+            let mut samples = self
+                .free_space
+                .remove(&disk.mount_point)
+                .unwrap_or_default();
+            while samples
+                .front()
+                .is_some_and(|(at, _)| now.saturating_duration_since(*at) > SPACE_TREND_WINDOW)
+            {
+                samples.pop_front();
+            }
+            samples.push_back((now, free));
+            disk.hours_to_full = hours_to_full(&samples);
+            free_space.insert(disk.mount_point.clone(), samples);
+        }
+        // Mounts that went away or didn't answer start over
+        self.free_space = free_space;
+    }
+
+    #[cfg(target_os = "linux")]
+    fn collect_network_mount_points(&self) -> HashSet<String> {
+        std::fs::read_to_string(&self.mounts_path)
+            .map(|mounts| network_mount_points(&mounts))
+            .unwrap_or_default()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn collect_network_mount_points(&self) -> HashSet<String> {
+        self.disks
+            .list()
+            .iter()
+            .filter(|disk| is_network_filesystem(&disk.file_system().to_string_lossy()))
+            .map(|disk| disk.mount_point().display().to_string())
+            .collect()
+    }
+
+    /// Network mounts missing from the sysinfo list, which leaves NFS and
+    /// CIFS out since reading a hard mount can hang.
+    fn unlisted_mounts(&self, network_mounts: &HashSet<String>) -> Vec<PathBuf> {
+        let listed: HashSet<String> = self
+            .disks
+            .list()
+            .iter()
+            .map(|disk| disk.mount_point().display().to_string())
+            .collect();
+        let mut unlisted: Vec<PathBuf> = network_mounts
+            .iter()
+            .filter(|mount_point| !listed.contains(*mount_point))
+            .map(PathBuf::from)
+            .collect();
+        unlisted.sort();
+        unlisted
+    }
+
+    /// Like [`Self::collect_disk_info`], but reads each disk's space with a
+    /// `budget` so one hung mount can't stall the whole report. Disks that
+    /// time out are left out of the totals and returned as errors.
+    async fn collect_disk_info_bounded(&mut self, budget: Duration) -> (DiskInfo, Vec<String>) {
+        // Listing mounts and I/O counters comes from /proc and doesn't touch
+        // the mounted filesystems
+        self.disks
+            .refresh_specifics(true, DiskRefreshKind::nothing().with_io_usage());
+        let io = self.disk_traffic();
+
+        let network_mounts = self.collect_network_mount_points();
+        let mut mount_points: Vec<PathBuf> = self
+            .disks
+            .list()
+            .iter()
+            .map(|disk| disk.mount_point().to_path_buf())
+            .collect();
+        mount_points.extend(self.unlisted_mounts(&network_mounts));
+        let (mut disks, errors) = collect_disk_space(
+            mount_points,
+            self.disk_space.clone(),
+            &self.pending_disk_reads,
+            budget,
+        )
+        .await;
+        self.update_space_trends(&mut disks, Instant::now());
+
+        (
+            DiskInfo::from_disks(disks, &network_mounts, io, self.collect_disk_io()),
+            errors,
+        )
+    }
+}
+
+/// Time since `*read_at`, which is moved to now. `None` on the first read.
+fn since_last_read(read_at: &mut Option<Instant>) -> Option<Duration> {
+    let now = Instant::now();
+    read_at
+        .replace(now)
+        .map(|previous| now.duration_since(previous))
+}
+
+/// A TCP connection by its local and remote address.
+type ConnectionKey = (SocketAddr, SocketAddr);
+
+/// What [`Metrics::collect_sockets`] found in the socket table.
+struct SocketCounts {
+    tcp_count: u32,
+    udp_count: u32,
+    tcp_states: HashMap<String, u32>,
+    close_wait: Vec<ConnectionKey>,
+}
+
+/// Counts the sockets in each TCP state, by the state's name.
+fn tally_tcp_states(states: impl IntoIterator<Item = TcpState>) -> HashMap<String, u32> {
+    let mut tally: HashMap<&'static str, u32> = HashMap::new();
+    for state in states {
+        *tally.entry(tcp_state_name(state)).or_insert(0) += 1;
+    }
+    tally
+        .into_iter()
+        .map(|(name, count)| (name.to_string(), count))
+        .collect()
+}
+
+fn tcp_state_name(state: TcpState) -> &'static str {
+    match state {
+        TcpState::Closed => "CLOSED",
+        TcpState::Listen => "LISTEN",
+        TcpState::SynSent => "SYN_SENT",
+        TcpState::SynReceived => "SYN_RECEIVED",
+        TcpState::Established => "ESTABLISHED",
+        TcpState::FinWait1 => "FIN_WAIT_1",
+        TcpState::FinWait2 => "FIN_WAIT_2",
+        TcpState::CloseWait => "CLOSE_WAIT",
+        TcpState::Closing => "CLOSING",
+        TcpState::LastAck => "LAST_ACK",
+        TcpState::TimeWait => "TIME_WAIT",
+        TcpState::DeleteTcb => "DELETE_TCB",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Records the connections in `close_wait` as seen at `now` and returns how
+/// many there are and how long the oldest has been seen. Connections that
+/// left CLOSE_WAIT are forgotten.
+fn tally_close_wait(
+    since: &mut HashMap<ConnectionKey, Instant>,
+    close_wait: &[ConnectionKey],
+    now: Instant,
+) -> (u32, Option<u64>) {
+    let current: HashSet<&ConnectionKey> = close_wait.iter().collect();
+    since.retain(|key, _| current.contains(key));
+    for key in close_wait {
+        since.entry(*key).or_insert(now);
+    }
+    let oldest = since
+        .values()
+        .map(|seen| now.saturating_duration_since(*seen).as_secs())
+        .max();
+    (since.len() as u32, oldest)
+}
+
+/// The bytes moved in each of the last few collection intervals, to report
+/// rates averaged over several intervals rather than only the latest one.
+#[derive(Default)]
+struct RateWindow {
+    intervals: VecDeque<(Duration, [u64; 2])>,
+}
+
+impl RateWindow {
+    /// Records `bytes` moved over `elapsed` (unset on the first read, which
+    /// reports 0) and returns the rate of each over the last `window`
+    /// intervals.
+    fn record(
+        &mut self,
+        elapsed: Option<Duration>,
+        bytes: [u64; 2],
+        window: usize,
+    ) -> [Option<f64>; 2] {
+        let Some(elapsed) = elapsed else {
+            return [Some(0.0), Some(0.0)];
+        };
+        self.intervals.push_back((elapsed, bytes));
+        while self.intervals.len() > window.max(1) {
+            self.intervals.pop_front();
+        }
+        let total: Duration = self.intervals.iter().map(|(elapsed, _)| *elapsed).sum();
+        [0, 1].map(|i| {
+            let bytes = self.intervals.iter().map(|(_, bytes)| bytes[i]).sum();
+            byte_rate(bytes, Some(total))
+        })
+    }
+}
+
+/// Byte counters of one network interface, as read from sysinfo.
+#[derive(Debug, Clone, Copy, Default)]
+struct InterfaceCounters {
+    total_received: u64,
+    total_transmitted: u64,
+    /// Since the previous read
+    received: u64,
+    transmitted: u64,
+}
+
+/// Lists the interfaces `filter` allows by name and totals their traffic,
+/// returning the received/sent bytes since boot and since the previous read.
+fn tally_interfaces<'a>(
+    counters: impl IntoIterator<Item = (&'a str, InterfaceCounters)>,
+    filter: &InterfaceFilter,
+    elapsed: Option<Duration>,
+) -> (Vec<InterfaceInfo>, [u64; 2], [u64; 2]) {
+    let (mut totals, mut moved) = ([0, 0], [0, 0]);
+    let mut interfaces = Vec::new();
+    for (name, counters) in counters {
+        if !filter.allows(name) {
+            continue;
+        }
+        totals[0] += counters.total_received;
+        totals[1] += counters.total_transmitted;
+        moved[0] += counters.received;
+        moved[1] += counters.transmitted;
+        interfaces.push(InterfaceInfo {
+            name: name.to_string(),
+            download_traffic: Some(counters.total_received),
+            upload_traffic: Some(counters.total_transmitted),
+            download_rate: byte_rate(counters.received, elapsed),
+            upload_rate: byte_rate(counters.transmitted, elapsed),
+        });
+    }
+    interfaces.sort_by(|a, b| a.name.cmp(&b.name));
+    (interfaces, totals, moved)
+}
+
+/// `bytes` moved over `elapsed` as bytes per second. Without a previous read
+/// (`elapsed` unset) that's 0, as the bytes counted since boot would make a
+/// spike.
+fn byte_rate(bytes: u64, elapsed: Option<Duration>) -> Option<f64> {
+    let Some(elapsed) = elapsed else {
+        return Some(0.0);
+    };
+    let secs = elapsed.as_secs_f64();
+    (secs > 0.0).then(|| bytes as f64 / secs)
+}
+
+/// Reads the space of every mount point concurrently on the blocking pool,
+/// giving each `budget` to answer. Mount points whose read from an earlier
+/// call is still in `pending` are skipped. Returns the disks that answered
+/// and an error message for each one that didn't.
+pub async fn collect_disk_space(
+    mount_points: Vec<PathBuf>,
+    source: Arc<dyn DiskSpace>,
+    pending: &PendingReads,
+    budget: Duration,
+) -> (Vec<DiskDetail>, Vec<String>) {
+    let reads = mount_points.into_iter().map(|mount_point| {
+        let source = source.clone();
+        let pending = pending.clone();
+        async move {
+            let display = mount_point.display().to_string();
+            if !pending.start(&mount_point) {
+                return Err(format!("disk {}: still hung from an earlier read", display));
+            }
+            let read = tokio::task::spawn_blocking(move || {
+                let space = source.space(&mount_point);
+                pending.finish(&mount_point);
+                space
+            });
+            match tokio::time::timeout(budget, read).await {
+                Ok(Ok(Some((total, available)))) => Ok(DiskDetail {
+                    mount_point: display,
+                    space_used: total.saturating_sub(available),
+                    space_total: total,
+                    hours_to_full: None,
+                }),
+                Ok(_) => Err(format!("disk {}: failed to read space", display)),
+                Err(_) => Err(format!(
+                    "disk {}: timed out after {}ms",
+                    display,
+                    budget.as_millis()
+                )),
+            }
+        }
+    });
+
+    let mut disks = Vec::new();
+    let mut errors = Vec::new();
+    for result in futures::future::join_all(reads).await {
+        match result {
+            Ok(disk) => disks.push(disk),
+            Err(e) => {
+                warn!(error = %e, "Skipping disk");
+                errors.push(e);
+            }
         }
+    }
+    (disks, errors)
+}
 
-        DiskInfo {
-            space_used,
-            space_total,
-            read,
-            write,
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_swap_counters(vmstat: &str) -> Option<(u64, u64)> {
+    let mut pages_in = None;
+    let mut pages_out = None;
+    for line in vmstat.lines() {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some("pswpin"), Some(value)) => pages_in = value.parse().ok(),
+            (Some("pswpout"), Some(value)) => pages_out = value.parse().ok(),
+            _ => {}
         }
     }
+    Some((pages_in?, pages_out?))
+}
+
+/// Parses the `prefix` table of `/proc/net/snmp` or `/proc/net/netstat`,
+/// a line of counter names followed by a line of their values, both starting
+/// with `<prefix>:`.
+fn parse_proc_net_table(contents: &str, prefix: &str) -> HashMap<String, u64> {
+    let mut lines = contents
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| *name == prefix)
+        .map(|(_, rest)| rest);
+    let (Some(names), Some(values)) = (lines.next(), lines.next()) else {
+        return HashMap::new();
+    };
+    names
+        .split_whitespace()
+        .zip(values.split_whitespace())
+        .filter_map(|(name, value)| Some((name.to_string(), value.parse().ok()?)))
+        .collect()
 }
 
+/// Fits a line to `(time, free bytes)` samples and extrapolates when free
+/// space reaches zero, counted from the newest sample.
+fn hours_to_full(samples: &VecDeque<(Instant, u64)>) -> Option<f64> {
+    if samples.len() < MIN_TREND_SAMPLES {
+        return None;
+    }
+    let (start, _) = *samples.front()?;
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|(at, free)| {
+            (
+                at.duration_since(start).as_secs_f64() / 3600.0,
+                *free as f64,
+            )
+        })
+        .collect();
+    let n = points.len() as f64;
+    let mean_t = points.iter().map(|(t, _)| t).sum::<f64>() / n;
+    let mean_free = points.iter().map(|(_, free)| free).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(t, free)| (t - mean_t) * (free - mean_free))
+        .sum();
+    let variance: f64 = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+    if variance == 0.0 {
+        return None;
+    }
+    // Bytes per hour; flat or growing free space never fills up
+    let slope = covariance / variance;
+    if slope >= 0.0 {
+        return None;
+    }
+    let (_, free_now) = *samples.back()?;
+    Some(free_now as f64 / -slope)
+}
+
+#[cfg(target_os = "linux")]
+fn collect_cpufreq() -> (Vec<u64>, Option<String>) {
+    read_cpufreq(Path::new("/sys/devices/system/cpu"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn collect_cpufreq() -> (Vec<u64>, Option<String>) {
+    (Vec::new(), None)
+}
+
+/// Reads `cpu<N>/cpufreq/scaling_cur_freq` of every core under `root` in
+/// core order, and the governor of the first core that has one.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn read_cpufreq(root: &Path) -> (Vec<u64>, Option<String>) {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return (Vec::new(), None);
+    };
+    let mut cores: Vec<(u32, PathBuf)> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let core = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("cpu")?
+                .parse()
+                .ok()?;
+            Some((core, entry.path().join("cpufreq")))
+        })
+        .collect();
+    cores.sort_by_key(|(core, _)| *core);
+
+    let read = |path: PathBuf| {
+        std::fs::read_to_string(path)
+            .ok()
+            .map(|s| s.trim().to_string())
+    };
+    let freqs = cores
+        .iter()
+        .filter_map(|(_, dir)| read(dir.join("scaling_cur_freq"))?.parse().ok())
+        .collect();
+    let governor = cores
+        .iter()
+        .find_map(|(_, dir)| read(dir.join("scaling_governor")).filter(|g| !g.is_empty()));
+    (freqs, governor)
+}
+
+/// Metrics this build can never report on the current OS, by their field
+/// name in the report.
+pub fn unsupported_metrics() -> Vec<&'static str> {
+    let mut unsupported = Vec::new();
+    if cfg!(windows) {
+        unsupported.extend(["system.loadAvg", "system.zombieCount"]);
+    }
+    if !cfg!(target_os = "linux") {
+        unsupported.extend([
+            "system.swapInRate",
+            "system.swapOutRate",
+            "network.tcpErrors",
+            "system.cpuThrottle",
+            "system.cpuFreqs",
+            "system.cpuGovernor",
+            "system.uninterruptibleCount",
+            "disk.devices",
+            "oomKills",
+        ]);
+    }
+    unsupported
+}
+
+fn count_zombies(statuses: impl Iterator<Item = ProcessStatus>) -> u32 {
+    statuses
+        .filter(|status| *status == ProcessStatus::Zombie)
+        .count() as u32
+}
+
+/// Counts the processes in uninterruptible sleep and names a few of them.
+fn find_uninterruptible<'a>(
+    processes: impl Iterator<Item = (ProcessStatus, Cow<'a, str>)>,
+) -> (u32, Vec<String>) {
+    let mut count = 0;
+    let mut names = BTreeSet::new();
+    for (status, name) in processes {
+        if status == ProcessStatus::UninterruptibleDiskSleep {
+            count += 1;
+            names.insert(name.into_owned());
+        }
+    }
+    (
+        count,
+        names.into_iter().take(MAX_UNINTERRUPTIBLE_NAMES).collect(),
+    )
+}
+
+/// A host always has some memory and at least one CPU, so zeros here mean
+/// sysinfo couldn't read the system rather than the system being idle.
+fn is_degraded(system: &SystemInfo, cpu_count: usize) -> bool {
+    system.memory_total == 0 || cpu_count == 0
+}
 
 #[test]
 fn test_system_info_collection() {
@@ -240,7 +2006,704 @@ fn test_system_info_collection() {
     assert!(system_info.process_count > 0);
 
     // Load average checks
-    assert!(system_info.load_avg.one >= 0.0);
-    assert!(system_info.load_avg.five >= 0.0);
-    assert!(system_info.load_avg.fifteen >= 0.0);
+    #[cfg(not(windows))]
+    {
+        let load_avg = system_info.load_avg.unwrap();
+        assert!(load_avg.one >= 0.0);
+        assert!(load_avg.five >= 0.0);
+        assert!(load_avg.fifteen >= 0.0);
+        assert!(system_info.zombie_count.is_some());
+    }
+}
+
+#[test]
+fn test_top_processes() {
+    let mut metrics = Metrics::new();
+    metrics.collect_system_info();
+    let top = metrics.collect_top_processes(3);
+
+    assert!(!top.is_empty() && top.len() <= 3);
+    assert!(top
+        .windows(2)
+        .all(|pair| pair[0].cpu_usage >= pair[1].cpu_usage));
+    assert!(metrics.collect_top_processes(0).is_empty());
+}
+
+#[test]
+fn test_top_process_names_can_be_masked() {
+    let mut metrics = Metrics::new();
+    metrics.collect_system_info();
+    let names: Vec<String> = metrics
+        .collect_top_processes(usize::MAX)
+        .into_iter()
+        .map(|process| process.name)
+        .collect();
+    let current = sysinfo::get_current_pid().unwrap().as_u32();
+
+    metrics.process_name_denylist = vec!["*".to_string()];
+    let redacted = metrics.collect_top_processes(usize::MAX);
+    assert!(redacted.iter().all(|p| p.name == REDACTED_PROCESS_NAME));
+    // Usage is still reported
+    let this = redacted.iter().find(|p| p.pid == current).unwrap();
+    assert!(this.memory > 0);
+    assert!(this.cpu_usage >= 0.0);
+
+    metrics.process_name_denylist = vec![];
+    metrics.hash_process_names = true;
+    let hashed = metrics.collect_top_processes(usize::MAX);
+    let this = hashed.iter().find(|p| p.pid == current).unwrap();
+    assert_eq!(this.name.len(), 12);
+    assert!(!names.contains(&this.name));
+    assert_eq!(
+        metrics.masked_process_name("customer-billing"),
+        metrics.masked_process_name("customer-billing")
+    );
+    assert_ne!(
+        metrics.masked_process_name("customer-billing"),
+        metrics.masked_process_name("customer-reports")
+    );
+
+    // The denylist wins over hashing
+    metrics.process_name_denylist = vec!["customer-*".to_string()];
+    assert_eq!(
+        metrics.masked_process_name("customer-billing"),
+        REDACTED_PROCESS_NAME
+    );
+    assert_ne!(metrics.masked_process_name("nginx"), "nginx");
+}
+
+#[test]
+fn test_temperature_collection() {
+    let mut metrics = Metrics::new();
+    // Most CI machines and VMs have no sensors at all
+    let temperatures = metrics.collect_temperature_info();
+    let json = serde_json::to_value(&temperatures).unwrap();
+    assert_eq!(json.as_array().map(Vec::len), Some(temperatures.len()));
+}
+
+#[cfg(windows)]
+#[test]
+fn test_load_average_unsupported_on_windows() {
+    let mut metrics = Metrics::new();
+    let system_info = metrics.collect_system_info();
+
+    assert_eq!(system_info.load_avg, None);
+    assert_eq!(system_info.zombie_count, None);
+    let json = serde_json::to_value(&system_info).unwrap();
+    assert!(json.get("loadAvg").is_none());
+    assert!(unsupported_metrics().contains(&"system.loadAvg"));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_everything_supported_on_linux() {
+    assert!(unsupported_metrics().is_empty());
+}
+
+#[test]
+fn test_machine_id_is_stable() {
+    let mut metrics = Metrics::new();
+    let first = metrics.collect_vm_info().machine_id;
+    let second = metrics.collect_vm_info().machine_id;
+
+    assert!(first.as_deref().is_some_and(|id| !id.is_empty()));
+    assert_eq!(first, second);
+    assert_eq!(first, Metrics::new().collect_vm_info().machine_id);
+}
+
+#[tokio::test]
+async fn test_custom_gauges_from_commands() {
+    let gauge = |name: &str, command: &str| GaugeConfig {
+        name: name.to_string(),
+        command: command.to_string(),
+        timeout_ms: 5000,
+    };
+    let mut metrics = Metrics::with_collectors(vec![]);
+    metrics.gauges = vec![
+        gauge("answer", "echo 42"),
+        gauge("broken", "echo not-a-number"),
+    ];
+    let report = metrics.collet_metrics().await;
+
+    assert_eq!(
+        report.custom_gauges,
+        HashMap::from([("answer".to_string(), 42.0)])
+    );
+    assert_eq!(
+        report.collection_errors,
+        vec!["gauge broken: output 'not-a-number' is not a number"]
+    );
+}
+
+#[tokio::test]
+async fn test_collector_selection() {
+    let mut metrics = Metrics::with_collectors(vec![Collector::System]);
+    let report = metrics.collet_metrics().await;
+
+    assert!(report.system.is_some());
+    assert!(report.network.is_none());
+    assert!(report.disk.is_none());
+
+    // Top processes come with the system collector only
+    let mut metrics = Metrics::with_collectors(vec![Collector::Network]);
+    metrics.top_processes = 3;
+    let report = metrics.collet_metrics().await;
+    assert!(report.top_processes.is_none());
+}
+
+#[test]
+fn test_zero_memory_is_degraded() {
+    let mut system_info = Metrics::new().collect_system_info();
+    assert!(!is_degraded(&system_info, 1));
+
+    system_info.memory_total = 0;
+    system_info.memory_used = 0;
+    assert!(is_degraded(&system_info, 1));
+    assert!(is_degraded(&system_info, 0));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_swap_rates_from_vmstat() {
+    let mut metrics = Metrics::with_collectors(vec![]);
+    let start = Instant::now();
+
+    let first = "nr_free_pages 1000\npswpin 100\npswpout 50\npgfault 9\n";
+    assert_eq!(metrics.update_swap_rates(first, start), (None, None));
+
+    let second = "nr_free_pages 900\npswpin 300\npswpout 70\npgfault 12\n";
+    let later = start + std::time::Duration::from_secs(10);
+    assert_eq!(
+        metrics.update_swap_rates(second, later),
+        (Some(20.0), Some(2.0))
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_tcp_error_rates_from_proc() {
+    let mut metrics = Metrics::with_collectors(vec![]);
+    let start = Instant::now();
+    let snmp = |retrans: u64, in_errs: u64| {
+        format!(
+            "Ip: Forwarding DefaultTTL\nIp: 1 64\n\
+             Tcp: RtoAlgorithm ActiveOpens AttemptFails EstabResets CurrEstab RetransSegs InErrs OutRsts\n\
+             Tcp: 1 100 4 2 10 {} {} 6\n\
+             Udp: InDatagrams NoPorts\nUdp: 500 3\n",
+            retrans, in_errs
+        )
+    };
+    let netstat = |timeouts: u64| {
+        format!(
+            "TcpExt: SyncookiesSent ListenDrops TCPTimeouts\nTcpExt: 0 1 {}\n\
+             IpExt: InNoRoutes\nIpExt: 0\n",
+            timeouts
+        )
+    };
+
+    assert_eq!(
+        metrics.update_tcp_errors(&snmp(1000, 5), Some(&netstat(20)), start),
+        None
+    );
+
+    let later = start + std::time::Duration::from_secs(10);
+    let rates = metrics
+        .update_tcp_errors(&snmp(1250, 7), Some(&netstat(30)), later)
+        .unwrap();
+    assert_eq!(rates.retransmits, 25.0);
+    assert_eq!(rates.in_errors, 0.2);
+    assert_eq!(rates.out_resets, 0.0);
+    assert_eq!(rates.timeouts, Some(1.0));
+    assert_eq!(rates.listen_drops, Some(0.0));
+
+    // Without /proc/net/netstat only its counters are missing
+    let even_later = later + std::time::Duration::from_secs(10);
+    let rates = metrics
+        .update_tcp_errors(&snmp(1250, 7), None, even_later)
+        .unwrap();
+    assert_eq!(rates.retransmits, 0.0);
+    assert_eq!(rates.timeouts, None);
+}
+
+#[tokio::test]
+async fn test_hung_disk_does_not_stall_others() {
+    struct HungMount(std::sync::atomic::AtomicUsize);
+    impl DiskSpace for HungMount {
+        fn space(&self, mount_point: &Path) -> Option<(u64, u64)> {
+            if mount_point == Path::new("/mnt/stale-nfs") {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                std::thread::sleep(Duration::from_secs(1));
+            }
+            Some((100, 40))
+        }
+    }
+
+    let mount_points = || {
+        vec![
+            PathBuf::from("/"),
+            PathBuf::from("/mnt/stale-nfs"),
+            PathBuf::from("/home"),
+        ]
+    };
+    let source = Arc::new(HungMount(Default::default()));
+    let pending = PendingReads::default();
+    let start = Instant::now();
+    let (disks, errors) = collect_disk_space(
+        mount_points(),
+        source.clone(),
+        &pending,
+        Duration::from_millis(100),
+    )
+    .await;
+
+    assert!(start.elapsed() < Duration::from_millis(900));
+    let mounts: Vec<&str> = disks.iter().map(|d| d.mount_point.as_str()).collect();
+    assert_eq!(mounts, vec!["/", "/home"]);
+    assert!(disks
+        .iter()
+        .all(|d| d.space_total == 100 && d.space_used == 60));
+    assert_eq!(errors, vec!["disk /mnt/stale-nfs: timed out after 100ms"]);
+
+    // The hung read still holds its thread, so it isn't started again
+    let (disks, errors) = collect_disk_space(
+        mount_points(),
+        source.clone(),
+        &pending,
+        Duration::from_millis(100),
+    )
+    .await;
+    assert_eq!(disks.len(), 2);
+    assert_eq!(
+        errors,
+        vec!["disk /mnt/stale-nfs: still hung from an earlier read"]
+    );
+    assert_eq!(source.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // Once it returns the mount is read again
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    collect_disk_space(
+        mount_points(),
+        source.clone(),
+        &pending,
+        Duration::from_millis(100),
+    )
+    .await;
+    assert_eq!(source.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_warmup_samples_leave_out_rates() {
+    let mut metrics = Metrics::with_collectors(vec![Collector::Network, Collector::Disk]);
+    metrics.warmup_samples = 2;
+    for _ in 0..2 {
+        let warmup = serde_json::to_value(metrics.collet_metrics().await).unwrap();
+        assert_eq!(warmup["warmup"], true);
+        // The counters seed the baseline, the rates aren't sent yet
+        assert!(warmup["network"]["downloadTraffic"].is_u64());
+        assert!(warmup["network"].get("downloadRate").is_none());
+        assert!(warmup["disk"].get("readRate").is_none());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let report = serde_json::to_value(metrics.collet_metrics().await).unwrap();
+    assert!(report.get("warmup").is_none());
+    assert!(report["network"]["downloadRate"].is_number());
+    assert!(report["disk"]["readRate"].is_number());
+}
+
+#[tokio::test]
+async fn test_counter_mode_picks_counters_or_rates() {
+    let mut metrics = Metrics::with_collectors(vec![Collector::Network, Collector::Disk]);
+    metrics.collet_metrics().await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let with_counters = serde_json::to_value(metrics.collet_metrics().await).unwrap();
+    assert!(with_counters["network"]["downloadTraffic"].is_u64());
+    assert!(with_counters["disk"]["read"].is_u64());
+    assert!(with_counters["network"]["downloadRate"].is_number());
+    assert!(with_counters["disk"]["readRate"].is_number());
+
+    metrics.counters = CounterMode::RatesOnly;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let without = serde_json::to_value(metrics.collet_metrics().await).unwrap();
+    assert!(without["network"].get("downloadTraffic").is_none());
+    assert!(without["network"].get("uploadTraffic").is_none());
+    assert!(without["disk"].get("read").is_none());
+    assert!(without["disk"].get("write").is_none());
+    // Rates are still there
+    assert!(without["network"]["downloadRate"].is_number());
+    assert!(without["network"]["uploadRate"].is_number());
+    assert!(without["disk"]["readRate"].is_number());
+    assert!(without["disk"]["writeRate"].is_number());
+
+    metrics.counters = CounterMode::CountersOnly;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let counters_only = serde_json::to_value(metrics.collet_metrics().await).unwrap();
+    assert!(counters_only["network"]["downloadTraffic"].is_u64());
+    assert!(counters_only["network"]["uploadTraffic"].is_u64());
+    assert!(counters_only["disk"]["read"].is_u64());
+    assert!(counters_only["disk"]["write"].is_u64());
+    assert!(counters_only["network"].get("downloadRate").is_none());
+    assert!(counters_only["network"].get("uploadRate").is_none());
+    assert!(counters_only["disk"].get("readRate").is_none());
+    assert!(counters_only["disk"].get("writeRate").is_none());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_disk_io_time_from_diskstats() {
+    let mut metrics = Metrics::with_collectors(vec![]);
+
+    // reads, merged, sectors, ms reading, writes, merged, sectors, ms writing, in flight, ...
+    let first = "   8       0 sda 1000 0 8000 4000 500 0 4000 1000 0 3000 5000\n\
+                   7       0 loop0 10 0 80 5 0 0 0 0 0 5 5\n";
+    let devices = metrics.update_disk_io(first);
+    assert_eq!(devices.len(), 1);
+    assert_eq!(devices[0].avg_io_time_ms, None);
+
+    // 100 more requests taking 800ms in total, 4 requests queued
+    let second = "   8       0 sda 1060 0 8480 4500 540 0 4320 1300 4 3600 5800\n";
+    let devices = metrics.update_disk_io(second);
+    assert_eq!(
+        devices,
+        vec![DiskIoStats {
+            name: "sda".to_string(),
+            avg_io_time_ms: Some(8.0),
+            queue_depth: 4,
+        }]
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_cpu_throttle_from_cpu_stat() {
+    let mut metrics = Metrics::with_collectors(vec![]);
+
+    let first = "usage_usec 5000000\nuser_usec 3000000\nsystem_usec 2000000\n\
+                 nr_periods 1000\nnr_throttled 40\nthrottled_usec 900000\n";
+    assert_eq!(metrics.update_cpu_throttle(first), None);
+
+    let second = "usage_usec 5600000\nuser_usec 3400000\nsystem_usec 2200000\n\
+                  nr_periods 1100\nnr_throttled 65\nthrottled_usec 1150000\n";
+    assert_eq!(
+        metrics.update_cpu_throttle(second),
+        Some(CpuThrottle {
+            throttled_count: 25,
+            throttled_time_ms: 250.0,
+        })
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_cpufreq_from_sysfs() {
+    let root = tempfile::tempdir().unwrap();
+    for (core, freq) in [(0, "2400000"), (1, "800000"), (10, "3100000")] {
+        let dir = root.path().join(format!("cpu{}/cpufreq", core));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("scaling_cur_freq"), format!("{}\n", freq)).unwrap();
+        std::fs::write(dir.join("scaling_governor"), "powersave\n").unwrap();
+    }
+    // Not cores
+    std::fs::create_dir_all(root.path().join("cpufreq/policy0")).unwrap();
+    std::fs::create_dir_all(root.path().join("cpuidle")).unwrap();
+
+    let (freqs, governor) = read_cpufreq(root.path());
+    assert_eq!(freqs, vec![2_400_000, 800_000, 3_100_000]);
+    assert_eq!(governor.as_deref(), Some("powersave"));
+
+    // A VM without cpufreq
+    let bare = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(bare.path().join("cpu0")).unwrap();
+    assert_eq!(read_cpufreq(bare.path()), (vec![], None));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_network_mounts_are_reported_separately() {
+    let mounts = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                  proc /proc proc rw,nosuid 0 0\n\
+                  fileserver:/export /mnt/share nfs4 rw,vers=4.2 0 0\n";
+    let network_mounts = network_mount_points(mounts);
+    assert_eq!(network_mounts, HashSet::from(["/mnt/share".to_string()]));
+
+    let disk = |mount_point: &str, space_used, space_total| DiskDetail {
+        mount_point: mount_point.to_string(),
+        space_used,
+        space_total,
+        hours_to_full: None,
+    };
+    let info = DiskInfo::from_disks(
+        vec![disk("/", 30, 100), disk("/mnt/share", 500, 2000)],
+        &network_mounts,
+        DiskTraffic::default(),
+        vec![],
+    );
+
+    assert_eq!((info.space_used, info.space_total), (30, 100));
+    assert_eq!(info.disks, vec![disk("/", 30, 100)]);
+    assert_eq!(
+        (info.network_space_used, info.network_space_total),
+        (500, 2000)
+    );
+    assert_eq!(info.network_mounts, vec![disk("/mnt/share", 500, 2000)]);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_network_mounts_are_read_from_proc_mounts() {
+    struct Share;
+    impl DiskSpace for Share {
+        fn space(&self, mount_point: &Path) -> Option<(u64, u64)> {
+            (mount_point == Path::new("/mnt/share")).then_some((2000, 1500))
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let mounts_path = dir.path().join("mounts");
+    std::fs::write(
+        &mounts_path,
+        "/dev/sda1 / ext4 rw,relatime 0 0\n\
+         fileserver:/export /mnt/share nfs4 rw,vers=4.2 0 0\n",
+    )
+    .unwrap();
+    let mut metrics = Metrics::with_collectors(vec![Collector::Disk]);
+    metrics.mounts_path = mounts_path;
+    metrics.disk_space = Arc::new(Share);
+    metrics.pending_disk_reads = PendingReads::default();
+    let share = DiskDetail {
+        mount_point: "/mnt/share".to_string(),
+        space_used: 500,
+        space_total: 2000,
+        hours_to_full: None,
+    };
+
+    // sysinfo never lists the NFS mount, so it's found in the mounts file
+    let (info, errors) = metrics.collect_disk_info().await;
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(info.network_mounts, vec![share.clone()]);
+    assert_eq!(info.network_space_total, 2000);
+    assert!(info.disks.iter().all(|d| d.mount_point != "/mnt/share"));
+
+    let (info, _) = metrics
+        .collect_disk_info_bounded(Duration::from_millis(500))
+        .await;
+    assert_eq!(info.network_mounts, vec![share]);
+}
+
+#[test]
+fn test_rate_window_smooths_bursts() {
+    // A link busy every other second
+    let bursts = [0, 1000, 0, 1000, 0, 1000, 0, 1000];
+    let rates = |window| {
+        let mut rates = RateWindow::default();
+        bursts
+            .iter()
+            .map(|&bytes| rates.record(Some(Duration::from_secs(1)), [bytes, 0], window)[0])
+            .map(Option::unwrap)
+            .collect::<Vec<_>>()
+    };
+    let spread = |rates: &[f64]| {
+        let settled = &rates[3..];
+        let max = settled.iter().cloned().fold(f64::MIN, f64::max);
+        let min = settled.iter().cloned().fold(f64::MAX, f64::min);
+        max - min
+    };
+
+    let single = rates(1);
+    assert_eq!(single[..2], [0.0, 1000.0]);
+    let windowed = rates(4);
+    assert_eq!(windowed[3..], [500.0; 5]);
+    assert!(spread(&windowed) < spread(&single));
+}
+
+#[test]
+fn test_rates_from_two_samples() {
+    let mut rates = RateWindow::default();
+    // Nothing to compare the first read against
+    assert_eq!(
+        rates.record(None, [10_000, 10_000], 1),
+        [Some(0.0), Some(0.0)]
+    );
+    assert_eq!(
+        rates.record(Some(Duration::from_secs(2)), [2000, 500], 1),
+        [Some(1000.0), Some(250.0)]
+    );
+    assert_eq!(byte_rate(10_000, None), Some(0.0));
+    assert_eq!(
+        byte_rate(3000, Some(Duration::from_millis(1500))),
+        Some(2000.0)
+    );
+}
+
+#[tokio::test]
+async fn test_monotonic_timestamps_survive_wall_clock_steps() {
+    let mut metrics = Metrics::with_collectors(vec![]);
+    let mut clock = SampleClock::new(TimestampSource::Both);
+    // The wall clock is stepped back by a minute between the samples
+    let wall = [1_700_000_060_000, 1_700_000_000_000, 1_700_000_000_500];
+
+    let mut samples = Vec::new();
+    for wall_ms in wall {
+        let mut report = metrics.collet_metrics().await;
+        clock.stamp(&mut report, wall_ms, crate::history::mono_nanos());
+        samples.push(report);
+    }
+
+    let collected_at: Vec<_> = samples.iter().map(|s| s.collected_at).collect();
+    assert_eq!(collected_at, wall.map(Some));
+    let mono: Vec<u64> = samples.iter().map(|s| s.mono_ns.unwrap()).collect();
+    assert!(mono.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // Only the chosen clock is sent
+    let mut report = metrics.collet_metrics().await;
+    SampleClock::new(TimestampSource::Monotonic).stamp(&mut report, 1, 2);
+    assert_eq!((report.collected_at, report.mono_ns), (None, Some(2)));
+    SampleClock::new(TimestampSource::Wall).stamp(&mut report, 1, 2);
+    assert_eq!((report.collected_at, report.mono_ns), (Some(1), None));
+}
+
+#[test]
+fn test_interface_filter_totals() {
+    let counters = |total: u64, moved: u64| InterfaceCounters {
+        total_received: total,
+        total_transmitted: total / 2,
+        received: moved,
+        transmitted: moved / 2,
+    };
+    let interfaces = [
+        ("eth0", counters(1000, 100)),
+        ("lo", counters(50_000, 5000)),
+        ("docker0", counters(700, 70)),
+        ("veth1a2b", counters(700, 70)),
+        ("wg0", counters(300, 30)),
+    ];
+    let elapsed = Some(Duration::from_secs(1));
+
+    let (listed, totals, moved) =
+        tally_interfaces(interfaces, &InterfaceFilter::default(), elapsed);
+    let names: Vec<&str> = listed.iter().map(|i| i.name.as_str()).collect();
+    assert_eq!(names, ["eth0", "wg0"]);
+    assert_eq!((totals, moved), ([1300, 650], [130, 65]));
+    assert_eq!(listed[0].download_rate, Some(100.0));
+
+    let only_eth = InterfaceFilter {
+        include: vec!["eth*".to_string()],
+        ..Default::default()
+    };
+    let (listed, totals, _) = tally_interfaces(interfaces, &only_eth, elapsed);
+    assert_eq!(listed.len(), 1);
+    assert_eq!(totals, [1000, 500]);
+
+    let everything = InterfaceFilter {
+        include: vec![],
+        exclude: vec![],
+    };
+    let (listed, totals, _) = tally_interfaces(interfaces, &everything, elapsed);
+    assert_eq!(listed.len(), 5);
+    assert_eq!(totals, [52_700, 26_350]);
+}
+
+#[test]
+fn test_tcp_state_tally() {
+    let mut states = vec![TcpState::Listen, TcpState::Established, TcpState::CloseWait];
+    states.extend(std::iter::repeat_n(TcpState::TimeWait, 5000));
+    states.push(TcpState::Established);
+
+    let tally = tally_tcp_states(states);
+    assert_eq!(tally.len(), 4);
+    assert_eq!(tally["TIME_WAIT"], 5000);
+    assert_eq!(tally["ESTABLISHED"], 2);
+    assert_eq!(tally["LISTEN"], 1);
+    assert_eq!(tally["CLOSE_WAIT"], 1);
+    assert_eq!(tally.values().sum::<u32>(), 5004);
+    assert!(tally_tcp_states(Vec::<TcpState>::new()).is_empty());
+}
+
+#[test]
+fn test_close_wait_tally() {
+    let conn = |local: &str, remote: &str| (local.parse().unwrap(), remote.parse().unwrap());
+    let leaked = conn("10.0.0.2:8080", "10.0.0.9:51000");
+    let other = conn("10.0.0.2:8080", "10.0.0.9:51001");
+    let mut since = HashMap::new();
+    let start = Instant::now();
+
+    assert_eq!(tally_close_wait(&mut since, &[], start), (0, None));
+    assert_eq!(tally_close_wait(&mut since, &[leaked], start), (1, Some(0)));
+    let later = start + Duration::from_secs(90);
+    assert_eq!(
+        tally_close_wait(&mut since, &[leaked, other], later),
+        (2, Some(90))
+    );
+
+    // Closed by the application at last, the age comes from the newer one
+    let even_later = later + Duration::from_secs(30);
+    assert_eq!(
+        tally_close_wait(&mut since, &[other], even_later),
+        (1, Some(30))
+    );
+}
+
+#[test]
+fn test_zombie_count() {
+    let statuses = [
+        ProcessStatus::Run,
+        ProcessStatus::Zombie,
+        ProcessStatus::Sleep,
+        ProcessStatus::Zombie,
+        ProcessStatus::Dead,
+    ];
+    assert_eq!(count_zombies(statuses.into_iter()), 2);
+}
+
+#[test]
+fn test_uninterruptible_count() {
+    let processes = [
+        (ProcessStatus::Run, "nginx"),
+        (ProcessStatus::UninterruptibleDiskSleep, "postgres"),
+        (ProcessStatus::Sleep, "sshd"),
+        (ProcessStatus::UninterruptibleDiskSleep, "jbd2/sda1-8"),
+        (ProcessStatus::UninterruptibleDiskSleep, "postgres"),
+    ];
+    let (count, names) = find_uninterruptible(
+        processes
+            .into_iter()
+            .map(|(status, name)| (status, Cow::Borrowed(name))),
+    );
+    assert_eq!(count, 3);
+    assert_eq!(names, ["jbd2/sda1-8", "postgres"]);
+}
+
+#[test]
+fn test_hours_to_full_from_declining_free_space() {
+    const GB: u64 = 1 << 30;
+    let mut metrics = Metrics::with_collectors(vec![]);
+    let start = Instant::now();
+    let disk = |space_used| DiskDetail {
+        mount_point: "/var".to_string(),
+        space_used,
+        space_total: 100 * GB,
+        hours_to_full: None,
+    };
+
+    // 1 GB more used every 10 minutes, with 10 GB free at the last sample
+    let mut estimate = None;
+    for i in 0..6 {
+        let mut disks = vec![disk((85 + i) * GB)];
+        metrics.update_space_trends(&mut disks, start + Duration::from_secs(600 * i));
+        estimate = disks[0].hours_to_full;
+        if i < 2 {
+            assert_eq!(estimate, None);
+        }
+    }
+    let estimate = estimate.unwrap();
+    assert!(
+        (estimate - 10.0 / 6.0).abs() < 0.01,
+        "estimate {}",
+        estimate
+    );
+
+    // Space being freed up has no time to full
+    let mut disks = vec![disk(50 * GB)];
+    metrics.update_space_trends(&mut disks, start + Duration::from_secs(3600));
+    assert_eq!(disks[0].hours_to_full, None);
 }
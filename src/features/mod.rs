@@ -1 +1,8 @@
-pub mod metrics;
\ No newline at end of file
+pub mod gateway;
+pub mod gauge;
+pub mod identity;
+pub mod kubernetes;
+pub mod machine_id;
+pub mod metrics;
+pub mod oom;
+pub mod snmp;
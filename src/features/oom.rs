@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OomEvent {
+    pub pid: u32,
+    /// Command name of the killed process
+    pub comm: String,
+    /// Unix timestamp in milliseconds of the kill
+    pub timestamp: u64,
+}
+
+/// Follows the kernel log for OOM-killer entries. Only entries logged after
+/// the reader was opened are reported, so restarts don't report old kills
+/// again.
+#[cfg(target_os = "linux")]
+pub struct KmsgReader {
+    file: std::fs::File,
+    boot_time_ms: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl KmsgReader {
+    /// Opens `/dev/kmsg`, which needs root or `CAP_SYSLOG` on most systems.
+    pub fn open() -> std::io::Result<Self> {
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/kmsg")?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            boot_time_ms: sysinfo::System::boot_time() * 1000,
+        })
+    }
+
+    /// Returns the OOM kills logged since the previous call.
+    pub fn read_events(&mut self) -> Vec<OomEvent> {
+        use std::io::{ErrorKind, Read};
+
+        let mut events = Vec::new();
+        // Each read returns exactly one record
+        let mut record = [0u8; 8192];
+        loop {
+            match self.file.read(&mut record) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let line = String::from_utf8_lossy(&record[..n]);
+                    events.extend(parse_kmsg_record(&line, self.boot_time_ms));
+                }
+                // Records were overwritten before we read them, skip ahead
+                Err(e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    tracing::warn!(error = %e, "Failed to read kernel log");
+                    break;
+                }
+            }
+        }
+        events
+    }
+}
+
+/// Parses a `/dev/kmsg` record (`prio,seq,usecs,flags;message`) into an
+/// event if it is an OOM-killer entry such as
+/// `Out of memory: Killed process 1234 (stress) total-vm:...`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_kmsg_record(record: &str, boot_time_ms: u64) -> Option<OomEvent> {
+    let (header, message) = record.split_once(';')?;
+    let usecs: u64 = header.split(',').nth(2)?.parse().ok()?;
+
+    let rest = message.split_once("Killed process ")?.1;
+    let (pid, rest) = rest.split_once(' ')?;
+    let comm = rest.strip_prefix('(')?.split_once(')')?.0;
+
+    Some(OomEvent {
+        pid: pid.parse().ok()?,
+        comm: comm.to_string(),
+        timestamp: boot_time_ms + usecs / 1000,
+    })
+}
+
+#[test]
+fn test_parse_oom_kmsg_record() {
+    let record = "3,1342,84213376123,-;Out of memory: Killed process 4242 (stress-ng) \
+                  total-vm:1048576kB, anon-rss:917504kB, file-rss:0kB, shmem-rss:0kB, UID:0 \
+                  pgtables:2048kB oom_score_adj:0\n";
+
+    assert_eq!(
+        parse_kmsg_record(record, 1_700_000_000_000),
+        Some(OomEvent {
+            pid: 4242,
+            comm: "stress-ng".to_string(),
+            timestamp: 1_700_000_000_000 + 84_213_376,
+        })
+    );
+    assert_eq!(
+        parse_kmsg_record("6,1343,84213400000,-;eth0: link up\n", 0),
+        None
+    );
+}
@@ -0,0 +1,446 @@
+//! Polls SNMP v2c agents for a configured list of OIDs, for devices such as
+//! switches and UPSes that can't run vmonitor themselves. SNMP v3 (USM
+//! authentication and privacy) is not supported. The client is only built
+//! with the `snmp` cargo feature; the report types always exist so samples
+//! deserialize the same either way.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::config::SnmpDevice;
+
+/// One polled OID of one device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SnmpReading {
+    /// The device's configured name, or its address
+    pub device: String,
+    pub oid: String,
+    pub value: SnmpValue,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SnmpValue {
+    /// INTEGER
+    Integer(i64),
+    /// Counter32, Gauge32, TimeTicks and Counter64
+    Unsigned(u64),
+    /// OCTET STRING, IpAddress and OBJECT IDENTIFIER, as text
+    Text(String),
+}
+
+/// The readings of the last poll of every device, and an error for each
+/// device that didn't answer.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SnmpPoll {
+    pub readings: Vec<SnmpReading>,
+    pub errors: Vec<String>,
+}
+
+/// Polls `devices` every `interval` and publishes the result to `polls`, so
+/// every endpoint reports the same readings and each device is asked once
+/// per interval. Never returns.
+#[cfg(feature = "snmp")]
+pub async fn poll_every(
+    devices: Vec<SnmpDevice>,
+    interval: Duration,
+    polls: watch::Sender<SnmpPoll>,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    let mut request_id = 0i32;
+    loop {
+        ticks.tick().await;
+        let poll = poll_all(&devices, request_id).await;
+        request_id = request_id.wrapping_add(devices.len() as i32);
+        polls.send_replace(poll);
+    }
+}
+
+/// Without the `snmp` feature there is nothing to poll with, so this only
+/// warns about the configured devices.
+#[cfg(not(feature = "snmp"))]
+pub async fn poll_every(
+    devices: Vec<SnmpDevice>,
+    _interval: Duration,
+    _polls: watch::Sender<SnmpPoll>,
+) {
+    if !devices.is_empty() {
+        let devices: Vec<&str> = devices.iter().map(|d| d.display_name()).collect();
+        tracing::warn!(
+            devices = ?devices,
+            "SNMP devices are configured, but vmonitor was built without the snmp feature"
+        );
+    }
+    std::future::pending().await
+}
+
+#[cfg(feature = "snmp")]
+pub use client::*;
+
+#[cfg(feature = "snmp")]
+mod client {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use tokio::time::timeout;
+    use tracing::warn;
+
+    use super::{SnmpDevice, SnmpPoll, SnmpReading, SnmpValue};
+
+    /// How long a device may take to answer before it's reported unreachable.
+    pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+    const SNMP_V2C: i64 = 1;
+
+    const TAG_INTEGER: u8 = 0x02;
+    const TAG_OCTET_STRING: u8 = 0x04;
+    const TAG_NULL: u8 = 0x05;
+    const TAG_OID: u8 = 0x06;
+    const TAG_SEQUENCE: u8 = 0x30;
+    const TAG_IP_ADDRESS: u8 = 0x40;
+    const TAG_COUNTER32: u8 = 0x41;
+    const TAG_GAUGE32: u8 = 0x42;
+    const TAG_TIMETICKS: u8 = 0x43;
+    const TAG_COUNTER64: u8 = 0x46;
+    pub const PDU_GET_REQUEST: u8 = 0xa0;
+    pub const PDU_RESPONSE: u8 = 0xa2;
+
+    /// A v2c message carrying a single PDU. Variable bindings without a
+    /// value (requests, or `noSuchObject` in responses) hold `None`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Message {
+        pub community: String,
+        pub pdu_type: u8,
+        pub request_id: i32,
+        pub error_status: i64,
+        pub bindings: Vec<(String, Option<SnmpValue>)>,
+    }
+
+    impl Message {
+        pub fn encode(&self) -> Result<Vec<u8>, String> {
+            let mut bindings = Vec::new();
+            for (oid, value) in &self.bindings {
+                let mut binding = encode_oid(oid)?;
+                binding.extend(match value {
+                    None => tlv(TAG_NULL, &[]),
+                    Some(SnmpValue::Integer(n)) => tlv(TAG_INTEGER, &integer_bytes(*n)),
+                    Some(SnmpValue::Unsigned(n)) => tlv(TAG_COUNTER64, &unsigned_bytes(*n)),
+                    Some(SnmpValue::Text(text)) => tlv(TAG_OCTET_STRING, text.as_bytes()),
+                });
+                bindings.extend(tlv(TAG_SEQUENCE, &binding));
+            }
+
+            let mut pdu = tlv(TAG_INTEGER, &integer_bytes(self.request_id.into()));
+            pdu.extend(tlv(TAG_INTEGER, &integer_bytes(self.error_status)));
+            pdu.extend(tlv(TAG_INTEGER, &integer_bytes(0)));
+            pdu.extend(tlv(TAG_SEQUENCE, &bindings));
+
+            let mut message = tlv(TAG_INTEGER, &integer_bytes(SNMP_V2C));
+            message.extend(tlv(TAG_OCTET_STRING, self.community.as_bytes()));
+            message.extend(tlv(self.pdu_type, &pdu));
+            Ok(tlv(TAG_SEQUENCE, &message))
+        }
+
+        pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+            let mut reader = Reader(bytes);
+            let mut message = Reader(reader.expect(TAG_SEQUENCE)?);
+            let version = decode_integer(message.expect(TAG_INTEGER)?)?;
+            if version != SNMP_V2C {
+                return Err(format!("unsupported SNMP version {}", version));
+            }
+            let community = String::from_utf8_lossy(message.expect(TAG_OCTET_STRING)?).into();
+            let (pdu_type, pdu) = message.next()?;
+            let mut pdu = Reader(pdu);
+            let request_id = decode_integer(pdu.expect(TAG_INTEGER)?)? as i32;
+            let error_status = decode_integer(pdu.expect(TAG_INTEGER)?)?;
+            pdu.expect(TAG_INTEGER)?;
+
+            let mut bindings = Vec::new();
+            let mut list = Reader(pdu.expect(TAG_SEQUENCE)?);
+            while !list.0.is_empty() {
+                let mut binding = Reader(list.expect(TAG_SEQUENCE)?);
+                let oid = decode_oid(binding.expect(TAG_OID)?);
+                let (tag, value) = binding.next()?;
+                let value = match tag {
+                    TAG_INTEGER => Some(SnmpValue::Integer(decode_integer(value)?)),
+                    TAG_COUNTER32 | TAG_GAUGE32 | TAG_TIMETICKS | TAG_COUNTER64 => {
+                        Some(SnmpValue::Unsigned(decode_unsigned(value)?))
+                    }
+                    TAG_OCTET_STRING => {
+                        Some(SnmpValue::Text(String::from_utf8_lossy(value).into_owned()))
+                    }
+                    TAG_IP_ADDRESS if value.len() == 4 => Some(SnmpValue::Text(format!(
+                        "{}.{}.{}.{}",
+                        value[0], value[1], value[2], value[3]
+                    ))),
+                    TAG_OID => Some(SnmpValue::Text(decode_oid(value))),
+                    // NULL and the noSuchObject/noSuchInstance/endOfMibView
+                    // exceptions
+                    _ => None,
+                };
+                bindings.push((oid, value));
+            }
+
+            Ok(Self {
+                community,
+                pdu_type,
+                request_id,
+                error_status,
+                bindings,
+            })
+        }
+    }
+
+    /// Polls every device concurrently, numbering the requests from
+    /// `request_id`. Devices that don't answer leave out their readings and
+    /// are listed in `errors`.
+    pub async fn poll_all(devices: &[SnmpDevice], request_id: i32) -> SnmpPoll {
+        let polls = devices
+            .iter()
+            .enumerate()
+            .map(|(i, device)| poll(device, request_id.wrapping_add(i as i32 + 1)));
+        let results = futures::future::join_all(polls).await;
+
+        let mut poll = SnmpPoll::default();
+        for (device, result) in devices.iter().zip(results) {
+            match result {
+                Ok(readings) => poll.readings.extend(readings),
+                Err(e) => {
+                    let e = format!("snmp {}: {}", device.display_name(), e);
+                    warn!(error = %e, "Skipping SNMP device");
+                    poll.errors.push(e);
+                }
+            }
+        }
+        poll
+    }
+
+    /// `host` with port 161 added unless it has one. IPv6 addresses take a
+    /// port only in brackets, as `[::1]:1161`.
+    fn agent_address(host: &str) -> String {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return SocketAddr::new(ip, 161).to_string();
+        }
+        let has_port = match host.strip_prefix('[') {
+            Some(rest) => rest.contains("]:"),
+            None => host.contains(':'),
+        };
+        if has_port {
+            host.to_string()
+        } else {
+            format!("{}:161", host)
+        }
+    }
+
+    /// Reads all of `device`'s OIDs with one GET request.
+    pub async fn poll(device: &SnmpDevice, request_id: i32) -> Result<Vec<SnmpReading>, String> {
+        let request = Message {
+            community: device.community.clone(),
+            pdu_type: PDU_GET_REQUEST,
+            request_id,
+            error_status: 0,
+            bindings: device.oids.iter().map(|oid| (oid.clone(), None)).collect(),
+        }
+        .encode()?;
+
+        let address = tokio::net::lookup_host(agent_address(&device.host))
+            .await
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or_else(|| format!("{} did not resolve", device.host))?;
+        let local: IpAddr = if address.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = UdpSocket::bind((local, 0))
+            .await
+            .map_err(|e| e.to_string())?;
+        socket.connect(address).await.map_err(|e| e.to_string())?;
+        socket.send(&request).await.map_err(|e| e.to_string())?;
+
+        let mut buf = vec![0; 65535];
+        let response = timeout(REQUEST_TIMEOUT, async {
+            loop {
+                let len = socket.recv(&mut buf).await.map_err(|e| e.to_string())?;
+                // Late answers to an earlier poll are skipped
+                match Message::decode(&buf[..len]) {
+                    Ok(message)
+                        if message.pdu_type == PDU_RESPONSE && message.request_id == request_id =>
+                    {
+                        return Ok(message)
+                    }
+                    Ok(_) => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+        .await
+        .map_err(|_| format!("no answer within {}s", REQUEST_TIMEOUT.as_secs()))??;
+
+        if response.error_status != 0 {
+            return Err(format!(
+                "agent returned error status {}",
+                response.error_status
+            ));
+        }
+        Ok(response
+            .bindings
+            .into_iter()
+            .filter_map(|(oid, value)| {
+                Some(SnmpReading {
+                    device: device.display_name().to_string(),
+                    oid,
+                    value: value?,
+                })
+            })
+            .collect())
+    }
+
+    fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        let len = value.len();
+        if len < 0x80 {
+            out.push(len as u8);
+        } else {
+            let bytes: Vec<u8> = len
+                .to_be_bytes()
+                .into_iter()
+                .skip_while(|b| *b == 0)
+                .collect();
+            out.push(0x80 | bytes.len() as u8);
+            out.extend(bytes);
+        }
+        out.extend_from_slice(value);
+        out
+    }
+
+    fn integer_bytes(n: i64) -> Vec<u8> {
+        let mut bytes = n.to_be_bytes().to_vec();
+        // Drop redundant sign bytes, keeping the one that carries the sign
+        while bytes.len() > 1
+            && ((bytes[0] == 0 && bytes[1] & 0x80 == 0)
+                || (bytes[0] == 0xff && bytes[1] & 0x80 != 0))
+        {
+            bytes.remove(0);
+        }
+        bytes
+    }
+
+    fn unsigned_bytes(n: u64) -> Vec<u8> {
+        let mut bytes: Vec<u8> = n
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        if bytes.first().is_none_or(|b| b & 0x80 != 0) {
+            bytes.insert(0, 0);
+        }
+        bytes
+    }
+
+    fn decode_integer(bytes: &[u8]) -> Result<i64, String> {
+        if bytes.is_empty() || bytes.len() > 8 {
+            return Err("malformed INTEGER".to_string());
+        }
+        let sign = if bytes[0] & 0x80 != 0 { -1i64 } else { 0 };
+        Ok(bytes.iter().fold(sign, |n, b| (n << 8) | i64::from(*b)))
+    }
+
+    fn decode_unsigned(bytes: &[u8]) -> Result<u64, String> {
+        let bytes = match bytes {
+            [0, rest @ ..] => rest,
+            _ => bytes,
+        };
+        if bytes.len() > 8 {
+            return Err("malformed counter".to_string());
+        }
+        Ok(bytes.iter().fold(0, |n, b| (n << 8) | u64::from(*b)))
+    }
+
+    fn encode_oid(oid: &str) -> Result<Vec<u8>, String> {
+        let arcs = oid
+            .trim_start_matches('.')
+            .split('.')
+            .map(|arc| arc.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("invalid OID '{}'", oid))?;
+        if arcs.len() < 2 {
+            return Err(format!("invalid OID '{}'", oid));
+        }
+        let mut bytes = Vec::new();
+        for arc in std::iter::once(arcs[0] * 40 + arcs[1]).chain(arcs[2..].iter().copied()) {
+            let mut groups = vec![(arc & 0x7f) as u8];
+            let mut rest = arc >> 7;
+            while rest > 0 {
+                groups.push((rest & 0x7f) as u8 | 0x80);
+                rest >>= 7;
+            }
+            bytes.extend(groups.into_iter().rev());
+        }
+        Ok(tlv(TAG_OID, &bytes))
+    }
+
+    fn decode_oid(bytes: &[u8]) -> String {
+        let mut arcs = Vec::new();
+        let mut arc = 0u64;
+        for b in bytes {
+            arc = (arc << 7) | u64::from(b & 0x7f);
+            if b & 0x80 == 0 {
+                if arcs.is_empty() {
+                    let first = (arc / 40).min(2);
+                    arcs.push(first);
+                    arcs.push(arc - first * 40);
+                } else {
+                    arcs.push(arc);
+                }
+                arc = 0;
+            }
+        }
+        arcs.iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl<'a> Reader<'a> {
+        fn next(&mut self) -> Result<(u8, &'a [u8]), String> {
+            let truncated = || "truncated SNMP message".to_string();
+            let (&tag, rest) = self.0.split_first().ok_or_else(truncated)?;
+            let (&first, mut rest) = rest.split_first().ok_or_else(truncated)?;
+            let len = if first & 0x80 == 0 {
+                usize::from(first)
+            } else {
+                let count = usize::from(first & 0x7f);
+                if count > 4 || rest.len() < count {
+                    return Err(truncated());
+                }
+                let (len, after) = rest.split_at(count);
+                rest = after;
+                len.iter().fold(0, |n, b| (n << 8) | usize::from(*b))
+            };
+            if rest.len() < len {
+                return Err(truncated());
+            }
+            let (value, rest) = rest.split_at(len);
+            self.0 = rest;
+            Ok((tag, value))
+        }
+
+        fn expect(&mut self, expected: u8) -> Result<&'a [u8], String> {
+            match self.next()? {
+                (tag, value) if tag == expected => Ok(value),
+                (tag, _) => Err(format!(
+                    "expected tag {:#04x}, found {:#04x}",
+                    expected, tag
+                )),
+            }
+        }
+    }
+}
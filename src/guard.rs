@@ -0,0 +1,105 @@
+use std::net::IpAddr;
+
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, timeout, Duration};
+use tracing::{info, warn};
+
+use crate::config::GuardConfig;
+use crate::features::gateway;
+
+/// How long `require_reachable` may take to accept a connection.
+const REACHABLE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Evaluates the guard every `recheck_secs` and publishes whether reporting
+/// is allowed. The first evaluation happens before this returns, so
+/// endpoints never report before the network is known to be trusted.
+pub async fn spawn(config: GuardConfig) -> (JoinHandle<()>, watch::Receiver<bool>) {
+    let initial = check(&config).await;
+    if let Err(reason) = &initial {
+        warn!(reason = %reason, "Reporting gated by network guard");
+    }
+    let (tx, rx) = watch::channel(initial.is_ok());
+
+    let task = tokio::spawn(async move {
+        let mut recheck = interval(Duration::from_secs(config.recheck_secs.max(1)));
+        recheck.tick().await;
+        loop {
+            recheck.tick().await;
+            let result = check(&config).await;
+            match (&result, *tx.borrow()) {
+                (Ok(()), false) => info!("Network guard conditions met, reporting resumed"),
+                (Err(reason), true) => warn!(reason = %reason, "Reporting gated by network guard"),
+                _ => {}
+            }
+            tx.send_replace(result.is_ok());
+        }
+    });
+
+    (task, rx)
+}
+
+/// Checks every configured condition, returning why reporting is gated if
+/// one doesn't hold.
+pub async fn check(config: &GuardConfig) -> Result<(), String> {
+    if !config.gateway_macs.is_empty() {
+        let mac = gateway::default_gateway()
+            .and_then(gateway_mac)
+            .ok_or_else(|| "default gateway MAC unknown".to_string())?;
+        if !config
+            .gateway_macs
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&mac))
+        {
+            return Err(format!("gateway MAC {} is not allowed", mac));
+        }
+    }
+
+    if let Some(host) = &config.require_reachable {
+        match timeout(REACHABLE_TIMEOUT, TcpStream::connect(host.as_str())).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(format!("{} is unreachable: {}", host, e)),
+            Err(_) => return Err(format!("{} is unreachable: timed out", host)),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn gateway_mac(gateway: IpAddr) -> Option<String> {
+    let arp = std::fs::read_to_string("/proc/net/arp").ok()?;
+    parse_arp_mac(&arp, gateway)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn gateway_mac(_gateway: IpAddr) -> Option<String> {
+    None
+}
+
+/// Finds the hardware address of `ip` in `/proc/net/arp`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_arp_mac(arp: &str, ip: IpAddr) -> Option<String> {
+    arp.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[0].parse::<IpAddr>().ok()? != ip {
+            return None;
+        }
+        // Incomplete entries have an all-zero address
+        (fields[3] != "00:00:00:00:00:00").then(|| fields[3].to_string())
+    })
+}
+
+#[test]
+fn test_parse_arp_mac() {
+    let arp = "IP address       HW type     Flags       HW address            Mask     Device\n\
+               192.168.1.1      0x1         0x2         00:11:22:aa:bb:cc     *        eth0\n\
+               192.168.1.7      0x1         0x0         00:00:00:00:00:00     *        eth0\n";
+
+    assert_eq!(
+        parse_arp_mac(arp, "192.168.1.1".parse().unwrap()).as_deref(),
+        Some("00:11:22:aa:bb:cc")
+    );
+    assert_eq!(parse_arp_mac(arp, "192.168.1.7".parse().unwrap()), None);
+}
@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tokio::time::Duration;
+
+use crate::features::metrics::ReportData;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HistorySample {
+    /// Unix timestamp in milliseconds
+    pub collected_at: u64,
+    #[serde(flatten)]
+    pub report: ReportData,
+}
+
+/// Rolling in-memory window of recently collected samples.
+pub struct History {
+    window: Duration,
+    samples: RwLock<VecDeque<HistorySample>>,
+}
+
+impl History {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: RwLock::new(VecDeque::new()),
+        }
+    }
+
+    /// Records a sample collected at `collected_at` and drops samples that
+    /// have fallen out of the window.
+    pub async fn record_at(&self, collected_at: u64, report: ReportData) {
+        let mut samples = self.samples.write().await;
+        samples.push_back(HistorySample {
+            collected_at,
+            report,
+        });
+
+        let cutoff = collected_at.saturating_sub(self.window.as_millis() as u64);
+        while samples.front().is_some_and(|s| s.collected_at < cutoff) {
+            samples.pop_front();
+        }
+    }
+
+    /// Returns the most recent sample, if any.
+    pub async fn latest(&self) -> Option<HistorySample> {
+        self.samples.read().await.back().cloned()
+    }
+
+    /// Returns samples from the last `since`, oldest first.
+    pub async fn since(&self, since: Duration) -> Vec<HistorySample> {
+        let cutoff = now_millis().saturating_sub(since.as_millis() as u64);
+        self.samples
+            .read()
+            .await
+            .iter()
+            .filter(|s| s.collected_at >= cutoff)
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Nanoseconds since the first call in this process, from a clock that isn't
+/// affected by changes to the system time.
+pub fn mono_nanos() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
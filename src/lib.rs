@@ -1,5 +1,16 @@
 pub mod api;
 pub mod app;
 pub mod config;
+pub mod config_watch;
+#[cfg(unix)]
+pub mod control;
+pub mod dashboard;
+pub mod features;
+pub mod guard;
+pub mod history;
+pub mod logfile;
 pub mod monitor;
-pub mod features;
\ No newline at end of file
+pub mod signing;
+pub mod sinks;
+pub mod supervisor;
+pub mod transport;
@@ -1,13 +1,9 @@
-mod api;
-mod app;
 mod cli;
-mod config;
-mod monitor;
-mod features;
 
 use clap::Parser;
 use std::env;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use vmonitor::{app, config, features, logfile, signing};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -30,6 +26,44 @@ struct Args {
     #[arg(short, long, default_value = "info")]
     log_level: String,
 
+    /// Write logs to FILE instead of stdout. SIGUSR1 or the control socket's
+    /// `REOPEN-LOGS` reopens it after rotation
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<String>,
+
+    /// Only run the given collectors (comma-separated: system, network, disk, gateway, oom),
+    /// overriding `report.collect` from the config file
+    #[arg(long, value_delimiter = ',')]
+    collect: Option<Vec<config::Collector>>,
+
+    /// Serve a live dashboard over HTTP on the given address (e.g. `:8080`,
+    /// loopback only unless a `[control]` token is set, which it then requires)
+    #[arg(long, value_name = "ADDR")]
+    dashboard: Option<String>,
+
+    /// Before connecting, wait for a default route (Linux only), giving up
+    /// after SECS
+    #[arg(long, value_name = "SECS", num_args = 0..=1, default_missing_value = "60")]
+    wait_for_network: Option<u64>,
+
+    /// Refuse to load a config whose detached signature (`<config>.sig`) doesn't
+    /// verify against `--public-key`
+    #[arg(long, requires = "public_key")]
+    require_signed: bool,
+
+    /// ed25519 public key (PEM) used by `--require-signed`
+    #[arg(long, value_name = "FILE")]
+    public_key: Option<String>,
+
+    /// Refuse every operation that would modify the config file
+    #[arg(long)]
+    read_only: bool,
+
+    /// Keep a copy of every config that loads (`<config>.last-good`) and start
+    /// with it when the config doesn't load, instead of exiting
+    #[arg(long)]
+    last_known_good: bool,
+
     #[command(subcommand)]
     command: Option<cli::Commands>,
 }
@@ -43,40 +77,147 @@ async fn main() {
     // Parse command line arguments
     let args = Args::parse();
 
+    let log_file = match &args.log_file {
+        Some(path) => match logfile::LogFile::open(path) {
+            Ok(log_file) => Some(log_file),
+            Err(e) => {
+                eprintln!("Failed to open log file {}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     // Initialize tracing subscriber with specified log level
-    tracing_subscriber::fmt()
-        .with_env_filter(&args.log_level)
-        .init();
+    let subscriber = tracing_subscriber::fmt().with_env_filter(&args.log_level);
+    match log_file.clone() {
+        Some(log_file) => subscriber
+            .with_ansi(false)
+            .with_writer(move || log_file.clone())
+            .init(),
+        None => subscriber.init(),
+    }
 
     // Get config path from environment variable or command line argument
     let config_path = env::var(&args.env_var).unwrap_or(args.config);
 
     // Handle subcommands first
     if let Some(command) = args.command {
-        let exit_code = cli::handle_command(command, &config_path);
-        std::process::exit(if exit_code == std::process::ExitCode::SUCCESS { 0 } else { 1 });
+        let exit_code = cli::handle_command(command, &config_path, args.read_only).await;
+        std::process::exit(if exit_code == std::process::ExitCode::SUCCESS {
+            0
+        } else {
+            1
+        });
     }
 
     info!(config_path = %config_path, "Starting application");
 
-    // Load configuration from config file
-    let config = match config::AppConfig::from_file(&config_path) {
-        Ok(mut cfg) => {
-            for endpoint in cfg.endpoints.iter_mut() {
-                if endpoint.connection.is_none() {
-                    endpoint.connection = Some(cfg.connection);
-                }
+    // Before loading the config, as endpoint templates may use ${MACHINE_ID}
+    let machine_id_path = features::machine_id::fallback_path(&config_path);
+    let machine_id = features::machine_id::resolve(Some(&machine_id_path));
+
+    let public_key = match args.public_key.filter(|_| args.require_signed) {
+        Some(path) => match std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|key| signing::parse_public_key(&key))
+        {
+            Ok(key) => Some(key),
+            Err(e) => {
+                error!(error = %e, path = %path, "Failed to read public key");
+                std::process::exit(1);
             }
-            cfg
         },
+        None => None,
+    };
+
+    // Load configuration from config file
+    let loaded = if args.last_known_good {
+        config::AppConfig::load_or_last_known_good(&config_path, public_key.as_deref()).map(
+            |(cfg, error)| {
+                if let Some(error) = error {
+                    warn!(
+                        error = %error,
+                        path = %config::last_known_good_path(&config_path),
+                        "Config failed to load, RUNNING WITH THE LAST KNOWN GOOD CONFIG; \
+                         fix the config and restart"
+                    );
+                }
+                cfg
+            },
+        )
+    } else {
+        config::AppConfig::load(&config_path, public_key.as_deref())
+    };
+    let config = match loaded {
+        Ok(cfg) => cfg,
         Err(e) => {
             error!(error = %e, "Failed to load config");
             std::process::exit(1);
         }
     };
     info!("Configuration loaded");
+    let unsupported = features::metrics::unsupported_metrics();
+    if !unsupported.is_empty() {
+        info!(metrics = ?unsupported, "Some metrics are not available on this platform and won't be reported");
+    }
 
     // Create and run the application
-    let app = app::App::new(config);
+    let mut app = app::App::new(config, config_path.clone());
+    if let Some(machine_id) = machine_id {
+        app = app.with_machine_id(machine_id);
+    }
+    if let Some(collectors) = args.collect {
+        info!(collectors = ?collectors, "Overriding configured collectors");
+        app = app.with_collectors(collectors);
+    }
+    if let Some(addr) = args.dashboard {
+        app = app.with_dashboard(addr);
+    }
+    if let Some(secs) = args.wait_for_network {
+        app = app.with_wait_for_network(std::time::Duration::from_secs(secs));
+    }
+    if let Some(key) = public_key {
+        app = app.with_required_signature(key);
+    }
+    if let Some(log_file) = log_file {
+        // logrotate's `postrotate` conventionally sends SIGUSR1
+        #[cfg(unix)]
+        {
+            let log_file = log_file.clone();
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                match signal(SignalKind::user_defined1()) {
+                    Ok(mut sigusr1) => {
+                        while sigusr1.recv().await.is_some() {
+                            match log_file.reopen() {
+                                Ok(()) => info!("Reopened log file on SIGUSR1"),
+                                Err(e) => error!(error = %e, "Failed to reopen log file"),
+                            }
+                        }
+                    }
+                    Err(e) => error!(error = %e, "Failed to listen for SIGUSR1"),
+                }
+            });
+        }
+        app = app.with_log_file(log_file);
+    }
+
+    // systemd stops services with SIGTERM, shut down gracefully on it too
+    #[cfg(unix)]
+    {
+        let shutdown = app.shutdown_handle();
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut sigterm) => {
+                    sigterm.recv().await;
+                    info!("Received SIGTERM");
+                    shutdown.notify_one();
+                }
+                Err(e) => error!(error = %e, "Failed to listen for SIGTERM"),
+            }
+        });
+    }
     app.run().await;
 }
@@ -1,254 +1,1866 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
 use crate::api;
-use crate::config::Endpoint;
-use crate::features::metrics::Metrics;
+use crate::config::{
+    Collector, CompressionConfig, ConnectionConfig, DetailLevel, Encoding, Endpoint, FlushOrder,
+    GaugeConfig, IntervalAuthority, MetricsFormat, ReportConfig, TrustedSelfSigned,
+};
+use crate::features::gateway;
+use crate::features::metrics::{Metrics, ReportData, SampleClock};
+use crate::features::snmp::SnmpPoll;
+use crate::history::{mono_nanos, now_millis};
+use crate::transport::{self, Connection, FrameSink, FrameStream, Transport};
 use futures::{sink::SinkExt, stream::StreamExt};
-use futures_util::stream::SplitStream;
 use tokio::{
-    net::TcpStream,
-    sync::{mpsc, watch},
-    time::{interval, sleep, Duration},
-};
-use tokio_tungstenite::{
-    tungstenite::{Bytes, Message},
-    MaybeTlsStream, WebSocketStream,
+    sync::{broadcast, mpsc, watch, Notify},
+    task::JoinSet,
+    time::{interval, interval_at, sleep, sleep_until, timeout, timeout_at, Duration, Instant},
 };
+use tokio_tungstenite::tungstenite::{Bytes, Message};
 use tracing::{debug, error, info, warn};
 
+/// How long to wait for the server to answer a `vm_info_hash` before falling
+/// back to sending the full VM info.
+const VM_INFO_NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long an endpoint with `ready_handshake` may take to answer the
+/// application ping before the connection is retried.
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often `--wait-for-network` checks for a default route.
+const NETWORK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Consecutive overrunning collections before an adaptive interval widens.
+const OVERRUNS_BEFORE_WIDENING: u32 = 3;
+/// How far an adaptive interval may widen, as a multiple of the configured one.
+const MAX_INTERVAL_STRETCH: u32 = 4;
+/// Commands that collect VM info may arrive this many times less often than
+/// `max_commands_per_sec`.
+const EXPENSIVE_COMMAND_DIVISOR: u32 = 5;
+
+/// What a running [`Monitor`] reports to in-process subscribers, see
+/// [`crate::app::App::subscribe`].
+#[derive(Debug, Clone)]
+pub enum MonitorEvent {
+    /// A freshly collected sample, before the endpoint's detail level and
+    /// field map are applied. Also emitted while the endpoint is disconnected.
+    Sample {
+        endpoint: String,
+        report: Box<ReportData>,
+    },
+    Connected {
+        endpoint: String,
+    },
+    Disconnected {
+        endpoint: String,
+    },
+    /// The endpoint's monitor panicked, see [`crate::supervisor::supervise`]
+    Panicked {
+        endpoint: String,
+        message: String,
+        /// Whether it is started again, or was given up on after panicking
+        /// too often
+        restarting: bool,
+    },
+}
+
 #[derive(Clone)]
 struct Config {
     metrics_interval: Duration,
+    collectors: Vec<Collector>,
+    field_map: BTreeMap<String, String>,
+    /// Starts as the endpoint's `format`, a `reconfigure` command may change it
+    format: MetricsFormat,
+    /// How samples are collected and shaped; `collect` is ignored in favour
+    /// of `collectors`, which a `reconfigure` command may change
+    report: ReportConfig,
+    snmp: Option<watch::Receiver<SnmpPoll>>,
+    gauges: Vec<GaugeConfig>,
+    config_fingerprint: Option<String>,
+    machine_id: Option<String>,
+    /// Temporarily replaces `metrics_interval`, see [`IntervalOverride`]
+    interval_override: Option<Duration>,
 }
 impl Config {
-    fn new() -> Self {
+    fn new(collectors: Vec<Collector>) -> Self {
         Self {
             metrics_interval: Duration::from_secs(10),
+            collectors,
+            field_map: BTreeMap::new(),
+            format: MetricsFormat::default(),
+            report: ReportConfig::default(),
+            snmp: None,
+            gauges: Vec::new(),
+            config_fingerprint: None,
+            machine_id: None,
+            interval_override: None,
         }
     }
-    fn validate(&self) -> Result<(), String> {
+
+    fn effective_interval(&self) -> Duration {
+        self.interval_override.unwrap_or(self.metrics_interval)
+    }
+    fn validate(&self, endpoint: &Endpoint) -> Result<(), String> {
         if self.metrics_interval < Duration::from_secs(1) {
             return Err("Metrics interval must be at least 1 second".to_string());
         }
+        if endpoint.split_by_collector && self.format == MetricsFormat::Array {
+            return Err("split_by_collector can't be combined with format = \"array\"".to_string());
+        }
         Ok(())
     }
 }
+/// Updates a running monitor, see [`Monitor::handle`].
+#[derive(Clone)]
+pub struct MonitorHandle {
+    config_tx: watch::Sender<Config>,
+    sample_stats: Arc<SampleStats>,
+}
+
+impl MonitorHandle {
+    /// How far the monitor is behind its shared collector.
+    pub fn sample_stats(&self) -> Arc<SampleStats> {
+        self.sample_stats.clone()
+    }
+
+    /// How often the monitor currently sends metrics, as configured, pushed
+    /// by the server or overridden.
+    pub fn effective_interval(&self) -> Duration {
+        self.config_tx.borrow().effective_interval()
+    }
+
+    /// The collectors the monitor currently reports, as configured or
+    /// pushed by the server.
+    pub fn collectors(&self) -> Vec<Collector> {
+        self.config_tx.borrow().collectors.clone()
+    }
+
+    /// Reports `fingerprint` in the VM info from now on, for reloads that
+    /// leave the monitor running.
+    pub fn set_config_fingerprint(&self, fingerprint: String) {
+        // Only read when VM info is sent, so receivers, which restart the
+        // metrics interval on every change, aren't woken
+        self.config_tx.send_if_modified(|config| {
+            config.config_fingerprint = Some(fingerprint);
+            false
+        });
+    }
+}
+
 pub struct Monitor {
     pub endpoint: Endpoint,
     config_tx: watch::Sender<Config>,
     config_rx: watch::Receiver<Config>,
+    guard: Option<watch::Receiver<bool>>,
+    startup: Option<StartupGate>,
+    reconnect: Option<watch::Receiver<()>>,
+    interval_override: Option<watch::Receiver<Option<IntervalOverride>>>,
+    events: Option<broadcast::Sender<MonitorEvent>>,
+    trusted_self_signed: Vec<TrustedSelfSigned>,
+    compression: CompressionConfig,
+    shared: Option<SharedCollector>,
+    sample_stats: Arc<SampleStats>,
+}
+
+/// A metrics interval used instead of the configured or server-pushed one
+/// until `until`, e.g. set over the control socket for live debugging.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntervalOverride {
+    pub interval: Duration,
+    pub until: Instant,
+}
+
+/// Holds back the first connection attempt after startup, so a booting host
+/// isn't hit with failed connects before its network is up.
+#[derive(Debug, Clone, Copy)]
+pub struct StartupGate {
+    /// No connection is attempted before this instant
+    pub not_before: Instant,
+    /// When set, also wait for a default route (Linux only), giving up at
+    /// this instant
+    pub network_deadline: Option<Instant>,
+}
+
+impl StartupGate {
+    pub async fn wait(&self) {
+        sleep_until(self.not_before).await;
+
+        let Some(deadline) = self.network_deadline else {
+            return;
+        };
+        if !cfg!(target_os = "linux") || Instant::now() >= deadline {
+            return;
+        }
+        while gateway::default_gateway().is_none() {
+            if Instant::now() >= deadline {
+                warn!("No default route yet, connecting anyway");
+                return;
+            }
+            sleep(NETWORK_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Metric frames produced while an endpoint is disconnected, oldest first,
+/// to be sent once it's connected again. Drops the oldest frame when full.
+/// With the array format, each frame keeps the `metrics_schema` frame it was
+/// encoded with, which is sent ahead of it however many frames were dropped.
+#[derive(Clone)]
+struct OfflineBuffer {
+    capacity: usize,
+    frames: Arc<Mutex<BufferedFrames>>,
+}
+
+/// An encoded `metrics_schema` message, shared by the frames encoded with it.
+type SchemaFrame = Arc<Vec<u8>>;
+
+#[derive(Default)]
+struct BufferedFrames {
+    frames: VecDeque<(Option<SchemaFrame>, Vec<u8>)>,
+    /// The last schema pinned, for the frames pushed after it
+    schema: Option<SchemaFrame>,
+}
+
+impl OfflineBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: Arc::new(Mutex::new(BufferedFrames::default())),
+        }
+    }
+
+    /// Adds `frame` and returns whether an older one had to make room.
+    fn push(&self, frame: Vec<u8>) -> bool {
+        let mut buffered = self.frames.lock().unwrap();
+        let schema = buffered.schema.clone();
+        buffered.frames.push_back((schema, frame));
+        if buffered.frames.len() > self.capacity {
+            buffered.frames.pop_front();
+            return true;
+        }
+        false
+    }
+
+    /// Makes `schema` the one the next frames are sent after. Doesn't take
+    /// room of its own.
+    fn pin_schema(&self, schema: Vec<u8>) {
+        self.frames.lock().unwrap().schema = Some(Arc::new(schema));
+    }
+
+    /// Empties the buffer, returning the frames to send in `order`, each
+    /// preceded by its schema unless the previous one already was.
+    fn take(&self, order: FlushOrder) -> Vec<Vec<u8>> {
+        let frames = std::mem::take(&mut *self.frames.lock().unwrap()).frames;
+        let frames: Vec<_> = match order {
+            FlushOrder::OldestFirst => frames.into(),
+            FlushOrder::NewestFirst => frames.into_iter().rev().collect(),
+            FlushOrder::LatestOnly => frames.into_iter().last().into_iter().collect(),
+        };
+        let mut sent_schema: Option<SchemaFrame> = None;
+        let mut ordered = Vec::with_capacity(frames.len());
+        for (schema, frame) in frames {
+            if let Some(schema) = schema {
+                if !sent_schema
+                    .as_ref()
+                    .is_some_and(|sent| Arc::ptr_eq(sent, &schema))
+                {
+                    ordered.push(schema.to_vec());
+                    sent_schema = Some(schema);
+                }
+            }
+            ordered.push(frame);
+        }
+        ordered
+    }
 }
 
 enum WriteMessage {
     Data(Vec<u8>),
+    Ping,
     Pong(Bytes),
     Close,
 }
 
 impl Monitor {
-    pub fn new(endpoint: Endpoint) -> Self {
-        let (config_tx, config_rx) = watch::channel(Config::new());
+    pub fn new(endpoint: Endpoint, collectors: Vec<Collector>) -> Self {
+        let mut config = Config::new(collectors);
+        if let Some(secs) = endpoint.metrics_interval {
+            config.metrics_interval = Duration::from_secs(secs);
+        }
+        config.format = endpoint.format;
+        let (config_tx, config_rx) = watch::channel(config);
         Self {
             endpoint,
             config_tx,
             config_rx,
+            guard: None,
+            startup: None,
+            reconnect: None,
+            interval_override: None,
+            events: None,
+            trusted_self_signed: Vec::new(),
+            compression: CompressionConfig::default(),
+            shared: None,
+            sample_stats: Arc::default(),
         }
     }
 
+    /// A handle to update the monitor once it runs.
+    pub fn handle(&self) -> MonitorHandle {
+        MonitorHandle {
+            config_tx: self.config_tx.clone(),
+            sample_stats: self.sample_stats.clone(),
+        }
+    }
+
+    /// Sends the samples of `shared` instead of collecting its own, so
+    /// endpoints sharing a host run the collectors once. Each sample is cut
+    /// down to the endpoint's collectors, `top_processes` and detail level,
+    /// and passed on at the endpoint's interval; the shared collector has to
+    /// run at least as often.
+    ///
+    /// A monitor that can't send as fast, because its server stopped
+    /// reading, falls behind rather than holding up the collector and the
+    /// other monitors: once more samples are waiting than `shared` holds,
+    /// it skips the oldest and counts them in [`Monitor::handle`]'s
+    /// [`MonitorHandle::sample_stats`]. Skipped samples are lost to that
+    /// endpoint; they aren't kept for it like the samples of a disconnected
+    /// one.
+    pub fn with_shared_collector(mut self, shared: SharedCollector) -> Self {
+        self.sample_stats = Arc::new(SampleStats::new(&shared));
+        self.shared = Some(shared);
+        self
+    }
+
+    /// Publishes samples and connection changes to `events`.
+    pub fn with_events(mut self, events: broadcast::Sender<MonitorEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Accepts self-signed certificates from the hosts in
+    /// `trusted_self_signed`, see [`TrustedSelfSigned`].
+    pub fn with_trusted_self_signed(mut self, trusted_self_signed: Vec<TrustedSelfSigned>) -> Self {
+        self.trusted_self_signed = trusted_self_signed;
+        self
+    }
+
+    /// Compresses frames at the level of `compression` once the server
+    /// accepts the algorithm the endpoint's connection offers.
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Drops the current connection and reconnects, resolving the server
+    /// address again, whenever `reconnect` is signalled.
+    pub fn with_reconnect_signal(mut self, reconnect: watch::Receiver<()>) -> Self {
+        self.reconnect = Some(reconnect);
+        self
+    }
+
+    /// Waits for `startup` before the first connection attempt.
+    pub fn with_startup_gate(mut self, startup: StartupGate) -> Self {
+        self.startup = Some(startup);
+        self
+    }
+
+    /// Suppresses connecting and reporting while `guard` is false, see
+    /// [`crate::guard`].
+    pub fn with_guard(mut self, guard: watch::Receiver<bool>) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Renames reported fields before they are sent, see
+    /// [`api::remap_fields`].
+    pub fn with_field_map(self, field_map: BTreeMap<String, String>) -> Self {
+        self.config_tx
+            .send_modify(|config| config.field_map = field_map);
+        self
+    }
+
+    /// Collects and shapes samples as `report` says, see
+    /// [`Metrics::configure`]. The collectors stay the ones the monitor was
+    /// created with.
+    pub fn with_report(self, report: ReportConfig) -> Self {
+        self.config_tx.send_modify(|config| config.report = report);
+        self
+    }
+
+    /// Reports `fingerprint` in the VM info, see
+    /// [`crate::config::AppConfig::fingerprint`].
+    pub fn with_config_fingerprint(self, fingerprint: String) -> Self {
+        self.config_tx
+            .send_modify(|config| config.config_fingerprint = Some(fingerprint));
+        self
+    }
+
+    /// Reports `machine_id` in the VM info instead of reading it from the OS,
+    /// see [`crate::features::machine_id::resolve`].
+    pub fn with_machine_id(self, machine_id: String) -> Self {
+        self.config_tx
+            .send_modify(|config| config.machine_id = Some(machine_id));
+        self
+    }
+
+    /// Runs `gauges` with every collection, see [`Metrics::gauges`].
+    pub fn with_gauges(self, gauges: Vec<GaugeConfig>) -> Self {
+        self.config_tx.send_modify(|config| config.gauges = gauges);
+        self
+    }
+
+    /// Reports the SNMP readings published to `snmp`, see
+    /// [`Metrics::snmp_shared`].
+    pub fn with_snmp(self, snmp: watch::Receiver<SnmpPoll>) -> Self {
+        self.config_tx
+            .send_modify(|config| config.snmp = Some(snmp));
+        self
+    }
+
+    /// Sends metrics at the interval in `interval_override` while it holds
+    /// one.
+    pub fn with_interval_override(
+        mut self,
+        interval_override: watch::Receiver<Option<IntervalOverride>>,
+    ) -> Self {
+        self.interval_override = Some(interval_override);
+        self
+    }
+
+    /// Reports to the endpoint over its `transport` until giving up on it.
     pub async fn run(&self) {
+        let transport = transport::for_kind(self.endpoint.transport);
+        tokio::select! {
+            _ = self.run_connections(&*transport) => {}
+            _ = self.forward_interval_override() => {}
+        }
+    }
+
+    /// Copies the current interval override into the config the send loop
+    /// watches, for as long as the monitor runs.
+    async fn forward_interval_override(&self) {
+        let Some(mut overrides) = self.interval_override.clone() else {
+            return std::future::pending().await;
+        };
+        loop {
+            let interval = overrides.borrow_and_update().map(|o| o.interval);
+            self.config_tx.send_if_modified(|config| {
+                let changed = config.interval_override != interval;
+                config.interval_override = interval;
+                changed
+            });
+            if overrides.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        }
+    }
+
+    async fn run_connections(&self, transport: &dyn Transport) {
         let mut retry_count = 0;
+        // Connections in a row the server accepted but never became ready on
+        let mut unready_count = 0;
+        let mut reconnect = self.reconnect.clone();
+        let offline_buffer = OfflineBuffer::new(self.endpoint.offline_buffer_size);
+        // Collects into `offline_buffer` and for the event subscribers while
+        // no connection is up; dropping the set stops it with the monitor
+        let mut offline_task = JoinSet::new();
+        let collect_offline = self.endpoint.offline_buffer_size > 0 || self.events.is_some();
+
+        if let Some(startup) = &self.startup {
+            startup.wait().await;
+        }
+        if collect_offline {
+            offline_task.spawn(self.collect_offline(offline_buffer.clone()));
+        }
 
         loop {
             let endpoint = self.endpoint.clone();
-            let strategy = endpoint.connection.unwrap();
+            let strategy = endpoint.connection.unwrap_or_default();
 
-            let socket = match api::connect_websocket(
-                endpoint.server.as_str(),
-                endpoint.secret.as_str(),
-                &strategy,
-            )
-            .await
+            if let Some(mut guard) = self.guard.clone() {
+                if !*guard.borrow() {
+                    info!(endpoint = %endpoint.name, "Not connecting, reporting is gated by the network guard");
+                }
+                if guard.wait_for(|open| *open).await.is_err() {
+                    return;
+                }
+            }
+
+            let secret = match endpoint.resolve_secret() {
+                Ok(secret) => secret,
+                Err(e) => {
+                    error!(endpoint = %endpoint.name, error = %e, "Failed to read secret file");
+                    sleep(Duration::from_secs(strategy.max_delay)).await;
+                    continue;
+                }
+            };
+
+            if let Err(e) = api::build_transport_request(
+                endpoint.transport,
+                &endpoint.server,
+                &endpoint.path,
+                &secret,
+                strategy.auth_mode,
+                &endpoint.headers,
+            ) {
+                error!(endpoint = %endpoint.name, error = %e, "Invalid server URL for endpoint, skipping it");
+                return;
+            }
+
+            // A signal raised while disconnected is satisfied by this connect
+            if let Some(reconnect) = &mut reconnect {
+                reconnect.borrow_and_update();
+            }
+            let Connection {
+                sink: mut write,
+                stream: mut read,
+                compression: algorithm,
+            } = match transport
+                .connect(&endpoint, &secret, &strategy, &self.trusted_self_signed)
+                .await
             {
-                Some(socket) => socket,
-                None => {
+                Ok(connected) => connected,
+                Err(_) => {
                     return;
                 }
             };
-            let (mut write, mut read) = socket.split();
             let (tx, mut rx) = mpsc::channel::<WriteMessage>(100);
 
-            let write_task = tokio::spawn(async move {
-                while let Some(msg) = rx.recv().await {
-                    match msg {
-                        WriteMessage::Data(data) => {
-                            if let Err(e) = write.send(Message::Binary(Bytes::from(data))).await {
-                                eprintln!("Write error: {}", e);
-                                break;
-                            }
-                        }
-                        WriteMessage::Pong(data) => {
-                            if let Err(e) = write.send(Message::Pong(Bytes::from(data))).await {
-                                eprintln!("Write error: {}", e);
-                                break;
-                            }
-                        }
-                        WriteMessage::Close => {
-                            if let Err(e) = write.send(Message::Close(None)).await {
-                                eprintln!("Write error: {}", e);
-                            }
-                            break;
-                        }
-                    }
-                }
+            let coalesce = Duration::from_millis(endpoint.send_coalesce_ms);
+            let compression = CompressionConfig {
+                algorithm,
+                ..self.compression
+            };
+            let encoding = endpoint.encoding;
+            let mut write_task = tokio::spawn(async move {
+                Monitor::write_frames(&mut write, &mut rx, coalesce, compression, encoding).await;
             });
+            if endpoint.ready_handshake && !Monitor::wait_ready(&endpoint, &mut read, &tx).await {
+                let _ = tx.send(WriteMessage::Close).await;
+                let _ = write_task.await;
+                // Counts against `max_retries` like a failed connect, which
+                // it is as far as sending metrics goes
+                unready_count += 1;
+                if strategy.max_retries >= 0 && unready_count > strategy.max_retries {
+                    error!(endpoint = %endpoint.name, attempts = unready_count, "Server never signalled readiness, giving up");
+                    return;
+                }
+                retry_count += 1;
+                let delay = strategy.backoff_delay(retry_count);
+                warn!(endpoint = %endpoint.name, next_attempt_in = delay, "Server did not signal readiness, reconnecting");
+                sleep(Duration::from_secs(delay)).await;
+                continue;
+            }
+            unready_count = 0;
+
+            emit(
+                &self.events,
+                MonitorEvent::Connected {
+                    endpoint: endpoint.name.clone(),
+                },
+            );
+            let connected_at = Instant::now();
+
+            offline_task.shutdown().await;
+            let buffered = offline_buffer.take(endpoint.flush_order);
+            if !buffered.is_empty() {
+                info!(endpoint = %endpoint.name, count = buffered.len(), "Sending metrics collected while disconnected");
+            }
+            for frame in buffered {
+                if tx.send(WriteMessage::Data(frame)).await.is_err() {
+                    break;
+                }
+            }
+
             let send_metrics_tx = tx.clone();
             let metrics_config_rx = self.config_rx.clone();
-            let send_metrics_task = tokio::spawn(async move {
-                Monitor::send_metrics(send_metrics_tx, metrics_config_rx).await;
+            let guard = self.guard.clone();
+            let metrics_endpoint = endpoint.clone();
+            let endpoint_lifetime = (endpoint.max_connection_lifetime_secs > 0)
+                .then(|| Duration::from_secs(endpoint.max_connection_lifetime_secs));
+            let events = self.events.clone();
+            let shared = self.shared.clone();
+            let sample_stats = self.sample_stats.clone();
+            let mut send_metrics_task = tokio::spawn(async move {
+                Monitor::send_metrics(
+                    send_metrics_tx,
+                    metrics_config_rx,
+                    guard,
+                    &metrics_endpoint,
+                    events,
+                    shared,
+                    sample_stats,
+                )
+                .await;
+            });
+            let (last_frame_tx, last_frame_rx) = watch::channel(Instant::now());
+            let liveness_tx = tx.clone();
+            let liveness_endpoint = endpoint.name.clone();
+            // Nothing ever comes back to show a one-way connection is alive
+            let duplex = endpoint.transport.duplex();
+            let mut liveness_task = tokio::spawn(async move {
+                if !duplex {
+                    return std::future::pending().await;
+                }
+                Monitor::check_liveness(&liveness_endpoint, liveness_tx, last_frame_rx, &strategy)
+                    .await
             });
             let command_handle_tx = tx.clone();
             let config_tx = self.config_tx.clone();
-            let command_handle_task = tokio::spawn(async move {
-                Monitor::handle_command(&endpoint, &mut read, command_handle_tx, config_tx).await
+            let endpoint_name = endpoint.name.clone();
+            let mut command_handle_task = tokio::spawn(async move {
+                Monitor::handle_command(
+                    &endpoint,
+                    &mut read,
+                    command_handle_tx,
+                    config_tx,
+                    last_frame_tx,
+                )
+                .await
             });
 
-            let _ = tokio::try_join!(write_task, send_metrics_task, command_handle_task);
+            let abort_handles = [
+                write_task.abort_handle(),
+                send_metrics_task.abort_handle(),
+                command_handle_task.abort_handle(),
+                liveness_task.abort_handle(),
+            ];
+            let reconnecting = tokio::select! {
+                // The connection is over once any side of it stops, e.g. the
+                // server closed it and the reader ended
+                result = async {
+                    tokio::select! {
+                        result = &mut write_task => result,
+                        result = &mut send_metrics_task => result,
+                        result = &mut command_handle_task => result,
+                        result = &mut liveness_task => result,
+                    }
+                } => {
+                    // Let a bug in a collector reach the supervisor instead
+                    // of passing for a dropped connection
+                    if let Err(e) = result {
+                        if e.is_panic() {
+                            for handle in &abort_handles {
+                                handle.abort();
+                            }
+                            std::panic::resume_unwind(e.into_panic());
+                        }
+                    }
+                    false
+                }
+                Ok(()) = async {
+                    match &mut reconnect {
+                        Some(reconnect) => reconnect.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    info!(endpoint = %endpoint_name, "Reconnecting to re-resolve the server address");
+                    true
+                }
+                _ = async {
+                    match endpoint_lifetime {
+                        Some(lifetime) => sleep(lifetime).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    info!(endpoint = %endpoint_name, "Connection reached its maximum lifetime, reconnecting");
+                    true
+                }
+            };
+            if reconnecting {
+                // Close the socket cleanly so the server sees a normal goodbye
+                let _ = tx.try_send(WriteMessage::Close);
+                let _ = timeout(Duration::from_secs(1), &mut write_task).await;
+            }
+            for handle in abort_handles {
+                handle.abort();
+            }
+            emit(
+                &self.events,
+                MonitorEvent::Disconnected {
+                    endpoint: endpoint_name,
+                },
+            );
+            if collect_offline && offline_task.is_empty() {
+                offline_task.spawn(self.collect_offline(offline_buffer.clone()));
+            }
+            if reconnecting {
+                continue;
+            }
 
-            retry_count += 1;
-            if strategy.max_retries >= 0 && retry_count > strategy.max_retries {
-                let delay = strategy.base_delay * 2u64.pow(retry_count.min(16) as u32 - 1);
-                let delay = delay.min(strategy.max_delay);
-
-                debug!(
-                    "Operation failed (attempt {}), retrying in {} seconds",
-                    retry_count, delay
-                );
-                sleep(Duration::from_secs(delay)).await;
+            // A connection that held up for a while starts the backoff over;
+            // ones dropping right away keep escalating it
+            if connected_at.elapsed() >= Duration::from_secs(strategy.max_delay) {
+                retry_count = 0;
             }
+            // Always back off, also with unlimited retries; giving up is left
+            // to `try_connect_websocket` and its `max_retries`
+            retry_count += 1;
+            let delay = strategy.backoff_delay(retry_count);
+            debug!(
+                "Connection dropped (attempt {}), reconnecting in {} seconds",
+                retry_count, delay
+            );
+            sleep(Duration::from_secs(delay)).await;
         }
     }
 
-    async fn send_metrics(tx: mpsc::Sender<WriteMessage>, mut config_rx: watch::Receiver<Config>) {
-        let mut metrics_interval = interval(config_rx.borrow().metrics_interval);
-        let mut metrics = Metrics::new();
+    /// Keeps collecting metrics as if connected, emitting the samples and
+    /// storing the frames in `buffer` instead of sending them.
+    fn collect_offline(&self, buffer: OfflineBuffer) -> impl Future<Output = ()> + Send + 'static {
+        let (tx, mut rx) = mpsc::channel::<WriteMessage>(100);
+        let config_rx = self.config_rx.clone();
+        let guard = self.guard.clone();
+        let endpoint = self.endpoint.clone();
+        let events = self.events.clone();
+        let shared = self.shared.clone();
+        let sample_stats = self.sample_stats.clone();
+        async move {
+            let collect = Monitor::send_metrics(
+                tx,
+                config_rx,
+                guard,
+                &endpoint,
+                events,
+                shared,
+                sample_stats,
+            );
+            let store = async {
+                while let Some(message) = rx.recv().await {
+                    let WriteMessage::Data(frame) = message else {
+                        continue;
+                    };
+                    // Without a buffer, collecting only feeds the events
+                    if buffer.capacity == 0 {
+                        continue;
+                    }
+                    if is_schema(&frame, endpoint.encoding) {
+                        buffer.pin_schema(frame);
+                    } else if buffer.push(frame) {
+                        debug!(endpoint = %endpoint.name, "Offline buffer is full, dropped the oldest metrics");
+                    }
+                }
+            };
+            tokio::join!(collect, store);
+        }
+    }
 
+    /// Pings the server every `ping_interval` and returns, ending the
+    /// connection, when no frame at all arrives within `pong_timeout` of a
+    /// ping. A half-open connection otherwise goes unnoticed, since reading
+    /// just waits forever. Never returns with a zero `ping_interval`.
+    async fn check_liveness(
+        endpoint: &str,
+        tx: mpsc::Sender<WriteMessage>,
+        mut last_frame: watch::Receiver<Instant>,
+        connection: &ConnectionConfig,
+    ) {
+        if connection.ping_interval == 0 {
+            return std::future::pending().await;
+        }
+        let ping_interval = Duration::from_secs(connection.ping_interval);
+        let pong_timeout = Duration::from_secs(connection.pong_timeout);
         loop {
-            tokio::select! {
-                result = config_rx.changed() => {
-                    if result.is_ok() {
-                        metrics_interval = interval(config_rx.borrow().metrics_interval);
-                        debug!("Metrics interval updated to {:?}", config_rx.borrow().metrics_interval);
-                    }
+            sleep(ping_interval).await;
+            let sent = Instant::now();
+            // A writer stuck on a full queue counts against the timeout too
+            let answered = async {
+                tx.send(WriteMessage::Ping).await.ok()?;
+                last_frame.wait_for(|seen| *seen >= sent).await.ok()
+            };
+            match timeout(pong_timeout, answered).await {
+                Ok(Some(_)) => {}
+                // The writer or reader is gone, so the connection is over anyway
+                Ok(None) => return,
+                Err(_) => {
+                    warn!(endpoint = %endpoint, timeout = ?pong_timeout, "Server did not answer a ping, reconnecting");
+                    return;
                 }
-                _ = metrics_interval.tick() => {
-                    let data = metrics.collet_metrics().await;
-                    let msg = api::Message {
-                        r#type: "metrics".to_string(),
-                        data,
-                    };
-                    match rmp_serde::to_vec_named(&msg) {
-                        Ok(binary_data) => {
-                            if let Err(e) = tx.send(WriteMessage::Data(binary_data)).await {
-                                warn!(error = %e, "Failed to report system data");
+            }
+        }
+    }
+
+    /// Forwards queued messages to the socket. With a nonzero `coalesce`
+    /// window, data messages arriving within it of the first one are sent as
+    /// a single `batch` message; pings, pongs and close bypass the window.
+    /// Data is compressed with `compression`, as the server accepted, and
+    /// sent as text when `encoding` is JSON.
+    async fn write_frames(
+        write: &mut FrameSink,
+        rx: &mut mpsc::Receiver<WriteMessage>,
+        coalesce: Duration,
+        compression: CompressionConfig,
+        encoding: Encoding,
+    ) {
+        let binary = |data| transport::data_message(data, encoding, compression);
+        while let Some(msg) = rx.recv().await {
+            let result = match msg {
+                WriteMessage::Data(data) if coalesce.is_zero() => write.send(binary(data)).await,
+                WriteMessage::Data(data) => {
+                    let mut batch = vec![data];
+                    let mut close = false;
+                    let deadline = Instant::now() + coalesce;
+                    while let Ok(Some(next)) = timeout_at(deadline, rx.recv()).await {
+                        match next {
+                            WriteMessage::Data(data) => batch.push(data),
+                            WriteMessage::Ping => {
+                                if let Err(e) = write.send(Message::Ping(Bytes::new())).await {
+                                    eprintln!("Write error: {}", e);
+                                    return;
+                                }
+                            }
+                            WriteMessage::Pong(ping) => {
+                                if let Err(e) = write.send(Message::Pong(ping)).await {
+                                    eprintln!("Write error: {}", e);
+                                    return;
+                                }
+                            }
+                            WriteMessage::Close => {
+                                close = true;
                                 break;
                             }
                         }
-                        Err(e) => {
-                            warn!(error = %e, "Failed to serialize system data");
+                    }
+                    let result = write.send(binary(encode_batch(batch))).await;
+                    if close && result.is_ok() {
+                        if let Err(e) = write.send(Message::Close(None)).await {
+                            eprintln!("Write error: {}", e);
                         }
+                        return;
                     }
+                    result
+                }
+                WriteMessage::Ping => write.send(Message::Ping(Bytes::new())).await,
+                WriteMessage::Pong(ping) => write.send(Message::Pong(ping)).await,
+                WriteMessage::Close => {
+                    if let Err(e) = write.send(Message::Close(None)).await {
+                        eprintln!("Write error: {}", e);
+                    }
+                    return;
                 }
+            };
+            if let Err(e) = result {
+                eprintln!("Write error: {}", e);
+                return;
             }
         }
     }
 
-    async fn handle_command(
-        endpoint: &Endpoint,
-        read: &mut SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    /// Sends a metrics message every interval, collected here or taken from
+    /// the `shared` collector.
+    async fn send_metrics(
         tx: mpsc::Sender<WriteMessage>,
-        config_tx: watch::Sender<Config>,
+        mut config_rx: watch::Receiver<Config>,
+        guard: Option<watch::Receiver<bool>>,
+        endpoint: &Endpoint,
+        events: Option<broadcast::Sender<MonitorEvent>>,
+        shared: Option<SharedCollector>,
+        sample_stats: Arc<SampleStats>,
     ) {
-        let mut metrics = Metrics::new();
+        let mut samples = shared
+            .as_ref()
+            .map(|shared| shared.subscribe(&sample_stats));
+        // The first sample is sent right away, as when collecting here
+        if let Some(shared) = &shared {
+            shared.wake.notify_one();
+        }
+        let mut metrics_interval = interval(config_rx.borrow().effective_interval());
+        let mut adaptive = AdaptiveInterval::new(config_rx.borrow().effective_interval());
+        let mut metrics = samples.is_none().then(|| {
+            let config = config_rx.borrow();
+            let mut metrics = Metrics::with_collectors(config.collectors.clone());
+            metrics.configure(&config.report);
+            metrics.snmp_shared = config.snmp.clone();
+            metrics.gauges = config.gauges.clone();
+            metrics.top_processes = endpoint.top_processes;
+            metrics
+        });
+        let mut pacer = Pacer::default();
+        let mut clock = SampleClock::new(config_rx.borrow().report.timestamp_source);
+        let mut encoder = FrameEncoder::new(endpoint, config_rx.borrow().format);
 
         loop {
-            let msg = read.next().await;
-            let Some(msg) = msg else {
-                break;
-            };
-            let command = match msg {
-                Ok(Message::Text(text)) => {
-                    debug!(endpoint = %endpoint.name, message = %text, "Received WebSocket message");
-                    match serde_json::from_str::<api::Message<serde_json::Value>>(&text) {
-                        Ok(value) => {
-                            debug!(endpoint = %endpoint.name, json = ?value, "Parsed WebSocket message");
-                            Some(value)
+            let data = tokio::select! {
+                result = config_rx.changed() => {
+                    if result.is_ok() {
+                        let config = config_rx.borrow();
+                        metrics_interval = interval(config.effective_interval());
+                        adaptive = AdaptiveInterval::new(config.effective_interval());
+                        if let Some(metrics) = &mut metrics {
+                            metrics.collectors = config.collectors.clone();
+                            metrics.configure(&config.report);
+                            metrics.snmp_shared = config.snmp.clone();
+                            metrics.gauges = config.gauges.clone();
                         }
-                        Err(e) => {
-                            warn!(endpoint = %endpoint.name, error = %e, "Failed to parse WebSocket message as JSON");
-                            None
+                        clock = SampleClock::new(config.report.timestamp_source);
+                        encoder.set_format(config.format);
+                        debug!("Metrics interval updated to {:?}", config.effective_interval());
+                    }
+                    continue;
+                }
+                _ = metrics_interval.tick(), if metrics.is_some() => {
+                    let Some(metrics) = &mut metrics else {
+                        continue;
+                    };
+                    if guard.as_ref().is_some_and(|guard| !*guard.borrow()) {
+                        debug!("Skipping metrics, reporting is gated by the network guard");
+                        continue;
+                    }
+                    let started = Instant::now();
+                    let mut data = metrics.collet_metrics().await;
+                    clock.stamp(&mut data, now_millis(), mono_nanos());
+                    if config_rx.borrow().report.adaptive_interval {
+                        if let Some(effective) = adaptive.record(started.elapsed()) {
+                            metrics_interval = interval_at(Instant::now() + effective, effective);
                         }
                     }
+                    data
                 }
-                Ok(Message::Binary(binary)) => {
-                    debug!(endpoint = %endpoint.name, binary = ?binary, "Received binary message");
-                    match rmp_serde::from_slice::<api::Message<serde_json::Value>>(&binary) {
-                        Ok(api_msg) => Some(api_msg),
-                        Err(e) => {
-                            warn!(endpoint = %endpoint.name, error = %e, "Failed to parse as api::Message");
-                            None
+                sample = async { samples.as_mut().unwrap().recv().await }, if samples.is_some() => {
+                    let sample = match sample {
+                        Ok(sample) => {
+                            sample_stats.position.fetch_add(1, Ordering::Relaxed);
+                            sample
                         }
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            sample_stats.position.fetch_add(missed, Ordering::Relaxed);
+                            sample_stats.dropped.fetch_add(missed, Ordering::Relaxed);
+                            warn!(endpoint = %endpoint.name, missed, "Fell behind the shared collector, skipped the oldest samples");
+                            continue;
+                        }
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    };
+                    if guard.as_ref().is_some_and(|guard| !*guard.borrow()) {
+                        debug!("Skipping metrics, reporting is gated by the network guard");
+                        continue;
+                    }
+                    let config = config_rx.borrow();
+                    if !pacer.due(sample.mono_ns, config.effective_interval()) {
+                        continue;
                     }
+                    let mut data = sample.report.clone().only(&config.collectors);
+                    drop(config);
+                    match &mut data.top_processes {
+                        Some(_) if endpoint.top_processes == 0 => data.top_processes = None,
+                        Some(processes) => processes.truncate(endpoint.top_processes),
+                        None => {}
+                    }
+                    clock.stamp(&mut data, sample.collected_at, sample.mono_ns);
+                    data
                 }
-                Ok(Message::Ping(ping)) => {
-                    if let Err(e) = tx.send(WriteMessage::Pong(ping)).await {
-                        error!(endpoint = %endpoint.name, error = %e, "Failed to send pong response");
+            };
+            emit(
+                &events,
+                MonitorEvent::Sample {
+                    endpoint: endpoint.name.clone(),
+                    report: Box::new(data.clone()),
+                },
+            );
+            let encoded = {
+                let config = config_rx.borrow();
+                encoder.encode(data, &config.collectors, &config.field_map)
+            };
+            match encoded {
+                Ok(frames) => {
+                    for binary_data in frames {
+                        if let Err(e) = tx.send(WriteMessage::Data(binary_data)).await {
+                            warn!(error = %e, "Failed to report system data");
+                            return;
+                        }
                     }
-                    None
                 }
-                _ => None,
+                Err(e) => {
+                    warn!(error = %e, "Failed to serialize system data");
+                }
+            }
+        }
+    }
+
+    async fn handle_command(
+        endpoint: &Endpoint,
+        read: &mut FrameStream,
+        tx: mpsc::Sender<WriteMessage>,
+        config_tx: watch::Sender<Config>,
+        last_frame: watch::Sender<Instant>,
+    ) {
+        let mut metrics = Metrics::new();
+        metrics.config_fingerprint = config_tx.borrow().config_fingerprint.clone();
+        metrics.machine_id = config_tx.borrow().machine_id.clone();
+        let mut limiter = CommandLimiter::new(endpoint.max_commands_per_sec);
+
+        // Servers that don't understand `vm_info_hash` never answer it, so the
+        // full VM info is sent once the deadline passes
+        let mut negotiation_deadline = None;
+        if endpoint.vm_info_dedup {
+            let hash = metrics.collect_vm_info().content_hash();
+            let request = api::Message {
+                r#type: "vm_info_hash".to_string(),
+                data: api::VMInfoHash { hash },
+            };
+            if let Ok(frame) = api::encode(&request, endpoint.encoding) {
+                if let Err(e) = tx.send(WriteMessage::Data(frame)).await {
+                    warn!(endpoint = %endpoint.name, error = %e, "Failed to send VM info hash");
+                }
+                negotiation_deadline = Some(Instant::now() + VM_INFO_NEGOTIATION_TIMEOUT);
+            }
+        }
+        // The server can't ask for it over a one-way transport
+        if !endpoint.transport.duplex() {
+            Monitor::send_vm_info(endpoint, &mut metrics, &tx, &config_tx).await;
+        }
+
+        loop {
+            let msg = match negotiation_deadline {
+                Some(deadline) => match timeout_at(deadline, read.next()).await {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        info!(endpoint = %endpoint.name, "Server did not answer VM info hash, sending full VM info");
+                        negotiation_deadline = None;
+                        Monitor::send_vm_info(endpoint, &mut metrics, &tx, &config_tx).await;
+                        continue;
+                    }
+                },
+                None => read.next().await,
             };
+            let Some(msg) = msg else {
+                break;
+            };
+            // Any frame, not just a pong, shows the connection is alive
+            last_frame.send_replace(Instant::now());
+            let command = Monitor::parse_message(endpoint, msg, &tx)
+                .await
+                .filter(|command| limiter.allow(endpoint, &command.r#type, Instant::now()));
 
-            match command {
-                Some(value) => match value.r#type.as_str() {
+            if let Some(value) = command {
+                match value.r#type.as_str() {
                     "get_info" => {
-                        let vm_info = metrics.collect_vm_info();
-                        info!(endpoint = %endpoint.name, vm_info = ?vm_info, "Sending VM info response");
-                        let response = api::Message {
-                            r#type: "vm_info".to_string(),
-                            data: vm_info,
-                        };
-                        if let Ok(msgpack) = rmp_serde::to_vec_named(&response) {
-                            if let Err(e) = tx.send(WriteMessage::Data(msgpack)).await {
-                                warn!(endpoint = %endpoint.name, error = %e, "Failed to send VM info response");
-                            }
-                            info!(endpoint = %endpoint.name, "Sent VM info response");
-                        }
+                        Monitor::send_vm_info(endpoint, &mut metrics, &tx, &config_tx).await;
+                    }
+                    "vm_info_known" => {
+                        debug!(endpoint = %endpoint.name, "Server already has this VM info");
+                        negotiation_deadline = None;
+                    }
+                    "vm_info_unknown" => {
+                        negotiation_deadline = None;
+                        Monitor::send_vm_info(endpoint, &mut metrics, &tx, &config_tx).await;
                     }
                     "update_config" => {
                         if let Ok(probe_config) =
                             serde_json::from_value::<api::ProbeConfig>(value.data)
                         {
-                            info!(endpoint = %endpoint.name, config = ?probe_config, "Received server configuration");
-                            let new_config = Config {
-                                metrics_interval: Duration::from_secs(
-                                    probe_config.metrics_interval,
-                                ),
-                            };
-                            if let Err(e) = new_config.validate() {
-                                warn!(error = %e, "Invalid configuration received");
-                                continue;
+                            Monitor::apply_server_config(endpoint, probe_config, &config_tx);
+                        }
+                    }
+                    "reconfigure" => {
+                        if !endpoint.allow_remote_reconfigure {
+                            warn!(endpoint = %endpoint.name, "Ignoring reconfigure, the endpoint doesn't allow remote reconfiguration");
+                            continue;
+                        }
+                        match serde_json::from_value::<api::Reconfigure>(value.data) {
+                            Ok(reconfigure) => {
+                                Monitor::apply_reconfigure(endpoint, reconfigure, &config_tx)
                             }
-                            if let Err(e) = config_tx.send(new_config) {
-                                warn!(error = %e, "Failed to update configuration");
-                            } else {
-                                info!("Configuration updated successfully");
+                            Err(e) => {
+                                warn!(endpoint = %endpoint.name, error = %e, "Invalid reconfigure received")
                             }
                         }
                     }
                     _ => {
                         info!(endpoint = %endpoint.name, message = ?value, "Received unknown message type")
                     }
-                },
+                }
+            }
+        }
+    }
+
+    /// Decodes a WebSocket frame into an application message, answering
+    /// WebSocket pings along the way.
+    async fn parse_message(
+        endpoint: &Endpoint,
+        msg: Result<Message, tokio_tungstenite::tungstenite::Error>,
+        tx: &mpsc::Sender<WriteMessage>,
+    ) -> Option<api::Message<serde_json::Value>> {
+        match msg {
+            Ok(Message::Text(text)) => {
+                debug!(endpoint = %endpoint.name, message = %text, "Received WebSocket message");
+                match serde_json::from_str::<api::Message<serde_json::Value>>(&text) {
+                    Ok(value) => {
+                        debug!(endpoint = %endpoint.name, json = ?value, "Parsed WebSocket message");
+                        Some(value)
+                    }
+                    Err(e) => {
+                        warn!(endpoint = %endpoint.name, error = %e, "Failed to parse WebSocket message as JSON");
+                        None
+                    }
+                }
+            }
+            Ok(Message::Binary(binary)) => {
+                debug!(endpoint = %endpoint.name, binary = ?binary, "Received binary message");
+                match api::decode::<api::Message<serde_json::Value>>(&binary, endpoint.encoding) {
+                    Ok(api_msg) => Some(api_msg),
+                    Err(e) => {
+                        warn!(endpoint = %endpoint.name, error = %e, "Failed to parse as api::Message");
+                        None
+                    }
+                }
+            }
+            Ok(Message::Ping(ping)) => {
+                if let Err(e) = tx.send(WriteMessage::Pong(ping)).await {
+                    error!(endpoint = %endpoint.name, error = %e, "Failed to send pong response");
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Sends an application-level `ping` and waits for the server to answer
+    /// with `pong` or `ready`. Returns false if the server closes the
+    /// connection or doesn't answer within [`READY_TIMEOUT`].
+    async fn wait_ready(
+        endpoint: &Endpoint,
+        read: &mut FrameStream,
+        tx: &mpsc::Sender<WriteMessage>,
+    ) -> bool {
+        let ping = api::Message {
+            r#type: "ping".to_string(),
+            data: (),
+        };
+        let Ok(frame) = api::encode(&ping, endpoint.encoding) else {
+            return false;
+        };
+        if tx.send(WriteMessage::Data(frame)).await.is_err() {
+            return false;
+        }
+
+        let deadline = Instant::now() + READY_TIMEOUT;
+        loop {
+            let Ok(Some(msg)) = timeout_at(deadline, read.next()).await else {
+                return false;
+            };
+            match Monitor::parse_message(endpoint, msg, tx).await {
+                Some(value) if value.r#type == "pong" || value.r#type == "ready" => {
+                    debug!(endpoint = %endpoint.name, "Server is ready for metrics");
+                    return true;
+                }
+                Some(value) => {
+                    debug!(endpoint = %endpoint.name, message = ?value, "Ignoring message received before readiness")
+                }
                 None => {}
             }
         }
     }
+
+    async fn send_vm_info(
+        endpoint: &Endpoint,
+        metrics: &mut Metrics,
+        tx: &mpsc::Sender<WriteMessage>,
+        config_tx: &watch::Sender<Config>,
+    ) {
+        // A reload may have changed it since the connection was made
+        metrics.config_fingerprint = config_tx.borrow().config_fingerprint.clone();
+        let vm_info = metrics.collect_vm_info();
+        info!(endpoint = %endpoint.name, vm_info = ?vm_info, "Sending VM info response");
+        let response = api::Message {
+            r#type: "vm_info".to_string(),
+            data: vm_info,
+        };
+        if let Ok(frame) = api::encode(&response, endpoint.encoding) {
+            if let Err(e) = tx.send(WriteMessage::Data(frame)).await {
+                warn!(endpoint = %endpoint.name, error = %e, "Failed to send VM info response");
+            }
+            info!(endpoint = %endpoint.name, "Sent VM info response");
+        }
+    }
+
+    fn apply_server_config(
+        endpoint: &Endpoint,
+        probe_config: api::ProbeConfig,
+        config_tx: &watch::Sender<Config>,
+    ) {
+        info!(endpoint = %endpoint.name, config = ?probe_config, "Received server configuration");
+        if endpoint.interval_authority == IntervalAuthority::Local {
+            info!(endpoint = %endpoint.name, "Ignoring server configuration, interval is set locally");
+            return;
+        }
+        let metrics_interval = Duration::from_secs(probe_config.metrics_interval);
+        if config_tx.borrow().metrics_interval == metrics_interval {
+            // Some servers push the same configuration on every poll
+            debug!(endpoint = %endpoint.name, "Server configuration unchanged");
+            return;
+        }
+        let new_config = Config {
+            metrics_interval,
+            ..config_tx.borrow().clone()
+        };
+        if let Err(e) = new_config.validate(endpoint) {
+            warn!(error = %e, "Invalid configuration received");
+            return;
+        }
+        if let Err(e) = config_tx.send(new_config) {
+            warn!(error = %e, "Failed to update configuration");
+        } else {
+            info!("Configuration updated successfully");
+        }
+    }
+
+    /// Applies a server's `reconfigure` to this endpoint's collection, all of
+    /// it or, if the result is invalid, none of it.
+    fn apply_reconfigure(
+        endpoint: &Endpoint,
+        reconfigure: api::Reconfigure,
+        config_tx: &watch::Sender<Config>,
+    ) {
+        info!(endpoint = %endpoint.name, reconfigure = ?reconfigure, "Received reconfigure");
+        let mut new_config = config_tx.borrow().clone();
+        if let Some(secs) = reconfigure.metrics_interval {
+            if endpoint.interval_authority == IntervalAuthority::Local {
+                info!(endpoint = %endpoint.name, "Ignoring the pushed interval, interval is set locally");
+            } else {
+                new_config.metrics_interval = Duration::from_secs(secs);
+            }
+        }
+        if let Some(collectors) = reconfigure.collectors {
+            new_config.collectors = collectors;
+        }
+        if let Some(field_map) = reconfigure.field_map {
+            new_config.field_map = field_map;
+        }
+        if let Some(format) = reconfigure.format {
+            new_config.format = format;
+        }
+        if let Err(e) = new_config.validate(endpoint) {
+            warn!(endpoint = %endpoint.name, error = %e, "Invalid reconfigure received");
+            return;
+        }
+        if let Err(e) = config_tx.send(new_config) {
+            warn!(error = %e, "Failed to update configuration");
+        } else {
+            info!(endpoint = %endpoint.name, "Reconfigured by the server");
+        }
+    }
+}
+
+/// Turns samples into the frames sent to an endpoint, as its `format`,
+/// `encoding`, `detail_level`, `split_by_collector` and
+/// `heartbeat_when_blind` say.
+/// Keeps what spans samples: the `seq` tying split messages together and
+/// the array schema.
+pub struct FrameEncoder {
+    detail_level: DetailLevel,
+    split_by_collector: bool,
+    heartbeat_when_blind: bool,
+    format: MetricsFormat,
+    encoding: Encoding,
+    seq: u64,
+    array_encoder: api::ArrayEncoder,
+}
+
+impl FrameEncoder {
+    pub fn new(endpoint: &Endpoint, format: MetricsFormat) -> Self {
+        Self {
+            detail_level: endpoint.detail_level,
+            split_by_collector: endpoint.split_by_collector,
+            heartbeat_when_blind: endpoint.heartbeat_when_blind,
+            format,
+            encoding: endpoint.encoding,
+            seq: 0,
+            array_encoder: api::ArrayEncoder::default(),
+        }
+    }
+
+    /// Switches to `format`, starting over with a fresh schema.
+    pub fn set_format(&mut self, format: MetricsFormat) {
+        if format != self.format {
+            self.format = format;
+            self.array_encoder = api::ArrayEncoder::default();
+        }
+    }
+
+    /// Encodes one sample as the messages to send, in order. `collectors`
+    /// are the sections a split sample is sent as.
+    pub fn encode(
+        &mut self,
+        data: ReportData,
+        collectors: &[Collector],
+        field_map: &BTreeMap<String, String>,
+    ) -> Result<Vec<Vec<u8>>, String> {
+        let encoding = self.encoding;
+        let message = |r#type: &str, data| {
+            api::encode(
+                &api::Message {
+                    r#type: r#type.to_string(),
+                    data,
+                },
+                encoding,
+            )
+        };
+        if self.heartbeat_when_blind && data.is_blind() {
+            debug!(errors = ?data.collection_errors, "Collection failed, sending heartbeat");
+            let heartbeat = api::Heartbeat {
+                timestamp: now_millis(),
+                uptime: data.uptime,
+                collection_errors: data.collection_errors,
+            };
+            return api::encode(
+                &api::Message {
+                    r#type: "heartbeat".to_string(),
+                    data: heartbeat,
+                },
+                encoding,
+            )
+            .map(|frame| vec![frame]);
+        }
+        let data = data.at_level(self.detail_level);
+        if self.split_by_collector {
+            self.seq += 1;
+            let data = serde_json::to_value(data).unwrap_or_default();
+            return api::split_by_collector(data, collectors, self.seq)
+                .into_iter()
+                .map(|(r#type, mut data)| {
+                    api::remap_fields(&mut data, field_map);
+                    message(&r#type, data)
+                })
+                .collect();
+        }
+        if self.format == MetricsFormat::Array {
+            let mut data = serde_json::to_value(data).unwrap_or_default();
+            api::remap_fields(&mut data, field_map);
+            let (schema, array) = self.array_encoder.encode(&data);
+            let schema = schema.map(|schema| {
+                api::encode(
+                    &api::Message {
+                        r#type: "metrics_schema".to_string(),
+                        data: schema,
+                    },
+                    encoding,
+                )
+            });
+            let array = api::encode(
+                &api::Message {
+                    r#type: "metrics_array".to_string(),
+                    data: array,
+                },
+                encoding,
+            );
+            return schema.into_iter().chain(std::iter::once(array)).collect();
+        }
+        let encoded = if field_map.is_empty() {
+            api::encode(
+                &api::Message {
+                    r#type: "metrics".to_string(),
+                    data,
+                },
+                encoding,
+            )
+        } else {
+            let mut data = serde_json::to_value(&data).unwrap_or_default();
+            api::remap_fields(&mut data, field_map);
+            message("metrics", data)
+        };
+        encoded.map(|frame| vec![frame])
+    }
+}
+
+/// A collector shared by several monitors, see
+/// [`Monitor::with_shared_collector`].
+#[derive(Debug, Clone)]
+pub struct SharedCollector {
+    samples: broadcast::Sender<Arc<SharedSample>>,
+    /// Notified by a monitor that wants a sample now rather than at the next
+    /// interval, after connecting
+    pub wake: Arc<Notify>,
+    /// Samples published so far
+    published: Arc<AtomicU64>,
+    capacity: usize,
+}
+
+impl SharedCollector {
+    /// A monitor falling more than `capacity` samples behind skips the
+    /// oldest.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            samples: broadcast::channel(capacity).0,
+            wake: Arc::new(Notify::new()),
+            published: Arc::default(),
+            capacity,
+        }
+    }
+
+    /// Hands `sample` to every monitor. Never waits, however far behind
+    /// they are.
+    pub fn publish(&self, sample: Arc<SharedSample>) {
+        self.published.fetch_add(1, Ordering::Relaxed);
+        // Fails only while no monitor is connected
+        let _ = self.samples.send(sample);
+    }
+
+    /// Receives the samples published from now on, counting them in `stats`.
+    fn subscribe(&self, stats: &SampleStats) -> broadcast::Receiver<Arc<SharedSample>> {
+        let samples = self.samples.subscribe();
+        stats
+            .position
+            .store(self.published.load(Ordering::Relaxed), Ordering::Relaxed);
+        samples
+    }
+}
+
+/// How far a monitor is behind its [`SharedCollector`], see
+/// [`Monitor::with_shared_collector`].
+#[derive(Debug, Default)]
+pub struct SampleStats {
+    /// Of the collector; zero for a monitor collecting itself
+    published: Arc<AtomicU64>,
+    capacity: u64,
+    /// How many samples were published before the next one the monitor
+    /// takes
+    position: AtomicU64,
+    /// Skipped samples the monitor has noticed
+    dropped: AtomicU64,
+}
+
+impl SampleStats {
+    fn new(shared: &SharedCollector) -> Self {
+        Self {
+            published: shared.published.clone(),
+            capacity: shared.capacity as u64,
+            ..Default::default()
+        }
+    }
+
+    fn behind(&self) -> u64 {
+        self.published
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.position.load(Ordering::Relaxed))
+    }
+
+    /// Samples waiting for the monitor to take them.
+    pub fn lag(&self) -> u64 {
+        self.behind().min(self.capacity)
+    }
+
+    /// Samples skipped because the monitor fell too far behind, including
+    /// those pushed out while it is still stuck sending.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed) + self.behind().saturating_sub(self.capacity)
+    }
+}
+
+/// One sample of a [`SharedCollector`].
+#[derive(Debug, Clone)]
+pub struct SharedSample {
+    /// Unix time in milliseconds
+    pub collected_at: u64,
+    /// See [`crate::history::mono_nanos`]
+    pub mono_ns: u64,
+    pub report: ReportData,
+}
+
+/// Picks the samples of a faster collector to pass on at a slower interval.
+/// A sample is due once nine tenths of the interval have passed since the
+/// last one, so every sample of a collector running at the same interval
+/// passes despite jitter.
+#[derive(Debug, Default)]
+pub struct Pacer {
+    last_mono_ns: Option<u64>,
+}
+
+impl Pacer {
+    /// Whether the sample taken at `mono_ns` is due, recording it if so.
+    pub fn due(&mut self, mono_ns: u64, interval: Duration) -> bool {
+        let min_gap = (interval - interval / 10).as_nanos() as u64;
+        let due = self
+            .last_mono_ns
+            .is_none_or(|last| mono_ns.saturating_sub(last) >= min_gap);
+        if due {
+            self.last_mono_ns = Some(mono_ns);
+        }
+        due
+    }
+}
+
+/// Tracks how long collections take against the configured metrics interval
+/// and widens the effective interval to the collection time plus a margin
+/// while collection keeps overrunning, narrowing it again once collection is
+/// fast. Never goes below the configured interval or above
+/// [`MAX_INTERVAL_STRETCH`] times it.
+pub struct AdaptiveInterval {
+    configured: Duration,
+    effective: Duration,
+    overruns: u32,
+}
+
+impl AdaptiveInterval {
+    pub fn new(configured: Duration) -> Self {
+        Self {
+            configured,
+            effective: configured,
+            overruns: 0,
+        }
+    }
+
+    pub fn configured(&self) -> Duration {
+        self.configured
+    }
+
+    pub fn effective(&self) -> Duration {
+        self.effective
+    }
+
+    /// Records one collection and returns the new effective interval if it
+    /// changed.
+    pub fn record(&mut self, collection_time: Duration) -> Option<Duration> {
+        let target = (collection_time * 3 / 2)
+            .clamp(self.configured, self.configured * MAX_INTERVAL_STRETCH);
+
+        if collection_time >= self.effective {
+            self.overruns += 1;
+            if self.overruns < OVERRUNS_BEFORE_WIDENING || target == self.effective {
+                return None;
+            }
+            warn!(
+                collection_time = ?collection_time,
+                interval = ?target,
+                "Collection can't keep up with the metrics interval, widening it"
+            );
+        } else {
+            self.overruns = 0;
+            if target >= self.effective {
+                return None;
+            }
+            info!(interval = ?target, "Collection caught up, narrowing the metrics interval");
+        }
+        self.effective = target;
+        self.overruns = 0;
+        Some(target)
+    }
+}
+
+/// Per command type token buckets, so a server flooding the client with
+/// commands can't keep it busy collecting VM info.
+pub struct CommandLimiter {
+    per_sec: u32,
+    buckets: HashMap<&'static str, CommandBucket>,
+}
+
+struct CommandBucket {
+    tokens: f64,
+    per_sec: f64,
+    refilled: Instant,
+    /// Commands dropped since the last one let through
+    dropped: u64,
+}
+
+impl CommandLimiter {
+    /// Allows `per_sec` commands of each type a second, in bursts of as many;
+    /// 0 allows every command.
+    pub fn new(per_sec: u32) -> Self {
+        Self {
+            per_sec,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Whether a `command` arriving at `now` is handled. Dropped ones are
+    /// logged when the first of a run is dropped and once one is let through
+    /// again.
+    pub fn allow(&mut self, endpoint: &Endpoint, command: &str, now: Instant) -> bool {
+        if self.per_sec == 0 {
+            return true;
+        }
+        // Unknown types share a bucket, so a server can't grow the map
+        let (kind, per_sec) = match command {
+            "get_info" | "vm_info_unknown" => {
+                ("vm_info", (self.per_sec / EXPENSIVE_COMMAND_DIVISOR).max(1))
+            }
+            "vm_info_known" => ("vm_info_known", self.per_sec),
+            "update_config" => ("update_config", self.per_sec),
+            "reconfigure" => ("reconfigure", self.per_sec),
+            _ => ("unknown", self.per_sec),
+        };
+        let bucket = self.buckets.entry(kind).or_insert_with(|| CommandBucket {
+            tokens: per_sec as f64,
+            per_sec: per_sec as f64,
+            refilled: now,
+            dropped: 0,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket.per_sec).min(bucket.per_sec);
+        bucket.refilled = now;
+        if bucket.tokens < 1.0 {
+            if bucket.dropped == 0 {
+                warn!(endpoint = %endpoint.name, command = %command, "Server is sending commands too fast, dropping them");
+            }
+            bucket.dropped += 1;
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        if bucket.dropped > 0 {
+            info!(endpoint = %endpoint.name, command = %command, dropped = bucket.dropped, "Handling commands again");
+            bucket.dropped = 0;
+        }
+        true
+    }
+}
+
+/// Publishes `event` if anyone may be listening. Having no subscribers is
+/// not an error.
+fn emit(events: &Option<broadcast::Sender<MonitorEvent>>, event: MonitorEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
+
+/// Combines encoded messages into one `batch` message whose data is the
+/// array of the original messages. Each frame already is a complete msgpack
+/// value, so it's copied in behind the map and array headers as it is. A
+/// single message is sent unchanged.
+fn encode_batch(mut frames: Vec<Vec<u8>>) -> Vec<u8> {
+    if frames.len() == 1 {
+        return frames.remove(0);
+    }
+    let mut encoded = Vec::with_capacity(frames.iter().map(Vec::len).sum::<usize>() + 24);
+    // Writing to a Vec can't fail
+    let _ = rmp::encode::write_map_len(&mut encoded, 2);
+    let _ = rmp::encode::write_str(&mut encoded, "type");
+    let _ = rmp::encode::write_str(&mut encoded, "batch");
+    let _ = rmp::encode::write_str(&mut encoded, "data");
+    let _ = rmp::encode::write_array_len(&mut encoded, frames.len() as u32);
+    for frame in frames {
+        encoded.extend_from_slice(&frame);
+    }
+    encoded
+}
+
+/// Whether an encoded message is a `metrics_schema`.
+fn is_schema(frame: &[u8], encoding: Encoding) -> bool {
+    api::decode::<api::Message<serde::de::IgnoredAny>>(frame, encoding)
+        .is_ok_and(|message| message.r#type == "metrics_schema")
+}
+
+#[test]
+fn test_batch_wraps_frames_as_they_are() {
+    let frame = |r#type: &str, data: u32| {
+        rmp_serde::to_vec_named(&api::Message {
+            r#type: r#type.to_string(),
+            data,
+        })
+        .unwrap()
+    };
+    let batch = encode_batch(vec![frame("metrics", 1), frame("vm_info", 2)]);
+    let batch: api::Message<Vec<api::Message<u32>>> = rmp_serde::from_slice(&batch).unwrap();
+    assert_eq!(batch.r#type, "batch");
+    let messages: Vec<(&str, u32)> = batch
+        .data
+        .iter()
+        .map(|m| (m.r#type.as_str(), m.data))
+        .collect();
+    assert_eq!(messages, [("metrics", 1), ("vm_info", 2)]);
+
+    assert_eq!(encode_batch(vec![frame("metrics", 1)]), frame("metrics", 1));
+}
+
+#[test]
+fn test_local_interval_authority_ignores_server() {
+    let push = || api::ProbeConfig {
+        metrics_interval: 30,
+    };
+
+    let (config_tx, config_rx) = watch::channel(Config::new(vec![]));
+    let local = Endpoint {
+        interval_authority: IntervalAuthority::Local,
+        ..Default::default()
+    };
+    Monitor::apply_server_config(&local, push(), &config_tx);
+    assert_eq!(config_rx.borrow().metrics_interval, Duration::from_secs(10));
+
+    let server = Endpoint {
+        interval_authority: IntervalAuthority::Server,
+        ..Default::default()
+    };
+    Monitor::apply_server_config(&server, push(), &config_tx);
+    assert_eq!(config_rx.borrow().metrics_interval, Duration::from_secs(30));
+}
+
+#[test]
+fn test_unchanged_server_config_is_not_propagated() {
+    let push = || api::ProbeConfig {
+        metrics_interval: 30,
+    };
+
+    let (config_tx, mut config_rx) = watch::channel(Config::new(vec![]));
+    let endpoint = Endpoint::default();
+    Monitor::apply_server_config(&endpoint, push(), &config_tx);
+    assert!(config_rx.has_changed().unwrap());
+    config_rx.mark_unchanged();
+
+    Monitor::apply_server_config(&endpoint, push(), &config_tx);
+    assert!(!config_rx.has_changed().unwrap());
+    assert_eq!(config_rx.borrow().metrics_interval, Duration::from_secs(30));
+}
+
+#[test]
+fn test_adaptive_interval_widens_and_recovers() {
+    let mut adaptive = AdaptiveInterval::new(Duration::from_secs(2));
+
+    // A single slow collection is tolerated
+    assert_eq!(adaptive.record(Duration::from_secs(3)), None);
+    assert_eq!(adaptive.record(Duration::from_millis(100)), None);
+
+    // Consistently slow collections widen it to the collection time plus margin
+    for _ in 0..OVERRUNS_BEFORE_WIDENING - 1 {
+        assert_eq!(adaptive.record(Duration::from_secs(4)), None);
+    }
+    assert_eq!(
+        adaptive.record(Duration::from_secs(4)),
+        Some(Duration::from_secs(6))
+    );
+    assert_eq!(adaptive.effective, Duration::from_secs(6));
+
+    // Bounded even when collection gets much slower
+    for _ in 0..OVERRUNS_BEFORE_WIDENING - 1 {
+        adaptive.record(Duration::from_secs(60));
+    }
+    assert_eq!(
+        adaptive.record(Duration::from_secs(60)),
+        Some(Duration::from_secs(8))
+    );
+
+    // Back to the configured interval once collection is fast again
+    assert_eq!(
+        adaptive.record(Duration::from_millis(100)),
+        Some(Duration::from_secs(2))
+    );
+    assert_eq!(adaptive.record(Duration::from_millis(100)), None);
+}
+
+#[test]
+fn test_offline_buffer_drops_oldest() {
+    let buffer = OfflineBuffer::new(2);
+    assert!(!buffer.push(vec![1u8]));
+    assert!(!buffer.push(vec![2u8]));
+    assert!(buffer.push(vec![3u8]));
+    assert_eq!(buffer.take(FlushOrder::OldestFirst), [vec![2u8], vec![3u8]]);
+    assert!(buffer.take(FlushOrder::OldestFirst).is_empty());
+}
+
+#[test]
+fn test_offline_buffer_flush_order() {
+    let filled = || {
+        let buffer = OfflineBuffer::new(3);
+        for frame in 1..=4u8 {
+            buffer.push(vec![frame]);
+        }
+        buffer
+    };
+    // The first frame made room for the fourth
+    assert_eq!(
+        filled().take(FlushOrder::OldestFirst),
+        [vec![2], vec![3], vec![4]]
+    );
+    assert_eq!(
+        filled().take(FlushOrder::NewestFirst),
+        [vec![4], vec![3], vec![2]]
+    );
+    assert_eq!(filled().take(FlushOrder::LatestOnly), [vec![4]]);
+
+    let buffer = filled();
+    buffer.take(FlushOrder::LatestOnly);
+    assert!(buffer.take(FlushOrder::OldestFirst).is_empty());
+}
+
+#[test]
+fn test_offline_buffer_keeps_schemas_with_their_frames() {
+    let buffer = OfflineBuffer::new(2);
+    buffer.pin_schema(vec![10]);
+    buffer.push(vec![1]);
+    buffer.push(vec![2]);
+    buffer.pin_schema(vec![20]);
+    buffer.push(vec![3]);
+    // The first schema stays with the frame still using it
+    assert_eq!(
+        buffer.take(FlushOrder::OldestFirst),
+        [vec![10], vec![2], vec![20], vec![3]]
+    );
+
+    buffer.pin_schema(vec![10]);
+    buffer.push(vec![1]);
+    buffer.push(vec![2]);
+    assert_eq!(buffer.take(FlushOrder::LatestOnly), [vec![10], vec![2]]);
+    // A flush forgets the schema, the next collection sends its own
+    buffer.push(vec![3]);
+    assert_eq!(buffer.take(FlushOrder::NewestFirst), [vec![3]]);
+}
+
+#[test]
+fn test_command_limiter_drops_excess_commands() {
+    let endpoint = Endpoint::default();
+    let mut limiter = CommandLimiter::new(10);
+    let start = Instant::now();
+
+    let allowed = (0..100)
+        .filter(|_| limiter.allow(&endpoint, "update_config", start))
+        .count();
+    assert_eq!(allowed, 10);
+    // VM info is more expensive, and limited separately
+    let allowed = (0..100)
+        .filter(|_| limiter.allow(&endpoint, "get_info", start))
+        .count();
+    assert_eq!(allowed, 2);
+
+    // Tokens come back over time
+    let later = start + Duration::from_millis(500);
+    assert!(limiter.allow(&endpoint, "get_info", later));
+    assert!(!limiter.allow(&endpoint, "get_info", later));
+
+    let mut unlimited = CommandLimiter::new(0);
+    assert!((0..100).all(|_| unlimited.allow(&endpoint, "get_info", start)));
+}
+
+#[test]
+fn test_configured_metrics_interval_is_initial() {
+    let endpoint = Endpoint {
+        metrics_interval: Some(5),
+        ..Default::default()
+    };
+    let monitor = Monitor::new(endpoint, vec![]);
+    assert_eq!(
+        monitor.config_rx.borrow().metrics_interval,
+        Duration::from_secs(5)
+    );
+
+    // Until the server pushes another one
+    Monitor::apply_server_config(
+        &monitor.endpoint,
+        api::ProbeConfig {
+            metrics_interval: 30,
+        },
+        &monitor.config_tx,
+    );
+    assert_eq!(
+        monitor.config_rx.borrow().metrics_interval,
+        Duration::from_secs(30)
+    );
+
+    let unset = Monitor::new(Endpoint::default(), vec![]);
+    assert_eq!(
+        unset.config_rx.borrow().metrics_interval,
+        Duration::from_secs(10)
+    );
+}
+
+#[test]
+fn test_pacer_passes_samples_at_its_interval() {
+    let mut pacer = Pacer::default();
+    let second = 1_000_000_000;
+    let passed: Vec<u64> = [0, 1, 2, 3, 4, 5, 6]
+        .into_iter()
+        // Jitter doesn't hold back a sample that is a little early
+        .map(|n| n * second - if n == 4 { second / 20 } else { 0 })
+        .filter(|&mono_ns| pacer.due(mono_ns, Duration::from_secs(2)))
+        .map(|mono_ns| mono_ns.div_ceil(second))
+        .collect();
+    assert_eq!(passed, [0, 2, 4, 6]);
 }
@@ -0,0 +1,87 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+/// DER prefix of an ed25519 SubjectPublicKeyInfo, followed by the 32 key bytes.
+const SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Where the detached signature of `config_path` is kept.
+pub fn signature_path(config_path: &str) -> String {
+    format!("{}.sig", config_path)
+}
+
+/// Parses an ed25519 public key, either PEM as written by
+/// `openssl pkey -pubout` or the base64 of the raw 32 bytes.
+pub fn parse_public_key(text: &str) -> Result<Vec<u8>, String> {
+    let der = decode_pem_or_base64(text)?;
+    match der.len() {
+        32 => Ok(der),
+        44 if der.starts_with(&SPKI_PREFIX) => Ok(der[SPKI_PREFIX.len()..].to_vec()),
+        _ => Err("not an ed25519 public key".to_string()),
+    }
+}
+
+/// Checks `signature` (base64) over `data` against `public_key`.
+pub fn verify(data: &[u8], signature: &str, public_key: &[u8]) -> Result<(), String> {
+    let signature = STANDARD
+        .decode(signature.trim())
+        .map_err(|e| format!("invalid signature encoding: {}", e))?;
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(data, &signature)
+        .map_err(|_| "signature does not match".to_string())
+}
+
+/// Signs `data` with a PKCS#8 private key (PEM, as written by
+/// `openssl genpkey -algorithm ed25519`) and returns the base64 signature.
+pub fn sign(data: &[u8], private_key: &str) -> Result<String, String> {
+    let pkcs8 = decode_pem_or_base64(private_key)?;
+    let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pkcs8)
+        .map_err(|e| format!("invalid ed25519 private key: {}", e))?;
+    Ok(STANDARD.encode(key_pair.sign(data)))
+}
+
+/// Generates a key pair, returned as (private key PEM, public key PEM).
+pub fn generate_key_pair() -> Result<(String, String), String> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| "failed to generate key".to_string())?;
+    let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+        .map_err(|e| format!("failed to generate key: {}", e))?;
+
+    let mut spki = SPKI_PREFIX.to_vec();
+    spki.extend_from_slice(key_pair.public_key().as_ref());
+    Ok((
+        encode_pem("PRIVATE KEY", pkcs8.as_ref()),
+        encode_pem("PUBLIC KEY", &spki),
+    ))
+}
+
+/// Compares without returning early, so the time taken doesn't reveal how
+/// much of a guessed token was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn decode_pem_or_base64(text: &str) -> Result<Vec<u8>, String> {
+    let body: String = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    STANDARD
+        .decode(body)
+        .map_err(|e| format!("invalid key encoding: {}", e))
+}
+
+fn encode_pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
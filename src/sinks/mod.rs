@@ -0,0 +1,151 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Stdout, Write};
+
+use crate::config::SinkConfig;
+use crate::history::HistorySample;
+
+mod plaintext;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "syslog")]
+mod syslog;
+
+pub use plaintext::{GraphiteSink, StatsdSink};
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteSink;
+#[cfg(feature = "syslog")]
+pub use syslog::SyslogSink;
+
+/// A local destination for collected samples. Writes may be buffered, so
+/// `flush` must be called before the sink is dropped to avoid losing data.
+pub trait Sink: Send {
+    fn name(&self) -> &str;
+    fn write(&mut self, sample: &HistorySample) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+pub fn build(config: &SinkConfig) -> io::Result<Box<dyn Sink>> {
+    match config {
+        SinkConfig::File { path } => Ok(Box::new(FileSink::open(path)?)),
+        SinkConfig::Stdout => Ok(Box::new(StdoutSink::new())),
+        SinkConfig::Graphite {
+            address,
+            prefix,
+            host,
+        } => Ok(Box::new(GraphiteSink::new(
+            address,
+            &metric_path(prefix, host),
+        ))),
+        SinkConfig::Statsd {
+            address,
+            prefix,
+            host,
+        } => Ok(Box::new(StatsdSink::new(
+            address,
+            &metric_path(prefix, host),
+        )?)),
+        #[cfg(feature = "sqlite")]
+        SinkConfig::Sqlite {
+            path,
+            retention_days,
+        } => Ok(Box::new(SqliteSink::open(path, *retention_days)?)),
+        #[cfg(not(feature = "sqlite"))]
+        SinkConfig::Sqlite { .. } => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "vmonitor was built without the `sqlite` feature",
+        )),
+        #[cfg(feature = "syslog")]
+        SinkConfig::Syslog {
+            address,
+            facility,
+            metrics,
+            alerts,
+        } => Ok(Box::new(SyslogSink::new(
+            address, facility, metrics, alerts,
+        )?)),
+        #[cfg(not(feature = "syslog"))]
+        SinkConfig::Syslog { .. } => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "vmonitor was built without the `syslog` feature",
+        )),
+    }
+}
+
+/// Appends samples to a file as JSON lines.
+pub struct FileSink {
+    name: String,
+    writer: BufWriter<File>,
+}
+
+impl FileSink {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            name: format!("file:{}", path),
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, sample: &HistorySample) -> io::Result<()> {
+        write_json_line(&mut self.writer, sample)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
+/// Prints samples to stdout as JSON lines.
+pub struct StdoutSink {
+    writer: BufWriter<Stdout>,
+}
+
+impl StdoutSink {
+    pub fn new() -> Self {
+        Self {
+            writer: BufWriter::new(io::stdout()),
+        }
+    }
+}
+
+impl Default for StdoutSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write(&mut self, sample: &HistorySample) -> io::Result<()> {
+        write_json_line(&mut self.writer, sample)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// `prefix.host`, with dots in the hostname replaced so it stays one path
+/// segment.
+fn metric_path(prefix: &str, host: &Option<String>) -> String {
+    let host = host
+        .clone()
+        .or_else(sysinfo::System::host_name)
+        .unwrap_or_else(|| "unknown".to_string());
+    format!("{}.{}", prefix, host.replace('.', "_"))
+}
+
+fn write_json_line(writer: &mut impl Write, sample: &HistorySample) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, sample)?;
+    writer.write_all(b"\n")
+}
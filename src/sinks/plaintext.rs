@@ -0,0 +1,184 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use super::Sink;
+use crate::history::HistorySample;
+
+/// How long connecting to a Graphite server may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a write may wait on a Graphite server that stopped reading
+/// before the connection is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Largest StatsD datagram, small enough not to fragment on common networks.
+const MAX_DATAGRAM: usize = 1432;
+
+/// Flattens the numeric fields of a sample into `(path, value)` pairs like
+/// `system.cpu_usage`, with snake_case names. Per-core, per-disk and other
+/// list fields are left out since they have no stable path.
+pub(super) fn metric_lines(sample: &HistorySample) -> Vec<(String, f64)> {
+    let mut metrics = Vec::new();
+    if let Ok(value) = serde_json::to_value(&sample.report) {
+        flatten(&value, String::new(), &mut metrics);
+    }
+    metrics
+}
+
+fn flatten(value: &Value, path: String, metrics: &mut Vec<(String, f64)>) {
+    match value {
+        Value::Number(number) => {
+            if let Some(number) = number.as_f64() {
+                metrics.push((path, number));
+            }
+        }
+        Value::Object(object) => {
+            for (key, value) in object {
+                let key = to_snake_case(key);
+                let path = if path.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                flatten(value, path, metrics);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn to_snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len() + 4);
+    for c in name.chars() {
+        if c.is_ascii_uppercase() {
+            result.push('_');
+            result.push(c.to_ascii_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Writes `<path>.<metric> <value> <timestamp>` lines to a Graphite server
+/// over TCP, reconnecting on the next sample after a failed write.
+pub struct GraphiteSink {
+    name: String,
+    address: String,
+    path: String,
+    stream: Option<TcpStream>,
+}
+
+impl GraphiteSink {
+    pub fn new(address: &str, path: &str) -> Self {
+        Self {
+            name: format!("graphite:{}", address),
+            address: address.to_string(),
+            path: path.to_string(),
+            stream: None,
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect_timeout(&resolve(&self.address)?, CONNECT_TIMEOUT)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        Ok(stream)
+    }
+}
+
+impl Sink for GraphiteSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, sample: &HistorySample) -> io::Result<()> {
+        let timestamp = sample.collected_at / 1000;
+        let mut lines = String::new();
+        for (metric, value) in metric_lines(sample) {
+            lines.push_str(&format!(
+                "{}.{} {} {}\n",
+                self.path, metric, value, timestamp
+            ));
+        }
+
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => self.stream.insert(self.connect()?),
+        };
+        let result = stream.write_all(lines.as_bytes());
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sends `<path>.<metric>:<value>|g` gauges to a StatsD server over UDP,
+/// packing as many as fit into each datagram.
+pub struct StatsdSink {
+    name: String,
+    path: String,
+    socket: UdpSocket,
+}
+
+impl StatsdSink {
+    pub fn new(address: &str, path: &str) -> io::Result<Self> {
+        let addr = resolve(address)?;
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Self {
+            name: format!("statsd:{}", address),
+            path: path.to_string(),
+            socket,
+        })
+    }
+}
+
+impl Sink for StatsdSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, sample: &HistorySample) -> io::Result<()> {
+        let mut datagram = String::new();
+        for (metric, value) in metric_lines(sample) {
+            let line = format!("{}.{}:{}|g", self.path, metric, value);
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+                self.socket.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub(super) fn resolve(address: &str) -> io::Result<SocketAddr> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::other(format!("{} did not resolve", address)))
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use rusqlite::{params, Connection};
+use serde_json::Value;
+
+use super::Sink;
+use crate::history::{now_millis, HistorySample};
+
+/// How often samples past the retention are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS samples (
+        collected_at INTEGER NOT NULL,
+        uptime INTEGER NOT NULL,
+        cpu_usage REAL,
+        memory_used INTEGER,
+        memory_total INTEGER,
+        download_rate REAL,
+        upload_rate REAL,
+        disk_used INTEGER,
+        disk_total INTEGER,
+        report TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS samples_collected_at ON samples (collected_at);
+";
+
+/// Inserts samples into the `samples` table of a SQLite database, with the
+/// common metrics as columns and the whole report as JSON in `report`.
+/// Samples older than the retention are deleted once an hour.
+pub struct SqliteSink {
+    name: String,
+    connection: Connection,
+    /// Milliseconds samples are kept for, `None` keeps them forever
+    retention: Option<u64>,
+    last_pruned: Option<Instant>,
+}
+
+impl SqliteSink {
+    pub fn open(path: &str, retention_days: u32) -> io::Result<Self> {
+        let connection = Connection::open(path).map_err(to_io)?;
+        connection.execute_batch(SCHEMA).map_err(to_io)?;
+        Ok(Self {
+            name: format!("sqlite:{}", path),
+            connection,
+            retention: (retention_days > 0).then(|| retention_days as u64 * DAY_MILLIS),
+            last_pruned: None,
+        })
+    }
+
+    /// Deletes samples collected more than the retention before `now` (Unix
+    /// milliseconds) and returns how many.
+    pub fn prune(&self, now: u64) -> io::Result<usize> {
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        let cutoff = now.saturating_sub(retention) as i64;
+        self.connection
+            .execute(
+                "DELETE FROM samples WHERE collected_at < ?1",
+                params![cutoff],
+            )
+            .map_err(to_io)
+    }
+}
+
+impl Sink for SqliteSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, sample: &HistorySample) -> io::Result<()> {
+        let report = serde_json::to_value(&sample.report)?;
+        let int = |pointer: &str| {
+            report
+                .pointer(pointer)
+                .and_then(Value::as_u64)
+                .map(|v| v as i64)
+        };
+        let real = |pointer: &str| report.pointer(pointer).and_then(Value::as_f64);
+        self.connection
+            .execute(
+                "INSERT INTO samples (collected_at, uptime, cpu_usage, memory_used, memory_total,
+                    download_rate, upload_rate, disk_used, disk_total, report)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    sample.collected_at as i64,
+                    sample.report.uptime as i64,
+                    real("/system/cpuUsage"),
+                    int("/system/memoryUsed"),
+                    int("/system/memoryTotal"),
+                    real("/network/downloadRate"),
+                    real("/network/uploadRate"),
+                    int("/disk/spaceUsed"),
+                    int("/disk/spaceTotal"),
+                    report.to_string(),
+                ],
+            )
+            .map_err(to_io)?;
+
+        if self
+            .last_pruned
+            .is_none_or(|pruned| pruned.elapsed() >= PRUNE_INTERVAL)
+        {
+            self.last_pruned = Some(Instant::now());
+            self.prune(now_millis())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Every insert is committed on its own
+        Ok(())
+    }
+}
+
+fn to_io(e: rusqlite::Error) -> io::Error {
+    io::Error::other(e)
+}
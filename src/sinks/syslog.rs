@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+
+use ::syslog::{Facility, Formatter5424, LogFormat, Logger, LoggerBackend, Severity};
+
+use super::plaintext::{metric_lines, resolve};
+use super::Sink;
+use crate::config::SyslogAlert;
+use crate::history::HistorySample;
+
+/// Metrics in the summary when the sink doesn't list any.
+const DEFAULT_METRICS: &[&str] = &[
+    "system.cpu_usage",
+    "system.memory_used",
+    "system.memory_total",
+    "system.load_avg.one",
+    "network.download_rate",
+    "network.upload_rate",
+    "disk.space_used",
+    "disk.space_total",
+];
+/// Private enterprise number used for the structured data ids.
+const SD_ID_SUFFIX: &str = "@32473";
+const APP_NAME: &str = "vmonitor";
+
+/// What [`Formatter5424`] takes as structured data; the crate doesn't export
+/// its own alias.
+type StructuredData = HashMap<String, HashMap<String, String>>;
+
+/// MSGID of the per-sample summary.
+const MSGID_METRICS: u32 = 1;
+/// MSGID of alerts starting or stopping.
+const MSGID_ALERT: u32 = 2;
+
+/// Sends an RFC 5424 summary message per sample to a syslog server over UDP,
+/// with the selected metrics as structured data, and a message at the alert's
+/// severity whenever an alert starts firing (and a notice when it clears).
+/// Messages are stamped with the time they are sent.
+pub struct SyslogSink {
+    name: String,
+    logger: Logger<LoggerBackend, Formatter5424>,
+    metrics: Vec<String>,
+    alerts: Vec<(SyslogAlert, Severity)>,
+    /// Whether each alert was firing on the last sample it could be checked
+    firing: HashMap<usize, bool>,
+}
+
+impl SyslogSink {
+    pub fn new(
+        address: &str,
+        facility: &str,
+        metrics: &[String],
+        alerts: &[SyslogAlert],
+    ) -> io::Result<Self> {
+        let facility = facility.parse::<Facility>().map_err(|()| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown syslog facility '{}'", facility),
+            )
+        })?;
+        let alerts = alerts
+            .iter()
+            .map(|alert| {
+                let severity = parse_severity(&alert.severity).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("unknown syslog severity '{}'", alert.severity),
+                    )
+                })?;
+                Ok((alert.clone(), severity))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let addr = resolve(address)?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        }
+        .parse()
+        .unwrap();
+        let hostname = sysinfo::System::host_name()
+            .filter(|name| !name.is_empty())
+            .map(|name| name.replace(' ', "_"));
+        let formatter = Formatter5424 {
+            facility,
+            hostname,
+            process: APP_NAME.to_string(),
+            pid: std::process::id(),
+        };
+        let logger = ::syslog::udp(formatter, local, addr).map_err(to_io_error)?;
+
+        let metrics = if metrics.is_empty() {
+            DEFAULT_METRICS.iter().map(|m| m.to_string()).collect()
+        } else {
+            metrics.to_vec()
+        };
+
+        Ok(Self {
+            name: format!("syslog:{}", address),
+            logger,
+            metrics,
+            alerts,
+            firing: HashMap::new(),
+        })
+    }
+
+    fn send(
+        &mut self,
+        severity: Severity,
+        msgid: u32,
+        sd: StructuredData,
+        msg: String,
+    ) -> io::Result<()> {
+        self.logger
+            .formatter
+            .format(&mut self.logger.backend, severity, (msgid, sd, msg))
+            .map_err(to_io_error)
+    }
+}
+
+impl Sink for SyslogSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, sample: &HistorySample) -> io::Result<()> {
+        let values: HashMap<String, f64> = metric_lines(sample).into_iter().collect();
+
+        let selected: Vec<(&str, f64)> = self
+            .metrics
+            .iter()
+            .filter_map(|metric| values.get(metric).map(|value| (metric.as_str(), *value)))
+            .collect();
+        let mut messages = Vec::new();
+        if !selected.is_empty() {
+            let params = selected
+                .iter()
+                .map(|(metric, value)| (metric.to_string(), value.to_string()))
+                .collect();
+            let msg: Vec<String> = selected
+                .iter()
+                .map(|(metric, value)| format!("{}={}", metric, value))
+                .collect();
+            messages.push((
+                Severity::LOG_INFO,
+                MSGID_METRICS,
+                structured_data("metrics", params),
+                msg.join(" "),
+            ));
+        }
+
+        for (index, (alert, severity)) in self.alerts.iter().enumerate() {
+            let Some(&value) = values.get(&alert.metric) else {
+                continue;
+            };
+            let firing = value > alert.above;
+            let was_firing = self.firing.insert(index, firing).unwrap_or(false);
+            if firing == was_firing {
+                continue;
+            }
+            let sd = structured_data(
+                "alert",
+                HashMap::from([
+                    ("metric".to_string(), alert.metric.clone()),
+                    ("value".to_string(), value.to_string()),
+                    ("threshold".to_string(), alert.above.to_string()),
+                ]),
+            );
+            let (severity, msg) = if firing {
+                (
+                    *severity,
+                    format!("{} is {} (above {})", alert.metric, value, alert.above),
+                )
+            } else {
+                (
+                    Severity::LOG_NOTICE,
+                    format!(
+                        "{} is back to {} (threshold {})",
+                        alert.metric, value, alert.above
+                    ),
+                )
+            };
+            messages.push((severity, MSGID_ALERT, sd, msg));
+        }
+
+        for (severity, msgid, sd, msg) in messages {
+            self.send(severity, msgid, sd, msg)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One structured data element, with the id under our enterprise number.
+fn structured_data(id: &str, params: HashMap<String, String>) -> StructuredData {
+    HashMap::from([(format!("{}{}", id, SD_ID_SUFFIX), params)])
+}
+
+/// Keeps the causes the syslog crate chains, e.g. `Format: Connection refused`.
+fn to_io_error(e: ::syslog::Error) -> io::Error {
+    let causes: Vec<String> = e.iter().map(|cause| cause.to_string()).collect();
+    io::Error::other(causes.join(": "))
+}
+
+fn parse_severity(name: &str) -> Option<Severity> {
+    match name {
+        "emerg" => Some(Severity::LOG_EMERG),
+        "alert" => Some(Severity::LOG_ALERT),
+        "crit" => Some(Severity::LOG_CRIT),
+        "err" | "error" => Some(Severity::LOG_ERR),
+        "warning" | "warn" => Some(Severity::LOG_WARNING),
+        "notice" => Some(Severity::LOG_NOTICE),
+        "info" => Some(Severity::LOG_INFO),
+        "debug" => Some(Severity::LOG_DEBUG),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_parse_severity() {
+    assert!(matches!(
+        parse_severity("warn"),
+        Some(Severity::LOG_WARNING)
+    ));
+    assert!(parse_severity("nope").is_none());
+}
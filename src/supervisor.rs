@@ -0,0 +1,103 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+
+use futures::FutureExt;
+use tokio::sync::broadcast;
+use tokio::time::{sleep, Duration, Instant};
+use tracing::error;
+
+use crate::monitor::MonitorEvent;
+
+/// When a panicking monitor is restarted and when it is given up on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartPolicy {
+    /// Panics within `window` after which the monitor isn't restarted again
+    pub max_panics: usize,
+    pub window: Duration,
+    /// Delay before the first restart, doubling with every panic in the
+    /// window up to `max_delay`
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_panics: 5,
+            window: Duration::from_secs(600),
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Runs the future `start` returns for `endpoint` and starts a new one
+/// whenever it panics, logging the panic and publishing it to `events`.
+/// Returns once the future completes, or when it panicked
+/// `policy.max_panics` times within `policy.window`, so an endpoint that
+/// can't run doesn't restart in a loop.
+pub async fn supervise<F, Fut>(
+    endpoint: &str,
+    policy: RestartPolicy,
+    events: Option<broadcast::Sender<MonitorEvent>>,
+    mut start: F,
+) where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut panics: VecDeque<Instant> = VecDeque::new();
+    loop {
+        let Err(panic) = AssertUnwindSafe(start()).catch_unwind().await else {
+            return;
+        };
+        let message = panic_message(panic);
+        let now = Instant::now();
+        panics.push_back(now);
+        while panics
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > policy.window)
+        {
+            panics.pop_front();
+        }
+
+        let restarting = panics.len() < policy.max_panics;
+        if let Some(events) = &events {
+            let _ = events.send(MonitorEvent::Panicked {
+                endpoint: endpoint.to_string(),
+                message: message.clone(),
+                restarting,
+            });
+        }
+        if !restarting {
+            error!(
+                endpoint = %endpoint,
+                panics = panics.len(),
+                message = %message,
+                "Monitor keeps panicking, giving up on this endpoint"
+            );
+            return;
+        }
+        let exponent = (panics.len() as u32 - 1).min(16);
+        let delay = (policy.base_delay * 2u32.pow(exponent)).min(policy.max_delay);
+        error!(
+            endpoint = %endpoint,
+            message = %message,
+            restart_in = ?delay,
+            "Monitor panicked, restarting it"
+        );
+        sleep(delay).await;
+    }
+}
+
+/// The message a panic was raised with, if it was a string.
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
@@ -0,0 +1,446 @@
+//! The ways an endpoint reaches its server, picked by its `transport`, see
+//! [`TransportKind`].
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+
+use futures::future::BoxFuture;
+use futures::{Sink, Stream, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Duration};
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tracing::{debug, error, warn};
+
+use crate::api::{self, ConnectError};
+use crate::config::{
+    Compression, CompressionConfig, ConnectionConfig, Encoding, Endpoint, TransportKind,
+    TrustedSelfSigned,
+};
+
+/// Sends messages to the server.
+pub type FrameSink = Pin<Box<dyn Sink<Message, Error = WsError> + Send>>;
+/// Receives what the server sends.
+pub type FrameStream = Pin<Box<dyn Stream<Item = Result<Message, WsError>> + Send>>;
+
+/// An open connection to the server.
+pub struct Connection {
+    pub sink: FrameSink,
+    /// Never yields anything over a transport that isn't
+    /// [`TransportKind::duplex`]
+    pub stream: FrameStream,
+    /// What the server accepted, see [`api::compress_frame`]
+    pub compression: Compression,
+}
+
+/// A way of reaching a server. Messages go out and come in as WebSocket
+/// messages over every transport, so the monitor sends metrics and handles
+/// commands the same way over each of them.
+pub trait Transport: Send + Sync {
+    /// Connects `endpoint`, retrying failed attempts as `connection` says.
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        secret: &'a str,
+        connection: &'a ConnectionConfig,
+        trusted_self_signed: &'a [TrustedSelfSigned],
+    ) -> BoxFuture<'a, Result<Connection, ConnectError>>;
+}
+
+/// Wraps an encoded frame as the message carrying it: text for JSON,
+/// otherwise binary, compressed with `compression` as the server accepted.
+pub fn data_message(frame: Vec<u8>, encoding: Encoding, compression: CompressionConfig) -> Message {
+    match encoding {
+        Encoding::Json => match String::from_utf8(frame) {
+            Ok(text) => Message::Text(text.into()),
+            Err(e) => Message::Binary(e.into_bytes().into()),
+        },
+        _ => Message::Binary(api::compress_frame(frame, compression).into()),
+    }
+}
+
+/// The transport of `kind`.
+pub fn for_kind(kind: TransportKind) -> Box<dyn Transport> {
+    match kind {
+        TransportKind::WebSocket => Box::new(WebSocket),
+        TransportKind::Http => Box::new(Http),
+        TransportKind::Unix => Box::new(Unix),
+    }
+}
+
+/// See [`TransportKind::WebSocket`].
+pub struct WebSocket;
+
+impl Transport for WebSocket {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        secret: &'a str,
+        connection: &'a ConnectionConfig,
+        trusted_self_signed: &'a [TrustedSelfSigned],
+    ) -> BoxFuture<'a, Result<Connection, ConnectError>> {
+        Box::pin(async move {
+            let (socket, compression) = api::try_connect_websocket(
+                &endpoint.server,
+                &endpoint.path,
+                secret,
+                &endpoint.headers,
+                connection,
+                endpoint.pinned_cert_sha256.as_deref(),
+                trusted_self_signed,
+            )
+            .await?;
+            let (sink, stream) = socket.split();
+            Ok(Connection {
+                sink: Box::pin(sink),
+                stream: Box::pin(stream),
+                compression,
+            })
+        })
+    }
+}
+
+/// See [`TransportKind::Unix`]. The WebSocket handshake and everything after
+/// it are the same as over TCP.
+pub struct Unix;
+
+impl Transport for Unix {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        secret: &'a str,
+        connection: &'a ConnectionConfig,
+        _trusted_self_signed: &'a [TrustedSelfSigned],
+    ) -> BoxFuture<'a, Result<Connection, ConnectError>> {
+        Box::pin(with_retries(
+            &endpoint.server,
+            connection,
+            move || async move {
+                let mut request = api::build_transport_request(
+                    TransportKind::Unix,
+                    &endpoint.server,
+                    &endpoint.path,
+                    secret,
+                    connection.auth_mode,
+                    &endpoint.headers,
+                )
+                .map_err(ConnectError::Config)?;
+                let path = api::unix_socket_path(&endpoint.server).map_err(ConnectError::Config)?;
+                let offered = connection.compression.unwrap_or_default();
+                api::offer_compression(&mut request, offered);
+                let stream = connect_unix(path).await?;
+                let (socket, response) = tokio_tungstenite::client_async(request, stream)
+                    .await
+                    .map_err(|e| match &e {
+                        WsError::Http(response) if response.status() == 401 => {
+                            ConnectError::Unauthorized
+                        }
+                        _ => ConnectError::Failed(e.to_string()),
+                    })?;
+                debug!(url = %endpoint.server, "WebSocket connection established");
+                let (sink, stream) = socket.split();
+                Ok(Connection {
+                    sink: Box::pin(sink),
+                    stream: Box::pin(stream),
+                    compression: api::accepted_compression(&endpoint.server, offered, &response),
+                })
+            },
+        ))
+    }
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> Result<tokio::net::UnixStream, ConnectError> {
+    tokio::net::UnixStream::connect(path)
+        .await
+        .map_err(|e| ConnectError::Failed(format!("{}: {}", path, e)))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str) -> Result<TcpStream, ConnectError> {
+    Err(ConnectError::Config(
+        "unix sockets aren't available on this platform".to_string(),
+    ))
+}
+
+/// See [`TransportKind::Http`]. Every message is sent as the body of its own
+/// `POST` on a kept-alive connection, which is reopened when the server
+/// closes it. A message the server doesn't answer with a 2xx status ends the
+/// connection like a dropped WebSocket would. The server can't send
+/// anything back.
+pub struct Http;
+
+impl Transport for Http {
+    fn connect<'a>(
+        &'a self,
+        endpoint: &'a Endpoint,
+        secret: &'a str,
+        connection: &'a ConnectionConfig,
+        trusted_self_signed: &'a [TrustedSelfSigned],
+    ) -> BoxFuture<'a, Result<Connection, ConnectError>> {
+        Box::pin(async move {
+            let request = api::build_transport_request(
+                TransportKind::Http,
+                &endpoint.server,
+                &endpoint.path,
+                secret,
+                connection.auth_mode,
+                &endpoint.headers,
+            )
+            .map_err(ConnectError::Config)?;
+            let tls = match api::tls_connector(
+                request.uri(),
+                &endpoint.server,
+                endpoint.pinned_cert_sha256.as_deref(),
+                trusted_self_signed,
+            )? {
+                connector if request.uri().scheme_str() == Some("https") => Some(
+                    api::tls_client_config(connector)
+                        .map(TlsConnector::from)
+                        .map_err(ConnectError::Config)?,
+                ),
+                _ => None,
+            };
+            let mut poster = HttpPoster {
+                request,
+                content_type: endpoint.encoding.content_type(),
+                tls,
+                stream: None,
+            };
+            poster.stream =
+                Some(with_retries(&endpoint.server, connection, || poster.open()).await?);
+            debug!(url = %endpoint.server, "HTTP connection established");
+
+            let sink = futures::sink::unfold(poster, |mut poster, message: Message| async move {
+                match message {
+                    Message::Binary(body) => poster.post(&body).await?,
+                    Message::Text(body) => poster.post(body.as_bytes()).await?,
+                    // Pings go unanswered, and closing only stops sending
+                    _ => {}
+                }
+                Ok::<_, WsError>(poster)
+            });
+            Ok(Connection {
+                sink: Box::pin(sink),
+                stream: Box::pin(futures::stream::pending()),
+                compression: Compression::None,
+            })
+        })
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync> Io for T {}
+
+/// The most a response head may take, and the most of a body that is read.
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+struct HttpPoster {
+    /// Every message is sent with its URL and headers
+    request: Request,
+    content_type: &'static str,
+    tls: Option<TlsConnector>,
+    /// Kept alive between messages
+    stream: Option<BufReader<Box<dyn Io>>>,
+}
+
+impl HttpPoster {
+    async fn open(&self) -> Result<BufReader<Box<dyn Io>>, ConnectError> {
+        let uri = self.request.uri();
+        let host = uri.host().unwrap_or_default().to_string();
+        let port = uri
+            .port_u16()
+            .unwrap_or(if self.tls.is_some() { 443 } else { 80 });
+        let tcp = TcpStream::connect((host.trim_matches(['[', ']']), port))
+            .await
+            .map_err(|e| ConnectError::Failed(e.to_string()))?;
+        let stream: Box<dyn Io> = match &self.tls {
+            Some(tls) => {
+                let name = rustls::pki_types::ServerName::try_from(host.clone())
+                    .map_err(|e| ConnectError::Config(format!("'{}': {}", host, e)))?;
+                let stream = tls.connect(name, tcp).await.map_err(|e| {
+                    if api::is_pin_mismatch_io(&e) {
+                        ConnectError::PinMismatch
+                    } else {
+                        ConnectError::Failed(e.to_string())
+                    }
+                })?;
+                Box::new(stream)
+            }
+            None => Box::new(tcp),
+        };
+        Ok(BufReader::new(stream))
+    }
+
+    async fn post(&mut self, body: &[u8]) -> Result<(), WsError> {
+        if self.stream.is_none() {
+            let stream = self
+                .open()
+                .await
+                .map_err(|e| WsError::Io(io::Error::other(e.to_string())))?;
+            self.stream = Some(stream);
+        }
+        let uri = self.request.uri();
+        let mut head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            uri.path_and_query().map_or("/", |pq| pq.as_str()),
+            uri.authority().map_or("", |authority| authority.as_str()),
+            self.content_type,
+            body.len()
+        );
+        for (name, value) in self.request.headers() {
+            if let Ok(value) = value.to_str() {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        head.push_str("\r\n");
+
+        let stream = self.stream.as_mut().expect("opened above");
+        let result = async {
+            stream.write_all(head.as_bytes()).await?;
+            stream.write_all(body).await?;
+            stream.flush().await?;
+            read_response(stream).await
+        }
+        .await;
+        match result {
+            Ok(Response { status, keep_alive }) => {
+                if !keep_alive {
+                    self.stream = None;
+                }
+                match status {
+                    200..=299 => Ok(()),
+                    401 => {
+                        error!(url = %uri, "Authentication failed - invalid or missing auth token");
+                        Err(WsError::Io(io::Error::other(
+                            "authentication failed (HTTP 401)",
+                        )))
+                    }
+                    status => Err(WsError::Io(io::Error::other(format!(
+                        "the server answered HTTP {}",
+                        status
+                    )))),
+                }
+            }
+            Err(e) => {
+                self.stream = None;
+                Err(WsError::Io(e))
+            }
+        }
+    }
+}
+
+struct Response {
+    status: u16,
+    keep_alive: bool,
+}
+
+/// Reads a response, skipping its body.
+async fn read_response(stream: &mut BufReader<Box<dyn Io>>) -> io::Result<Response> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+    let mut head = stream.take(MAX_RESPONSE_BYTES);
+    let mut line = String::new();
+    head.read_line(&mut line).await?;
+    let status = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| invalid("malformed HTTP status line"))?;
+    let mut content_length = 0;
+    let mut chunked = false;
+    let mut keep_alive = line.starts_with("HTTP/1.1");
+    loop {
+        line.clear();
+        if head.read_line(&mut line).await? == 0 {
+            return Err(invalid("HTTP response ended in its headers"));
+        }
+        let Some((name, value)) = line.trim_end().split_once(':') else {
+            break;
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| invalid("malformed Content-Length"))?
+            }
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+            _ => {}
+        }
+    }
+
+    let stream = head.into_inner();
+    if chunked {
+        loop {
+            let mut size = String::new();
+            stream.take(1024).read_line(&mut size).await?;
+            let size = u64::from_str_radix(size.trim().split(';').next().unwrap_or(""), 16)
+                .map_err(|_| invalid("malformed chunk size"))?;
+            // The chunk and the line break after it
+            skip(stream, size + 2).await?;
+            if size == 0 {
+                break;
+            }
+        }
+    } else {
+        skip(stream, content_length).await?;
+    }
+    Ok(Response { status, keep_alive })
+}
+
+async fn skip(stream: &mut BufReader<Box<dyn Io>>, len: u64) -> io::Result<()> {
+    if len > MAX_RESPONSE_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "HTTP response body is too large",
+        ));
+    }
+    let skipped = tokio::io::copy(&mut stream.take(len), &mut tokio::io::sink()).await?;
+    if skipped < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Makes connection attempts until one succeeds, backing off between
+/// failures like [`api::try_connect_websocket`] and giving up after
+/// `max_retries`, or right away on an error retrying won't fix.
+async fn with_retries<T, F, Fut>(
+    server: &str,
+    connection: &ConnectionConfig,
+    mut attempt: F,
+) -> Result<T, ConnectError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ConnectError>>,
+{
+    let mut retry_count = 0;
+    loop {
+        let error = match attempt().await {
+            Ok(connected) => return Ok(connected),
+            Err(ConnectError::Failed(e)) => e,
+            Err(e) => {
+                error!(url = %server, error = %e, "Connection failed");
+                return Err(e);
+            }
+        };
+        error!(url = %server, error = %error, "Connection failed");
+        if connection.max_retries >= 0 && retry_count >= connection.max_retries {
+            error!(url = %server, "Failed to connect after {} attempts", retry_count);
+            return Err(ConnectError::Failed(error));
+        }
+        retry_count += 1;
+        let delay = connection.backoff_delay(retry_count as u32);
+        warn!(
+            retry = retry_count,
+            next_attempt_in = delay,
+            "Connection failed, retrying..."
+        );
+        sleep(Duration::from_secs(delay)).await;
+    }
+}
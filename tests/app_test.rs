@@ -1,34 +1,33 @@
-use vmonitor::app::App;
-use vmonitor::config::{AppConfig, Endpoint, ConnectionConfig};
 use tokio::time::Duration;
+use vmonitor::app::App;
+use vmonitor::config::{AppConfig, ConnectionConfig, Endpoint};
 
 #[tokio::test]
 async fn test_app_startup_shutdown() {
     // Create test config
     let config = AppConfig {
-        endpoints: vec![
-            Endpoint {
-                name: "test".to_string(),
-                server: "wss://test.example.com/ws".to_string(),
-                secret: "test-secret".to_string(),
-                enabled: true,
-                connection: None,
-            }
-        ],
+        endpoints: vec![Endpoint {
+            name: "test".to_string(),
+            server: "wss://test.example.com/ws".to_string(),
+            secret: "test-secret".to_string(),
+            enabled: true,
+            connection: None,
+            ..Default::default()
+        }],
         connection: ConnectionConfig {
             base_delay: 1,
             max_delay: 5,
             max_retries: 1,
+            ..Default::default()
         },
+        ..Default::default()
     };
 
     // Create app instance
-    let app = App::new(config);
+    let app = App::new(config, "config.toml".to_string());
 
     // Run app with timeout
-    let app_handle = tokio::spawn(async move {
-        app.run().await
-    });
+    let app_handle = tokio::spawn(async move { app.run().await });
 
     // Wait for a short period and then send shutdown signal
     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -45,29 +44,28 @@ async fn test_app_startup_shutdown() {
 async fn test_app_with_disabled_endpoints() {
     // Create test config with disabled endpoint
     let config = AppConfig {
-        endpoints: vec![
-            Endpoint {
-                name: "disabled".to_string(),
-                server: "wss://test.example.com/ws".to_string(),
-                secret: "test-secret".to_string(),
-                enabled: false,
-                connection: None,
-            }
-        ],
+        endpoints: vec![Endpoint {
+            name: "disabled".to_string(),
+            server: "wss://test.example.com/ws".to_string(),
+            secret: "test-secret".to_string(),
+            enabled: false,
+            connection: None,
+            ..Default::default()
+        }],
         connection: ConnectionConfig {
             base_delay: 1,
             max_delay: 5,
             max_retries: 1,
+            ..Default::default()
         },
+        ..Default::default()
     };
 
     // Create app instance
-    let app = App::new(config);
+    let app = App::new(config, "config.toml".to_string());
 
     // Run app with timeout
-    let app_handle = tokio::spawn(async move {
-        app.run().await
-    });
+    let app_handle = tokio::spawn(async move { app.run().await });
 
     // Wait for a short period and then send shutdown signal
     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -4,10 +4,10 @@ use tracing_subscriber::{fmt, EnvFilter};
 
 fn setup() {
     // Set up tracing subscriber to output to stderr
-    fmt()
+    let _ = fmt()
         .with_env_filter(EnvFilter::from_default_env())
         .with_writer(std::io::stderr)
-        .init();
+        .try_init();
 }
 
 #[test]
@@ -370,4 +370,779 @@ fn test_cli_nonexistent_endpoint() {
     assert!(!output.status.success());
     let stderr = String::from_utf8_lossy(&output.stderr);
     assert!(stderr.contains("not found"));
-} 
\ No newline at end of file
+}
+
+#[test]
+fn test_cli_collect_unknown_collector() {
+    setup();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--collect")
+        .arg("system,bogus")
+        .arg("version")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("unknown collector 'bogus'"));
+}
+
+#[test]
+fn test_cli_history_rejects_overflowing_duration() {
+    setup();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("history")
+        .arg("--since")
+        .arg("99999999999999999h")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Invalid duration"), "{}", stdout);
+}
+
+#[test]
+fn test_cli_selftest() {
+    setup();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("selftest")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("msgpack: passed"));
+    assert!(stdout.contains("json: passed"));
+    assert!(stdout.contains("all codecs passed"));
+}
+
+#[test]
+fn test_cli_read_only_refuses_changes() {
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    let original = r#"
+        [[endpoints]]
+        name = "test"
+        server = "wss://test.example.com/ws"
+        secret = "test-secret"
+        enabled = true
+        "#;
+    std::fs::write(&config_path, original).unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("--read-only")
+        .arg("add")
+        .arg("--name")
+        .arg("new")
+        .arg("--server")
+        .arg("wss://new.example.com/ws")
+        .arg("--secret")
+        .arg("new-secret")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(!output.status.success());
+    // Errors are logged to stdout
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("config is read-only"), "{}", stdout);
+    assert_eq!(std::fs::read_to_string(&config_path).unwrap(), original);
+
+    // Reading the config is still allowed
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("--read-only")
+        .arg("list")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("test"));
+
+    // The config file can demand the same
+    let read_only = format!("{}\n[security]\nread_only = true\n", original);
+    std::fs::write(&config_path, &read_only).unwrap();
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("disable")
+        .arg("--name")
+        .arg("test")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("config is read-only"), "{}", stdout);
+    assert_eq!(std::fs::read_to_string(&config_path).unwrap(), read_only);
+}
+
+#[test]
+fn test_cli_snapshot() {
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    let snapshot_path = temp_dir.path().join("snapshot.json");
+    std::fs::write(
+        &config_path,
+        r#"
+        [[endpoints]]
+        name = "test"
+        server = "wss://test.example.com/ws"
+        secret = "test-secret"
+        headers = { X-Api-Key = "test-api-key" }
+        "#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("snapshot")
+        .arg("--output")
+        .arg(&snapshot_path)
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let snapshot: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&snapshot_path).unwrap()).unwrap();
+
+    assert!(!snapshot["vm_info"]["hostname"].as_str().unwrap().is_empty());
+    assert!(snapshot["vm_info"]["memory"].as_u64().unwrap() > 0);
+    assert!(
+        snapshot["report"]["system"]["memoryTotal"]
+            .as_u64()
+            .unwrap()
+            > 0
+    );
+    assert!(snapshot["report"]["system"]["cpuUsage"].is_number());
+    assert_eq!(snapshot["config"]["endpoints"][0]["name"], "test");
+    // Secrets never end up in a bundle meant for sharing
+    assert!(snapshot["config"]["endpoints"][0]["secret"].is_null());
+    assert!(snapshot["config"]["endpoints"][0]["headers"]["X-Api-Key"].is_null());
+}
+
+#[test]
+fn test_cli_once() {
+    setup();
+    let temp_dir = tempdir().unwrap();
+    // No config file at all, `once` never reads it
+    let config_path = temp_dir.path().join("missing.toml");
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("--log-level")
+        .arg("off")
+        .arg("once")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let sample: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(sample["system"]["memoryTotal"].as_u64().unwrap() > 0);
+    assert!(!sample["vmInfo"]["hostname"].as_str().unwrap().is_empty());
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--log-level")
+        .arg("off")
+        .arg("once")
+        .arg("--format")
+        .arg("msgpack-hex")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.trim().chars().all(|c| c.is_ascii_hexdigit()),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_cli_test_endpoint() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+
+    // Rejects every handshake like a server that doesn't know the secret
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let rejecting = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.read(&mut [0; 4096]);
+            let _ = stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n");
+        }
+    });
+    // Nothing listens here anymore
+    let unreachable = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+            [[endpoints]]
+            name = "rejecting"
+            server = "ws://{}"
+            secret = "wrong-secret"
+
+            [[endpoints]]
+            name = "unreachable"
+            server = "ws://{}"
+            secret = "test-secret"
+            "#,
+            rejecting, unreachable
+        ),
+    )
+    .unwrap();
+
+    let test = |name: &str| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--config")
+            .arg(&config_path)
+            .arg("test")
+            .arg("--name")
+            .arg(name)
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    let output = test("rejecting");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("rejecting: authentication failed"),
+        "{}",
+        stdout
+    );
+
+    let output = test("unreachable");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("unreachable: connection failed:"),
+        "{}",
+        stdout
+    );
+
+    let output = test("missing");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("'missing' not found"), "{}", stdout);
+}
+
+#[test]
+fn test_cli_edit_endpoint() {
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    std::fs::write(
+        &config_path,
+        r#"
+        [[endpoints]]
+        name = "test"
+        server = "wss://test.example.com/ws"
+        secret = "old-secret"
+
+        [endpoints.connection]
+        base_delay = 2
+        max_delay = 30
+        max_retries = 5
+        "#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("edit")
+        .arg("--name")
+        .arg("test")
+        .arg("--secret")
+        .arg("new-secret")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Endpoint edited successfully"));
+
+    let config: toml::Value =
+        toml::from_str(&std::fs::read_to_string(&config_path).unwrap()).unwrap();
+    let endpoint = &config["endpoints"][0];
+    assert_eq!(endpoint["secret"].as_str(), Some("new-secret"));
+    // Everything else is kept
+    assert_eq!(
+        endpoint["server"].as_str(),
+        Some("wss://test.example.com/ws")
+    );
+    assert_eq!(endpoint["enabled"].as_bool(), Some(true));
+    assert_eq!(endpoint["connection"]["max_retries"].as_integer(), Some(5));
+
+    // Editing an endpoint that doesn't exist fails
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("edit")
+        .arg("--name")
+        .arg("missing")
+        .arg("--secret")
+        .arg("new-secret")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Endpoint with name 'missing' not found"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_cli_add_refuses_invalid_endpoint() {
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    let original = r#"
+        [[endpoints]]
+        name = "test"
+        server = "wss://test.example.com/ws"
+        secret = "test-secret"
+        "#;
+    std::fs::write(&config_path, original).unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("add")
+        .arg("--name")
+        .arg("plain")
+        .arg("--server")
+        .arg("http://h")
+        .arg("--secret")
+        .arg("test-secret")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Refusing to save an invalid config"), "{}", stdout);
+    // The config is left as it was
+    assert_eq!(std::fs::read_to_string(&config_path).unwrap(), original);
+}
+
+#[test]
+fn test_cli_refuses_to_change_template_endpoints() {
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    let original = r#"
+        endpoints = []
+
+        [[endpoint_template]]
+        name = "fleet"
+        server = "wss://collector.example.com/hosts/${HOSTNAME}"
+        secret = "fleet-token"
+        "#;
+    std::fs::write(&config_path, original).unwrap();
+
+    for args in [
+        vec!["remove", "--name", "fleet"],
+        vec!["enable", "--name", "fleet"],
+        vec!["disable", "--name", "fleet"],
+        vec!["edit", "--name", "fleet", "--secret", "new-secret"],
+    ] {
+        let output = Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--config")
+            .arg(&config_path)
+            .args(&args)
+            .output()
+            .expect("Failed to execute command");
+
+        assert!(!output.status.success(), "{:?}", args);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("[[endpoint_template]]"), "{}", stdout);
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap(), original);
+    }
+}
+
+#[test]
+fn test_cli_validate() {
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    let validate = || {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--config")
+            .arg(&config_path)
+            .arg("validate")
+            .output()
+            .expect("Failed to execute command")
+    };
+
+    std::fs::write(
+        &config_path,
+        r#"
+        [[endpoints]]
+        name = "test"
+        server = "wss://test.example.com/ws"
+        secret = "test-secret"
+        "#,
+    )
+    .unwrap();
+    let output = validate();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(String::from_utf8_lossy(&output.stdout).contains("OK, 1 endpoint(s)"));
+
+    let broken = [
+        (
+            r#"
+            [[endpoints]]
+            name = "typo"
+            server = "test.example.com/ws"
+            secret = "test-secret"
+            "#,
+            "endpoint 'typo': 'test.example.com/ws' is missing the ws:// or wss:// scheme",
+        ),
+        (
+            r#"
+            [[endpoints]]
+            name = "nosecret"
+            server = "wss://test.example.com/ws"
+            secret = ""
+            "#,
+            "endpoint 'nosecret': secret is empty",
+        ),
+        (
+            r#"
+            endpoints = []
+
+            [connection]
+            base_delay = 60
+            max_delay = 10
+            max_retries = -1
+            "#,
+            "connection: base_delay (60) is greater than max_delay (10)",
+        ),
+        (
+            r#"
+            [[endpoints]]
+            name = "twice"
+            server = "wss://a.example.com/ws"
+            secret = "test-secret"
+
+            [[endpoints]]
+            name = "twice"
+            server = "wss://b.example.com/ws"
+            secret = "test-secret"
+            "#,
+            "duplicate endpoint name 'twice'",
+        ),
+    ];
+    for (config, complaint) in broken {
+        std::fs::write(&config_path, config).unwrap();
+        let output = validate();
+        assert!(!output.status.success(), "{}", config);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(complaint), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_cli_list_endpoints_as_json() {
+    #[derive(serde::Deserialize)]
+    struct Listed {
+        name: String,
+        server: String,
+        enabled: bool,
+        has_connection_override: bool,
+    }
+
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    std::fs::write(
+        &config_path,
+        r#"
+        [[endpoints]]
+        name = "primary"
+        server = "wss://primary.example.com/ws"
+        secret = "test-secret"
+
+        [[endpoints]]
+        name = "backup"
+        server = "wss://backup.example.com/ws"
+        secret = "test-secret"
+        enabled = false
+
+        [endpoints.connection]
+        base_delay = 2
+        max_delay = 30
+        max_retries = 5
+        "#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("list")
+        .arg("--format")
+        .arg("json")
+        .output()
+        .expect("Failed to execute command");
+
+    assert!(output.status.success());
+    let listed: Vec<Listed> = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(listed.len(), 2);
+    assert_eq!(listed[0].name, "primary");
+    assert_eq!(listed[0].server, "wss://primary.example.com/ws");
+    assert!(listed[0].enabled);
+    assert!(!listed[0].has_connection_override);
+    assert_eq!(listed[1].name, "backup");
+    assert!(!listed[1].enabled);
+    assert!(listed[1].has_connection_override);
+}
+
+#[test]
+fn test_cli_replay_sends_samples_at_scaled_pace() {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+    use tokio_tungstenite::tungstenite::{accept, Message};
+
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    let samples_path = temp_dir.path().join("samples.jsonl");
+
+    // Records when each metrics frame arrives and its uptime
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (frames_tx, frames_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = accept(stream).unwrap();
+        while let Ok(message) = ws.read() {
+            if let Message::Binary(binary) = message {
+                let message: serde_json::Value = rmp_serde::from_slice(&binary).unwrap();
+                let _ = frames_tx.send((Instant::now(), message));
+            }
+        }
+    });
+
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+            [[endpoints]]
+            name = "collector"
+            server = "ws://{}"
+            secret = "test-secret"
+            "#,
+            addr
+        ),
+    )
+    .unwrap();
+    // Recorded a second apart
+    std::fs::write(
+        &samples_path,
+        r#"{"collectedAt":1700000000000,"uptime":1}
+{"collectedAt":1700000001000,"uptime":2}
+
+{"collectedAt":1700000002000,"uptime":3}
+"#,
+    )
+    .unwrap();
+
+    let output = Command::new("cargo")
+        .arg("run")
+        .arg("--")
+        .arg("--config")
+        .arg(&config_path)
+        .arg("replay")
+        .arg(&samples_path)
+        .arg("--endpoint")
+        .arg("collector")
+        .arg("--speed")
+        .arg("4")
+        .output()
+        .expect("Failed to execute command");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains("Replayed 3 sample(s) to collector"),
+        "{}",
+        stdout
+    );
+
+    let frames: Vec<(Instant, serde_json::Value)> = (0..3)
+        .map(|_| frames_rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    for (index, (_, message)) in frames.iter().enumerate() {
+        assert_eq!(message["type"], "metrics");
+        assert_eq!(message["data"]["uptime"], index as u64 + 1);
+    }
+    // A quarter of the recorded second between frames
+    for pair in frames.windows(2) {
+        let gap = pair[1].0 - pair[0].0;
+        assert!(gap >= Duration::from_millis(150), "{:?}", gap);
+        assert!(gap < Duration::from_millis(900), "{:?}", gap);
+    }
+}
+
+#[test]
+fn test_cli_replay_encodes_samples_like_the_daemon() {
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tokio_tungstenite::tungstenite::{accept, Message};
+
+    setup();
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    let samples_path = temp_dir.path().join("samples.jsonl");
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (frames_tx, frames_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        while let Ok((stream, _)) = listener.accept() {
+            let Ok(mut ws) = accept(stream) else {
+                continue;
+            };
+            while let Ok(message) = ws.read() {
+                if let Message::Binary(binary) = message {
+                    let message: serde_json::Value = rmp_serde::from_slice(&binary).unwrap();
+                    let _ = frames_tx.send(message);
+                }
+            }
+        }
+    });
+
+    std::fs::write(
+        &config_path,
+        format!(
+            r#"
+            [report]
+            timestamp_source = "monotonic"
+
+            [field_map]
+            uptime = "up"
+
+            [[endpoints]]
+            name = "collector"
+            server = "ws://{}"
+            secret = "test-secret"
+            format = "array"
+            "#,
+            addr
+        ),
+    )
+    .unwrap();
+    std::fs::write(
+        &samples_path,
+        r#"{"collectedAt":1700000000000,"uptime":1}
+{"collectedAt":1700000001000,"uptime":2}
+"#,
+    )
+    .unwrap();
+
+    let replay = |speed: &str| {
+        Command::new("cargo")
+            .arg("run")
+            .arg("--")
+            .arg("--config")
+            .arg(&config_path)
+            .arg("replay")
+            .arg(&samples_path)
+            .arg("--endpoint")
+            .arg("collector")
+            .arg("--speed")
+            .arg(speed)
+            .output()
+            .expect("Failed to execute command")
+    };
+    let output = replay("100");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    // The schema goes first, with the mapped names and the configured clock
+    let frames: Vec<serde_json::Value> = (0..3)
+        .map(|_| frames_rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(frames[0]["type"], "metrics_schema");
+    let schema = frames[0]["data"].to_string();
+    assert!(schema.contains("\"up\""), "{}", schema);
+    assert!(schema.contains("monoNs"), "{}", schema);
+    assert!(!schema.contains("collectedAt"), "{}", schema);
+    assert_eq!(frames[1]["type"], "metrics_array");
+    assert_eq!(frames[2]["type"], "metrics_array");
+
+    // A pace too slow to wait for is an error, not a panic
+    let output = replay("1e-300");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("can't wait"), "{}", stdout);
+}
@@ -1,12 +1,19 @@
 mod common;
 
+use common::TestConfig;
+use std::collections::HashMap;
 use std::fs;
 use std::time::Duration;
 use tempfile::tempdir;
-use tokio::time::sleep;
-use vmonitor::app::App;
-use vmonitor::config::{AppConfig, Endpoint, ConnectionConfig};
-use common::TestConfig;
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout};
+use vmonitor::app::{App, ReloadThrottle};
+use vmonitor::config::{
+    AppConfig, Compression, CompressionConfig, ConfigChange, ConnectionConfig, Encoding, Endpoint,
+    SinkConfig, SnmpDevice, TransportKind,
+};
+use vmonitor::monitor::MonitorEvent;
+use vmonitor::signing;
 
 fn create_default_config() -> AppConfig {
     AppConfig {
@@ -15,7 +22,9 @@ fn create_default_config() -> AppConfig {
             base_delay: 1,
             max_delay: 60,
             max_retries: -1,
+            ..Default::default()
         },
+        ..Default::default()
     }
 }
 
@@ -28,10 +37,11 @@ fn test_endpoint_with_defaults() {
         secret: "test-secret".to_string(),
         enabled: true,
         connection: None,
+        ..Default::default()
     };
 
     assert_eq!(
-        endpoint.connection.clone().unwrap_or_else(|| default_config.connection.clone()),
+        endpoint.connection.unwrap_or(default_config.connection),
         default_config.connection
     );
 }
@@ -43,6 +53,7 @@ fn test_endpoint_with_overrides() {
         base_delay: 2,
         max_delay: 30,
         max_retries: 3,
+        ..Default::default()
     };
 
     let endpoint = Endpoint {
@@ -50,10 +61,14 @@ fn test_endpoint_with_overrides() {
         server: "ws://test.com".to_string(),
         secret: "test-secret".to_string(),
         enabled: true,
-        connection: Some(custom_connection.clone()),
+        connection: Some(custom_connection),
+        ..Default::default()
     };
 
-    assert_eq!(endpoint.connection.clone().unwrap_or_else(|| default_config.connection.clone()), custom_connection);
+    assert_eq!(
+        endpoint.connection.unwrap_or(default_config.connection),
+        custom_connection
+    );
 }
 
 #[test]
@@ -66,6 +81,7 @@ fn test_config_serialization() {
                 secret: "secret1".to_string(),
                 enabled: true,
                 connection: None,
+                ..Default::default()
             },
             Endpoint {
                 name: "test2".to_string(),
@@ -76,14 +92,18 @@ fn test_config_serialization() {
                     base_delay: 2,
                     max_delay: 30,
                     max_retries: 3,
+                    ..Default::default()
                 }),
+                ..Default::default()
             },
         ],
         connection: ConnectionConfig {
             base_delay: 1,
             max_delay: 60,
             max_retries: -1,
+            ..Default::default()
         },
+        ..Default::default()
     };
 
     let serialized = toml::to_string_pretty(&config).unwrap();
@@ -145,27 +165,30 @@ async fn test_dynamic_endpoint_management() {
 
     // Create initial config with one endpoint
     let initial_config = AppConfig {
-        endpoints: vec![
-            Endpoint {
-                name: "test1".to_string(),
-                server: "wss://test1.example.com/ws".to_string(),
-                secret: "secret1".to_string(),
-                enabled: true,
-                connection: None,
-            }
-        ],
+        endpoints: vec![Endpoint {
+            name: "test1".to_string(),
+            server: "wss://test1.example.com/ws".to_string(),
+            secret: "secret1".to_string(),
+            enabled: true,
+            connection: None,
+            ..Default::default()
+        }],
         connection: ConnectionConfig {
             base_delay: 1,
             max_delay: 5,
             max_retries: 1,
+            ..Default::default()
         },
+        ..Default::default()
     };
 
     // Save initial config
-    initial_config.save_to_file(config_path.to_str().unwrap()).unwrap();
+    initial_config
+        .save_to_file(config_path.to_str().unwrap())
+        .unwrap();
 
     // Create app instance
-    let app = App::new(initial_config);
+    let app = App::new(initial_config, config_path.to_str().unwrap().to_string());
 
     // Spawn app in background
     let app_handle = tokio::spawn(async move {
@@ -183,6 +206,7 @@ async fn test_dynamic_endpoint_management() {
         secret: "secret2".to_string(),
         enabled: true,
         connection: None,
+        ..Default::default()
     });
     config.save_to_file(config_path.to_str().unwrap()).unwrap();
 
@@ -224,33 +248,38 @@ async fn test_dynamic_endpoint_management() {
 
 #[tokio::test]
 async fn test_config_file_monitoring() {
-    // Create a temporary directory for our test config
+    // Create a temporary directory for our test config, away from the
+    // default `config.toml`
     let temp_dir = tempdir().unwrap();
     let config_path = temp_dir.path().join("test_config.toml");
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
     // Create initial config
     let initial_config = AppConfig {
-        endpoints: vec![
-            Endpoint {
-                name: "test".to_string(),
-                server: "wss://test.example.com/ws".to_string(),
-                secret: "secret".to_string(),
-                enabled: true,
-                connection: None,
-            }
-        ],
+        endpoints: vec![Endpoint {
+            name: "test".to_string(),
+            server: "wss://test.example.com/ws".to_string(),
+            secret: "secret".to_string(),
+            enabled: true,
+            connection: None,
+            ..Default::default()
+        }],
         connection: ConnectionConfig {
             base_delay: 1,
             max_delay: 5,
             max_retries: 1,
+            ..Default::default()
         },
+        ..Default::default()
     };
 
     // Save initial config
-    initial_config.save_to_file(config_path.to_str().unwrap()).unwrap();
+    initial_config
+        .save_to_file(config_path.to_str().unwrap())
+        .unwrap();
 
     // Create app instance
-    let app = App::new(initial_config);
+    let app = App::new(initial_config, config_path.to_str().unwrap().to_string());
 
     // Spawn app in background
     let app_handle = tokio::spawn(async move {
@@ -262,37 +291,165 @@ async fn test_config_file_monitoring() {
 
     // Test: Corrupt config file
     fs::write(&config_path, "invalid toml content").unwrap();
-    
+
     // Wait for config monitoring to detect the change
     sleep(Duration::from_secs(2)).await;
 
-    // Restore valid config
+    // Restore valid config, now pointing at a server we can observe
     let valid_config = AppConfig {
-        endpoints: vec![
-            Endpoint {
-                name: "test".to_string(),
-                server: "wss://test.example.com/ws".to_string(),
-                secret: "secret".to_string(),
-                enabled: true,
-                connection: None,
-            }
-        ],
+        endpoints: vec![Endpoint {
+            name: "test".to_string(),
+            server: format!("ws://{}", listener.local_addr().unwrap()),
+            secret: "secret".to_string(),
+            enabled: true,
+            connection: None,
+            ..Default::default()
+        }],
         connection: ConnectionConfig {
             base_delay: 1,
             max_delay: 5,
             max_retries: 1,
+            ..Default::default()
         },
+        ..Default::default()
     };
-    valid_config.save_to_file(config_path.to_str().unwrap()).unwrap();
+    valid_config
+        .save_to_file(config_path.to_str().unwrap())
+        .unwrap();
 
-    // Wait for config to be reloaded
-    sleep(Duration::from_secs(2)).await;
+    // The reload of the file at `config_path` connects the new endpoint
+    timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("config at the given path was not reloaded")
+        .unwrap();
 
     // Clean up
     app_handle.abort();
     let _ = app_handle.await;
 }
 
+#[tokio::test]
+async fn test_reload_restarts_only_changed_endpoints() {
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+
+    // A server per endpoint that keeps every connection open
+    let mut endpoints = Vec::new();
+    for name in ["a", "b", "c"] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        endpoints.push(Endpoint {
+            name: name.to_string(),
+            server: format!("ws://{}", listener.local_addr().unwrap()),
+            secret: "secret".to_string(),
+            ..Default::default()
+        });
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(ws) = tokio_tungstenite::accept_async(stream).await {
+                    connections.push(ws);
+                }
+            }
+        });
+    }
+    let mut config = AppConfig {
+        endpoints,
+        connection: ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: -1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    config.save_to_file(config_path.to_str().unwrap()).unwrap();
+
+    let app = App::new(config.clone(), config_path.to_str().unwrap().to_string());
+    let mut events = app.subscribe();
+    let app_handle = tokio::spawn(async move { app.run().await });
+
+    // Counts connections per endpoint until `expected` have been seen
+    async fn count_until(
+        events: &mut tokio::sync::broadcast::Receiver<MonitorEvent>,
+        connected: &mut HashMap<String, usize>,
+        expected: usize,
+    ) {
+        timeout(Duration::from_secs(5), async {
+            while connected.values().sum::<usize>() < expected {
+                if let Ok(MonitorEvent::Connected { endpoint }) = events.recv().await {
+                    *connected.entry(endpoint).or_insert(0) += 1;
+                }
+            }
+        })
+        .await
+        .expect("endpoints did not connect");
+    }
+    let mut connected = HashMap::new();
+    count_until(&mut events, &mut connected, 3).await;
+
+    config.endpoints[1].secret = "rotated".to_string();
+    config.save_to_file(config_path.to_str().unwrap()).unwrap();
+
+    count_until(&mut events, &mut connected, 4).await;
+    // Give a wrongly restarted endpoint time to show up too
+    sleep(Duration::from_millis(500)).await;
+    while let Ok(event) = events.try_recv() {
+        assert!(
+            !matches!(event, MonitorEvent::Connected { .. }),
+            "{:?}",
+            event
+        );
+    }
+    assert_eq!(connected["a"], 1);
+    assert_eq!(connected["b"], 2);
+    assert_eq!(connected["c"], 1);
+
+    app_handle.abort();
+    let _ = app_handle.await;
+}
+
+#[tokio::test]
+async fn test_persistent_reload_failure_escalates() {
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+    let marker = temp_dir.path().join("alerted");
+
+    let config = AppConfig {
+        reload_failure_threshold: 3,
+        reload_failure_command: Some(format!(
+            "echo \"$VMONITOR_RELOAD_ERROR\" > {}",
+            marker.display()
+        )),
+        ..create_default_config()
+    };
+    config.save_to_file(config_path.to_str().unwrap()).unwrap();
+
+    let app = App::new(config, config_path.to_str().unwrap().to_string());
+    let app_handle = tokio::spawn(async move { app.run().await });
+    sleep(Duration::from_millis(500)).await;
+
+    // The path becomes a directory, which can never be read as a config
+    fs::remove_file(&config_path).unwrap();
+    fs::create_dir(&config_path).unwrap();
+
+    // The first failures only warn
+    sleep(Duration::from_millis(1000)).await;
+    assert!(!marker.exists());
+
+    timeout(Duration::from_secs(5), async {
+        while !marker.exists() {
+            sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("reload failure command did not run");
+    sleep(Duration::from_millis(100)).await;
+    assert!(!fs::read_to_string(&marker).unwrap().trim().is_empty());
+
+    app_handle.abort();
+    let _ = app_handle.await;
+}
+
 #[tokio::test]
 async fn test_concurrent_config_changes() {
     // Create a temporary directory for our test config
@@ -301,27 +458,30 @@ async fn test_concurrent_config_changes() {
 
     // Create initial config
     let initial_config = AppConfig {
-        endpoints: vec![
-            Endpoint {
-                name: "test".to_string(),
-                server: "wss://test.example.com/ws".to_string(),
-                secret: "secret".to_string(),
-                enabled: true,
-                connection: None,
-            }
-        ],
+        endpoints: vec![Endpoint {
+            name: "test".to_string(),
+            server: "wss://test.example.com/ws".to_string(),
+            secret: "secret".to_string(),
+            enabled: true,
+            connection: None,
+            ..Default::default()
+        }],
         connection: ConnectionConfig {
             base_delay: 1,
             max_delay: 5,
             max_retries: 1,
+            ..Default::default()
         },
+        ..Default::default()
     };
 
     // Save initial config
-    initial_config.save_to_file(config_path.to_str().unwrap()).unwrap();
+    initial_config
+        .save_to_file(config_path.to_str().unwrap())
+        .unwrap();
 
     // Create app instance
-    let app = App::new(initial_config);
+    let app = App::new(initial_config, config_path.to_str().unwrap().to_string());
 
     // Spawn app in background
     let app_handle = tokio::spawn(async move {
@@ -345,4 +505,827 @@ async fn test_concurrent_config_changes() {
     // Clean up
     app_handle.abort();
     let _ = app_handle.await;
-} 
\ No newline at end of file
+}
+
+#[test]
+fn test_rapid_config_changes_are_coalesced() {
+    let mut throttle = ReloadThrottle::new(Duration::from_secs(2));
+    let start = tokio::time::Instant::now();
+
+    // Make 10 rapid edits, 100ms apart
+    let mut reconciles = 0;
+    for i in 0..10 {
+        if throttle.try_acquire(start + Duration::from_millis(100 * i)) {
+            reconciles += 1;
+        }
+    }
+    assert_eq!(reconciles, 1);
+
+    // The latest version is applied once the window has passed
+    assert!(throttle.try_acquire(start + Duration::from_secs(2)));
+}
+
+#[test]
+fn test_secret_file_rotation() {
+    let test_config = TestConfig::new();
+    let secret_path = test_config.config_path.with_file_name("token");
+    std::fs::write(&secret_path, "first-token\n").unwrap();
+
+    let endpoint = Endpoint {
+        name: "test".to_string(),
+        server: "ws://test.com".to_string(),
+        secret: format!("file:{}", secret_path.to_str().unwrap()),
+        enabled: true,
+        connection: None,
+        ..Default::default()
+    };
+    assert_eq!(endpoint.resolve_secret().unwrap(), "first-token");
+
+    // The next connection attempt picks up the rotated token
+    std::fs::write(&secret_path, "second-token\n").unwrap();
+    assert_eq!(endpoint.resolve_secret().unwrap(), "second-token");
+
+    // Literal secrets are used as-is
+    let literal = Endpoint {
+        secret: "literal".to_string(),
+        ..endpoint
+    };
+    assert_eq!(literal.resolve_secret().unwrap(), "literal");
+}
+
+fn write_signed_config(test_config: &TestConfig) -> (String, Vec<u8>) {
+    let (private_key, public_key) = signing::generate_key_pair().unwrap();
+    let public_key = signing::parse_public_key(&public_key).unwrap();
+
+    let mut config = create_default_config();
+    config.endpoints.push(Endpoint {
+        name: "signed".to_string(),
+        server: "wss://collector.example.com".to_string(),
+        secret: "secret".to_string(),
+        ..Default::default()
+    });
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    config.save_to_file(&path).unwrap();
+
+    let signature = signing::sign(&fs::read(&path).unwrap(), &private_key).unwrap();
+    fs::write(signing::signature_path(&path), signature).unwrap();
+    (path, public_key)
+}
+
+#[test]
+fn test_signed_config_loads() {
+    let test_config = TestConfig::new();
+    let (path, public_key) = write_signed_config(&test_config);
+
+    let config = AppConfig::from_signed_file(&path, &public_key).unwrap();
+    assert_eq!(config.endpoints[0].server, "wss://collector.example.com");
+}
+
+#[test]
+fn test_tampered_config_is_rejected() {
+    let test_config = TestConfig::new();
+    let (path, public_key) = write_signed_config(&test_config);
+
+    // Redirect telemetry without re-signing
+    let tampered = fs::read_to_string(&path)
+        .unwrap()
+        .replace("collector.example.com", "attacker.example.net");
+    fs::write(&path, tampered).unwrap();
+
+    let err = AppConfig::from_signed_file(&path, &public_key).unwrap_err();
+    assert!(err.contains("signature does not match"), "{}", err);
+}
+
+#[test]
+fn test_fingerprint_tracks_config_changes_but_not_secrets() {
+    let mut config = create_default_config();
+    config.endpoints.push(Endpoint {
+        name: "primary".to_string(),
+        server: "wss://a.example.com".to_string(),
+        secret: "secret-1".to_string(),
+        ..Default::default()
+    });
+    let fingerprint = config.fingerprint();
+    assert_eq!(fingerprint.len(), 12);
+    assert_eq!(config.fingerprint(), fingerprint);
+
+    config.endpoints[0].secret = "secret-2".to_string();
+    assert_eq!(config.fingerprint(), fingerprint);
+
+    config.endpoints[0]
+        .headers
+        .insert("X-Api-Key".to_string(), "key-1".to_string());
+    let with_header = config.fingerprint();
+    config.endpoints[0]
+        .headers
+        .insert("X-Api-Key".to_string(), "key-2".to_string());
+    assert_eq!(config.fingerprint(), with_header);
+    let redacted = config.redacted();
+    assert!(redacted["endpoints"][0]["headers"]["X-Api-Key"].is_null());
+
+    config.snmp.push(SnmpDevice {
+        name: None,
+        host: "10.0.0.2".to_string(),
+        community: "private-1".to_string(),
+        oids: vec!["1.3.6.1.2.1.1.3.0".to_string()],
+    });
+    let with_device = config.fingerprint();
+    config.snmp[0].community = "private-2".to_string();
+    assert_eq!(config.fingerprint(), with_device);
+    assert!(config.redacted()["snmp"][0]["community"].is_null());
+    config.snmp.clear();
+
+    config.endpoints[0].server = "wss://b.example.com".to_string();
+    assert_ne!(config.fingerprint(), fingerprint);
+}
+
+#[test]
+fn test_reloading_an_unchanged_file_is_not_a_change() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    fs::write(
+        &path,
+        r#"
+        [connection]
+        base_delay = 2
+        max_delay = 30
+
+        [[endpoints]]
+        name = "primary"
+        server = "wss://collector.example.com"
+        secret = "secret"
+        "#,
+    )
+    .unwrap();
+
+    // Loaded at startup the way main does, then again by the file watcher
+    let (started, _) = AppConfig::load_or_last_known_good(&path, None).unwrap();
+    let reloaded = AppConfig::load(&path, None).unwrap();
+    assert_eq!(reloaded, started);
+    assert_eq!(reloaded.fingerprint(), started.fingerprint());
+    assert!(started.diff(&reloaded).changes.is_empty());
+    // The endpoint still gets the shared settings
+    assert_eq!(started.endpoints[0].connection, None);
+    assert_eq!(started.connection_for(&started.endpoints[0]).max_delay, 30);
+}
+
+#[test]
+fn test_read_only_config_refuses_save() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    fs::write(&path, "original").unwrap();
+
+    let mut config = create_default_config();
+    config.security.read_only = true;
+    let err = config.save_to_file(&path).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    assert!(err.to_string().contains("read-only"));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+}
+
+#[test]
+fn test_duplicate_file_sink_path_is_rejected() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    fs::write(
+        &path,
+        r#"
+        endpoints = []
+
+        [[sinks]]
+        kind = "file"
+        path = "/var/log/vmonitor/samples.jsonl"
+
+        [[sinks]]
+        kind = "file"
+        path = "/var/log/vmonitor/samples.jsonl"
+        "#,
+    )
+    .unwrap();
+
+    let err = AppConfig::from_file(&path).unwrap_err().to_string();
+    assert!(err.contains("/var/log/vmonitor/samples.jsonl"), "{}", err);
+}
+
+#[test]
+fn test_connection_delays_are_validated() {
+    let delays = |base_delay, max_delay| ConnectionConfig {
+        base_delay,
+        max_delay,
+        ..Default::default()
+    };
+    assert!(delays(1, 60).validate().is_ok());
+    assert!(delays(30, 30).validate().is_ok());
+
+    let err = delays(100, 10).validate().unwrap_err();
+    assert!(
+        err.contains("base_delay (100) is greater than max_delay (10)"),
+        "{}",
+        err
+    );
+    assert!(delays(0, 10).validate().is_err());
+    let no_pong_timeout = ConnectionConfig {
+        pong_timeout: 0,
+        ..Default::default()
+    };
+    assert!(no_pong_timeout.validate().is_err());
+
+    // Loading reports which endpoint is wrong
+    let mut config = create_default_config();
+    config.endpoints.push(Endpoint {
+        name: "slow".to_string(),
+        server: "wss://slow.example.com/ws".to_string(),
+        secret: "secret".to_string(),
+        connection: Some(delays(100, 10)),
+        ..Default::default()
+    });
+    let err = config.validate().unwrap_err();
+    assert!(err.starts_with("endpoint 'slow': base_delay"), "{}", err);
+
+    config.endpoints.clear();
+    config.connection = delays(0, 10);
+    let err = config.validate().unwrap_err();
+    assert!(err.starts_with("connection: base_delay"), "{}", err);
+}
+
+#[test]
+fn test_compression_level_is_validated_per_algorithm() {
+    let mut config = create_default_config();
+    config.compression = CompressionConfig {
+        algorithm: Compression::Gzip,
+        level: Some(9),
+    };
+    assert!(config.validate().is_ok());
+
+    config.compression.level = Some(12);
+    assert_eq!(
+        config.validate().unwrap_err(),
+        "compression: level 12 is out of range for gzip (0-9)"
+    );
+
+    // The level also has to suit an algorithm only a connection offers
+    config.compression = CompressionConfig {
+        algorithm: Compression::None,
+        level: Some(0),
+    };
+    assert!(config.validate().is_ok());
+    config.endpoints.push(Endpoint {
+        name: "zstd".to_string(),
+        server: "wss://zstd.example.com/ws".to_string(),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            compression: Some(Compression::Zstd),
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    let err = config.validate().unwrap_err();
+    assert!(err.starts_with("endpoint 'zstd': "), "{}", err);
+}
+
+#[test]
+fn test_duplicate_endpoint_names_are_rejected() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    let mut contents = String::new();
+    for name in ["web", "db", "web", "cache", "db"] {
+        contents.push_str(&format!(
+            "[[endpoints]]\nname = \"{}\"\nserver = \"wss://{}.example.com/ws\"\nsecret = \"secret\"\n\n",
+            name, name
+        ));
+    }
+    fs::write(&path, contents).unwrap();
+
+    let err = AppConfig::from_file(&path).unwrap_err().to_string();
+    assert!(
+        err.contains("duplicate endpoint names 'db', 'web'"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_conflicting_names_and_targets_are_rejected() {
+    let mut config = create_default_config();
+    for server in ["wss://a.example.com", "wss://b.example.com"] {
+        config.endpoints.push(Endpoint {
+            name: "primary".to_string(),
+            server: server.to_string(),
+            secret: "secret".to_string(),
+            ..Default::default()
+        });
+    }
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("'primary'"), "{}", err);
+    config.endpoints.pop();
+
+    // A file sink may not write over the control socket
+    config.control.socket = Some("/run/vmonitor/control.sock".to_string());
+    config.sinks.push(SinkConfig::File {
+        path: "/run/vmonitor/control.sock".to_string(),
+    });
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("/run/vmonitor/control.sock"), "{}", err);
+    config.sinks.clear();
+
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_unsupported_server_scheme_is_rejected() {
+    let mut config = create_default_config();
+    config.endpoints.push(Endpoint {
+        name: "typo".to_string(),
+        server: "wss//example.com/ws".to_string(),
+        secret: "secret".to_string(),
+        ..Default::default()
+    });
+    assert_eq!(
+        config.validate().unwrap_err(),
+        "endpoint 'typo': 'wss//example.com/ws' is missing the ws:// or wss:// scheme"
+    );
+
+    config.endpoints[0].server = "grpc://example.com".to_string();
+    assert_eq!(
+        config.validate().unwrap_err(),
+        "endpoint 'typo': unsupported scheme 'grpc' in 'grpc://example.com', expected ws:// or wss://"
+    );
+
+    config.endpoints[0].server = "wss://example.com/ws".to_string();
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn test_websocket_msgpack_endpoint_loads() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    fs::write(
+        &path,
+        r#"
+        [[endpoints]]
+        name = "web"
+        server = "wss://collector.example.com/ws"
+        secret = "s3cret"
+        transport = "websocket"
+        encoding = "msgpack"
+        allow_remote_reconfigure = true
+        "#,
+    )
+    .unwrap();
+
+    let config = AppConfig::from_file(&path).unwrap();
+    assert_eq!(config.endpoints[0].transport, TransportKind::WebSocket);
+    assert_eq!(config.endpoints[0].encoding, Encoding::Msgpack);
+}
+
+#[test]
+fn test_http_json_endpoint_loads() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    fs::write(
+        &path,
+        r#"
+        [[endpoints]]
+        name = "ingest"
+        server = "https://ingest.example.com"
+        path = "/v1/metrics"
+        secret = "s3cret"
+        transport = "http"
+        encoding = "json"
+        "#,
+    )
+    .unwrap();
+
+    let config = AppConfig::from_file(&path).unwrap();
+    assert_eq!(config.endpoints[0].transport, TransportKind::Http);
+    assert_eq!(config.endpoints[0].encoding, Encoding::Json);
+}
+
+#[test]
+fn test_unsupported_transport_combination_is_rejected_at_load() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    fs::write(
+        &path,
+        r#"
+        [[endpoints]]
+        name = "ingest"
+        server = "https://ingest.example.com"
+        secret = "s3cret"
+        transport = "http"
+        encoding = "json"
+        allow_remote_reconfigure = true
+        "#,
+    )
+    .unwrap();
+
+    let err = AppConfig::from_file(&path).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "endpoint 'ingest': allow_remote_reconfigure needs commands from the server, which aren't available over transport = \"http\""
+    );
+
+    // The scheme has to match the transport too
+    fs::write(
+        &path,
+        r#"
+        [[endpoints]]
+        name = "ingest"
+        server = "wss://ingest.example.com"
+        secret = "s3cret"
+        transport = "http"
+        "#,
+    )
+    .unwrap();
+    let err = AppConfig::from_file(&path).unwrap_err().to_string();
+    assert!(err.contains("expected http:// or https://"), "{}", err);
+}
+
+#[tokio::test]
+async fn test_endpoint_with_unsupported_scheme_does_not_stop_others() {
+    let temp_dir = tempdir().unwrap();
+    let config_path = temp_dir.path().join("test_config.toml");
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            if let Ok(ws) = tokio_tungstenite::accept_async(stream).await {
+                connections.push(ws);
+            }
+        }
+    });
+    // Built directly, so the bad endpoint gets past validation
+    let mut config = create_default_config();
+    for (name, server) in [("bad", "grpc://example.com".to_string()), ("good", server)] {
+        config.endpoints.push(Endpoint {
+            name: name.to_string(),
+            server,
+            secret: "secret".to_string(),
+            ..Default::default()
+        });
+    }
+
+    let app = App::new(config, config_path.to_str().unwrap().to_string());
+    let mut events = app.subscribe();
+    let app_handle = tokio::spawn(async move { app.run().await });
+
+    let endpoint = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(MonitorEvent::Connected { endpoint }) = events.recv().await {
+                return endpoint;
+            }
+        }
+    })
+    .await
+    .expect("the good endpoint did not connect");
+    assert_eq!(endpoint, "good");
+
+    app_handle.abort();
+    let _ = app_handle.await;
+}
+
+#[test]
+fn test_corrupt_config_falls_back_to_last_known_good() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    let mut config = create_default_config();
+    config.endpoints.push(Endpoint {
+        name: "primary".to_string(),
+        server: "wss://collector.example.com".to_string(),
+        secret: "secret".to_string(),
+        ..Default::default()
+    });
+    config.save_to_file(&path).unwrap();
+
+    let (loaded, error) = AppConfig::load_or_last_known_good(&path, None).unwrap();
+    assert!(error.is_none());
+    assert_eq!(loaded.endpoints[0].name, "primary");
+
+    // A deploy pushes a broken config
+    fs::write(&path, "endpoints = [ this is not toml").unwrap();
+    let (loaded, error) = AppConfig::load_or_last_known_good(&path, None).unwrap();
+    assert!(error.is_some());
+    assert_eq!(loaded.endpoints[0].server, "wss://collector.example.com");
+
+    // Without a cache there is nothing to fall back to
+    fs::remove_file(vmonitor::config::last_known_good_path(&path)).unwrap();
+    assert!(AppConfig::load_or_last_known_good(&path, None).is_err());
+}
+
+#[test]
+fn test_endpoint_template_expands_host_values() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    std::env::set_var("VMONITOR_TEST_FLEET_TOKEN", "fleet-token");
+    fs::write(
+        &path,
+        r#"
+        endpoints = []
+
+        [[endpoint_template]]
+        name = "fleet-${HOSTNAME}"
+        server = "wss://collector.example.com/hosts/${HOSTNAME}"
+        secret = "${VMONITOR_TEST_FLEET_TOKEN}"
+        "#,
+    )
+    .unwrap();
+
+    let hostname = sysinfo::System::host_name().unwrap();
+    let config = AppConfig::from_file(&path).unwrap();
+    assert_eq!(config.endpoints.len(), 1);
+    assert_eq!(config.endpoints[0].name, format!("fleet-{}", hostname));
+    assert_eq!(
+        config.endpoints[0].server,
+        format!("wss://collector.example.com/hosts/{}", hostname)
+    );
+    assert_eq!(config.endpoints[0].secret, "fleet-token");
+    // Templates can hold a literal secret too
+    let redacted = config.redacted();
+    assert!(redacted["endpoint_template"][0]["secret"].is_null());
+
+    // Saving keeps the template, not the host-specific expansion
+    config.save_to_file(&path).unwrap();
+    let saved = fs::read_to_string(&path).unwrap();
+    assert!(saved.contains("${HOSTNAME}"));
+    assert!(!saved.contains(&format!("hosts/{}", hostname)));
+    assert_eq!(
+        AppConfig::from_file(&path).unwrap().endpoints,
+        config.endpoints
+    );
+}
+
+#[test]
+fn test_endpoint_template_expands_path_and_headers() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    std::env::set_var("VMONITOR_TEST_FLEET_KEY", "fleet-key");
+    fs::write(
+        &path,
+        r#"
+        endpoints = []
+
+        [[endpoint_template]]
+        name = "fleet"
+        server = "wss://collector.example.com"
+        path = "/hosts/${HOSTNAME}/ws"
+        secret = "secret"
+
+        [endpoint_template.headers]
+        X-Api-Key = "${VMONITOR_TEST_FLEET_KEY}"
+        X-Host = "${HOSTNAME}"
+        "#,
+    )
+    .unwrap();
+
+    let hostname = sysinfo::System::host_name().unwrap();
+    let config = AppConfig::from_file(&path).unwrap();
+    let endpoint = &config.endpoints[0];
+    assert_eq!(endpoint.path, format!("/hosts/{}/ws", hostname));
+    assert_eq!(endpoint.headers["X-Api-Key"], "fleet-key");
+    assert_eq!(endpoint.headers["X-Host"], hostname);
+
+    // A missing variable names the header it's in
+    std::env::remove_var("VMONITOR_TEST_FLEET_KEY");
+    let err = AppConfig::from_file(&path).unwrap_err().to_string();
+    assert!(err.contains("headers.X-Api-Key"), "{}", err);
+}
+
+#[test]
+fn test_endpoint_fields_expand_environment_variables() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    std::env::set_var("VMONITOR_TEST_ENV_SECRET", "from-env");
+    std::env::remove_var("VMONITOR_TEST_ENV_HOST");
+    fs::write(
+        &path,
+        r#"
+        [[endpoints]]
+        name = "web"
+        server = "wss://${VMONITOR_TEST_ENV_HOST:-collector.example.com}/ws"
+        secret = "${VMONITOR_TEST_ENV_SECRET}"
+        "#,
+    )
+    .unwrap();
+
+    let config = AppConfig::from_file(&path).unwrap();
+    assert_eq!(config.endpoints[0].secret, "from-env");
+    assert_eq!(config.endpoints[0].server, "wss://collector.example.com/ws");
+    assert_eq!(config.endpoints[0].name, "web");
+
+    // Saving keeps the references, not the secret
+    config.save_to_file(&path).unwrap();
+    let saved = fs::read_to_string(&path).unwrap();
+    assert!(saved.contains("${VMONITOR_TEST_ENV_SECRET}"));
+    assert!(!saved.contains("from-env"));
+    assert_eq!(
+        AppConfig::from_file(&path).unwrap().endpoints,
+        config.endpoints
+    );
+}
+
+#[test]
+fn test_endpoint_headers_expand_environment_variables() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    std::env::set_var("VMONITOR_TEST_PROXY_KEY", "proxy-key");
+    fs::write(
+        &path,
+        r#"
+        [[endpoints]]
+        name = "web"
+        server = "wss://collector.example.com/ws"
+        secret = "s3cret"
+
+        [endpoints.headers]
+        X-Api-Key = "${VMONITOR_TEST_PROXY_KEY}"
+        Host = "metrics.internal"
+        "#,
+    )
+    .unwrap();
+
+    let config = AppConfig::from_file(&path).unwrap();
+    assert_eq!(config.endpoints[0].headers["X-Api-Key"], "proxy-key");
+    assert_eq!(config.endpoints[0].headers["Host"], "metrics.internal");
+
+    config.save_to_file(&path).unwrap();
+    let saved = fs::read_to_string(&path).unwrap();
+    assert!(saved.contains("${VMONITOR_TEST_PROXY_KEY}"));
+    assert!(!saved.contains("proxy-key"));
+    assert_eq!(
+        AppConfig::from_file(&path).unwrap().endpoints,
+        config.endpoints
+    );
+}
+
+#[test]
+fn test_authorization_header_conflicting_with_auth_mode_is_rejected() {
+    let config: AppConfig = toml::from_str(
+        r#"
+        [[endpoints]]
+        name = "web"
+        server = "wss://collector.example.com/ws"
+        secret = "s3cret"
+        connection = { auth_mode = "header" }
+        headers = { authorization = "Basic dXNlcjpwYXNz" }
+        "#,
+    )
+    .unwrap();
+    let err = config.validate().unwrap_err();
+    assert!(err.contains("endpoint 'web'"), "{}", err);
+    assert!(err.contains("Authorization"), "{}", err);
+}
+
+#[test]
+fn test_unset_environment_variable_is_rejected() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    std::env::remove_var("VMONITOR_TEST_ENV_UNSET");
+    fs::write(
+        &path,
+        r#"
+        [[endpoints]]
+        name = "web"
+        server = "wss://collector.example.com/ws"
+        secret = "${VMONITOR_TEST_ENV_UNSET}"
+        "#,
+    )
+    .unwrap();
+
+    let err = AppConfig::from_file(&path).unwrap_err().to_string();
+    assert!(err.contains("endpoint 'web' secret"), "{}", err);
+    assert!(err.contains("${VMONITOR_TEST_ENV_UNSET}"), "{}", err);
+}
+
+#[test]
+fn test_save_replaces_config_atomically() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    fs::write(&path, "endpoints = []\n").unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+
+    let mut config = create_default_config();
+    config.endpoints.push(Endpoint {
+        name: "web".to_string(),
+        server: "wss://web.example.com/ws".to_string(),
+        secret: "secret".to_string(),
+        ..Default::default()
+    });
+    config.save_to_file(&path).unwrap();
+
+    assert_eq!(
+        AppConfig::from_file(&path).unwrap().endpoints,
+        config.endpoints
+    );
+    // Only the config itself is left in its directory
+    let entries: Vec<_> = fs::read_dir(test_config.temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, ["test_config.toml"]);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[test]
+fn test_unknown_placeholder_is_rejected() {
+    let err = vmonitor::config::expand_placeholders("wss://${NOPE}/ws", |_| None).unwrap_err();
+    assert!(err.contains("${NOPE}"), "{}", err);
+}
+
+#[test]
+fn test_interface_filter_patterns() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.to_str().unwrap().to_string();
+    fs::write(
+        &path,
+        r#"
+        endpoints = []
+
+        [report.interface_filter]
+        include = ["eth*", "wg0"]
+        "#,
+    )
+    .unwrap();
+
+    let filter = AppConfig::from_file(&path).unwrap().report.interface_filter;
+    assert!(filter.allows("eth0"));
+    assert!(filter.allows("wg0"));
+    assert!(!filter.allows("wg1"));
+    assert!(!filter.allows("ens3"));
+    // The default excludes still apply
+    assert!(filter.exclude.contains(&"lo".to_string()));
+
+    assert!(vmonitor::config::glob_match("br-*", "br-4f2a"));
+    assert!(vmonitor::config::glob_match("*eth*", "veth0"));
+    assert!(!vmonitor::config::glob_match("docker*", "mydocker0"));
+}
+
+#[test]
+fn test_trusted_host_patterns_match_one_label() {
+    use vmonitor::config::host_match;
+
+    assert!(host_match("*.lab.example.com", "a.lab.example.com"));
+    assert!(host_match("*.lab.example.com", "A.Lab.Example.com"));
+    assert!(host_match(
+        "collector-*.example.com",
+        "collector-2.example.com"
+    ));
+    assert!(!host_match("*.lab.example.com", "a.b.lab.example.com"));
+    assert!(!host_match("*.lab.example.com", "lab.example.com"));
+    assert!(!host_match("*", "evil.example.com"));
+    assert!(host_match("10.0.0.*", "10.0.0.7"));
+}
+
+#[test]
+fn test_config_diff_classifies_changes() {
+    let endpoint = |name: &str, enabled: bool| Endpoint {
+        name: name.to_string(),
+        server: "wss://collector.example.com".to_string(),
+        secret: "secret".to_string(),
+        enabled,
+        ..Default::default()
+    };
+    let old = AppConfig {
+        endpoints: vec![endpoint("primary", true), endpoint("backup", false)],
+        ..Default::default()
+    };
+    let mut new = AppConfig {
+        endpoints: vec![
+            endpoint("primary", true),
+            endpoint("backup", true),
+            endpoint("eu", true),
+        ],
+        ..old.clone()
+    };
+    new.endpoints[0].secret = "rotated".to_string();
+    new.connection.max_delay = 120;
+    new.report.interval_secs = 30;
+
+    let diff = old.diff(&new);
+    assert_eq!(
+        diff.changes,
+        vec![
+            ConfigChange::EndpointChanged {
+                name: "primary".to_string(),
+                fields: vec!["secret".to_string()],
+            },
+            ConfigChange::EndpointEnabled("backup".to_string()),
+            ConfigChange::EndpointAdded("eu".to_string()),
+            ConfigChange::IntervalChanged { from: 10, to: 30 },
+            ConfigChange::SettingChanged("connection.max_delay".to_string()),
+        ]
+    );
+    // Names what changed, never the values
+    assert!(!diff.to_string().contains("rotated"));
+    assert!(old.diff(&old).changes.is_empty());
+}
@@ -0,0 +1,459 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::TestConfig;
+use futures::StreamExt;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use vmonitor::api;
+use vmonitor::config::{ConnectionConfig, Endpoint};
+use vmonitor::control::{self, ControlState};
+use vmonitor::features::metrics::Metrics;
+use vmonitor::history::{now_millis, History, HistorySample};
+use vmonitor::logfile::LogFile;
+use vmonitor::monitor::{Monitor, SharedCollector, SharedSample};
+
+#[tokio::test]
+async fn test_history_returns_samples_in_order() {
+    let history = Arc::new(History::new(Duration::from_secs(300)));
+    let mut metrics = Metrics::with_collectors(vec![]);
+    let now = now_millis();
+
+    // One sample beyond the requested range, then three inside it
+    for offset in [200_000, 30_000, 20_000, 10_000] {
+        let report = metrics.collet_metrics().await;
+        history.record_at(now - offset, report).await;
+    }
+
+    let test_config = TestConfig::new();
+    let socket = test_config.config_path.with_file_name("control.sock");
+    let socket = socket.to_str().unwrap().to_string();
+    let state = Arc::new(ControlState {
+        history: history.clone(),
+        reconnect: watch::channel(()).0,
+        interval_override: watch::channel(None).0,
+        log_file: None,
+        sample_stats: watch::channel(BTreeMap::new()).1,
+        token: None,
+        allowed_uids: vec![],
+    });
+    let server_socket = socket.clone();
+    let server = tokio::spawn(async move { control::serve(&server_socket, state).await });
+
+    // Wait for the socket to be bound
+    sleep(Duration::from_millis(100)).await;
+
+    let lines = tokio::task::spawn_blocking(move || control::request(&socket, None, "HISTORY 60"))
+        .await
+        .unwrap()
+        .unwrap();
+    let samples: Vec<HistorySample> = lines
+        .iter()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    let timestamps: Vec<u64> = samples.iter().map(|s| s.collected_at).collect();
+    assert_eq!(timestamps, vec![now - 30_000, now - 20_000, now - 10_000]);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_history_window_drops_old_samples() {
+    let history = History::new(Duration::from_secs(60));
+    let mut metrics = Metrics::with_collectors(vec![]);
+    let now = now_millis();
+
+    history
+        .record_at(now - 120_000, metrics.collet_metrics().await)
+        .await;
+    history.record_at(now, metrics.collet_metrics().await).await;
+
+    let samples = history.since(Duration::from_secs(3600)).await;
+    assert_eq!(samples.len(), 1);
+    assert_eq!(samples[0].collected_at, now);
+}
+
+#[tokio::test]
+async fn test_reresolve_reconnects_endpoints() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    // Resolved again on every connect, so a reconnect picks up new records
+    let endpoint = Endpoint {
+        name: "reresolve".to_string(),
+        server: format!("ws://localhost:{}", server.local_addr().unwrap().port()),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let (reconnect, reconnect_rx) = watch::channel(());
+    let monitor = tokio::spawn(async move {
+        Monitor::new(endpoint, vec![])
+            .with_reconnect_signal(reconnect_rx)
+            .run()
+            .await
+    });
+
+    let (stream, _) = server.accept().await.unwrap();
+    let _first = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let test_config = TestConfig::new();
+    let socket = test_config.config_path.with_file_name("control.sock");
+    let socket = socket.to_str().unwrap().to_string();
+    let state = Arc::new(ControlState {
+        history: Arc::new(History::new(Duration::from_secs(60))),
+        reconnect,
+        interval_override: watch::channel(None).0,
+        log_file: None,
+        sample_stats: watch::channel(BTreeMap::new()).1,
+        token: None,
+        allowed_uids: vec![],
+    });
+    let server_socket = socket.clone();
+    let control_server = tokio::spawn(async move { control::serve(&server_socket, state).await });
+    sleep(Duration::from_millis(100)).await;
+
+    let response =
+        tokio::task::spawn_blocking(move || control::request(&socket, None, "RERESOLVE"))
+            .await
+            .unwrap()
+            .unwrap();
+    assert_eq!(response, vec!["OK".to_string()]);
+
+    // The first connection is still open, so this can only be a reconnect
+    let (stream, _) = timeout(Duration::from_secs(5), server.accept())
+        .await
+        .expect("no reconnect after RERESOLVE")
+        .unwrap();
+    tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    monitor.abort();
+    control_server.abort();
+}
+
+#[tokio::test]
+async fn test_reopen_logs_writes_to_fresh_file() {
+    let test_config = TestConfig::new();
+    let path = test_config.config_path.with_file_name("vmonitor.log");
+    let rotated = test_config.config_path.with_file_name("vmonitor.log.1");
+    let mut log_file = LogFile::open(&path).unwrap();
+    writeln!(log_file, "before rotation").unwrap();
+
+    let socket = test_config.config_path.with_file_name("control.sock");
+    let socket = socket.to_str().unwrap().to_string();
+    let state = Arc::new(ControlState {
+        history: Arc::new(History::new(Duration::from_secs(60))),
+        reconnect: watch::channel(()).0,
+        interval_override: watch::channel(None).0,
+        log_file: Some(log_file.clone()),
+        sample_stats: watch::channel(BTreeMap::new()).1,
+        token: None,
+        allowed_uids: vec![],
+    });
+    let server_socket = socket.clone();
+    let server = tokio::spawn(async move { control::serve(&server_socket, state).await });
+    sleep(Duration::from_millis(100)).await;
+
+    // What logrotate does without copytruncate
+    std::fs::rename(&path, &rotated).unwrap();
+    writeln!(log_file, "still to the moved file").unwrap();
+
+    let response =
+        tokio::task::spawn_blocking(move || control::request(&socket, None, "REOPEN-LOGS"))
+            .await
+            .unwrap()
+            .unwrap();
+    assert_eq!(response, vec!["OK".to_string()]);
+    writeln!(log_file, "after rotation").unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(&rotated).unwrap(),
+        "before rotation\nstill to the moved file\n"
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "after rotation\n");
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_token_is_required_when_configured() {
+    let test_config = TestConfig::new();
+    let socket = test_config.config_path.with_file_name("control.sock");
+    let socket = socket.to_str().unwrap().to_string();
+    let state = Arc::new(ControlState {
+        history: Arc::new(History::new(Duration::from_secs(60))),
+        reconnect: watch::channel(()).0,
+        interval_override: watch::channel(None).0,
+        log_file: None,
+        sample_stats: watch::channel(BTreeMap::new()).1,
+        token: Some("s3cret".to_string()),
+        allowed_uids: vec![],
+    });
+    let server_socket = socket.clone();
+    let server = tokio::spawn(async move { control::serve(&server_socket, state).await });
+    sleep(Duration::from_millis(100)).await;
+
+    // Only the daemon's user may open the socket at all
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let request = |token: Option<&'static str>| {
+        let socket = socket.clone();
+        tokio::task::spawn_blocking(move || control::request(&socket, token, "HISTORY 60"))
+    };
+    let err = request(None).await.unwrap().unwrap_err();
+    assert!(
+        err.to_string().contains("authentication required"),
+        "{}",
+        err
+    );
+    let err = request(Some("wrong")).await.unwrap().unwrap_err();
+    assert!(err.to_string().contains("invalid token"), "{}", err);
+    assert!(request(Some("s3cret")).await.unwrap().is_ok());
+
+    // A request is read only up to a limit
+    let long = format!("HISTORY {}", "9".repeat(10_000));
+    let err = tokio::task::spawn_blocking(move || control::request(&socket, Some("s3cret"), &long))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(err.to_string().contains("request too long"), "{}", err);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_peers_outside_allowed_uids_are_rejected() {
+    let test_config = TestConfig::new();
+    let socket = test_config.config_path.with_file_name("control.sock");
+    let socket = socket.to_str().unwrap().to_string();
+    let state = Arc::new(ControlState {
+        history: Arc::new(History::new(Duration::from_secs(60))),
+        reconnect: watch::channel(()).0,
+        interval_override: watch::channel(None).0,
+        log_file: None,
+        sample_stats: watch::channel(BTreeMap::new()).1,
+        token: None,
+        // No test runs as this user
+        allowed_uids: vec![u32::MAX - 1],
+    });
+    let server_socket = socket.clone();
+    let server = tokio::spawn(async move { control::serve(&server_socket, state).await });
+    sleep(Duration::from_millis(100)).await;
+
+    let err = tokio::task::spawn_blocking(move || control::request(&socket, None, "HISTORY 60"))
+        .await
+        .unwrap()
+        .unwrap_err();
+    assert!(err.to_string().contains("not allowed"), "{}", err);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_set_interval_overrides_temporarily() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = Endpoint {
+        name: "override".to_string(),
+        server: format!("ws://{}", server.local_addr().unwrap()),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let (interval_override, override_rx) = watch::channel(None);
+    let monitor = tokio::spawn(async move {
+        Monitor::new(endpoint, vec![])
+            .with_interval_override(override_rx)
+            .run()
+            .await
+    });
+    let (stream, _) = server.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let test_config = TestConfig::new();
+    let socket = test_config.config_path.with_file_name("control.sock");
+    let socket = socket.to_str().unwrap().to_string();
+    let state = Arc::new(ControlState {
+        history: Arc::new(History::new(Duration::from_secs(60))),
+        reconnect: watch::channel(()).0,
+        interval_override: interval_override.clone(),
+        log_file: None,
+        sample_stats: watch::channel(BTreeMap::new()).1,
+        token: None,
+        allowed_uids: vec![],
+    });
+    let server_socket = socket.clone();
+    let control_server = tokio::spawn(async move { control::serve(&server_socket, state).await });
+    sleep(Duration::from_millis(100)).await;
+
+    let bad_socket = socket.clone();
+    let bad = tokio::task::spawn_blocking(move || {
+        control::request(&bad_socket, None, "SET-INTERVAL 0 3")
+    })
+    .await
+    .unwrap();
+    assert!(bad.is_err());
+    // Would overflow the deadline
+    let bad_socket = socket.clone();
+    let too_long = tokio::task::spawn_blocking(move || {
+        control::request(&bad_socket, None, "SET-INTERVAL 1 18446744073709551615")
+    })
+    .await
+    .unwrap();
+    assert!(too_long.unwrap_err().to_string().contains("too large"));
+    let response =
+        tokio::task::spawn_blocking(move || control::request(&socket, None, "SET-INTERVAL 1 3"))
+            .await
+            .unwrap()
+            .unwrap();
+    assert_eq!(response, vec!["OK".to_string()]);
+
+    // Counts metrics messages received within `window`
+    async fn count_metrics<S>(ws: &mut S, window: Duration) -> usize
+    where
+        S: futures::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        let mut count = 0;
+        let _ = timeout(window, async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Binary(binary) = msg {
+                    let msg: vmonitor::api::Message<serde_json::Value> =
+                        rmp_serde::from_slice(&binary).unwrap();
+                    if msg.r#type == "metrics" {
+                        count += 1;
+                    }
+                }
+            }
+        })
+        .await;
+        count
+    }
+
+    // The configured interval is 10s, so more than one sample can only come
+    // from the override
+    assert!(count_metrics(&mut ws, Duration::from_millis(2900)).await >= 3);
+
+    // Back on the configured interval once the window has passed, which
+    // restarts it with one immediate sample
+    count_metrics(&mut ws, Duration::from_secs(1)).await;
+    assert_eq!(*interval_override.borrow(), None);
+    assert_eq!(count_metrics(&mut ws, Duration::from_secs(3)).await, 0);
+
+    monitor.abort();
+    control_server.abort();
+}
+
+#[tokio::test]
+async fn test_status_reports_drops_of_a_stalled_endpoint() {
+    let shared = SharedCollector::new(4);
+    let mut servers = Vec::new();
+    let mut monitors = Vec::new();
+    let mut sample_stats = BTreeMap::new();
+    for name in ["healthy", "stalled"] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Endpoint {
+            name: name.to_string(),
+            server: format!("ws://{}", listener.local_addr().unwrap()),
+            secret: "secret".to_string(),
+            metrics_interval: Some(1),
+            connection: Some(ConnectionConfig {
+                base_delay: 1,
+                max_delay: 1,
+                max_retries: 0,
+                // The stalled server doesn't answer pings either
+                ping_interval: 0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let monitor = Monitor::new(endpoint, vec![]).with_shared_collector(shared.clone());
+        sample_stats.insert(name.to_string(), monitor.handle().sample_stats());
+        monitors.push(tokio::spawn(async move { monitor.run().await }));
+        let (stream, _) = listener.accept().await.unwrap();
+        servers.push(tokio_tungstenite::accept_async(stream).await.unwrap());
+    }
+    let mut healthy = servers.remove(0);
+    // Never read from, so the stalled monitor's writes back up
+    let _stalled = servers.remove(0);
+    // Give both monitors time to subscribe
+    sleep(Duration::from_millis(500)).await;
+
+    // Big enough that the stalled connection's buffers fill up
+    let mut report = Metrics::with_collectors(vec![]).collet_metrics().await;
+    report.custom_gauges = (0..1500)
+        .map(|n| (format!("gauge_with_a_rather_long_name_{:08}", n), n as f64))
+        .collect();
+    for n in 0..250u64 {
+        let collected_at = 1_700_000_000_000 + n * 1000;
+        shared.publish(Arc::new(SharedSample {
+            collected_at,
+            mono_ns: n * 1_000_000_000,
+            report: report.clone(),
+        }));
+        // Every sample reaches the healthy endpoint as soon as it's published
+        timeout(Duration::from_secs(2), async {
+            while let Some(Ok(frame)) = healthy.next().await {
+                if let Message::Binary(binary) = frame {
+                    let message =
+                        rmp_serde::from_slice::<api::Message<serde_json::Value>>(&binary).unwrap();
+                    if message.r#type == "metrics" && message.data["collectedAt"] == collected_at {
+                        return;
+                    }
+                }
+            }
+            panic!("healthy connection closed");
+        })
+        .await
+        .unwrap_or_else(|_| panic!("sample {} didn't reach the healthy endpoint", n));
+    }
+
+    let test_config = TestConfig::new();
+    let socket = test_config.config_path.with_file_name("control.sock");
+    let socket = socket.to_str().unwrap().to_string();
+    let state = Arc::new(ControlState {
+        history: Arc::new(History::new(Duration::from_secs(60))),
+        reconnect: watch::channel(()).0,
+        interval_override: watch::channel(None).0,
+        log_file: None,
+        sample_stats: watch::channel(sample_stats).1,
+        token: None,
+        allowed_uids: vec![],
+    });
+    let server_socket = socket.clone();
+    let control_server = tokio::spawn(async move { control::serve(&server_socket, state).await });
+    sleep(Duration::from_millis(100)).await;
+
+    let lines = tokio::task::spawn_blocking(move || control::request(&socket, None, "STATUS"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    assert_eq!(lines[0], "healthy lag=0 dropped=0");
+    let (lag, dropped) = lines[1]
+        .strip_prefix("stalled lag=")
+        .and_then(|rest| rest.split_once(" dropped="))
+        .unwrap_or_else(|| panic!("unexpected status line {:?}", lines[1]));
+    assert_eq!(lag, "4", "{:?}", lines);
+    assert!(dropped.parse::<u64>().unwrap() > 0, "{:?}", lines);
+
+    for monitor in monitors {
+        monitor.abort();
+    }
+    control_server.abort();
+}
@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Duration;
+use vmonitor::config::Collector;
+use vmonitor::dashboard::{self, METRICS_PATH};
+use vmonitor::features::metrics::Metrics;
+use vmonitor::history::{now_millis, History, HistorySample};
+
+async fn get(addr: std::net::SocketAddr, path: &str) -> (String, String) {
+    get_with(addr, path, "").await
+}
+
+async fn get_with(addr: std::net::SocketAddr, path: &str, headers: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+        path, headers
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+#[tokio::test]
+async fn test_dashboard_serves_page_and_latest_sample() {
+    let history = Arc::new(History::new(Duration::from_secs(300)));
+    let mut metrics = Metrics::with_collectors(vec![Collector::System]);
+    let collected_at = now_millis();
+    let report = metrics.collet_metrics().await;
+    history.record_at(collected_at, report.clone()).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(dashboard::serve(listener, history, None));
+
+    let (status, body) = get(addr, "/").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(body.contains("<title>vmonitor</title>"));
+
+    let (status, body) = get(addr, METRICS_PATH).await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let sample: HistorySample = serde_json::from_str(&body).unwrap();
+    assert_eq!(sample.collected_at, collected_at);
+    assert_eq!(sample.report, report);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_dashboard_requires_token_when_configured() {
+    let history = Arc::new(History::new(Duration::from_secs(300)));
+    let mut metrics = Metrics::with_collectors(vec![Collector::System]);
+    let report = metrics.collet_metrics().await;
+    history.record_at(now_millis(), report).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(dashboard::serve(
+        listener,
+        history,
+        Some("s3cret".to_string()),
+    ));
+
+    // The page itself holds no data
+    let (status, _) = get(addr, "/").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+
+    let (status, _) = get(addr, METRICS_PATH).await;
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    let (status, _) = get_with(addr, METRICS_PATH, "Authorization: Bearer wrong\r\n").await;
+    assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+    let (status, body) = get_with(addr, METRICS_PATH, "Authorization: Bearer s3cret\r\n").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(serde_json::from_str::<HistorySample>(&body).is_ok());
+
+    server.abort();
+}
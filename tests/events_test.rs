@@ -0,0 +1,102 @@
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration};
+use vmonitor::app::App;
+use vmonitor::config::{AppConfig, ConnectionConfig, Endpoint};
+use vmonitor::monitor::MonitorEvent;
+
+#[tokio::test]
+async fn test_subscriber_receives_samples() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = AppConfig {
+        endpoints: vec![Endpoint {
+            name: "embedded".to_string(),
+            server: format!("ws://{}", listener.local_addr().unwrap()),
+            secret: "secret".to_string(),
+            connection: Some(ConnectionConfig {
+                base_delay: 1,
+                max_delay: 1,
+                max_retries: 0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    let app = App::new(config, "config.toml".to_string());
+    let mut events = app.subscribe();
+    let shutdown = app.shutdown_handle();
+    let app_handle = tokio::spawn(async move { app.run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let _ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let mut connected = false;
+    let sample = timeout(Duration::from_secs(5), async {
+        loop {
+            match events.recv().await.unwrap() {
+                MonitorEvent::Connected { endpoint } => {
+                    assert_eq!(endpoint, "embedded");
+                    connected = true;
+                }
+                MonitorEvent::Sample { endpoint, report } if connected => {
+                    assert_eq!(endpoint, "embedded");
+                    return report;
+                }
+                MonitorEvent::Sample { .. } => {}
+                MonitorEvent::Disconnected { .. } => panic!("disconnected"),
+                MonitorEvent::Panicked { message, .. } => panic!("monitor panicked: {}", message),
+            }
+        }
+    })
+    .await
+    .expect("no sample event");
+    assert!(sample.system.is_some());
+
+    shutdown.notify_one();
+    let _ = timeout(Duration::from_secs(2), app_handle).await;
+}
+
+#[tokio::test]
+async fn test_subscriber_receives_samples_while_disconnected() {
+    // Nothing listens on the port once the listener is dropped
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = format!("ws://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let config = AppConfig {
+        endpoints: vec![Endpoint {
+            name: "unreachable".to_string(),
+            server,
+            secret: "secret".to_string(),
+            connection: Some(ConnectionConfig {
+                base_delay: 1,
+                max_delay: 1,
+                max_retries: -1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+
+    let app = App::new(config, "config.toml".to_string());
+    let mut events = app.subscribe();
+    let shutdown = app.shutdown_handle();
+    let app_handle = tokio::spawn(async move { app.run().await });
+
+    let endpoint = timeout(Duration::from_secs(5), async {
+        loop {
+            match events.recv().await.unwrap() {
+                MonitorEvent::Sample { endpoint, .. } => return endpoint,
+                MonitorEvent::Connected { .. } => panic!("connected"),
+                _ => {}
+            }
+        }
+    })
+    .await
+    .expect("no sample event");
+    assert_eq!(endpoint, "unreachable");
+
+    shutdown.notify_one();
+    let _ = timeout(Duration::from_secs(2), app_handle).await;
+}
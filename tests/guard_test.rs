@@ -0,0 +1,84 @@
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use vmonitor::api;
+use vmonitor::config::{ConnectionConfig, Endpoint, GuardConfig};
+use vmonitor::guard;
+use vmonitor::monitor::Monitor;
+
+fn is_metrics(msg: &Message) -> bool {
+    match msg {
+        Message::Binary(binary) => rmp_serde::from_slice::<api::Message<serde_json::Value>>(binary)
+            .is_ok_and(|m| m.r#type == "metrics"),
+        _ => false,
+    }
+}
+
+/// Whether a metrics frame arrives within `secs` seconds.
+async fn metrics_within(ws: &mut WebSocketStream<TcpStream>, secs: u64) -> bool {
+    timeout(Duration::from_secs(secs), async {
+        while let Some(Ok(msg)) = ws.next().await {
+            if is_metrics(&msg) {
+                return;
+            }
+        }
+        panic!("connection closed");
+    })
+    .await
+    .is_ok()
+}
+
+#[tokio::test]
+async fn test_guard_stops_and_resumes_reporting() {
+    // The "corporate" host that must be reachable for reporting to happen
+    let trusted = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let trusted_addr = trusted.local_addr().unwrap();
+    let (guard_task, guard) = guard::spawn(GuardConfig {
+        gateway_macs: vec![],
+        require_reachable: Some(trusted_addr.to_string()),
+        recheck_secs: 1,
+    })
+    .await;
+    assert!(*guard.borrow());
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = Endpoint {
+        name: "guarded".to_string(),
+        server: format!("ws://{}", server.local_addr().unwrap()),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let monitor =
+        tokio::spawn(async move { Monitor::new(endpoint, vec![]).with_guard(guard).run().await });
+
+    let (stream, _) = server.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+    ws.send(Message::Text(
+        r#"{"type":"update_config","data":{"metrics_interval":1}}"#.into(),
+    ))
+    .await
+    .unwrap();
+
+    assert!(metrics_within(&mut ws, 3).await);
+
+    // Leave the trusted network and let the guard notice
+    drop(trusted);
+    sleep(Duration::from_millis(2500)).await;
+    while timeout(Duration::from_millis(10), ws.next()).await.is_ok() {}
+    assert!(!metrics_within(&mut ws, 2).await);
+
+    // Back on the trusted network
+    let _trusted = TcpListener::bind(trusted_addr).await.unwrap();
+    assert!(metrics_within(&mut ws, 4).await);
+
+    monitor.abort();
+    guard_task.abort();
+}
@@ -0,0 +1,1083 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use vmonitor::api;
+use vmonitor::config::{
+    Collector, ConnectionConfig, DetailLevel, Encoding, Endpoint, GaugeConfig, MetricsFormat,
+    TransportKind,
+};
+use vmonitor::features::metrics::{Metrics, ReportData};
+use vmonitor::monitor::{Monitor, SharedCollector, SharedSample};
+
+fn message_type(msg: &Message) -> Option<String> {
+    match msg {
+        Message::Binary(binary) => rmp_serde::from_slice::<api::Message<serde_json::Value>>(binary)
+            .ok()
+            .map(|m| m.r#type),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn test_known_vm_info_hash_is_not_resent() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "dedup".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        vm_info_dedup: true,
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move { Monitor::new(endpoint, vec![]).run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    // The hash comes first, before any metrics
+    let first = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(message_type(&first).as_deref(), Some("vm_info_hash"));
+
+    ws.send(Message::Text(
+        r#"{"type":"vm_info_known","data":null}"#.into(),
+    ))
+    .await
+    .unwrap();
+
+    // Wait past the negotiation timeout to make sure the fallback doesn't fire
+    let mut received = Vec::new();
+    let _ = timeout(Duration::from_secs(4), async {
+        while let Some(Ok(msg)) = ws.next().await {
+            received.extend(message_type(&msg));
+        }
+    })
+    .await;
+
+    assert!(received.iter().any(|t| t == "metrics"));
+    assert!(!received.iter().any(|t| t == "vm_info"));
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_field_map_renames_sent_fields() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "mapped".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let field_map = BTreeMap::from([("system.cpu_usage".to_string(), "cpu_pct".to_string())]);
+    let monitor = tokio::spawn(async move {
+        Monitor::new(endpoint, vec![Collector::System])
+            .with_field_map(field_map)
+            .run()
+            .await
+    });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let frame = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let Message::Binary(binary) = frame else {
+        panic!("expected a binary frame, got {:?}", frame);
+    };
+    let msg: api::Message<serde_json::Value> = rmp_serde::from_slice(&binary).unwrap();
+    assert_eq!(msg.r#type, "metrics");
+
+    let system = msg.data["system"].as_object().unwrap();
+    assert!(system.contains_key("cpu_pct"));
+    assert!(!system.contains_key("cpuUsage"));
+    // Unmapped fields keep their default names
+    assert!(system.contains_key("memoryUsed"));
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_ready_handshake_precedes_metrics() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "handshake".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        ready_handshake: true,
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move { Monitor::new(endpoint, vec![]).run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let first = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(message_type(&first).as_deref(), Some("ping"));
+
+    // Nothing else may arrive until the server signals readiness
+    assert!(timeout(Duration::from_millis(500), ws.next())
+        .await
+        .is_err());
+
+    ws.send(Message::Text(r#"{"type":"pong","data":null}"#.into()))
+        .await
+        .unwrap();
+
+    let next = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(message_type(&next).as_deref(), Some("metrics"));
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_ready_handshake_failures_count_as_retries() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "never-ready".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 1,
+            ..Default::default()
+        }),
+        ready_handshake: true,
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move { Monitor::new(endpoint, vec![]).run().await });
+
+    // Hang up on every ping instead of answering it
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    let server = tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            let _ = ws.close(None).await;
+        }
+    });
+
+    // One attempt plus one retry, then the monitor gives up
+    timeout(Duration::from_secs(10), monitor)
+        .await
+        .expect("monitor kept retrying past max_retries")
+        .unwrap();
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn test_coalesced_messages_arrive_in_one_frame() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "batched".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        send_coalesce_ms: 2000,
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move { Monitor::new(endpoint, vec![]).run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    // The first metrics collection and the info response both fall within
+    // the window
+    ws.send(Message::Text(r#"{"type":"get_info","data":null}"#.into()))
+        .await
+        .unwrap();
+
+    let frame = timeout(Duration::from_secs(5), ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let Message::Binary(binary) = frame else {
+        panic!("expected a binary frame, got {:?}", frame);
+    };
+    let batch: api::Message<Vec<api::Message<serde_json::Value>>> =
+        rmp_serde::from_slice(&binary).unwrap();
+    assert_eq!(batch.r#type, "batch");
+
+    let mut types: Vec<&str> = batch.data.iter().map(|m| m.r#type.as_str()).collect();
+    types.sort();
+    assert_eq!(types, vec!["metrics", "vm_info"]);
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_vm_info_carries_config_fingerprint() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "fingerprinted".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        max_commands_per_sec: 0,
+        ..Default::default()
+    };
+    let monitor =
+        Monitor::new(endpoint, vec![]).with_config_fingerprint("0123456789ab".to_string());
+    let handle = monitor.handle();
+    let monitor = tokio::spawn(async move { monitor.run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let mut fingerprints = Vec::new();
+    for round in 0..3 {
+        if round == 2 {
+            // A reload that keeps the monitor running
+            handle.set_config_fingerprint("ba9876543210".to_string());
+        }
+        ws.send(Message::Text(r#"{"type":"get_info","data":null}"#.into()))
+            .await
+            .unwrap();
+        let vm_info = timeout(Duration::from_secs(5), async {
+            while let Some(Ok(Message::Binary(binary))) = ws.next().await {
+                let msg: api::Message<serde_json::Value> = rmp_serde::from_slice(&binary).unwrap();
+                if msg.r#type == "vm_info" {
+                    return msg.data;
+                }
+            }
+            panic!("connection closed");
+        })
+        .await
+        .unwrap();
+        fingerprints.push(vm_info["configFingerprint"].clone());
+    }
+    assert_eq!(
+        fingerprints,
+        vec!["0123456789ab", "0123456789ab", "ba9876543210"]
+    );
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_heartbeat_when_collection_fails() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "blind".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        heartbeat_when_blind: true,
+        ..Default::default()
+    };
+    // The only thing to collect is a gauge that always fails
+    let gauges = vec![GaugeConfig {
+        name: "broken".to_string(),
+        command: "exit 1".to_string(),
+        timeout_ms: 5000,
+    }];
+    let monitor = tokio::spawn(async move {
+        Monitor::new(endpoint, vec![])
+            .with_gauges(gauges)
+            .run()
+            .await
+    });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+    ws.send(Message::Text(
+        r#"{"type":"update_config","data":{"metrics_interval":1}}"#.into(),
+    ))
+    .await
+    .unwrap();
+
+    let mut heartbeats = Vec::new();
+    let _ = timeout(Duration::from_secs(3), async {
+        while let Some(Ok(Message::Binary(binary))) = ws.next().await {
+            let msg: api::Message<serde_json::Value> = rmp_serde::from_slice(&binary).unwrap();
+            assert_ne!(msg.r#type, "metrics");
+            if msg.r#type == "heartbeat" {
+                heartbeats.push(msg.data);
+            }
+        }
+    })
+    .await;
+
+    // One right away, then one per interval
+    assert!(heartbeats.len() >= 2, "{:?}", heartbeats);
+    assert!(heartbeats[0]["timestamp"].as_u64().unwrap() > 0);
+    assert!(heartbeats[0]["uptime"].is_u64());
+    let errors = heartbeats[0]["collectionErrors"].as_array().unwrap();
+    assert!(errors[0].as_str().unwrap().starts_with("gauge broken:"));
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_connection_is_recycled_after_its_lifetime() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    // A long backoff, so a reconnect that escalated would miss the deadline
+    let endpoint = Endpoint {
+        name: "recycled".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 30,
+            max_delay: 30,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        max_connection_lifetime_secs: 1,
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move { Monitor::new(endpoint, vec![]).run().await });
+
+    for _ in 0..2 {
+        let (stream, _) = timeout(Duration::from_secs(3), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let started = std::time::Instant::now();
+
+        // The client says goodbye on its own once the lifetime is up
+        let closed = timeout(Duration::from_secs(3), async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if msg.is_close() {
+                    return true;
+                }
+            }
+            false
+        })
+        .await
+        .unwrap();
+        assert!(closed);
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_dropped_connections_back_off_with_unlimited_retries() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "dropped".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: -1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move { Monitor::new(endpoint, vec![]).run().await });
+
+    // Every connection is closed as soon as it is opened
+    let mut attempts = 0;
+    let _ = timeout(Duration::from_secs(3), async {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            attempts += 1;
+            if let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await {
+                let _ = ws.close(None).await;
+            }
+        }
+    })
+    .await;
+
+    // One right away, then one per second rather than as fast as possible
+    assert!((2..=4).contains(&attempts), "{} attempts", attempts);
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_flood_of_server_commands_is_dropped() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "flooded".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        max_commands_per_sec: 10,
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move { Monitor::new(endpoint, vec![]).run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    for _ in 0..1000 {
+        ws.send(Message::Text(r#"{"type":"get_info","data":null}"#.into()))
+            .await
+            .unwrap();
+    }
+
+    let mut vm_infos = 0;
+    let _ = timeout(Duration::from_secs(2), async {
+        while let Some(Ok(msg)) = ws.next().await {
+            if message_type(&msg).as_deref() == Some("vm_info") {
+                vm_infos += 1;
+            }
+        }
+    })
+    .await;
+
+    // A burst of two, then two a second
+    assert!(vm_infos >= 1, "no get_info was answered");
+    assert!(vm_infos <= 8, "{} get_info answered", vm_infos);
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_reconfigure_only_applies_when_allowed() {
+    async fn next_metrics(
+        ws: &mut tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+    ) -> serde_json::Value {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let Some(Ok(Message::Binary(binary))) = ws.next().await else {
+                    continue;
+                };
+                let msg: api::Message<serde_json::Value> = rmp_serde::from_slice(&binary).unwrap();
+                if msg.r#type == "metrics" {
+                    return msg.data;
+                }
+            }
+        })
+        .await
+        .expect("no metrics received")
+    }
+
+    for allowed in [true, false] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let endpoint = Endpoint {
+            name: "managed".to_string(),
+            server: format!("ws://{}", addr),
+            secret: "secret".to_string(),
+            connection: Some(ConnectionConfig {
+                base_delay: 1,
+                max_delay: 1,
+                max_retries: 0,
+                ..Default::default()
+            }),
+            allow_remote_reconfigure: allowed,
+            ..Default::default()
+        };
+        let monitor = tokio::spawn(async move {
+            Monitor::new(endpoint, vec![Collector::System, Collector::Disk])
+                .run()
+                .await
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        assert!(next_metrics(&mut ws).await.get("disk").is_some());
+
+        ws.send(Message::Text(
+            r#"{"type":"reconfigure","data":{"collectors":["system"]}}"#.into(),
+        ))
+        .await
+        .unwrap();
+        // Makes the next sample go out right away, whether or not the
+        // reconfigure was applied
+        ws.send(Message::Text(
+            r#"{"type":"update_config","data":{"metrics_interval":1}}"#.into(),
+        ))
+        .await
+        .unwrap();
+
+        let report = next_metrics(&mut ws).await;
+        assert!(report.get("system").is_some());
+        assert_eq!(report.get("disk").is_none(), allowed, "{}", report);
+
+        monitor.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_reconfigure_switches_the_format() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "managed".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        allow_remote_reconfigure: true,
+        ..Default::default()
+    };
+    let monitor =
+        tokio::spawn(async move { Monitor::new(endpoint, vec![Collector::System]).run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+    ws.send(Message::Text(
+        r#"{"type":"reconfigure","data":{"format":"array","metrics_interval":1}}"#.into(),
+    ))
+    .await
+    .unwrap();
+
+    // The first array is preceded by its schema
+    let types = timeout(Duration::from_secs(5), async {
+        let mut types = Vec::new();
+        while let Some(Ok(msg)) = ws.next().await {
+            let Some(r#type) = message_type(&msg) else {
+                continue;
+            };
+            if r#type == "metrics_array" {
+                types.push(r#type);
+                return types;
+            }
+            if r#type.starts_with("metrics") {
+                types.push(r#type);
+            }
+        }
+        types
+    })
+    .await
+    .expect("no metrics_array received");
+    assert!(
+        types.ends_with(&["metrics_schema".to_string(), "metrics_array".to_string()]),
+        "{:?}",
+        types
+    );
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_split_by_collector_sends_typed_messages() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "split".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        split_by_collector: true,
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move {
+        Monitor::new(
+            endpoint,
+            vec![Collector::System, Collector::Network, Collector::Disk],
+        )
+        .run()
+        .await
+    });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let mut messages = Vec::new();
+    while messages.len() < 3 {
+        let frame = timeout(Duration::from_secs(5), ws.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        if let Message::Binary(binary) = frame {
+            messages
+                .push(rmp_serde::from_slice::<api::Message<serde_json::Value>>(&binary).unwrap());
+        }
+    }
+
+    let types: Vec<&str> = messages.iter().map(|m| m.r#type.as_str()).collect();
+    assert_eq!(types, ["system_metrics", "network_metrics", "disk_metrics"]);
+    for (message, section) in messages.iter().zip(["system", "network", "disk"]) {
+        assert!(message.data.get(section).is_some(), "{:?}", message);
+        assert_eq!(message.data["seq"], 1);
+        assert_eq!(message.data["collectedAt"], messages[0].data["collectedAt"]);
+        assert!(message.data["collectedAt"].is_u64());
+    }
+    // Each section is only sent once
+    assert!(messages[0].data.get("disk").is_none());
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_array_format_decodes_with_advertised_schema() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "array".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        metrics_interval: Some(1),
+        format: MetricsFormat::Array,
+        ..Default::default()
+    };
+    let monitor =
+        tokio::spawn(async move { Monitor::new(endpoint, vec![Collector::System]).run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let mut schema: Option<api::MetricsSchema> = None;
+    let mut reports = Vec::new();
+    while reports.len() < 2 {
+        let frame = timeout(Duration::from_secs(5), ws.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let Message::Binary(binary) = frame else {
+            continue;
+        };
+        let message = rmp_serde::from_slice::<api::Message<serde_json::Value>>(&binary).unwrap();
+        match message.r#type.as_str() {
+            "metrics_schema" => {
+                let new: api::MetricsSchema = serde_json::from_value(message.data).unwrap();
+                if let Some(old) = &schema {
+                    // Fields are only appended
+                    assert!(new.version > old.version);
+                    assert_eq!(new.fields[..old.fields.len()], old.fields[..]);
+                }
+                schema = Some(new);
+            }
+            "metrics_array" => {
+                let array: api::MetricsArray = serde_json::from_value(message.data).unwrap();
+                let schema = schema.as_ref().expect("schema is sent before the values");
+                let report = api::decode_array(schema, &array).unwrap();
+                let report: ReportData = serde_json::from_value(report).unwrap();
+
+                // The positional frame is smaller than the named one
+                let named = rmp_serde::to_vec_named(&api::Message {
+                    r#type: "metrics".to_string(),
+                    data: &report,
+                })
+                .unwrap();
+                assert!(
+                    binary.len() < named.len(),
+                    "{} >= {}",
+                    binary.len(),
+                    named.len()
+                );
+                reports.push(report);
+            }
+            other => panic!("unexpected message {}", other),
+        }
+    }
+
+    let system = reports[1].system.as_ref().unwrap();
+    assert!(system.memory_total > 0);
+    assert!(reports[1].uptime >= reports[0].uptime);
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_silent_server_is_reconnected_after_pong_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "silent".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: -1,
+            ping_interval: 1,
+            pong_timeout: 1,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let monitor =
+        tokio::spawn(async move { Monitor::new(endpoint, vec![Collector::System]).run().await });
+
+    // Accepts, then never reads, so pings go unanswered like on a half-open
+    // connection
+    let (stream, _) = listener.accept().await.unwrap();
+    let silent = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let reconnect = timeout(Duration::from_secs(10), listener.accept()).await;
+    assert!(reconnect.is_ok(), "monitor did not reconnect");
+    drop(silent);
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_metrics_collected_while_disconnected_are_sent_on_reconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "offline".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: -1,
+            ..Default::default()
+        }),
+        metrics_interval: Some(1),
+        offline_buffer_size: 10,
+        ..Default::default()
+    };
+    let monitor =
+        tokio::spawn(async move { Monitor::new(endpoint, vec![Collector::System]).run().await });
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+    while message_type(&ws.next().await.unwrap().unwrap()).as_deref() != Some("metrics") {}
+    ws.close(None).await.unwrap();
+    drop(ws);
+
+    // Reconnect attempts wait in the backlog until accepted
+    tokio::time::sleep(Duration::from_millis(3500)).await;
+    let reconnected_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let mut collected_at = Vec::new();
+    while let Ok(Some(Ok(frame))) = timeout(Duration::from_millis(500), ws.next()).await {
+        if let Message::Binary(binary) = frame {
+            let message =
+                rmp_serde::from_slice::<api::Message<serde_json::Value>>(&binary).unwrap();
+            if message.r#type == "metrics" {
+                collected_at.push(message.data["collectedAt"].as_u64().unwrap());
+            }
+        }
+    }
+
+    // Sent in the order collected, the ones from the outage first
+    assert!(
+        collected_at.windows(2).all(|pair| pair[0] <= pair[1]),
+        "{:?}",
+        collected_at
+    );
+    let buffered = collected_at
+        .iter()
+        .filter(|at| **at < reconnected_at)
+        .count();
+    assert!(
+        buffered >= 2,
+        "{:?} before {}",
+        collected_at,
+        reconnected_at
+    );
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_array_metrics_buffered_before_the_first_connect_decode() {
+    // Nothing listens until the monitor has buffered a few samples
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    let endpoint = Endpoint {
+        name: "offline-array".to_string(),
+        server: format!("ws://{}", addr),
+        secret: "secret".to_string(),
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: -1,
+            ..Default::default()
+        }),
+        metrics_interval: Some(1),
+        format: MetricsFormat::Array,
+        // Fewer than the samples taken, so the first ones are dropped
+        offline_buffer_size: 2,
+        ..Default::default()
+    };
+    let monitor =
+        tokio::spawn(async move { Monitor::new(endpoint, vec![Collector::System]).run().await });
+
+    tokio::time::sleep(Duration::from_millis(3500)).await;
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let mut schema: Option<api::MetricsSchema> = None;
+    let mut reports = Vec::new();
+    while reports.len() < 3 {
+        let frame = timeout(Duration::from_secs(5), ws.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let Message::Binary(binary) = frame else {
+            continue;
+        };
+        let message = rmp_serde::from_slice::<api::Message<serde_json::Value>>(&binary).unwrap();
+        match message.r#type.as_str() {
+            "metrics_schema" => schema = Some(serde_json::from_value(message.data).unwrap()),
+            "metrics_array" => {
+                let array: api::MetricsArray = serde_json::from_value(message.data).unwrap();
+                let schema = schema.as_ref().expect("schema is sent before the values");
+                let report = api::decode_array(schema, &array).unwrap();
+                reports.push(serde_json::from_value::<ReportData>(report).unwrap());
+            }
+            _ => {}
+        }
+    }
+    assert!(reports.iter().all(|report| report.system.is_some()));
+
+    monitor.abort();
+}
+
+#[tokio::test]
+async fn test_summary_and_full_endpoints_share_one_collector() {
+    let shared = SharedCollector::new(4);
+    let mut servers = Vec::new();
+    let mut monitors = Vec::new();
+    for (name, detail_level) in [
+        ("summary", DetailLevel::Summary),
+        ("full", DetailLevel::Full),
+    ] {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Endpoint {
+            name: name.to_string(),
+            server: format!("ws://{}", listener.local_addr().unwrap()),
+            secret: "secret".to_string(),
+            connection: Some(ConnectionConfig {
+                base_delay: 1,
+                max_delay: 1,
+                max_retries: 0,
+                ..Default::default()
+            }),
+            detail_level,
+            ..Default::default()
+        };
+        let monitor =
+            Monitor::new(endpoint, vec![Collector::System]).with_shared_collector(shared.clone());
+        monitors.push(tokio::spawn(async move { monitor.run().await }));
+        let (stream, _) = listener.accept().await.unwrap();
+        servers.push(tokio_tungstenite::accept_async(stream).await.unwrap());
+    }
+
+    // One collection, handed to both
+    let mut metrics = Metrics::with_collectors(vec![Collector::System]);
+    let sample = Arc::new(SharedSample {
+        collected_at: 1_700_000_000_000,
+        mono_ns: 1,
+        report: metrics.collet_metrics().await,
+    });
+    let mut frames = Vec::new();
+    for ws in &mut servers {
+        let frame = timeout(Duration::from_secs(5), async {
+            loop {
+                // Resent until the monitor has subscribed after connecting
+                shared.publish(sample.clone());
+                if let Ok(Some(Ok(Message::Binary(binary)))) =
+                    timeout(Duration::from_millis(200), ws.next()).await
+                {
+                    let message =
+                        rmp_serde::from_slice::<api::Message<serde_json::Value>>(&binary).unwrap();
+                    if message.r#type == "metrics" {
+                        return message.data;
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+        frames.push(frame);
+    }
+
+    let (summary, full) = (&frames[0], &frames[1]);
+    let cores = |frame: &serde_json::Value| {
+        frame["system"]
+            .get("cpuCores")
+            .and_then(|cores| cores.as_array())
+            .map_or(0, |cores| cores.len())
+    };
+    assert_eq!(cores(summary), 0);
+    assert_eq!(
+        cores(full),
+        sample.report.system.as_ref().unwrap().cpu_cores.len()
+    );
+    assert!(cores(full) > 0);
+    assert_eq!(summary["collectedAt"], 1_700_000_000_000u64);
+    assert_eq!(summary["collectedAt"], full["collectedAt"]);
+    assert_eq!(summary["uptime"], full["uptime"]);
+    assert_eq!(summary["system"]["cpuUsage"], full["system"]["cpuUsage"]);
+
+    for monitor in monitors {
+        monitor.abort();
+    }
+}
+
+#[tokio::test]
+async fn test_http_endpoint_posts_json_messages() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let endpoint = Endpoint {
+        name: "ingest".to_string(),
+        server: format!("http://{}", addr),
+        path: "/v1/metrics".to_string(),
+        secret: "secret".to_string(),
+        transport: TransportKind::Http,
+        encoding: Encoding::Json,
+        connection: Some(ConnectionConfig {
+            base_delay: 1,
+            max_delay: 1,
+            max_retries: 0,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let monitor = tokio::spawn(async move { Monitor::new(endpoint, vec![]).run().await });
+
+    // Every message is a POST of its own on the one kept-alive connection.
+    // The server can't ask for the VM info, so it's sent unasked
+    let (stream, _) = timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut stream = BufReader::new(stream);
+    let mut received = Vec::new();
+    while !(received.iter().any(|t| t == "vm_info") && received.iter().any(|t| t == "metrics")) {
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await.unwrap();
+        assert!(
+            request_line.starts_with("POST /v1/metrics"),
+            "{}",
+            request_line
+        );
+        let mut content_length = 0;
+        let mut content_type = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let Some((name, value)) = line.trim_end().split_once(": ") else {
+                break;
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().unwrap(),
+                "content-type" => content_type = value.to_string(),
+                _ => {}
+            }
+        }
+        assert_eq!(content_type, "application/json");
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await.unwrap();
+        let message: api::Message<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        received.push(message.r#type);
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+    }
+
+    monitor.abort();
+}
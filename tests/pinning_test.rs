@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::TlsAcceptor;
+use vmonitor::api;
+use vmonitor::config::{ConnectionConfig, TrustedSelfSigned};
+
+const CERT: &[u8] = include_bytes!("fixtures/pinned_cert.pem");
+const KEY: &[u8] = include_bytes!("fixtures/pinned_key.pem");
+/// `openssl x509 -noout -fingerprint -sha256 -in fixtures/pinned_cert.pem`
+const FINGERPRINT: &str =
+    "81:7B:DC:6F:C8:FF:57:6B:8C:4D:64:84:B1:1E:C3:57:10:40:1E:0B:58:D5:E2:0A:E8:44:19:F7:29:1D:61:C9";
+
+/// Serves WebSocket over TLS with the fixture certificate, returning the
+/// server URL.
+async fn spawn_tls_server() -> String {
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .unwrap()
+    .with_no_client_auth()
+    .with_single_cert(
+        vec![CertificateDer::from_pem_slice(CERT).unwrap()],
+        PrivateKeyDer::from_pem_slice(KEY).unwrap(),
+    )
+    .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                if let Ok(tls) = acceptor.accept(stream).await {
+                    let _ws = tokio_tungstenite::accept_async(tls).await;
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            });
+        }
+    });
+    format!("wss://{}", addr)
+}
+
+fn connection() -> ConnectionConfig {
+    ConnectionConfig {
+        base_delay: 1,
+        max_delay: 1,
+        max_retries: 3,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_matching_pin_connects() {
+    let server = spawn_tls_server().await;
+    let socket = timeout(
+        Duration::from_secs(5),
+        api::try_connect_websocket(
+            &server,
+            "/wss/probe",
+            "secret",
+            &BTreeMap::new(),
+            &connection(),
+            Some(FINGERPRINT),
+            &[],
+        ),
+    )
+    .await
+    .unwrap();
+    assert!(socket.is_ok());
+}
+
+#[tokio::test]
+async fn test_mismatched_pin_is_rejected_without_retrying() {
+    let server = spawn_tls_server().await;
+    let wrong_pin = "00".repeat(32);
+    // Retries would take at least a second each
+    let socket = timeout(
+        Duration::from_millis(900),
+        api::try_connect_websocket(
+            &server,
+            "/wss/probe",
+            "secret",
+            &BTreeMap::new(),
+            &connection(),
+            Some(&wrong_pin),
+            &[],
+        ),
+    )
+    .await
+    .expect("a pin mismatch must not be retried");
+    assert_eq!(socket.err(), Some(api::ConnectError::PinMismatch));
+}
+
+#[test]
+fn test_parse_fingerprint() {
+    assert_eq!(
+        api::parse_fingerprint(FINGERPRINT).unwrap(),
+        FINGERPRINT.replace(':', "").to_lowercase()
+    );
+    assert!(api::parse_fingerprint("AB:CD").is_err());
+}
+
+#[tokio::test]
+async fn test_self_signed_cert_trusted_only_for_listed_hosts() {
+    let server = spawn_tls_server().await;
+    let trusted = |host: &str, sha256: Option<&str>| {
+        vec![TrustedSelfSigned {
+            host: host.to_string(),
+            sha256: sha256.map(str::to_string),
+        }]
+    };
+    let no_retries = ConnectionConfig {
+        max_retries: 0,
+        ..connection()
+    };
+    let connect = |trusted: Vec<TrustedSelfSigned>| {
+        let server = server.clone();
+        async move {
+            timeout(
+                Duration::from_secs(5),
+                api::try_connect_websocket(
+                    &server,
+                    "/wss/probe",
+                    "secret",
+                    &BTreeMap::new(),
+                    &no_retries,
+                    None,
+                    &trusted,
+                ),
+            )
+            .await
+            .unwrap()
+            .is_ok()
+        }
+    };
+
+    assert!(connect(trusted("127.0.0.1", None)).await);
+    assert!(connect(trusted("127.0.0.*", Some(FINGERPRINT))).await);
+    // Other hosts keep full verification
+    assert!(!connect(trusted("collector.internal", None)).await);
+    assert!(!connect(vec![]).await);
+    // A fingerprint narrows it to that one certificate
+    assert!(!connect(trusted("127.0.0.1", Some(&"00".repeat(32)))).await);
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use common::TestConfig;
+use tokio::time::{sleep, timeout, Duration};
+use vmonitor::app::App;
+use vmonitor::config::{AppConfig, Collector, ReportConfig, SinkConfig};
+use vmonitor::features::metrics::Metrics;
+use vmonitor::history::{now_millis, HistorySample};
+use vmonitor::sinks;
+
+#[tokio::test]
+async fn test_file_sink_flushed_on_shutdown() {
+    let test_config = TestConfig::new();
+    let sink_path = test_config.config_path.with_file_name("metrics.jsonl");
+
+    let config = AppConfig {
+        report: ReportConfig {
+            interval_secs: 1,
+            ..Default::default()
+        },
+        sinks: vec![SinkConfig::File {
+            path: sink_path.to_str().unwrap().to_string(),
+        }],
+        ..Default::default()
+    };
+
+    let app = App::new(
+        config,
+        test_config.config_path.to_str().unwrap().to_string(),
+    );
+    let shutdown = app.shutdown_handle();
+    let app_handle = tokio::spawn(async move { app.run().await });
+
+    // Collect a couple of samples, which stay in the sink's write buffer
+    sleep(Duration::from_millis(1500)).await;
+    shutdown.notify_one();
+
+    timeout(Duration::from_secs(2), app_handle)
+        .await
+        .expect("App failed to shutdown")
+        .expect("App panicked");
+
+    let contents = std::fs::read_to_string(&sink_path).unwrap();
+    assert!(contents.ends_with('\n'));
+    let samples: Vec<HistorySample> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert!(!samples.is_empty());
+}
+
+#[tokio::test]
+async fn test_statsd_sink_sends_gauges() {
+    let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+
+    let mut metrics = Metrics::with_collectors(vec![Collector::System]);
+    let sample = HistorySample {
+        collected_at: now_millis(),
+        report: metrics.collet_metrics().await,
+    };
+    let system = sample.report.system.as_ref().unwrap();
+
+    let mut sink = sinks::build(&SinkConfig::Statsd {
+        address: receiver.local_addr().unwrap().to_string(),
+        prefix: "vm".to_string(),
+        host: Some("web-1.example.com".to_string()),
+    })
+    .unwrap();
+    sink.write(&sample).unwrap();
+
+    let mut lines = Vec::new();
+    let mut buf = [0; 2048];
+    while !lines
+        .iter()
+        .any(|l: &String| l.contains(".load_avg.fifteen:"))
+    {
+        let len = receiver.recv(&mut buf).unwrap();
+        let datagram = std::str::from_utf8(&buf[..len]).unwrap();
+        lines.extend(datagram.lines().map(str::to_string));
+    }
+
+    assert!(lines.contains(&format!(
+        "vm.web-1_example_com.system.memory_total:{}|g",
+        system.memory_total
+    )));
+    assert!(lines.contains(&format!(
+        "vm.web-1_example_com.system.process_count:{}|g",
+        system.process_count
+    )));
+    assert!(lines.contains(&format!(
+        "vm.web-1_example_com.uptime:{}|g",
+        sample.report.uptime
+    )));
+    assert!(lines
+        .iter()
+        .all(|l| l.starts_with("vm.web-1_example_com.") && l.ends_with("|g")));
+}
+
+#[tokio::test]
+async fn test_graphite_sink_sends_lines() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut metrics = Metrics::with_collectors(vec![Collector::System]);
+    let sample = HistorySample {
+        collected_at: now_millis(),
+        report: metrics.collet_metrics().await,
+    };
+    let system = sample.report.system.as_ref().unwrap();
+
+    let mut sink = sinks::build(&SinkConfig::Graphite {
+        address: listener.local_addr().unwrap().to_string(),
+        prefix: "vm".to_string(),
+        host: Some("web-1.example.com".to_string()),
+    })
+    .unwrap();
+    sink.write(&sample).unwrap();
+    sink.flush().unwrap();
+    drop(sink);
+
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut received = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut received).unwrap();
+
+    let timestamp = sample.collected_at / 1000;
+    assert!(received.lines().any(|line| line
+        == format!(
+            "vm.web-1_example_com.system.memory_total {} {}",
+            system.memory_total, timestamp
+        )));
+    assert!(received.lines().all(|line| {
+        let fields: Vec<&str> = line.split(' ').collect();
+        fields.len() == 3
+            && fields[0].starts_with("vm.web-1_example_com.")
+            && fields[1].parse::<f64>().is_ok()
+            && fields[2] == timestamp.to_string()
+    }));
+}
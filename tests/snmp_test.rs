@@ -0,0 +1,163 @@
+#![cfg(feature = "snmp")]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use vmonitor::config::SnmpDevice;
+use vmonitor::features::metrics::Metrics;
+use vmonitor::features::snmp::{
+    poll_every, Message, SnmpPoll, SnmpReading, SnmpValue, PDU_GET_REQUEST, PDU_RESPONSE,
+};
+
+const SYS_UPTIME: &str = "1.3.6.1.2.1.1.3.0";
+const UPS_BATTERY_CAPACITY: &str = "1.3.6.1.2.1.33.1.2.4.0";
+
+/// Answers GET requests for the two known OIDs, reporting any other OID as
+/// missing. Returns the agent's address.
+async fn spawn_agent(community: &'static str) -> String {
+    spawn_agent_on("127.0.0.1:0", community, Arc::default()).await
+}
+
+/// Like [`spawn_agent`], on `bind` and counting the requests in `requests`.
+async fn spawn_agent_on(bind: &str, community: &'static str, requests: Arc<AtomicUsize>) -> String {
+    let socket = UdpSocket::bind(bind).await.unwrap();
+    let addr = socket.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 65535];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let request = Message::decode(&buf[..len]).unwrap();
+            assert_eq!(request.pdu_type, PDU_GET_REQUEST);
+            requests.fetch_add(1, Ordering::SeqCst);
+            if request.community != community {
+                continue;
+            }
+            let bindings = request
+                .bindings
+                .into_iter()
+                .map(|(oid, _)| {
+                    let value = match oid.as_str() {
+                        SYS_UPTIME => Some(SnmpValue::Unsigned(123_456)),
+                        UPS_BATTERY_CAPACITY => Some(SnmpValue::Integer(87)),
+                        _ => None,
+                    };
+                    (oid, value)
+                })
+                .collect();
+            let response = Message {
+                pdu_type: PDU_RESPONSE,
+                bindings,
+                ..request
+            };
+            socket
+                .send_to(&response.encode().unwrap(), peer)
+                .await
+                .unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_snmp_values_are_reported() {
+    let host = spawn_agent("monitoring").await;
+    let mut metrics = Metrics::with_collectors(vec![]);
+    metrics.snmp_devices = vec![SnmpDevice {
+        name: Some("ups".to_string()),
+        host,
+        community: "monitoring".to_string(),
+        oids: vec![
+            SYS_UPTIME.to_string(),
+            UPS_BATTERY_CAPACITY.to_string(),
+            "1.3.6.1.4.1.99999.1.0".to_string(),
+        ],
+    }];
+
+    let report = metrics.collet_metrics().await;
+    assert_eq!(
+        report.snmp,
+        vec![
+            SnmpReading {
+                device: "ups".to_string(),
+                oid: SYS_UPTIME.to_string(),
+                value: SnmpValue::Unsigned(123_456),
+            },
+            SnmpReading {
+                device: "ups".to_string(),
+                oid: UPS_BATTERY_CAPACITY.to_string(),
+                value: SnmpValue::Integer(87),
+            },
+        ]
+    );
+    assert!(report.collection_errors.is_empty());
+}
+
+#[tokio::test]
+async fn test_unreachable_device_is_skipped() {
+    // Wrong community, so the agent never answers
+    let host = spawn_agent("monitoring").await;
+    let mut metrics = Metrics::with_collectors(vec![]);
+    metrics.snmp_devices = vec![SnmpDevice {
+        name: None,
+        host: host.clone(),
+        community: "public".to_string(),
+        oids: vec![SYS_UPTIME.to_string()],
+    }];
+
+    let report = metrics.collet_metrics().await;
+    assert!(report.snmp.is_empty());
+    assert_eq!(
+        report.collection_errors,
+        vec![format!("snmp {}: no answer within 2s", host)]
+    );
+}
+
+#[tokio::test]
+async fn test_ipv6_device_is_polled() {
+    let Ok(probe) = UdpSocket::bind("[::1]:0").await else {
+        // No IPv6 loopback in this environment
+        return;
+    };
+    drop(probe);
+    let host = spawn_agent_on("[::1]:0", "public", Arc::default()).await;
+    assert!(host.starts_with("[::1]:"));
+    let mut metrics = Metrics::with_collectors(vec![]);
+    metrics.snmp_devices = vec![SnmpDevice {
+        name: None,
+        host,
+        community: "public".to_string(),
+        oids: vec![SYS_UPTIME.to_string()],
+    }];
+
+    let report = metrics.collet_metrics().await;
+    assert!(report.collection_errors.is_empty());
+    assert_eq!(report.snmp.len(), 1);
+    assert_eq!(report.snmp[0].value, SnmpValue::Unsigned(123_456));
+}
+
+#[tokio::test]
+async fn test_one_poll_is_shared_by_every_collector() {
+    let requests = Arc::new(AtomicUsize::new(0));
+    let host = spawn_agent_on("127.0.0.1:0", "public", requests.clone()).await;
+    let device = SnmpDevice {
+        name: Some("switch".to_string()),
+        host,
+        community: "public".to_string(),
+        oids: vec![SYS_UPTIME.to_string()],
+    };
+    let (polls, mut rx) = watch::channel(SnmpPoll::default());
+    tokio::spawn(poll_every(vec![device], Duration::from_secs(3600), polls));
+    rx.changed().await.unwrap();
+
+    for _ in 0..3 {
+        let mut metrics = Metrics::with_collectors(vec![]);
+        metrics.snmp_shared = Some(rx.clone());
+        let report = metrics.collet_metrics().await;
+        assert_eq!(report.snmp.len(), 1);
+        assert_eq!(report.snmp[0].device, "switch");
+    }
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,53 @@
+#![cfg(feature = "sqlite")]
+
+use tempfile::tempdir;
+use vmonitor::config::Collector;
+use vmonitor::features::metrics::Metrics;
+use vmonitor::history::{now_millis, HistorySample};
+use vmonitor::sinks::{Sink, SqliteSink};
+
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+#[tokio::test]
+async fn test_sqlite_sink_inserts_and_prunes_samples() {
+    let temp_dir = tempdir().unwrap();
+    let path = temp_dir.path().join("metrics.db");
+    let path = path.to_str().unwrap();
+
+    let mut metrics = Metrics::with_collectors(vec![Collector::System, Collector::Disk]);
+    let report = metrics.collet_metrics().await;
+    let now = now_millis();
+
+    let mut sink = SqliteSink::open(path, 7).unwrap();
+    for collected_at in [now, now - 10 * DAY_MILLIS] {
+        sink.write(&HistorySample {
+            collected_at,
+            report: report.clone(),
+        })
+        .unwrap();
+    }
+    sink.flush().unwrap();
+
+    let db = rusqlite::Connection::open(path).unwrap();
+    let count = |db: &rusqlite::Connection| -> i64 {
+        db.query_row("SELECT COUNT(*) FROM samples", [], |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(count(&db), 2);
+
+    let (memory_total, report_json): (i64, String) = db
+        .query_row(
+            "SELECT memory_total, report FROM samples WHERE collected_at = ?1",
+            [now as i64],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    let system = report.system.as_ref().unwrap();
+    assert_eq!(memory_total as u64, system.memory_total);
+    let stored: serde_json::Value = serde_json::from_str(&report_json).unwrap();
+    assert_eq!(stored["uptime"], report.uptime);
+
+    // Only the sample from before the retention goes
+    assert_eq!(sink.prune(now_millis()).unwrap(), 1);
+    assert_eq!(count(&db), 1);
+}
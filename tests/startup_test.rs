@@ -0,0 +1,39 @@
+use tokio::net::TcpListener;
+use tokio::time::{timeout, Duration, Instant};
+use vmonitor::app::App;
+use vmonitor::config::{AppConfig, ConnectionConfig, Endpoint};
+
+#[tokio::test]
+async fn test_first_connect_waits_for_startup_delay() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = AppConfig {
+        endpoints: vec![Endpoint {
+            name: "delayed".to_string(),
+            server: format!("ws://{}", listener.local_addr().unwrap()),
+            secret: "secret".to_string(),
+            connection: Some(ConnectionConfig {
+                base_delay: 1,
+                max_delay: 1,
+                max_retries: 0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        startup_delay_secs: 1,
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    let app = App::new(config, "config.toml".to_string());
+    let shutdown = app.shutdown_handle();
+    let app_handle = tokio::spawn(async move { app.run().await });
+
+    timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("no connection attempt")
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    shutdown.notify_one();
+    let _ = timeout(Duration::from_secs(2), app_handle).await;
+}
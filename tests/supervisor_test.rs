@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::broadcast;
+use tokio::time::{timeout, Duration};
+use vmonitor::monitor::MonitorEvent;
+use vmonitor::supervisor::{supervise, RestartPolicy};
+
+fn fast_policy() -> RestartPolicy {
+    RestartPolicy {
+        max_panics: 3,
+        window: Duration::from_secs(60),
+        base_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(50),
+    }
+}
+
+#[tokio::test]
+async fn test_monitor_panicking_once_is_restarted() {
+    let (events, mut received) = broadcast::channel(16);
+    let starts = AtomicUsize::new(0);
+
+    timeout(
+        Duration::from_secs(5),
+        supervise("flaky", fast_policy(), Some(events), || {
+            let start = starts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if start == 0 {
+                    panic!("collector bug");
+                }
+            }
+        }),
+    )
+    .await
+    .expect("supervisor did not return after the monitor finished");
+
+    assert_eq!(starts.load(Ordering::SeqCst), 2);
+    match received.try_recv().unwrap() {
+        MonitorEvent::Panicked {
+            endpoint,
+            message,
+            restarting,
+        } => {
+            assert_eq!(endpoint, "flaky");
+            assert_eq!(message, "collector bug");
+            assert!(restarting);
+        }
+        event => panic!("unexpected event {:?}", event),
+    }
+    assert!(received.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_repeated_panics_give_up() {
+    let (events, mut received) = broadcast::channel(16);
+    let starts = AtomicUsize::new(0);
+
+    timeout(
+        Duration::from_secs(5),
+        supervise("broken", fast_policy(), Some(events), || {
+            starts.fetch_add(1, Ordering::SeqCst);
+            async { panic!("always") }
+        }),
+    )
+    .await
+    .expect("supervisor kept restarting");
+
+    assert_eq!(starts.load(Ordering::SeqCst), 3);
+    let mut last = None;
+    while let Ok(MonitorEvent::Panicked { restarting, .. }) = received.try_recv() {
+        last = Some(restarting);
+    }
+    assert_eq!(last, Some(false));
+}
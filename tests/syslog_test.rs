@@ -0,0 +1,89 @@
+#![cfg(feature = "syslog")]
+
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use vmonitor::config::{Collector, SinkConfig, SyslogAlert};
+use vmonitor::features::metrics::Metrics;
+use vmonitor::history::{now_millis, HistorySample};
+use vmonitor::sinks;
+
+#[tokio::test]
+async fn test_syslog_sink_sends_summary_and_alert() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let mut metrics = Metrics::with_collectors(vec![Collector::System]);
+    let sample = HistorySample {
+        collected_at: now_millis(),
+        report: metrics.collet_metrics().await,
+    };
+    let system = sample.report.system.as_ref().unwrap();
+
+    let mut sink = sinks::build(&SinkConfig::Syslog {
+        address: receiver.local_addr().unwrap().to_string(),
+        facility: "daemon".to_string(),
+        metrics: vec![
+            "system.memory_total".to_string(),
+            "system.process_count".to_string(),
+        ],
+        alerts: vec![SyslogAlert {
+            metric: "system.memory_total".to_string(),
+            above: 0.0,
+            severity: "warning".to_string(),
+        }],
+    })
+    .unwrap();
+    sink.write(&sample).unwrap();
+
+    let mut buf = [0; 2048];
+    let mut recv = || {
+        let len = receiver.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    };
+
+    // daemon (3) * 8 + info (6)
+    let summary = recv();
+    assert!(summary.starts_with("<30>1 "), "{}", summary);
+    let fields: Vec<&str> = summary.splitn(8, ' ').collect();
+    assert_eq!(fields.len(), 8);
+    assert!(fields[1].ends_with('Z'));
+    assert_eq!(fields[3], "vmonitor");
+    assert_eq!(fields[5], "1");
+    assert_eq!(fields[6], "[metrics@32473");
+    assert!(summary.contains(&format!(" system.memory_total=\"{}\"", system.memory_total)));
+    assert!(summary.contains(&format!(
+        " system.process_count=\"{}\"",
+        system.process_count
+    )));
+
+    // daemon (3) * 8 + warning (4)
+    let alert = recv();
+    assert!(alert.starts_with("<28>1 "), "{}", alert);
+    assert!(alert.contains(" 2 [alert@32473 "));
+    assert!(alert.contains(" metric=\"system.memory_total\""));
+
+    // Still above the threshold, so only the summary is sent again
+    sink.write(&sample).unwrap();
+    assert!(recv().starts_with("<30>1 "));
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    assert!(receiver.recv(&mut buf).is_err());
+}
+
+#[test]
+fn test_syslog_sink_rejects_unknown_facility() {
+    let result = sinks::build(&SinkConfig::Syslog {
+        address: "127.0.0.1:514".to_string(),
+        facility: "nope".to_string(),
+        metrics: Vec::new(),
+        alerts: Vec::new(),
+    });
+    assert_eq!(
+        result.err().unwrap().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+}